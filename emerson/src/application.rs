@@ -5,48 +5,105 @@ use iced::{
     Event::{KeyPressed, KeyReleased},
     KeyCode,
   },
-  window, Application, Color, Column, Command, Container, Element, Settings, Subscription,
+  window, Align, Application, Color, Column, Command, Container, Element, Length, Settings,
+  Subscription, Text,
 };
-use palmer::audio::AudioDriver;
-use palmer::input::Button;
-use palmer::Chip8;
-use std::fs::File;
+use palmer::audio::Silent;
+use palmer::input::{Button, Input, BUTTON_LIST};
+use palmer::keymap::{Keymap, KeymapConfig};
+use palmer::movie::{self, Movie};
+use palmer::{Chip8, EmulationError, Quirks, XorShift};
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 mod audio;
 mod grid;
+mod keys;
 
-use audio::NativeAudioDriver;
+use audio::{AudioOutput, SAMPLE_RATE};
 use grid::Grid;
+use keys::key_name;
+
+/// F1 to F4 save to the matching slot, holding shift loads from it instead.
+const SAVE_SLOT_KEYS: [KeyCode; 4] = [KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4];
+/// Restarts the game and records a movie of it, until `STOP_MOVIE_KEY`.
+const RECORD_MOVIE_KEY: KeyCode = KeyCode::F5;
+const STOP_MOVIE_KEY: KeyCode = KeyCode::F6;
+/// Asks for a new key for each button, for every game, or for this one only
+/// while holding shift.
+const REMAP_KEY: KeyCode = KeyCode::F7;
+const CANCEL_REMAP_KEY: KeyCode = KeyCode::Escape;
+
+/// A keymap being filled in, one button at a time in `BUTTON_LIST` order.
+struct Remapping {
+  keymap: Keymap,
+  next: usize,
+  rom_only: bool,
+}
 
 struct Emerson {
-  engine: palmer::Chip8<NativeAudioDriver>,
+  engine: palmer::Chip8<Silent>,
+  audio: AudioOutput,
+  game_path: PathBuf,
+  rom: Vec<u8>,
+  movie: Option<Movie>,
+  keymaps: KeymapConfig,
+  remapping: Option<Remapping>,
   display: grid::Grid,
   cartridge_loaded: bool,
+  error: Option<EmulationError>,
+  last_tick: Option<Instant>,
 }
 
-fn parse_key(key: KeyCode) -> Option<Button> {
-  match key {
-    KeyCode::Key1 => Some(Button::One),
-    KeyCode::Key2 => Some(Button::Two),
-    KeyCode::Key3 => Some(Button::Three),
-    KeyCode::Key4 => Some(Button::C),
-    KeyCode::Q => Some(Button::Four),
-    KeyCode::W => Some(Button::Five),
-    KeyCode::E => Some(Button::Six),
-    KeyCode::R => Some(Button::D),
-    KeyCode::A => Some(Button::Seven),
-    KeyCode::S => Some(Button::Eight),
-    KeyCode::D => Some(Button::Nine),
-    KeyCode::F => Some(Button::E),
-    KeyCode::Z => Some(Button::A),
-    KeyCode::X => Some(Button::Zero),
-    KeyCode::C => Some(Button::B),
-    KeyCode::V => Some(Button::F),
-    _ => None,
-  }
+fn parse_save_slot(key: KeyCode) -> Option<usize> {
+  SAVE_SLOT_KEYS
+    .iter()
+    .position(|slot_key| *slot_key == key)
+    .map(|position| position + 1)
+}
+
+/// Save states live next to the ROM, as `<rom>.state1` to `<rom>.state4`.
+fn state_path(game_path: &Path, slot: usize) -> PathBuf {
+  let mut path = game_path.as_os_str().to_owned();
+  path.push(format!(".state{}", slot));
+  PathBuf::from(path)
+}
+
+/// Movies live next to the ROM too, as `<rom>.movie`.
+fn movie_path(game_path: &Path) -> PathBuf {
+  let mut path = game_path.as_os_str().to_owned();
+  path.push(".movie");
+  PathBuf::from(path)
+}
+
+/// The keymaps live in the home directory, as `.xipe-oito-keymap`, since
+/// they are shared by every ROM.
+fn keymap_path() -> PathBuf {
+  std::env::var_os("HOME")
+    .map(PathBuf::from)
+    .unwrap_or_default()
+    .join(".xipe-oito-keymap")
+}
+
+fn load_keymaps() -> KeymapConfig {
+  let path = keymap_path();
+  let text = match fs::read_to_string(&path) {
+    Ok(text) => text,
+    Err(_) => return KeymapConfig::default(),
+  };
+  KeymapConfig::parse(&text).unwrap_or_else(|error| {
+    eprintln!("Could not load {}: {}", path.display(), error);
+    KeymapConfig::default()
+  })
+}
+
+fn seed() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|time| time.as_nanos() as u64)
+    .unwrap_or(XorShift::DEFAULT_SEED)
 }
 
 #[derive(Debug, Clone)]
@@ -81,6 +138,128 @@ pub fn run(game_path: PathBuf) -> iced::Result {
   })
 }
 
+impl Emerson {
+  /// Sends the buzzer's samples for the frames that just ran to the output.
+  fn play_audio(&mut self) {
+    let mut samples = vec![0.; self.engine.pending_audio_samples(SAMPLE_RATE)];
+    self.engine.fill_audio(&mut samples, SAMPLE_RATE);
+    self.audio.push(samples);
+  }
+
+  /// Has the grid draw again the lines that changed since the last frame.
+  fn show_changes(&mut self) {
+    let lines: Vec<usize> = self
+      .engine
+      .display
+      .dirty_regions()
+      .map(|region| region.y)
+      .collect();
+    if !lines.is_empty() {
+      self.display.update(grid::Message::Show(lines));
+      self.engine.display.acknowledge();
+    }
+  }
+
+  fn keymap(&self) -> &Keymap {
+    self.keymaps.for_rom(movie::rom_hash(&self.rom))
+  }
+
+  fn button(&self, key_code: KeyCode) -> Option<Button> {
+    key_name(key_code).and_then(|name| self.keymap().button(name))
+  }
+
+  fn start_remapping(&mut self, rom_only: bool) {
+    // Keys held now would never be released for the game.
+    self.engine.input = Input::new();
+    self.remapping = Some(Remapping {
+      keymap: self.keymap().clone(),
+      next: 0,
+      rom_only,
+    });
+  }
+
+  /// Takes `key_code` for the next button, and saves the keymap once every
+  /// button has one.
+  fn remap(&mut self, key_code: KeyCode) {
+    let remapping = match &mut self.remapping {
+      Some(remapping) => remapping,
+      None => return,
+    };
+    if key_code == CANCEL_REMAP_KEY {
+      self.remapping = None;
+      return;
+    }
+    let name = match key_name(key_code) {
+      Some(name) => name,
+      None => return,
+    };
+    remapping.keymap.remap(BUTTON_LIST[remapping.next], name);
+    remapping.next += 1;
+    if remapping.next < BUTTON_LIST.len() {
+      return;
+    }
+
+    let remapping = self.remapping.take().unwrap();
+    if remapping.rom_only {
+      let hash = movie::rom_hash(&self.rom);
+      self.keymaps.roms.insert(hash, remapping.keymap);
+    } else {
+      self.keymaps.keymap = remapping.keymap;
+    }
+    let path = keymap_path();
+    if let Err(error) = fs::write(&path, self.keymaps.to_text()) {
+      eprintln!("Could not save {}: {}", path.display(), error);
+    }
+  }
+
+  fn save_state(&self, slot: usize) {
+    let path = state_path(&self.game_path, slot);
+    if let Err(error) = fs::write(&path, self.engine.save_state()) {
+      eprintln!("Could not save {}: {}", path.display(), error);
+    }
+  }
+
+  fn load_state(&mut self, slot: usize) {
+    if self.movie.is_some() {
+      eprintln!("Stop recording the movie before loading a state");
+      return;
+    }
+    let path = state_path(&self.game_path, slot);
+    let result = fs::read(&path)
+      .map_err(|error| error.to_string())
+      .and_then(|state| {
+        self
+          .engine
+          .load_state(&state)
+          .map_err(|error| error.to_string())
+      });
+
+    match result {
+      Ok(()) => self.show_changes(),
+      Err(error) => eprintln!("Could not load {}: {}", path.display(), error),
+    }
+  }
+
+  fn record_movie(&mut self) {
+    match Movie::record(&mut self.engine, &self.rom, seed()) {
+      Ok(movie) => {
+        self.movie = Some(movie);
+        self.show_changes();
+      }
+      Err(error) => self.error = Some(error),
+    }
+  }
+
+  fn stop_movie(&mut self) {
+    if let Some(movie) = self.movie.take() {
+      let path = movie_path(&self.game_path);
+      if let Err(error) = fs::write(&path, movie.to_bytes()) {
+        eprintln!("Could not save {}: {}", path.display(), error);
+      }
+    }
+  }
+}
+
 impl Application for Emerson {
   type Message = Message;
   type Executor = executor::Default;
@@ -95,39 +274,64 @@ impl Application for Emerson {
   }
 
   fn new(flags: Flags) -> (Self, Command<Message>) {
-    let mut xipe = Chip8::new(NativeAudioDriver::new());
+    let mut xipe = Chip8::with_random_source(Silent, Quirks::default(), XorShift::new(seed()));
 
-    let mut file = File::open(flags.game_path).unwrap();
+    let mut file = File::open(&flags.game_path).unwrap();
     let mut buffer = Vec::new();
 
     file.read_to_end(&mut buffer).unwrap();
 
-    xipe.load(buffer);
+    let error = xipe.load(buffer.clone()).err();
 
     (
       Self {
         engine: xipe,
+        audio: AudioOutput::new(),
+        game_path: flags.game_path,
+        rom: buffer,
+        movie: None,
+        keymaps: load_keymaps(),
+        remapping: None,
         display: Grid::new(),
         cartridge_loaded: true,
+        error,
+        last_tick: None,
       },
       Command::none(),
     )
   }
 
   fn title(&self) -> String {
-    String::from("Xipe Oito!")
+    match (self.error, &self.remapping, &self.movie) {
+      (Some(error), _, _) => format!("Xipe Oito! - {}", error),
+      (None, Some(_), _) => String::from("Xipe Oito! - press Escape to cancel"),
+      (None, None, Some(_)) => String::from("Xipe Oito! - recording"),
+      (None, None, None) => String::from("Xipe Oito!"),
+    }
   }
 
   fn update(&mut self, message: Message) -> Command<Message> {
     match message {
-      Message::Tick(_) => {
+      Message::Tick(now) => {
+        let elapsed = match self.last_tick {
+          Some(last_tick) => now.saturating_duration_since(last_tick),
+          None => Duration::from_secs(0),
+        };
+        self.last_tick = Some(now);
+        if self.remapping.is_some() {
+          return Command::none();
+        }
+
         let mut closure = || {
-          self.engine.emulate_cycle();
-          if self.engine.should_draw() {
-            self
-              .display
-              .update(grid::Message::Show(self.engine.display.pixels));
+          let result = match &mut self.movie {
+            Some(movie) => movie.record_for(&mut self.engine, elapsed),
+            None => self.engine.run_for(elapsed),
+          };
+          if let Err(error) = result {
+            self.error = Some(error);
           }
+          self.play_audio();
+          self.show_changes();
         };
         closure();
       }
@@ -136,18 +340,34 @@ impl Application for Emerson {
         iced_native::Event::Keyboard(event) => match event {
           KeyPressed {
             key_code,
-            modifiers: _,
+            modifiers,
           } => {
-            if let Some(key) = parse_key(key_code) {
+            if self.remapping.is_some() {
+              self.remap(key_code)
+            } else if let Some(key) = self.button(key_code) {
               self.engine.input.key_down(key)
+            } else if key_code == REMAP_KEY {
+              self.start_remapping(modifiers.shift)
+            } else if key_code == RECORD_MOVIE_KEY {
+              self.record_movie()
+            } else if key_code == STOP_MOVIE_KEY {
+              self.stop_movie()
+            } else if let Some(slot) = parse_save_slot(key_code) {
+              if modifiers.shift {
+                self.load_state(slot)
+              } else {
+                self.save_state(slot)
+              }
             }
           }
           KeyReleased {
             key_code,
             modifiers: _,
           } => {
-            if let Some(key) = parse_key(key_code) {
-              self.engine.input.key_up(key)
+            if self.remapping.is_none() {
+              if let Some(key) = self.button(key_code) {
+                self.engine.input.key_up(key)
+              }
             }
           }
           _ => (),
@@ -160,9 +380,9 @@ impl Application for Emerson {
   }
 
   fn subscription(&self) -> Subscription<Self::Message> {
-    if self.cartridge_loaded {
+    if self.cartridge_loaded && self.error.is_none() {
       Subscription::batch(vec![
-        time::every(Duration::from_millis(2)).map(Message::Tick),
+        time::every(Duration::from_millis(16)).map(Message::Tick),
         iced_native::subscription::events().map(Message::Event),
       ])
     } else {
@@ -171,10 +391,33 @@ impl Application for Emerson {
   }
 
   fn view(&mut self) -> Element<Message> {
+    if let Some(remapping) = &self.remapping {
+      let games = if remapping.rom_only {
+        "this game"
+      } else {
+        "all games"
+      };
+      let content = Column::new()
+        .align_items(Align::Center)
+        .push(Text::new("Key for").size(5).color(Color::WHITE))
+        .push(
+          Text::new(BUTTON_LIST[remapping.next].to_label())
+            .size(14)
+            .color(Color::WHITE),
+        )
+        .push(Text::new(games).size(4).color(Color::WHITE));
+      return Container::new(content)
+        .width(Length::Fill)
+        .height(Length::Fill)
+        .center_x()
+        .center_y()
+        .into();
+    }
+
     let content = Column::new().push(
       self
         .display
-        .view()
+        .view(&self.engine.display)
         .map(move |message| Message::Display(message)),
     );
 
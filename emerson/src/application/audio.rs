@@ -1,42 +1,82 @@
-use palmer::audio::AudioDriver;
-use std::sync::mpsc::{self, Sender};
+use rodio::Source;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
 use std::thread;
+use std::time::Duration;
 
-pub struct NativeAudioDriver {
-  sender: Sender<Message>,
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Plays the samples the engine renders, on a thread of its own.
+pub struct AudioOutput {
+  sender: Sender<Vec<f32>>,
+}
+
+/// The buffers sent to the output, one after the other, with silence
+/// whenever the engine falls behind.
+struct QueueSource {
+  receiver: Receiver<Vec<f32>>,
+  buffer: Vec<f32>,
+  position: usize,
 }
 
-enum Message {
-  Play,
+impl Iterator for QueueSource {
+  type Item = f32;
+
+  fn next(&mut self) -> Option<f32> {
+    while self.position >= self.buffer.len() {
+      match self.receiver.try_recv() {
+        Ok(buffer) => {
+          self.buffer = buffer;
+          self.position = 0;
+        }
+        Err(TryRecvError::Empty) => return Some(0.),
+        Err(TryRecvError::Disconnected) => return None,
+      }
+    }
+    let sample = self.buffer[self.position];
+    self.position += 1;
+    Some(sample)
+  }
 }
 
-impl AudioDriver for NativeAudioDriver {
-  fn new() -> Self {
-    let (tx, rx) = mpsc::channel::<Message>();
+impl Source for QueueSource {
+  fn current_frame_len(&self) -> Option<usize> {
+    None
+  }
+
+  fn channels(&self) -> u16 {
+    1
+  }
+
+  fn sample_rate(&self) -> u32 {
+    SAMPLE_RATE
+  }
+
+  fn total_duration(&self) -> Option<Duration> {
+    None
+  }
+}
+
+impl AudioOutput {
+  pub fn new() -> Self {
+    let (tx, rx) = mpsc::channel::<Vec<f32>>();
 
     thread::spawn(move || {
       let (_stream, stream_handle) = rodio::OutputStream::try_default().unwrap();
       let sink = rodio::Sink::try_new(&stream_handle).unwrap();
-
-      let source = rodio::source::SineWave::new(440);
-      sink.append(source);
-      sink.pause();
-
-      for received in rx {
-        match received {
-          Message::Play => {
-            sink.play();
-            thread::sleep(std::time::Duration::from_millis(200));
-            sink.pause();
-          }
-        }
-      }
+      sink.append(QueueSource {
+        receiver: rx,
+        buffer: vec![],
+        position: 0,
+      });
+      sink.sleep_until_end();
     });
 
     Self { sender: tx }
   }
 
-  fn play_sound(&mut self) {
-    self.sender.send(Message::Play).unwrap();
+  pub fn push(&self, samples: Vec<f32>) {
+    if !samples.is_empty() {
+      self.sender.send(samples).unwrap();
+    }
   }
 }
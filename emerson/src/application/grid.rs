@@ -2,25 +2,26 @@ use iced::{
   canvas::{self, Cache, Canvas, Cursor, Geometry},
   Color, Element, Point, Rectangle, Size,
 };
-use palmer::display::{Pixels, SCREEN_HEIGHT, SCREEN_WIDTH};
+use palmer::display::{Display, HIRES_SCREEN_HEIGHT, PALETTE, SCREEN_HEIGHT, SCREEN_WIDTH};
 
+/// Keeps the geometry of each line of the screen apart, so that only the
+/// lines that changed are drawn again.
 pub struct Grid {
-  display: Pixels,
-  display_cache: Cache,
+  line_caches: Vec<Cache>,
 }
 
 impl Default for Grid {
   fn default() -> Self {
     Grid {
-      display: [false; SCREEN_HEIGHT * SCREEN_WIDTH],
-      display_cache: Cache::default(),
+      line_caches: (0..HIRES_SCREEN_HEIGHT).map(|_| Cache::default()).collect(),
     }
   }
 }
 
 #[derive(Debug, Clone)]
 pub enum Message {
-  Show(Pixels),
+  /// These lines of the engine's display changed and have to be drawn again.
+  Show(Vec<usize>),
 }
 
 impl Grid {
@@ -30,46 +31,63 @@ impl Grid {
 
   pub fn update(&mut self, message: Message) {
     match message {
-      Message::Show(pixels) => {
-        self.display_cache.clear();
-        self.display = pixels;
+      Message::Show(lines) => {
+        for line in lines {
+          self.line_caches[line].clear();
+        }
       }
     }
   }
 
-  pub fn view<'a>(&'a mut self) -> Element<'a, Message> {
-    Canvas::new(self)
-      .width(iced::Length::Units(SCREEN_WIDTH as u16))
-      .height(iced::Length::Units(SCREEN_HEIGHT as u16))
-      .into()
+  /// Draws the engine's display in place, without copying it.
+  pub fn view<'a>(&'a mut self, display: &'a Display) -> Element<'a, Message> {
+    Canvas::new(Screen {
+      display,
+      line_caches: &self.line_caches,
+    })
+    .width(iced::Length::Units(SCREEN_WIDTH as u16))
+    .height(iced::Length::Units(SCREEN_HEIGHT as u16))
+    .into()
   }
 }
 
-impl<'a> canvas::Program<Message> for Grid {
+struct Screen<'a> {
+  display: &'a Display,
+  line_caches: &'a [Cache],
+}
+
+impl<'a> canvas::Program<Message> for Screen<'a> {
   fn draw(&self, bounds: Rectangle, _cursor: Cursor) -> Vec<Geometry> {
-    let pixels = self.display_cache.draw(bounds.size(), |frame| {
-      frame.with_save(|frame| {
-        self
-          .display
-          .chunks(SCREEN_WIDTH)
-          .enumerate()
-          .for_each(|(line, pixel_line)| {
-            pixel_line
-              .into_iter()
+    let pixel_size = SCREEN_WIDTH as f32 / self.display.width() as f32;
+
+    self.line_caches[..self.display.height()]
+      .iter()
+      .enumerate()
+      .map(|(line, cache)| {
+        cache.draw(bounds.size(), |frame| {
+          frame.with_save(|frame| {
+            self
+              .display
+              .line(line)
               .enumerate()
               .for_each(|(column, pixel)| {
-                let color = if *pixel {
-                  Color::WHITE
-                } else {
-                  Color::TRANSPARENT
+                let color = match pixel {
+                  0 => Color::TRANSPARENT,
+                  value => {
+                    let [r, g, b] = PALETTE[value as usize];
+                    Color::from_rgb8(r, g, b)
+                  }
                 };
 
-                frame.fill_rectangle(Point::new(column as f32, line as f32), Size::UNIT, color)
-              })
+                frame.fill_rectangle(
+                  Point::new(column as f32 * pixel_size, line as f32 * pixel_size),
+                  Size::new(pixel_size, pixel_size),
+                  color,
+                )
+              });
           });
-      });
-    });
-
-    vec![pixels]
+        })
+      })
+      .collect()
   }
 }
@@ -0,0 +1,94 @@
+use iced::keyboard::KeyCode;
+
+/// The name a keymap knows `key` by, the same as the browser's
+/// `KeyboardEvent.code` for it. Keys no keymap should bind, like the
+/// function keys emerson uses itself, have none.
+pub fn key_name(key: KeyCode) -> Option<&'static str> {
+  let name = match key {
+    KeyCode::Key1 => "Digit1",
+    KeyCode::Key2 => "Digit2",
+    KeyCode::Key3 => "Digit3",
+    KeyCode::Key4 => "Digit4",
+    KeyCode::Key5 => "Digit5",
+    KeyCode::Key6 => "Digit6",
+    KeyCode::Key7 => "Digit7",
+    KeyCode::Key8 => "Digit8",
+    KeyCode::Key9 => "Digit9",
+    KeyCode::Key0 => "Digit0",
+    KeyCode::A => "KeyA",
+    KeyCode::B => "KeyB",
+    KeyCode::C => "KeyC",
+    KeyCode::D => "KeyD",
+    KeyCode::E => "KeyE",
+    KeyCode::F => "KeyF",
+    KeyCode::G => "KeyG",
+    KeyCode::H => "KeyH",
+    KeyCode::I => "KeyI",
+    KeyCode::J => "KeyJ",
+    KeyCode::K => "KeyK",
+    KeyCode::L => "KeyL",
+    KeyCode::M => "KeyM",
+    KeyCode::N => "KeyN",
+    KeyCode::O => "KeyO",
+    KeyCode::P => "KeyP",
+    KeyCode::Q => "KeyQ",
+    KeyCode::R => "KeyR",
+    KeyCode::S => "KeyS",
+    KeyCode::T => "KeyT",
+    KeyCode::U => "KeyU",
+    KeyCode::V => "KeyV",
+    KeyCode::W => "KeyW",
+    KeyCode::X => "KeyX",
+    KeyCode::Y => "KeyY",
+    KeyCode::Z => "KeyZ",
+    KeyCode::Numpad0 => "Numpad0",
+    KeyCode::Numpad1 => "Numpad1",
+    KeyCode::Numpad2 => "Numpad2",
+    KeyCode::Numpad3 => "Numpad3",
+    KeyCode::Numpad4 => "Numpad4",
+    KeyCode::Numpad5 => "Numpad5",
+    KeyCode::Numpad6 => "Numpad6",
+    KeyCode::Numpad7 => "Numpad7",
+    KeyCode::Numpad8 => "Numpad8",
+    KeyCode::Numpad9 => "Numpad9",
+    KeyCode::Divide => "NumpadDivide",
+    KeyCode::Multiply => "NumpadMultiply",
+    KeyCode::Subtract => "NumpadSubtract",
+    KeyCode::Add => "NumpadAdd",
+    KeyCode::NumpadEnter => "NumpadEnter",
+    KeyCode::Decimal => "NumpadDecimal",
+    KeyCode::Up => "ArrowUp",
+    KeyCode::Down => "ArrowDown",
+    KeyCode::Left => "ArrowLeft",
+    KeyCode::Right => "ArrowRight",
+    KeyCode::Space => "Space",
+    KeyCode::Enter => "Enter",
+    KeyCode::Tab => "Tab",
+    KeyCode::Backspace => "Backspace",
+    KeyCode::Insert => "Insert",
+    KeyCode::Delete => "Delete",
+    KeyCode::Home => "Home",
+    KeyCode::End => "End",
+    KeyCode::PageUp => "PageUp",
+    KeyCode::PageDown => "PageDown",
+    KeyCode::LShift => "ShiftLeft",
+    KeyCode::RShift => "ShiftRight",
+    KeyCode::LControl => "ControlLeft",
+    KeyCode::RControl => "ControlRight",
+    KeyCode::LAlt => "AltLeft",
+    KeyCode::RAlt => "AltRight",
+    KeyCode::Minus => "Minus",
+    KeyCode::Equals => "Equal",
+    KeyCode::LBracket => "BracketLeft",
+    KeyCode::RBracket => "BracketRight",
+    KeyCode::Backslash => "Backslash",
+    KeyCode::Semicolon => "Semicolon",
+    KeyCode::Apostrophe => "Quote",
+    KeyCode::Grave => "Backquote",
+    KeyCode::Comma => "Comma",
+    KeyCode::Period => "Period",
+    KeyCode::Slash => "Slash",
+    _ => return None,
+  };
+  Some(name)
+}
@@ -1,10 +1,16 @@
-use palmer::audio::AudioDriver;
+use palmer::audio::Silent;
 use palmer::input::Button;
-use palmer::Chip8;
+use palmer::keymap::{Keymap, KeymapConfig};
+use palmer::movie::{self, Movie};
+use palmer::{Chip8, Quirks, XorShift};
+use std::rc::Rc;
 use std::time::Duration;
+use wasm_bindgen::JsCast;
+use web_sys::{Blob, BlobPropertyBag, HtmlAnchorElement, Storage, Url};
 use yew::prelude::*;
 use yew::services::interval::{IntervalService, IntervalTask};
 use yew::services::keyboard::*;
+use yew::services::{ConsoleService, DialogService};
 use yew::services::reader::{File, FileData, ReaderService, ReaderTask};
 use yew::ChangeData;
 
@@ -12,41 +18,49 @@ mod audio;
 mod button;
 mod buttons;
 mod grid;
+mod keymap;
 
-use audio::WebAudioDriver;
+use audio::{AudioOutput, SAMPLE_RATE};
 use buttons::Buttons;
-use grid::Grid;
+use grid::{Changes, Grid};
+use keymap::KeymapEditor;
+
+/// Where the keymaps are kept between visits, as `KeymapConfig` text.
+const KEYMAP_STORAGE_KEY: &str = "keymap";
 
 pub struct Lake {
   link: ComponentLink<Lake>,
-  pub engine: Chip8<WebAudioDriver>,
+  pub engine: Chip8<Silent>,
+  audio: AudioOutput,
   tasks: Vec<ReaderTask>,
+  /// The loaded ROM and its file name, to restart it when recording.
+  rom: Option<(String, Vec<u8>)>,
+  movie: Option<Movie>,
+  /// What changed on the screen in the last tick, for the grid to paint.
+  changes: Rc<Changes>,
+  keymaps: KeymapConfig,
+  /// The button the next key press is bound to.
+  remapping: Option<Button>,
   is_running: bool,
+  last_tick: Option<f64>,
   _task: IntervalTask,
   _key_up_listener: KeyListenerHandle,
   _key_down_listener: KeyListenerHandle,
 }
 
-fn parse_key(key: &str) -> Option<Button> {
-  match key {
-    "Digit1" => Some(Button::One),
-    "Digit2" => Some(Button::Two),
-    "Digit3" => Some(Button::Three),
-    "Digit4" => Some(Button::C),
-    "KeyQ" => Some(Button::Four),
-    "KeyW" => Some(Button::Five),
-    "KeyE" => Some(Button::Six),
-    "KeyR" => Some(Button::D),
-    "KeyA" => Some(Button::Seven),
-    "KeyS" => Some(Button::Eight),
-    "KeyD" => Some(Button::Nine),
-    "KeyF" => Some(Button::E),
-    "KeyZ" => Some(Button::A),
-    "KeyX" => Some(Button::Zero),
-    "KeyC" => Some(Button::B),
-    "KeyV" => Some(Button::F),
-    _ => None,
-  }
+fn local_storage() -> Option<Storage> {
+  web_sys::window()?.local_storage().ok()?
+}
+
+fn load_keymaps() -> KeymapConfig {
+  let text = match local_storage().and_then(|storage| storage.get_item(KEYMAP_STORAGE_KEY).ok()?) {
+    Some(text) => text,
+    None => return KeymapConfig::default(),
+  };
+  KeymapConfig::parse(&text).unwrap_or_else(|error| {
+    ConsoleService::error(&format!("Could not load the keymap: {}", error));
+    KeymapConfig::default()
+  })
 }
 
 pub enum Message {
@@ -55,6 +69,85 @@ pub enum Message {
   Tick,
   KeyDownEvent(Option<Button>),
   KeyUpEvent(Option<Button>),
+  /// A key went down or up, by its `KeyboardEvent.code`.
+  KeyboardDown(String),
+  KeyboardUp(String),
+  RecordMovie,
+  StopMovie,
+  SelectPreset(String),
+  Remap(Button),
+  RomKeymap(bool),
+}
+
+/// Has the browser download `data` as a file called `name`.
+fn download(name: &str, data: &[u8]) {
+  let bytes = js_sys::Uint8Array::from(data);
+  let blob = Blob::new_with_u8_array_sequence_and_options(
+    &js_sys::Array::of1(&bytes),
+    BlobPropertyBag::new().type_("application/octet-stream"),
+  )
+  .unwrap();
+  let url = Url::create_object_url_with_blob(&blob).unwrap();
+
+  let document = web_sys::window().unwrap().document().unwrap();
+  let link: HtmlAnchorElement = document.create_element("a").unwrap().dyn_into().unwrap();
+  link.set_href(&url);
+  link.set_download(name);
+  link.click();
+  Url::revoke_object_url(&url).unwrap();
+}
+
+impl Lake {
+  fn rom_hash(&self) -> Option<u64> {
+    self.rom.as_ref().map(|(_, rom)| movie::rom_hash(rom))
+  }
+
+  fn keymap(&self) -> &Keymap {
+    match self.rom_hash() {
+      Some(hash) => self.keymaps.for_rom(hash),
+      None => &self.keymaps.keymap,
+    }
+  }
+
+  /// The keymap the loaded game plays with, which is the one for every game
+  /// unless it has its own.
+  fn keymap_mut(&mut self) -> &mut Keymap {
+    match self.rom_hash() {
+      Some(hash) if self.keymaps.roms.contains_key(&hash) => {
+        self.keymaps.roms.get_mut(&hash).unwrap()
+      }
+      _ => &mut self.keymaps.keymap,
+    }
+  }
+
+  fn save_keymaps(&self) {
+    let saved = local_storage().map(|storage| {
+      storage
+        .set_item(KEYMAP_STORAGE_KEY, &self.keymaps.to_text())
+        .is_ok()
+    });
+    if saved != Some(true) {
+      ConsoleService::error("Could not save the keymap");
+    }
+  }
+
+  /// Starts recording a movie of the loaded game from the start, or stops
+  /// and downloads the one being recorded.
+  fn movie_button(&self) -> Html {
+    match (&self.rom, &self.movie) {
+      (None, _) => html! {},
+      (Some(_), None) => html! {
+        <button class="game__movie" onclick=self.link.callback(|_| Message::RecordMovie)>
+          {"RECORD MOVIE"}
+        </button>
+      },
+      (Some(_), Some(_)) => html! {
+        <button class="game__movie" onclick=self.link.callback(|_| Message::StopMovie)>
+          {"DOWNLOAD MOVIE"}
+        </button>
+      },
+    }
+  }
 }
 
 impl Component for Lake {
@@ -64,26 +157,27 @@ impl Component for Lake {
   fn create(_props: Self::Properties, link: ComponentLink<Self>) -> Self {
     let tick_callback = link.callback(|_| Message::Tick);
 
-    let key_down_callback = link.callback(|e: KeyboardEvent| {
-      let key = parse_key(&e.code());
-      Message::KeyDownEvent(key)
-    });
-    let key_up_callback = link.callback(|e: KeyboardEvent| {
-      let key = parse_key(&e.code());
-      Message::KeyUpEvent(key)
-    });
+    let key_down_callback = link.callback(|e: KeyboardEvent| Message::KeyboardDown(e.code()));
+    let key_up_callback = link.callback(|e: KeyboardEvent| Message::KeyboardUp(e.code()));
 
     let wnd = &web_sys::window().unwrap();
-    let task = IntervalService::spawn(Duration::from_millis(2), tick_callback);
+    let task = IntervalService::spawn(Duration::from_millis(16), tick_callback);
     let key_down_listener = KeyboardService::register_key_down(wnd, key_down_callback);
     let key_up_listener = KeyboardService::register_key_up(wnd, key_up_callback);
 
-    let engine = Chip8::new(WebAudioDriver::new());
+    let engine = Chip8::new(Silent, Quirks::default());
     Self {
       link,
       engine: engine,
+      audio: AudioOutput::new(),
       tasks: vec![],
+      rom: None,
+      movie: None,
+      changes: Rc::new(Changes::default()),
+      keymaps: load_keymaps(),
+      remapping: None,
       is_running: false,
+      last_tick: None,
       _task: task,
       _key_down_listener: key_down_listener,
       _key_up_listener: key_up_listener,
@@ -100,13 +194,58 @@ impl Component for Lake {
       }
       Message::FileLoaded(file) => {
         self.is_running = false;
+        self.movie = None;
         self.engine.reset();
-        self.engine.load(file.content);
-        self.is_running = true;
+        self
+          .engine
+          .set_random_source(XorShift::new(js_sys::Date::now() as u64));
+        match self.engine.load(file.content.clone()) {
+          Ok(()) => {
+            self.is_running = true;
+            self.rom = Some((file.name, file.content));
+          }
+          Err(error) => DialogService::alert(&error.to_string()),
+        }
+      }
+      Message::RecordMovie => {
+        if let Some((_, rom)) = &self.rom {
+          match Movie::record(&mut self.engine, rom, js_sys::Date::now() as u64) {
+            Ok(movie) => {
+              self.movie = Some(movie);
+              self.is_running = true;
+            }
+            Err(error) => DialogService::alert(&error.to_string()),
+          }
+        }
+      }
+      Message::StopMovie => {
+        if let (Some(movie), Some((name, _))) = (self.movie.take(), &self.rom) {
+          download(&format!("{}.movie", name), &movie.to_bytes());
+        }
       }
       Message::Tick => {
+        let now = js_sys::Date::now();
+        let elapsed = match self.last_tick {
+          Some(last_tick) => Duration::from_secs_f64((now - last_tick).max(0.) / 1000.),
+          None => Duration::from_secs(0),
+        };
+        self.last_tick = Some(now);
+
         if self.is_running {
-          self.engine.emulate_cycle();
+          let result = match &mut self.movie {
+            Some(movie) => movie.record_for(&mut self.engine, elapsed),
+            None => self.engine.run_for(elapsed),
+          };
+          if let Err(error) = result {
+            ConsoleService::error(&error.to_string());
+            self.is_running = false;
+          }
+          let mut samples = vec![0.; self.engine.pending_audio_samples(SAMPLE_RATE)];
+          self.engine.fill_audio(&mut samples, SAMPLE_RATE);
+          self.audio.push(&mut samples);
+        }
+        if self.engine.display.is_dirty() {
+          self.changes = Rc::new(Changes::take(&mut self.engine.display));
         }
       }
       Message::KeyDownEvent(input) => {
@@ -119,6 +258,39 @@ impl Component for Lake {
           self.engine.input.key_up(key)
         }
       }
+      Message::KeyboardDown(code) => {
+        if let Some(button) = self.remapping.take() {
+          if code != "Escape" {
+            self.keymap_mut().remap(button, &code);
+            self.save_keymaps();
+          }
+        } else if let Some(key) = self.keymap().button(&code) {
+          self.engine.input.key_down(key)
+        }
+      }
+      Message::KeyboardUp(code) => {
+        if let Some(key) = self.keymap().button(&code) {
+          self.engine.input.key_up(key)
+        }
+      }
+      Message::SelectPreset(name) => {
+        if let Some(keymap) = Keymap::preset(&name) {
+          *self.keymap_mut() = keymap;
+          self.save_keymaps();
+        }
+      }
+      Message::Remap(button) => self.remapping = Some(button),
+      Message::RomKeymap(rom_only) => {
+        if let Some(hash) = self.rom_hash() {
+          if rom_only {
+            let keymap = self.keymaps.keymap.clone();
+            self.keymaps.roms.insert(hash, keymap);
+          } else {
+            self.keymaps.roms.remove(&hash);
+          }
+          self.save_keymaps();
+        }
+      }
     }
 
     true
@@ -129,13 +301,12 @@ impl Component for Lake {
   }
 
   fn view(&self) -> Html {
-    let should_draw = self.engine.should_draw();
-    let pixels = self.engine.display.pixels;
+    let changes = Rc::clone(&self.changes);
 
     html! {
       <main>
         <div class="view">
-          <Grid should_render=should_draw pixels=pixels />
+          <Grid changes=changes />
           <div class="game__loader">
             <input type="file" id="file" multiple=false onchange=self.link.callback(move |value| {
               let mut result = Vec::new();
@@ -150,6 +321,7 @@ impl Component for Lake {
               })
             />
             <label for="file">{"LOAD GAME"}</label>
+            { self.movie_button() }
           </div>
           <Buttons
             onkeydown=self.link.callback(|code| {
@@ -160,6 +332,15 @@ impl Component for Lake {
             })
             active_buttons=self.engine.input
           />
+          <KeymapEditor
+            keymap=self.keymap().clone()
+            rom_only={self.rom_hash().map_or(false, |hash| self.keymaps.roms.contains_key(&hash))}
+            has_rom=self.rom.is_some()
+            remapping=self.remapping
+            onpreset=self.link.callback(Message::SelectPreset)
+            onremap=self.link.callback(Message::Remap)
+            onromonly=self.link.callback(Message::RomKeymap)
+          />
         </div>
       </main>
     }
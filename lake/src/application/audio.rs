@@ -1,35 +1,56 @@
-use wasm_bindgen::{closure::Closure, JsCast};
-use web_sys::{AudioContext};
-use yew::utils::window;
-
-pub struct WebAudioDriver {}
+use web_sys::AudioContext;
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Plays the samples the engine renders, scheduling each buffer right after
+/// the previous one.
+pub struct AudioOutput {
+  /// Created on the first sound, since browsers only let a page make sound
+  /// after the user interacted with it.
+  context: Option<AudioContext>,
+  /// When the next buffer starts, on the context's clock.
+  next_start: f64,
+}
 
-impl palmer::audio::AudioDriver for WebAudioDriver {
-  fn new() -> Self {
-    Self {}
+impl AudioOutput {
+  pub fn new() -> Self {
+    Self {
+      context: None,
+      next_start: 0.,
+    }
   }
 
-  fn play_sound(&mut self) {
-    let ctx = AudioContext::new().unwrap();
-
-    let oscillator = ctx.create_oscillator().unwrap();
-
-    oscillator
-      .connect_with_audio_node(&ctx.destination())
+  pub fn push(&mut self, samples: &mut [f32]) {
+    if samples.iter().all(|sample| *sample == 0.) {
+      // Silence only needs to keep the following buffers in step, and
+      // there is nothing to keep in step with before the first sound.
+      if self.context.is_some() {
+        self.next_start += samples.len() as f64 / SAMPLE_RATE as f64;
+      }
+      return;
+    }
+    if self.context.is_none() {
+      self.context = AudioContext::new().ok();
+      if let Some(ctx) = &self.context {
+        self.next_start = ctx.current_time();
+      }
+    }
+    let ctx = match &self.context {
+      Some(ctx) => ctx,
+      None => return,
+    };
+
+    let buffer = ctx
+      .create_buffer(1, samples.len() as u32, SAMPLE_RATE as f32)
       .unwrap();
+    buffer.copy_to_channel(samples, 0).unwrap();
 
-    oscillator.start().unwrap();
-
-    let closure = Closure::once(move || {
-      oscillator.stop().unwrap();
-    });
-
-    let js_val = closure.as_ref();
-    let js_func = js_val.unchecked_ref::<js_sys::Function>();
-
-    let w = window();
-    w.set_timeout_with_callback_and_timeout_and_arguments_0(js_func, 200).unwrap();
+    let source = ctx.create_buffer_source().unwrap();
+    source.set_buffer(Some(&buffer));
+    source.connect_with_audio_node(&ctx.destination()).unwrap();
 
-    closure.forget();
+    let start = self.next_start.max(ctx.current_time());
+    source.start_with_when(start).unwrap();
+    self.next_start = start + buffer.duration();
   }
 }
@@ -1,29 +1,63 @@
-use palmer::display::{Pixels, SCREEN_WIDTH};
+use palmer::display::{Display, Region, PALETTE, SCREEN_WIDTH};
 use std::f64;
+use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use web_sys::{self, CanvasRenderingContext2d, HtmlCanvasElement};
 use yew::{html, Component, ComponentLink, Html, NodeRef, Properties, ShouldRender};
 
+/// The pixels that changed on the engine's display, so the grid can paint
+/// them without a copy of the whole screen.
+#[derive(Default)]
+pub struct Changes {
+  /// The width of the screen they were made on.
+  width: usize,
+  /// Each changed region, with the value of its pixels.
+  regions: Vec<(Region, Vec<u8>)>,
+}
+
+impl Changes {
+  /// Takes the changes since the display was last acknowledged, and
+  /// acknowledges them.
+  pub fn take(display: &mut Display) -> Changes {
+    let regions = display
+      .dirty_regions()
+      .map(|region| {
+        let pixels = (region.x..region.x + region.width)
+          .map(|x| display.pixel(x, region.y))
+          .collect();
+        (region, pixels)
+      })
+      .collect();
+    display.acknowledge();
+    Changes {
+      width: display.width(),
+      regions,
+    }
+  }
+}
+
 #[derive(Properties, Clone)]
 pub struct Props {
-  pub pixels: Pixels,
-  pub should_render: bool,
+  pub changes: Rc<Changes>,
 }
 
 pub struct Grid {
   node_ref: NodeRef,
   canvas_context: Option<CanvasRenderingContext2d>,
+  /// The changes painted last, as the same ones come back on every render.
+  painted: Rc<Changes>,
 }
 
 impl Component for Grid {
   type Message = ();
   type Properties = Props;
 
-  fn create(_props: Self::Properties, _link: ComponentLink<Self>) -> Self {
+  fn create(props: Self::Properties, _link: ComponentLink<Self>) -> Self {
     Grid {
       node_ref: NodeRef::default(),
       canvas_context: None,
+      painted: props.changes,
     }
   }
 
@@ -42,29 +76,28 @@ impl Component for Grid {
     false
   }
 
+  /// Repaints only the pixels that changed since the engine's display was
+  /// last acknowledged.
   fn change(&mut self, props: Self::Properties) -> ShouldRender {
-    if props.should_render {
-      match &self.canvas_context {
-        None => (),
-        Some(context) => {
-          props
-            .pixels
-            .chunks(SCREEN_WIDTH)
-            .enumerate()
-            .for_each(|(line, pixel_line)| {
-              pixel_line
-                .into_iter()
-                .enumerate()
-                .for_each(|(column, pixel)| {
-                  let x = (column * 10) as f64;
-                  let y = (line * 10) as f64;
-                  let color = if *pixel { "white" } else { "black" };
-                  context.set_fill_style(&(JsValue::from_str(color)));
-                  context.begin_path();
-                  context.rect(x, y, 10., 10.);
-                  context.fill();
-                })
-            });
+    if Rc::ptr_eq(&self.painted, &props.changes) {
+      return false;
+    }
+    self.painted = props.changes;
+    if let Some(context) = &self.canvas_context {
+      let changes = &self.painted;
+      let pixel_size = (SCREEN_WIDTH * 10 / changes.width) as f64;
+      let colors: Vec<JsValue> = PALETTE
+        .iter()
+        .map(|[r, g, b]| JsValue::from_str(&format!("rgb({}, {}, {})", r, g, b)))
+        .collect();
+      for (region, pixels) in changes.regions.iter() {
+        for (column, pixel) in (region.x..).zip(pixels) {
+          let x = column as f64 * pixel_size;
+          let y = region.y as f64 * pixel_size;
+          context.set_fill_style(&colors[*pixel as usize]);
+          context.begin_path();
+          context.rect(x, y, pixel_size, pixel_size);
+          context.fill();
         }
       }
     }
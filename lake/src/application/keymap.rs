@@ -0,0 +1,117 @@
+use palmer::input::{Button as InputButton, BUTTON_LIST};
+use palmer::keymap::{Keymap, PRESETS};
+use yew::{html, Callback, ChangeData, Component, ComponentLink, Html, Properties, ShouldRender};
+
+#[derive(Properties, Clone, PartialEq)]
+pub struct Props {
+  pub keymap: Keymap,
+  /// Whether `keymap` is only used for the loaded game.
+  pub rom_only: bool,
+  pub has_rom: bool,
+  /// The button waiting for a key press, if any.
+  pub remapping: Option<InputButton>,
+  pub onpreset: Callback<String>,
+  pub onremap: Callback<InputButton>,
+  pub onromonly: Callback<bool>,
+}
+
+pub enum Message {
+  Preset(String),
+  Remap(InputButton),
+  RomOnly(bool),
+}
+
+/// Shows which keys press each button. Clicking a button waits for the key
+/// to press it with, and the select swaps in a preset.
+pub struct KeymapEditor {
+  props: Props,
+  link: ComponentLink<Self>,
+}
+
+impl Component for KeymapEditor {
+  type Message = Message;
+  type Properties = Props;
+
+  fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+    Self { props, link }
+  }
+
+  fn update(&mut self, msg: Self::Message) -> ShouldRender {
+    match msg {
+      Message::Preset(name) => self.props.onpreset.emit(name),
+      Message::Remap(button) => self.props.onremap.emit(button),
+      Message::RomOnly(rom_only) => self.props.onromonly.emit(rom_only),
+    }
+
+    false
+  }
+
+  fn change(&mut self, props: Self::Properties) -> ShouldRender {
+    if self.props != props {
+      self.props = props;
+      return true;
+    }
+
+    false
+  }
+
+  fn view(&self) -> Html {
+    let preset = PRESETS
+      .iter()
+      .find(|name| Keymap::preset(name).as_ref() == Some(&self.props.keymap));
+    let presets: Html = PRESETS
+      .iter()
+      .map(|name| {
+        html! {
+          <option value={name.to_string()} selected={preset == Some(name)}>{name.to_uppercase()}</option>
+        }
+      })
+      .collect();
+
+    let buttons: Html = BUTTON_LIST
+      .iter()
+      .map(|button| {
+        let button = *button;
+        let keys = if self.props.remapping == Some(button) {
+          String::from("PRESS A KEY")
+        } else {
+          let keys: Vec<&str> = self.props.keymap.keys(button).collect();
+          keys.join(" ")
+        };
+        html! {
+          <button class="keymap__button" onclick=self.link.callback(move |_| Message::Remap(button))>
+            <strong>{button.to_label()}</strong>
+            <span>{keys}</span>
+          </button>
+        }
+      })
+      .collect();
+
+    let rom_only = self.props.rom_only;
+    html! {
+      <div class="keymap">
+        <div class="keymap__options">
+          <select onchange=self.link.callback(|change| match change {
+            ChangeData::Select(select) => Message::Preset(select.value()),
+            _ => Message::Preset(String::new()),
+          })>
+            <option value="" selected={preset.is_none()} disabled=true>{"CUSTOM"}</option>
+            {presets}
+          </select>
+          <label>
+            <input
+              type="checkbox"
+              checked=rom_only
+              disabled={!self.props.has_rom}
+              onclick=self.link.callback(move |_| Message::RomOnly(!rom_only))
+            />
+            {"THIS GAME ONLY"}
+          </label>
+        </div>
+        <div class="keymap__buttons">
+          {buttons}
+        </div>
+      </div>
+    }
+  }
+}
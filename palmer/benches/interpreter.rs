@@ -0,0 +1,67 @@
+//! Times the interpreter with and without its decode cache on a busy loop
+//! of arithmetic, BCD stores and draws. Run it with
+//! `cargo bench --bench interpreter`.
+
+use palmer::audio::Silent;
+use palmer::{octo, Chip8, Quirks};
+use std::time::{Duration, Instant};
+
+const FRAMES: usize = 2_000;
+const INSTRUCTIONS_PER_FRAME: u32 = 1_000;
+const RUNS: usize = 5;
+
+const SOURCE: &str = "
+: main
+  v4 := 10
+  v5 := 10
+  loop
+    v0 += 1
+    v1 := v0
+    v1 <<= v1
+    v2 ^= v1
+    if v0 == 0 then v3 += 1
+    i := scratch
+    bcd v2
+    i := hex v3
+    sprite v4 v5 5
+  again
+
+: scratch 0 0 0
+";
+
+fn time(rom: &[u8], cached: bool) -> Duration {
+  (0..RUNS)
+    .map(|_| {
+      let mut chip8 = Chip8::new(Silent, Quirks::default());
+      chip8.set_decode_cache(cached);
+      chip8.set_instructions_per_frame(INSTRUCTIONS_PER_FRAME);
+      chip8.load(rom.to_vec()).unwrap();
+      let start = Instant::now();
+      for _ in 0..FRAMES {
+        chip8.run_frame().unwrap();
+      }
+      start.elapsed()
+    })
+    .min()
+    .unwrap()
+}
+
+fn main() {
+  let rom = octo::compile(SOURCE).unwrap();
+  let instructions = FRAMES as f64 * INSTRUCTIONS_PER_FRAME as f64;
+
+  let uncached = time(&rom, false);
+  let cached = time(&rom, true);
+  for (name, elapsed) in [("uncached", uncached), ("cached", cached)].iter() {
+    println!(
+      "{:<8} {:>8.2?} {:>8.1} M instructions/s",
+      name,
+      elapsed,
+      instructions / elapsed.as_secs_f64() / 1e6
+    );
+  }
+  println!(
+    "speedup  {:.2}x",
+    uncached.as_secs_f64() / cached.as_secs_f64()
+  );
+}
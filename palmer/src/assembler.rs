@@ -0,0 +1,964 @@
+use std::collections::HashMap;
+use std::fs;
+
+use super::error::AssemblyError;
+use super::processor::{MEMORY_SIZE, PROGRAM_START};
+use super::source_map::{SourceLine, SourceMap};
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+const MAX_CONSTANT_DEPTH: usize = 64;
+
+const MNEMONICS: [&str; 31] = [
+  "SYS", "CLS", "RET", "SCD", "SCR", "SCL", "EXIT", "LOW", "HIGH", "JP", "CALL", "SE", "SNE",
+  "SAVE", "LOAD", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "SUBN", "SHL", "RND", "DRW",
+  "SKP", "SKNP", "PLANE", "AUDIO", "PITCH",
+];
+
+const KEYWORDS: [&str; 13] = [
+  "I", "DT", "ST", "K", "F", "HF", "B", "R", "LONG", "DB", "DW", "INCLUDE", "EQU",
+];
+
+/// Assembles source using the mnemonics `disassemble` emits into a ROM for
+/// `Chip8::load`. `include` reads files relative to the working directory.
+///
+/// Besides instructions, a line can hold a `label:`, a `NAME equ expression`
+/// constant, `db`/`dw` data and `include "file"`. Expressions take decimal,
+/// `0x` and `0b` numbers, symbols, `$` for the current address and the usual
+/// C operators. `;` starts a comment.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssemblyError> {
+  assemble_with_includes(source, |name| {
+    fs::read_to_string(name).map_err(|error| error.to_string())
+  })
+}
+
+/// Like `assemble`, but `read_include` is asked for the contents of included
+/// files.
+pub fn assemble_with_includes<F>(source: &str, read_include: F) -> Result<Vec<u8>, AssemblyError>
+where
+  F: FnMut(&str) -> Result<String, String>,
+{
+  assemble_with_source_map(source, "", read_include).map(|(rom, _)| rom)
+}
+
+/// Like `assemble_with_includes`, but also maps each instruction back to its
+/// line. Lines of `source` itself are attributed to `file_name`.
+pub fn assemble_with_source_map<F>(
+  source: &str,
+  file_name: &str,
+  read_include: F,
+) -> Result<(Vec<u8>, SourceMap), AssemblyError>
+where
+  F: FnMut(&str) -> Result<String, String>,
+{
+  let mut assembler = Assembler {
+    read_include: Box::new(read_include),
+    items: vec![],
+    symbols: HashMap::new(),
+    address: PROGRAM_START,
+  };
+  assembler.parse_source(source, None, 0)?;
+  let rom = assembler.emit()?;
+  Ok((rom, assembler.source_map(file_name)))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+  Identifier(String),
+  Number(i64),
+  Text(String),
+  Punctuation(char),
+  ShiftLeft,
+  ShiftRight,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+  kind: TokenKind,
+  column: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BinaryOperator {
+  Add,
+  Subtract,
+  Multiply,
+  Divide,
+  Remainder,
+  And,
+  Or,
+  Xor,
+  ShiftLeft,
+  ShiftRight,
+}
+
+#[derive(Debug, Clone)]
+enum Expression {
+  Number(i64),
+  Symbol(String, usize),
+  Here,
+  Negate(Box<Expression>),
+  Not(Box<Expression>),
+  Binary(BinaryOperator, Box<Expression>, Box<Expression>, usize),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+  Register(u8),
+  I,
+  IndirectI,
+  DelayTimer,
+  SoundTimer,
+  Key,
+  Font,
+  BigFont,
+  Bcd,
+  Flags,
+  Long(Expression, usize),
+  Value(Expression, usize),
+}
+
+#[derive(Debug, Clone)]
+struct Location {
+  file: Option<String>,
+  line: usize,
+}
+
+impl Location {
+  fn error(&self, column: usize, message: impl Into<String>) -> AssemblyError {
+    AssemblyError {
+      file: self.file.clone(),
+      line: self.line,
+      column,
+      message: message.into(),
+    }
+  }
+}
+
+enum Statement {
+  Instruction {
+    mnemonic: String,
+    operands: Vec<Operand>,
+  },
+  Bytes(Vec<(Expression, usize)>),
+  Words(Vec<(Expression, usize)>),
+}
+
+struct Item {
+  location: Location,
+  column: usize,
+  address: usize,
+  statement: Statement,
+}
+
+enum Symbol {
+  Address(usize),
+  Constant(Expression, Location),
+}
+
+type ReadInclude<'a> = Box<dyn FnMut(&str) -> Result<String, String> + 'a>;
+
+struct Assembler<'a> {
+  read_include: ReadInclude<'a>,
+  items: Vec<Item>,
+  symbols: HashMap<String, Symbol>,
+  address: usize,
+}
+
+fn tokenize(text: &str, location: &Location) -> Result<Vec<Token>, AssemblyError> {
+  let characters: Vec<char> = text.chars().collect();
+  let mut tokens = vec![];
+  let mut position = 0;
+
+  while position < characters.len() {
+    let character = characters[position];
+    let column = position + 1;
+    let start = position;
+
+    if character == ';' {
+      break;
+    } else if character.is_whitespace() {
+      position += 1;
+      continue;
+    } else if character.is_ascii_alphabetic() || character == '_' || character == '.' {
+      while position < characters.len()
+        && (characters[position].is_ascii_alphanumeric()
+          || characters[position] == '_'
+          || characters[position] == '.')
+      {
+        position += 1;
+      }
+      let name = characters[start..position].iter().collect();
+      tokens.push(Token {
+        kind: TokenKind::Identifier(name),
+        column,
+      });
+    } else if character.is_ascii_digit() {
+      while position < characters.len()
+        && (characters[position].is_ascii_alphanumeric() || characters[position] == '_')
+      {
+        position += 1;
+      }
+      let literal: String = characters[start..position]
+        .iter()
+        .filter(|character| **character != '_')
+        .collect();
+      let lowercase = literal.to_ascii_lowercase();
+      let parsed = if let Some(digits) = lowercase.strip_prefix("0x") {
+        i64::from_str_radix(digits, 16)
+      } else if let Some(digits) = lowercase.strip_prefix("0b") {
+        i64::from_str_radix(digits, 2)
+      } else {
+        lowercase.parse()
+      };
+      let value =
+        parsed.map_err(|_| location.error(column, format!("invalid number `{}`", literal)))?;
+      tokens.push(Token {
+        kind: TokenKind::Number(value),
+        column,
+      });
+    } else if character == '"' {
+      position += 1;
+      while position < characters.len() && characters[position] != '"' {
+        position += 1;
+      }
+      if position == characters.len() {
+        return Err(location.error(column, "unterminated string"));
+      }
+      let text = characters[start + 1..position].iter().collect();
+      position += 1;
+      tokens.push(Token {
+        kind: TokenKind::Text(text),
+        column,
+      });
+    } else if (character == '<' || character == '>')
+      && characters.get(position + 1) == Some(&character)
+    {
+      position += 2;
+      tokens.push(Token {
+        kind: if character == '<' {
+          TokenKind::ShiftLeft
+        } else {
+          TokenKind::ShiftRight
+        },
+        column,
+      });
+    } else if ",:()[]+-*/%&|^~$=".contains(character) {
+      position += 1;
+      tokens.push(Token {
+        kind: TokenKind::Punctuation(character),
+        column,
+      });
+    } else {
+      return Err(location.error(column, format!("unexpected character `{}`", character)));
+    }
+  }
+
+  Ok(tokens)
+}
+
+struct Parser<'t> {
+  tokens: &'t [Token],
+  position: usize,
+  location: &'t Location,
+  end_column: usize,
+}
+
+impl<'t> Parser<'t> {
+  fn peek(&self) -> Option<&'t TokenKind> {
+    self.tokens.get(self.position).map(|token| &token.kind)
+  }
+
+  fn column(&self) -> usize {
+    self
+      .tokens
+      .get(self.position)
+      .map_or(self.end_column, |token| token.column)
+  }
+
+  fn is_done(&self) -> bool {
+    self.position == self.tokens.len()
+  }
+
+  fn eat(&mut self, kind: &TokenKind) -> bool {
+    if self.peek() == Some(kind) {
+      self.position += 1;
+      true
+    } else {
+      false
+    }
+  }
+
+  fn expect(&mut self, kind: TokenKind, description: &str) -> Result<(), AssemblyError> {
+    if self.eat(&kind) {
+      Ok(())
+    } else {
+      Err(self.error(format!("expected {}", description)))
+    }
+  }
+
+  fn error(&self, message: impl Into<String>) -> AssemblyError {
+    self.location.error(self.column(), message)
+  }
+
+  fn expression(&mut self) -> Result<Expression, AssemblyError> {
+    self.binary(0)
+  }
+
+  fn binary(&mut self, level: usize) -> Result<Expression, AssemblyError> {
+    const LEVELS: [&[(TokenKind, BinaryOperator)]; 6] = [
+      &[(TokenKind::Punctuation('|'), BinaryOperator::Or)],
+      &[(TokenKind::Punctuation('^'), BinaryOperator::Xor)],
+      &[(TokenKind::Punctuation('&'), BinaryOperator::And)],
+      &[
+        (TokenKind::ShiftLeft, BinaryOperator::ShiftLeft),
+        (TokenKind::ShiftRight, BinaryOperator::ShiftRight),
+      ],
+      &[
+        (TokenKind::Punctuation('+'), BinaryOperator::Add),
+        (TokenKind::Punctuation('-'), BinaryOperator::Subtract),
+      ],
+      &[
+        (TokenKind::Punctuation('*'), BinaryOperator::Multiply),
+        (TokenKind::Punctuation('/'), BinaryOperator::Divide),
+        (TokenKind::Punctuation('%'), BinaryOperator::Remainder),
+      ],
+    ];
+
+    if level == LEVELS.len() {
+      return self.unary();
+    }
+
+    let mut left = self.binary(level + 1)?;
+    'operators: loop {
+      for (kind, operator) in LEVELS[level].iter() {
+        let column = self.column();
+        if self.eat(kind) {
+          let right = self.binary(level + 1)?;
+          left = Expression::Binary(*operator, Box::new(left), Box::new(right), column);
+          continue 'operators;
+        }
+      }
+      return Ok(left);
+    }
+  }
+
+  fn unary(&mut self) -> Result<Expression, AssemblyError> {
+    let column = self.column();
+    let kind = match self.peek() {
+      Some(kind) => kind.clone(),
+      None => return Err(self.error("expected an expression")),
+    };
+    self.position += 1;
+
+    match kind {
+      TokenKind::Number(value) => Ok(Expression::Number(value)),
+      TokenKind::Identifier(name) => Ok(Expression::Symbol(name, column)),
+      TokenKind::Punctuation('$') => Ok(Expression::Here),
+      TokenKind::Punctuation('-') => Ok(Expression::Negate(Box::new(self.unary()?))),
+      TokenKind::Punctuation('+') => self.unary(),
+      TokenKind::Punctuation('~') => Ok(Expression::Not(Box::new(self.unary()?))),
+      TokenKind::Punctuation('(') => {
+        let expression = self.expression()?;
+        self.expect(TokenKind::Punctuation(')'), "`)`")?;
+        Ok(expression)
+      }
+      _ => {
+        self.position -= 1;
+        Err(self.error("expected an expression"))
+      }
+    }
+  }
+
+  fn operand(&mut self) -> Result<Operand, AssemblyError> {
+    let column = self.column();
+    if self.eat(&TokenKind::Punctuation('[')) {
+      match self.peek() {
+        Some(TokenKind::Identifier(name)) if name.eq_ignore_ascii_case("I") => self.position += 1,
+        _ => return Err(self.error("expected `I`")),
+      }
+      self.expect(TokenKind::Punctuation(']'), "`]`")?;
+      return Ok(Operand::IndirectI);
+    }
+
+    if let Some(TokenKind::Identifier(name)) = self.peek() {
+      let keyword = match name.to_ascii_uppercase().as_str() {
+        "I" => Some(Operand::I),
+        "DT" => Some(Operand::DelayTimer),
+        "ST" => Some(Operand::SoundTimer),
+        "K" => Some(Operand::Key),
+        "F" => Some(Operand::Font),
+        "HF" => Some(Operand::BigFont),
+        "B" => Some(Operand::Bcd),
+        "R" => Some(Operand::Flags),
+        "LONG" => {
+          self.position += 1;
+          let column = self.column();
+          return Ok(Operand::Long(self.expression()?, column));
+        }
+        name => parse_register(name).map(Operand::Register),
+      };
+      if let Some(operand) = keyword {
+        self.position += 1;
+        return Ok(operand);
+      }
+    }
+
+    Ok(Operand::Value(self.expression()?, column))
+  }
+
+  fn list<T>(
+    &mut self,
+    mut item: impl FnMut(&mut Self) -> Result<T, AssemblyError>,
+  ) -> Result<Vec<T>, AssemblyError> {
+    let mut items = vec![];
+    if self.is_done() {
+      return Ok(items);
+    }
+    loop {
+      items.push(item(self)?);
+      if self.is_done() {
+        return Ok(items);
+      }
+      self.expect(TokenKind::Punctuation(','), "`,`")?;
+    }
+  }
+}
+
+fn parse_register(name: &str) -> Option<u8> {
+  let mut characters = name.chars();
+  match (characters.next(), characters.next(), characters.next()) {
+    (Some('V'), Some(digit), None) => digit.to_digit(16).map(|register| register as u8),
+    _ => None,
+  }
+}
+
+fn is_reserved(name: &str) -> bool {
+  let name = name.to_ascii_uppercase();
+  KEYWORDS.contains(&name.as_str())
+    || MNEMONICS.contains(&name.as_str())
+    || parse_register(&name).is_some()
+}
+
+fn instruction_size(mnemonic: &str, operands: &[Operand]) -> usize {
+  match (mnemonic, operands) {
+    ("LD", [Operand::I, Operand::Long(..)]) => 4,
+    _ => 2,
+  }
+}
+
+impl<'a> Assembler<'a> {
+  fn parse_source(
+    &mut self,
+    source: &str,
+    file: Option<String>,
+    depth: usize,
+  ) -> Result<(), AssemblyError> {
+    for (index, text) in source.lines().enumerate() {
+      let location = Location {
+        file: file.clone(),
+        line: index + 1,
+      };
+      let tokens = tokenize(text, &location)?;
+      let mut parser = Parser {
+        tokens: &tokens,
+        position: 0,
+        location: &location,
+        end_column: text.chars().count() + 1,
+      };
+      self.parse_line(&mut parser, depth)?;
+    }
+    Ok(())
+  }
+
+  fn define(
+    &mut self,
+    name: &str,
+    symbol: Symbol,
+    location: &Location,
+    column: usize,
+  ) -> Result<(), AssemblyError> {
+    if is_reserved(name) {
+      return Err(location.error(column, format!("`{}` is a reserved name", name)));
+    }
+    if self.symbols.contains_key(name) {
+      return Err(location.error(column, format!("`{}` is already defined", name)));
+    }
+    self.symbols.insert(name.to_string(), symbol);
+    Ok(())
+  }
+
+  fn parse_line(&mut self, parser: &mut Parser, depth: usize) -> Result<(), AssemblyError> {
+    let location = parser.location;
+
+    if let (
+      Some(Token {
+        kind: TokenKind::Identifier(name),
+        column,
+      }),
+      Some(next),
+    ) = (parser.tokens.first(), parser.tokens.get(1))
+    {
+      let is_constant = match &next.kind {
+        TokenKind::Identifier(keyword) => keyword.eq_ignore_ascii_case("EQU"),
+        TokenKind::Punctuation('=') => true,
+        _ => false,
+      };
+      if is_constant {
+        parser.position = 2;
+        let expression = parser.expression()?;
+        if !parser.is_done() {
+          return Err(parser.error("expected the end of the line"));
+        }
+        let symbol = Symbol::Constant(expression, location.clone());
+        return self.define(name, symbol, location, *column);
+      }
+      if next.kind == TokenKind::Punctuation(':') {
+        self.define(name, Symbol::Address(self.address), location, *column)?;
+        parser.position = 2;
+      }
+    }
+
+    let column = parser.column();
+    let mnemonic = match parser.peek() {
+      None => return Ok(()),
+      Some(TokenKind::Identifier(name)) => name.to_ascii_uppercase(),
+      Some(_) => return Err(parser.error("expected an instruction")),
+    };
+    parser.position += 1;
+
+    let (statement, size) = match mnemonic.as_str() {
+      "INCLUDE" => {
+        let name = match parser.peek() {
+          Some(TokenKind::Text(name)) => name.clone(),
+          _ => return Err(parser.error("expected a file name in quotes")),
+        };
+        parser.position += 1;
+        if !parser.is_done() {
+          return Err(parser.error("expected the end of the line"));
+        }
+        if depth == MAX_INCLUDE_DEPTH {
+          return Err(location.error(column, "includes are nested too deeply"));
+        }
+        let source = (self.read_include)(&name).map_err(|error| {
+          location.error(column, format!("cannot include `{}`: {}", name, error))
+        })?;
+        return self.parse_source(&source, Some(name), depth + 1);
+      }
+      "DB" | "DW" => {
+        let values = parser.list(|parser| {
+          let column = parser.column();
+          Ok((parser.expression()?, column))
+        })?;
+        if values.is_empty() {
+          return Err(parser.error("expected an expression"));
+        }
+        if mnemonic == "DB" {
+          let size = values.len();
+          (Statement::Bytes(values), size)
+        } else {
+          let size = values.len() * 2;
+          (Statement::Words(values), size)
+        }
+      }
+      _ => {
+        let operands = parser.list(Parser::operand)?;
+        let size = instruction_size(&mnemonic, &operands);
+        (Statement::Instruction { mnemonic, operands }, size)
+      }
+    };
+
+    self.items.push(Item {
+      location: location.clone(),
+      column,
+      address: self.address,
+      statement,
+    });
+    self.address += size;
+    if self.address > MEMORY_SIZE {
+      return Err(location.error(column, "the program does not fit in memory"));
+    }
+    Ok(())
+  }
+
+  fn evaluate(
+    &self,
+    expression: &Expression,
+    here: usize,
+    location: &Location,
+    depth: usize,
+  ) -> Result<i64, AssemblyError> {
+    let evaluate = |expression| self.evaluate(expression, here, location, depth);
+    match expression {
+      Expression::Number(value) => Ok(*value),
+      Expression::Here => Ok(here as i64),
+      Expression::Symbol(name, column) => match self.symbols.get(name) {
+        None => Err(location.error(*column, format!("`{}` is not defined", name))),
+        Some(Symbol::Address(address)) => Ok(*address as i64),
+        Some(Symbol::Constant(expression, definition)) => {
+          if depth == MAX_CONSTANT_DEPTH {
+            return Err(
+              location.error(*column, format!("`{}` is defined in terms of itself", name)),
+            );
+          }
+          self.evaluate(expression, here, definition, depth + 1)
+        }
+      },
+      Expression::Negate(value) => Ok(evaluate(value)?.wrapping_neg()),
+      Expression::Not(value) => Ok(!evaluate(value)?),
+      Expression::Binary(operator, left, right, column) => {
+        let (left, right) = (evaluate(left)?, evaluate(right)?);
+        let checked = |result: Option<i64>, message: &str| {
+          result.ok_or_else(|| location.error(*column, message))
+        };
+        match operator {
+          BinaryOperator::Add => Ok(left.wrapping_add(right)),
+          BinaryOperator::Subtract => Ok(left.wrapping_sub(right)),
+          BinaryOperator::Multiply => Ok(left.wrapping_mul(right)),
+          BinaryOperator::Divide => checked(left.checked_div(right), "division by zero"),
+          BinaryOperator::Remainder => checked(left.checked_rem(right), "division by zero"),
+          BinaryOperator::And => Ok(left & right),
+          BinaryOperator::Or => Ok(left | right),
+          BinaryOperator::Xor => Ok(left ^ right),
+          BinaryOperator::ShiftLeft => {
+            checked(left.checked_shl(right as u32), "shift is too large")
+          }
+          BinaryOperator::ShiftRight => {
+            checked(left.checked_shr(right as u32), "shift is too large")
+          }
+        }
+      }
+    }
+  }
+
+  /// Evaluates `expression` and checks it fits in `bits`. Bytes and words
+  /// also take negative values, which are stored in two's complement.
+  fn value(
+    &self,
+    expression: &Expression,
+    column: usize,
+    item: &Item,
+    bits: u32,
+  ) -> Result<u16, AssemblyError> {
+    let value = self.evaluate(expression, item.address, &item.location, 0)?;
+    let max = (1i64 << bits) - 1;
+    let min = match bits {
+      8 | 16 => -(1i64 << (bits - 1)),
+      _ => 0,
+    };
+    if value < min || value > max {
+      return Err(
+        item
+          .location
+          .error(column, format!("{} does not fit in {} bits", value, bits)),
+      );
+    }
+    Ok((value & max) as u16)
+  }
+
+  fn encode(
+    &self,
+    item: &Item,
+    mnemonic: &str,
+    operands: &[Operand],
+  ) -> Result<Vec<u16>, AssemblyError> {
+    use Operand::*;
+
+    let nibble = |expression, column| self.value(expression, column, item, 4);
+    let byte = |expression, column| self.value(expression, column, item, 8);
+    let address = |expression, column| self.value(expression, column, item, 12);
+    let x = |op_code: u16, vx: u8| op_code | (vx as u16) << 8;
+    let xy = |op_code: u16, vx: u8, vy: u8| x(op_code, vx) | (vy as u16) << 4;
+
+    let op_code = match (mnemonic, operands) {
+      ("SYS", [Value(target, column)]) => address(target, *column)?,
+      ("CLS", []) => 0x00E0,
+      ("RET", []) => 0x00EE,
+      ("SCD", [Value(rows, column)]) => 0x00C0 | nibble(rows, *column)?,
+      ("SCR", []) => 0x00FB,
+      ("SCL", []) => 0x00FC,
+      ("EXIT", []) => 0x00FD,
+      ("LOW", []) => 0x00FE,
+      ("HIGH", []) => 0x00FF,
+      ("JP", [Value(target, column)]) => 0x1000 | address(target, *column)?,
+      ("JP", [Register(0), Value(target, column)]) => 0xB000 | address(target, *column)?,
+      ("CALL", [Value(target, column)]) => 0x2000 | address(target, *column)?,
+      ("SE", [Register(vx), Value(value, column)]) => x(0x3000, *vx) | byte(value, *column)?,
+      ("SNE", [Register(vx), Value(value, column)]) => x(0x4000, *vx) | byte(value, *column)?,
+      ("SE", [Register(vx), Register(vy)]) => xy(0x5000, *vx, *vy),
+      ("SAVE", [Register(vx), Register(vy)]) => xy(0x5002, *vx, *vy),
+      ("LOAD", [Register(vx), Register(vy)]) => xy(0x5003, *vx, *vy),
+      ("LD", [Register(vx), Value(value, column)]) => x(0x6000, *vx) | byte(value, *column)?,
+      ("ADD", [Register(vx), Value(value, column)]) => x(0x7000, *vx) | byte(value, *column)?,
+      ("LD", [Register(vx), Register(vy)]) => xy(0x8000, *vx, *vy),
+      ("OR", [Register(vx), Register(vy)]) => xy(0x8001, *vx, *vy),
+      ("AND", [Register(vx), Register(vy)]) => xy(0x8002, *vx, *vy),
+      ("XOR", [Register(vx), Register(vy)]) => xy(0x8003, *vx, *vy),
+      ("ADD", [Register(vx), Register(vy)]) => xy(0x8004, *vx, *vy),
+      ("SUB", [Register(vx), Register(vy)]) => xy(0x8005, *vx, *vy),
+      ("SHR", [Register(vx)]) => xy(0x8006, *vx, *vx),
+      ("SHR", [Register(vx), Register(vy)]) => xy(0x8006, *vx, *vy),
+      ("SUBN", [Register(vx), Register(vy)]) => xy(0x8007, *vx, *vy),
+      ("SHL", [Register(vx)]) => xy(0x800E, *vx, *vx),
+      ("SHL", [Register(vx), Register(vy)]) => xy(0x800E, *vx, *vy),
+      ("SNE", [Register(vx), Register(vy)]) => xy(0x9000, *vx, *vy),
+      ("LD", [I, Value(target, column)]) => 0xA000 | address(target, *column)?,
+      ("LD", [I, Long(target, column)]) => {
+        return Ok(vec![0xF000, self.value(target, *column, item, 16)?]);
+      }
+      ("RND", [Register(vx), Value(mask, column)]) => x(0xC000, *vx) | byte(mask, *column)?,
+      ("DRW", [Register(vx), Register(vy), Value(height, column)]) => {
+        xy(0xD000, *vx, *vy) | nibble(height, *column)?
+      }
+      ("SKP", [Register(vx)]) => x(0xE09E, *vx),
+      ("SKNP", [Register(vx)]) => x(0xE0A1, *vx),
+      ("LD", [Register(vx), DelayTimer]) => x(0xF007, *vx),
+      ("LD", [Register(vx), Key]) => x(0xF00A, *vx),
+      ("LD", [DelayTimer, Register(vx)]) => x(0xF015, *vx),
+      ("LD", [SoundTimer, Register(vx)]) => x(0xF018, *vx),
+      ("PLANE", [Value(planes, column)]) => 0xF001 | nibble(planes, *column)? << 8,
+      ("AUDIO", []) => 0xF002,
+      ("PITCH", [Register(vx)]) => x(0xF03A, *vx),
+      ("ADD", [I, Register(vx)]) => x(0xF01E, *vx),
+      ("LD", [Font, Register(vx)]) => x(0xF029, *vx),
+      ("LD", [BigFont, Register(vx)]) => x(0xF030, *vx),
+      ("LD", [Bcd, Register(vx)]) => x(0xF033, *vx),
+      ("LD", [IndirectI, Register(vx)]) => x(0xF055, *vx),
+      ("LD", [Register(vx), IndirectI]) => x(0xF065, *vx),
+      ("LD", [Flags, Register(vx)]) => x(0xF075, *vx),
+      ("LD", [Register(vx), Flags]) => x(0xF085, *vx),
+      _ if MNEMONICS.contains(&mnemonic) => {
+        return Err(item.location.error(
+          item.column,
+          format!("`{}` does not take these operands", mnemonic),
+        ))
+      }
+      _ => {
+        return Err(
+          item
+            .location
+            .error(item.column, format!("unknown instruction `{}`", mnemonic)),
+        )
+      }
+    };
+
+    Ok(vec![op_code])
+  }
+
+  fn emit(&self) -> Result<Vec<u8>, AssemblyError> {
+    let mut rom = vec![];
+
+    for item in self.items.iter() {
+      match &item.statement {
+        Statement::Instruction { mnemonic, operands } => {
+          for word in self.encode(item, mnemonic, operands)? {
+            rom.extend_from_slice(&word.to_be_bytes());
+          }
+        }
+        Statement::Bytes(values) => {
+          for (expression, column) in values {
+            rom.push(self.value(expression, *column, item, 8)? as u8);
+          }
+        }
+        Statement::Words(values) => {
+          for (expression, column) in values {
+            rom.extend_from_slice(&self.value(expression, *column, item, 16)?.to_be_bytes());
+          }
+        }
+      }
+    }
+
+    Ok(rom)
+  }
+
+  fn source_map(&self, file_name: &str) -> SourceMap {
+    let lines = self
+      .items
+      .iter()
+      .filter(|item| matches!(item.statement, Statement::Instruction { .. }))
+      .map(|item| SourceLine {
+        address: item.address as u16,
+        file: item
+          .location
+          .file
+          .clone()
+          .unwrap_or_else(|| file_name.to_string()),
+        line: item.location.line,
+      })
+      .collect();
+    SourceMap::new(lines)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::disassembler::disassemble;
+  use crate::random::{RandomSource, XorShift};
+
+  fn error_at(source: &str) -> (usize, usize, String) {
+    let error = assemble(source).unwrap_err();
+    (error.line, error.column, error.message)
+  }
+
+  #[test]
+  fn assembles_instructions() {
+    let source = "
+      CLS
+      ld v1, 0xF0
+      DRW V0, V1, 5
+      SHR VA
+      LD I, LONG 0x1234
+      LD [I], V6
+      LD V7, R
+      PLANE 3
+      JP V0, 0x300
+    ";
+
+    assert_eq!(
+      assemble(source).unwrap(),
+      vec![
+        0x00, 0xE0, 0x61, 0xF0, 0xD0, 0x15, 0x8A, 0xA6, 0xF0, 0x00, 0x12, 0x34, 0xF6, 0x55, 0xF7,
+        0x85, 0xF3, 0x01, 0xB3, 0x00,
+      ]
+    );
+  }
+
+  #[test]
+  fn labels_constants_and_expressions() {
+    let source = "
+      SPEED equ 2
+      HEIGHT = (SPEED + 3) * 2 - 1 ; 9
+    loop:
+      ADD V0, SPEED << 1
+      ADD V0, -1
+      DRW V0, V1, HEIGHT % 5
+      JP loop
+      JP $
+      LD I, sprite + 1
+    sprite: db 0x3C, 0b01111110
+    ";
+
+    assert_eq!(
+      assemble(source).unwrap(),
+      vec![0x70, 0x04, 0x70, 0xFF, 0xD0, 0x14, 0x12, 0x00, 0x12, 0x08, 0xA2, 0x0D, 0x3C, 0x7E]
+    );
+  }
+
+  #[test]
+  fn data_directives() {
+    assert_eq!(
+      assemble("db 1, 2, -1\ndw 0xABCD, end\nend:").unwrap(),
+      vec![0x01, 0x02, 0xFF, 0xAB, 0xCD, 0x02, 0x07]
+    );
+  }
+
+  #[test]
+  fn includes() {
+    let files: HashMap<&str, &str> = [
+      ("sprites.asm", "ball: db 0x80\ninclude \"constants.asm\""),
+      ("constants.asm", "SIZE equ 1"),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let read = |name: &str| {
+      files
+        .get(name)
+        .map(|source| source.to_string())
+        .ok_or_else(|| "not found".to_string())
+    };
+
+    let source = "LD I, ball\nDRW V0, V0, SIZE\ninclude \"sprites.asm\"";
+    assert_eq!(
+      assemble_with_includes(source, read).unwrap(),
+      vec![0xA2, 0x04, 0xD0, 0x01, 0x80]
+    );
+
+    let error = assemble_with_includes("include \"sprites.asm\"\nball:", read).unwrap_err();
+    assert_eq!(
+      error,
+      AssemblyError {
+        file: None,
+        line: 2,
+        column: 1,
+        message: "`ball` is already defined".to_string(),
+      }
+    );
+
+    let error = assemble_with_includes("include \"missing.asm\"", read).unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "1:1: cannot include `missing.asm`: not found"
+    );
+  }
+
+  #[test]
+  fn reports_positions() {
+    assert_eq!(
+      error_at("CLS\n  FOO V0"),
+      (2, 3, "unknown instruction `FOO`".to_string())
+    );
+    assert_eq!(
+      error_at("LD V0, later + nowhere\nlater:"),
+      (1, 16, "`nowhere` is not defined".to_string())
+    );
+    assert_eq!(
+      error_at("  ADD V0, 256"),
+      (1, 11, "256 does not fit in 8 bits".to_string())
+    );
+    assert_eq!(
+      error_at("LD DT, 5"),
+      (1, 1, "`LD` does not take these operands".to_string())
+    );
+    assert_eq!(error_at("JP (1 + 2"), (1, 10, "expected `)`".to_string()));
+    assert_eq!(
+      error_at("FOO equ BAR\nBAR equ FOO\nJP FOO"),
+      (2, 9, "`FOO` is defined in terms of itself".to_string())
+    );
+    assert_eq!(
+      error_at("v1: CLS"),
+      (1, 1, "`v1` is a reserved name".to_string())
+    );
+  }
+
+  #[test]
+  fn round_trips_disassembly() {
+    let program = assemble(
+      "
+        CALL draw
+      wait:
+        SKP V0
+        JP wait
+        SE V1, 4
+        LD I, LONG sprite
+        LD V2, K
+        EXIT
+      draw:
+        LD I, sprite
+        DRW V0, V1, 2
+        RET
+      sprite:
+        db 0xAA, 0x55, 0x00, 0xEE
+      ",
+    )
+    .unwrap();
+    assert_eq!(assemble(&disassemble(&program)).unwrap(), program);
+
+    for seed in 0..32 {
+      let mut random = XorShift::new(seed);
+      let rom: Vec<u8> = (0..1024).map(|_| random.next_byte()).collect();
+      assert_eq!(assemble(&disassemble(&rom)).unwrap(), rom);
+    }
+  }
+
+  #[test]
+  fn maps_instructions_to_lines() {
+    let source = "start:\n  CLS\n  db 1, 2\n  include \"sub.asm\"\n  JP start\n";
+    let (_, map) =
+      assemble_with_source_map(source, "game.asm", |_| Ok("  RET".to_string())).unwrap();
+    assert_eq!(
+      map.to_string(),
+      "0x0200 2 game.asm\n0x0204 1 sub.asm\n0x0206 5 game.asm\n"
+    );
+  }
+}
@@ -1,5 +1,113 @@
+pub const PATTERN_SIZE: usize = 16;
+pub const DEFAULT_PITCH: u8 = 64;
+
+/// An XO-CHIP audio pattern: 128 one-bit samples, most significant bit first.
+pub type Pattern = [u8; PATTERN_SIZE];
+
+/// Samples per second at which a pattern is played for a given `FX3A` pitch.
+pub fn pattern_sample_rate(pitch: u8) -> f32 {
+  4000. * 2f32.powf((pitch as f32 - 64.) / 48.)
+}
+
+/// The shape of the buzzer's tone, for ROMs that don't load a pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+  Sine,
+  Square,
+  Triangle,
+  Sawtooth,
+}
+
+impl Waveform {
+  /// The wave's value, between -1 and 1, at `phase` turns into its period.
+  pub fn sample(self, phase: f32) -> f32 {
+    let phase = phase.fract();
+    match self {
+      Waveform::Sine => (phase * 2. * std::f32::consts::PI).sin(),
+      Waveform::Square => {
+        if phase < 0.5 {
+          1.
+        } else {
+          -1.
+        }
+      }
+      Waveform::Triangle => 1. - 4. * (phase - 0.5).abs(),
+      Waveform::Sawtooth => 2. * phase - 1.,
+    }
+  }
+}
+
+/// How the buzzer sounds: `frequency` in Hz and `volume` between 0 and 1.
+/// The volume applies to XO-CHIP patterns too.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tone {
+  pub frequency: f32,
+  pub volume: f32,
+  pub waveform: Waveform,
+}
+
+impl Default for Tone {
+  fn default() -> Self {
+    Tone {
+      frequency: 440.,
+      volume: 0.25,
+      waveform: Waveform::Square,
+    }
+  }
+}
+
+/// Plays the buzzer. The engine starts the tone as soon as the sound timer
+/// is set above zero and stops it when the timer runs out, so a tone lasts
+/// as many 60 Hz frames as the ROM asked for.
 pub trait AudioDriver {
   fn new() -> Self;
 
-  fn play_sound(&mut self);
-}
\ No newline at end of file
+  fn start_tone(&mut self);
+
+  fn stop_tone(&mut self);
+
+  /// Applies to the next tones, and to the current one if it can.
+  fn set_tone(&mut self, tone: Tone);
+
+  /// Called whenever the ROM loads a new pattern (`F002`) or pitch (`FX3A`).
+  /// From then on the buzzer plays the pattern instead of the tone's waveform.
+  fn set_pattern(&mut self, pattern: Pattern, sample_rate: f32);
+}
+
+/// An `AudioDriver` that plays nothing, for running ROMs headless or for
+/// frontends that play the samples from `Chip8::fill_audio` instead.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Silent;
+
+impl AudioDriver for Silent {
+  fn new() -> Self {
+    Silent
+  }
+
+  fn start_tone(&mut self) {}
+
+  fn stop_tone(&mut self) {}
+
+  fn set_tone(&mut self, _tone: Tone) {}
+
+  fn set_pattern(&mut self, _pattern: Pattern, _sample_rate: f32) {}
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn waveforms_span_a_period() {
+    let samples = |waveform: Waveform| -> Vec<f32> {
+      [0., 0.25, 0.5, 0.75, 1.25]
+        .iter()
+        .map(|phase| (waveform.sample(*phase) * 100.).round() / 100.)
+        .collect()
+    };
+    assert_eq!(samples(Waveform::Sine), [0., 1., 0., -1., 1.]);
+    assert_eq!(samples(Waveform::Square), [1., 1., -1., -1., 1.]);
+    assert_eq!(samples(Waveform::Triangle), [-1., 0., 1., 0., 0.]);
+    assert_eq!(samples(Waveform::Sawtooth), [-1., -0.5, 0., 0.5, -0.5]);
+  }
+}
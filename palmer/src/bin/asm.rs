@@ -0,0 +1,59 @@
+use palmer::assembler::assemble_with_source_map;
+use palmer::octo;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+  let (source_path, rom_path) = match args.as_slice() {
+    [source_path, rom_path] => (Path::new(source_path), Path::new(rom_path)),
+    _ => {
+      eprintln!("usage: asm <source.asm|source.8o> <rom.ch8>");
+      process::exit(2);
+    }
+  };
+
+  let source = fs::read_to_string(source_path).unwrap_or_else(|error| {
+    eprintln!("asm: {}: {}", source_path.display(), error);
+    process::exit(1);
+  });
+
+  // Includes are looked up next to the file being assembled.
+  let directory = source_path.parent().unwrap_or_else(|| Path::new(""));
+  let file_name = source_path
+    .file_name()
+    .map(|name| name.to_string_lossy().into_owned())
+    .unwrap_or_default();
+  let result = match source_path.extension() {
+    Some(extension) if extension == "8o" => octo::compile(&source).map(|rom| (rom, None)),
+    _ => assemble_with_source_map(&source, &file_name, |name| {
+      fs::read_to_string(directory.join(name)).map_err(|error| error.to_string())
+    })
+    .map(|(rom, source_map)| (rom, Some(source_map))),
+  };
+  let (rom, source_map) = result.unwrap_or_else(|error| {
+    match error.file {
+      Some(_) => eprintln!("{}", error),
+      None => eprintln!("{}:{}", source_path.display(), error),
+    }
+    process::exit(1);
+  });
+
+  if let Err(error) = fs::write(rom_path, rom) {
+    eprintln!("asm: {}: {}", rom_path.display(), error);
+    process::exit(1);
+  }
+
+  // Debuggers find the map next to the ROM. File names in it are relative
+  // to the source, so this assumes the ROM is written next to it too.
+  if let Some(source_map) = source_map {
+    let mut map_path = rom_path.as_os_str().to_owned();
+    map_path.push(".map");
+    if let Err(error) = fs::write(&map_path, source_map.to_string()) {
+      eprintln!("asm: {}: {}", Path::new(&map_path).display(), error);
+      process::exit(1);
+    }
+  }
+}
@@ -0,0 +1,11 @@
+use palmer::dap::DapServer;
+use std::io::{self, BufReader};
+use std::process;
+
+fn main() {
+  let mut server = DapServer::new(io::stdout());
+  if let Err(error) = server.serve(BufReader::new(io::stdin())) {
+    eprintln!("dap: {}", error);
+    process::exit(1);
+  }
+}
@@ -0,0 +1,22 @@
+use palmer::disassembler::disassemble;
+use std::env;
+use std::fs;
+use std::process;
+
+fn main() {
+  let path = match env::args().nth(1) {
+    Some(path) => path,
+    None => {
+      eprintln!("usage: disasm <rom.ch8>");
+      process::exit(2);
+    }
+  };
+
+  match fs::read(&path) {
+    Ok(rom) => print!("{}", disassemble(&rom)),
+    Err(error) => {
+      eprintln!("disasm: {}: {}", path, error);
+      process::exit(1);
+    }
+  }
+}
@@ -0,0 +1,61 @@
+use palmer::audio::Silent;
+use palmer::debugger::Debugger;
+use palmer::gdb::{GdbStub, Stdio};
+use palmer::{Chip8, Quirks};
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::process;
+
+const DEFAULT_PORT: u16 = 1234;
+const USAGE: &str = "usage: gdbstub <rom.ch8> [--port <port> | --stdio]";
+
+fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+  let (rom_path, port) = match args.as_slice() {
+    [rom_path] => (rom_path, Some(DEFAULT_PORT)),
+    [rom_path, flag] if flag == "--stdio" => (rom_path, None),
+    [rom_path, flag, port] if flag == "--port" => match port.parse() {
+      Ok(port) => (rom_path, Some(port)),
+      Err(_) => {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+      }
+    },
+    _ => {
+      eprintln!("{}", USAGE);
+      process::exit(2);
+    }
+  };
+
+  let rom = fs::read(rom_path).unwrap_or_else(|error| {
+    eprintln!("gdbstub: {}: {}", rom_path, error);
+    process::exit(1);
+  });
+  let mut chip8 = Chip8::new(Silent, Quirks::default());
+  if let Err(error) = chip8.load(rom) {
+    eprintln!("gdbstub: {}: {}", rom_path, error);
+    process::exit(1);
+  }
+  let mut stub = GdbStub::new(Debugger::new(chip8));
+
+  let result = match port {
+    None => stub.serve(&mut Stdio),
+    Some(port) => {
+      let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|error| {
+        eprintln!("gdbstub: port {}: {}", port, error);
+        process::exit(1);
+      });
+      eprintln!("gdbstub: waiting for a client on 127.0.0.1:{}", port);
+      listener.accept().and_then(|(mut stream, _)| {
+        stream.set_nodelay(true)?;
+        stub.serve(&mut stream)
+      })
+    }
+  };
+
+  if let Err(error) = result {
+    eprintln!("gdbstub: {}", error);
+    process::exit(1);
+  }
+}
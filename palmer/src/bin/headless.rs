@@ -0,0 +1,217 @@
+use palmer::audio::Silent;
+use palmer::headless::{self, KeyEvent, Outcome, Script, StopCondition};
+use palmer::movie::Movie;
+use palmer::quirks::{Quirks, PRESETS};
+use palmer::{screenshot, wav, Chip8, MovieError};
+use std::env;
+use std::fs;
+use std::process;
+
+const DEFAULT_FRAMES: usize = 600;
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+const EXIT_IO: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_TIMEOUT: i32 = 3;
+const EXIT_EMULATION: i32 = 4;
+const EXIT_DESYNC: i32 = 5;
+
+const USAGE: &str = "usage: headless <rom.ch8> [options]
+  --frames <n>           frames to run, 600 by default
+  --until-pc <address>   stop right before the instruction at this address
+  --until-halt           stop when the ROM exits
+  --press <frame>:<key>  press a key (0-F) before the given frame
+  --release <frame>:<key>
+  --quirks <preset>      cosmac_vip, chip48, super_chip or modern (the default)
+  --movie <file>         play back an input movie instead of frames and keys, with its quirks
+  --screen <file>        write the screen as .pbm, .png or ASCII art (anything else)
+  --state <file>         write the registers and memory as JSON
+  --audio <file>         write the buzzer as a 16-bit mono WAV file
+  --sample-rate <hz>     sample rate of the WAV file, 44100 by default
+exits with 0 when done, 3 if the stop condition was not met in time, 4 on emulation errors
+and 5 if the movie was recorded with another ROM";
+
+struct Options {
+  rom_path: String,
+  script: Script,
+  quirks: Quirks,
+  movie_path: Option<String>,
+  screen_path: Option<String>,
+  state_path: Option<String>,
+  audio_path: Option<String>,
+  sample_rate: u32,
+}
+
+fn usage(message: &str) -> ! {
+  if !message.is_empty() {
+    eprintln!("headless: {}", message);
+  }
+  eprintln!("{}", USAGE);
+  process::exit(EXIT_USAGE);
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+  match text.strip_prefix("0x") {
+    Some(hex) => u16::from_str_radix(hex, 16).ok(),
+    None => text.parse().ok(),
+  }
+}
+
+fn parse_options() -> Options {
+  let mut args = env::args().skip(1);
+  let mut rom_path = None;
+  let mut script = Script::new(DEFAULT_FRAMES);
+  let mut quirks = Quirks::default();
+  let mut movie_path = None;
+  let mut screen_path = None;
+  let mut state_path = None;
+  let mut audio_path = None;
+  let mut sample_rate = DEFAULT_SAMPLE_RATE;
+
+  while let Some(arg) = args.next() {
+    let mut value = || {
+      args
+        .next()
+        .unwrap_or_else(|| usage(&format!("{} needs a value", arg)))
+    };
+    match arg.as_str() {
+      "--frames" => {
+        script.frames = value()
+          .parse()
+          .unwrap_or_else(|_| usage("invalid frame count"))
+      }
+      "--until-pc" => {
+        let address = parse_address(&value()).unwrap_or_else(|| usage("invalid address"));
+        script.stop = Some(StopCondition::ProgramCounter(address));
+      }
+      "--until-halt" => script.stop = Some(StopCondition::Halted),
+      "--press" | "--release" => {
+        let event =
+          KeyEvent::parse(&value(), arg == "--press").unwrap_or_else(|error| usage(&error));
+        script.keys.push(event);
+      }
+      "--quirks" => {
+        let name = value();
+        quirks = Quirks::preset(&name).unwrap_or_else(|| {
+          usage(&format!(
+            "unknown quirks `{}`, expected one of {}",
+            name,
+            PRESETS.join(", ")
+          ))
+        })
+      }
+      "--movie" => movie_path = Some(value()),
+      "--screen" => screen_path = Some(value()),
+      "--state" => state_path = Some(value()),
+      "--audio" => audio_path = Some(value()),
+      "--sample-rate" => {
+        sample_rate = value()
+          .parse()
+          .ok()
+          .filter(|rate| *rate > 0)
+          .unwrap_or_else(|| usage("invalid sample rate"))
+      }
+      _ if arg.starts_with("--") || rom_path.is_some() => {
+        usage(&format!("unexpected argument `{}`", arg))
+      }
+      _ => rom_path = Some(arg),
+    }
+  }
+
+  Options {
+    rom_path: rom_path.unwrap_or_else(|| usage("")),
+    script,
+    quirks,
+    movie_path,
+    screen_path,
+    state_path,
+    audio_path,
+    sample_rate,
+  }
+}
+
+fn write(path: &str, contents: &[u8]) {
+  if let Err(error) = fs::write(path, contents) {
+    eprintln!("headless: {}: {}", path, error);
+    process::exit(EXIT_IO);
+  }
+}
+
+fn main() {
+  let options = parse_options();
+
+  let rom = fs::read(&options.rom_path).unwrap_or_else(|error| {
+    eprintln!("headless: {}: {}", options.rom_path, error);
+    process::exit(EXIT_IO);
+  });
+  let mut chip8 = Chip8::new(Silent, options.quirks);
+  if let Err(error) = chip8.load(rom.clone()) {
+    eprintln!("headless: {}: {}", options.rom_path, error);
+    process::exit(EXIT_EMULATION);
+  }
+
+  let mut samples = vec![];
+  let result = match &options.movie_path {
+    Some(path) => {
+      let movie = fs::read(path)
+        .map_err(|error| error.to_string())
+        .and_then(|movie| Movie::from_bytes(&movie).map_err(|error| error.to_string()))
+        .unwrap_or_else(|error| {
+          eprintln!("headless: {}: {}", path, error);
+          process::exit(EXIT_IO);
+        });
+      movie
+        .play_with(&mut chip8, &rom, |chip8| {
+          if options.audio_path.is_some() {
+            headless::record_audio(chip8, options.sample_rate, &mut samples);
+          }
+        })
+        .map(|()| None)
+    }
+    None => match options.audio_path {
+      Some(_) => options
+        .script
+        .run_with_audio(&mut chip8, options.sample_rate, &mut samples),
+      None => options.script.run(&mut chip8),
+    }
+    .map(Some)
+    .map_err(MovieError::Emulation),
+  };
+
+  if let Some(path) = &options.screen_path {
+    let image = if path.ends_with(".pbm") {
+      screenshot::to_pbm(&chip8.display)
+    } else if path.ends_with(".png") {
+      screenshot::to_png(&chip8.display)
+    } else {
+      screenshot::to_ascii(&chip8.display).into_bytes()
+    };
+    write(path, &image);
+  }
+  if let Some(path) = &options.state_path {
+    let state = headless::state_json(&chip8);
+    write(path, format!("{:#}\n", state).as_bytes());
+  }
+  if let Some(path) = &options.audio_path {
+    write(path, &wav::to_wav(&samples, options.sample_rate));
+  }
+
+  match result {
+    Ok(Some(report)) if report.outcome == Outcome::TimedOut => {
+      eprintln!(
+        "headless: stop condition not met after {} frames",
+        report.frames
+      );
+      process::exit(EXIT_TIMEOUT);
+    }
+    Ok(_) => {}
+    Err(MovieError::Emulation(error)) => {
+      eprintln!("headless: {}", error);
+      process::exit(EXIT_EMULATION);
+    }
+    Err(error) => {
+      eprintln!("headless: {}", error);
+      process::exit(EXIT_DESYNC);
+    }
+  }
+}
@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+/// Rate at which the delay and sound timers count down.
+pub const TIMER_FREQUENCY: u32 = 60;
+pub const DEFAULT_INSTRUCTIONS_PER_SECOND: u32 = 700;
+
+/// The longest stretch of host time `Clock::frames_for` will catch up on.
+/// Anything beyond it (a suspended tab, a debugger pause) is dropped instead
+/// of being run all at once.
+pub const MAX_CATCH_UP_FRAMES: u32 = 6;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Splits host time into 60 Hz frames and frames into instructions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Clock {
+  instructions_per_second: u32,
+  instruction_remainder: u32,
+  // Host time not yet turned into frames, in nanoseconds times TIMER_FREQUENCY.
+  pending_time: u64,
+}
+
+impl Clock {
+  pub fn new(instructions_per_second: u32) -> Clock {
+    Clock {
+      instructions_per_second,
+      instruction_remainder: 0,
+      pending_time: 0,
+    }
+  }
+
+  pub fn instructions_per_second(&self) -> u32 {
+    self.instructions_per_second
+  }
+
+  pub fn set_instructions_per_second(&mut self, instructions_per_second: u32) {
+    self.instructions_per_second = instructions_per_second;
+    self.instruction_remainder = 0;
+  }
+
+  /// The sixtieths of an instruction carried over from the frames so far,
+  /// which decide how many the next ones run.
+  pub fn instruction_remainder(&self) -> u32 {
+    self.instruction_remainder
+  }
+
+  pub fn set_instruction_remainder(&mut self, remainder: u32) {
+    self.instruction_remainder = remainder % TIMER_FREQUENCY;
+  }
+
+  /// How many instructions the next frame should run. Speeds that are not a
+  /// multiple of 60 are spread so that every second runs exactly the
+  /// configured amount.
+  pub fn instructions_for_next_frame(&mut self) -> u32 {
+    let total = self.instructions_per_second + self.instruction_remainder;
+    self.instruction_remainder = total % TIMER_FREQUENCY;
+    total / TIMER_FREQUENCY
+  }
+
+  /// Adds host time and returns how many whole frames are now due.
+  pub fn frames_for(&mut self, elapsed: Duration) -> u32 {
+    self.pending_time += elapsed.as_nanos() as u64 * TIMER_FREQUENCY as u64;
+    let frames = self.pending_time / NANOS_PER_SECOND;
+    self.pending_time %= NANOS_PER_SECOND;
+    frames.min(MAX_CATCH_UP_FRAMES as u64) as u32
+  }
+}
+
+impl Default for Clock {
+  fn default() -> Clock {
+    Clock::new(DEFAULT_INSTRUCTIONS_PER_SECOND)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn spreads_instructions_over_a_second() {
+    let mut clock = Clock::new(700);
+    let per_frame: Vec<u32> = (0..TIMER_FREQUENCY)
+      .map(|_| clock.instructions_for_next_frame())
+      .collect();
+    assert!(per_frame.iter().all(|count| *count == 11 || *count == 12));
+    assert_eq!(per_frame.iter().sum::<u32>(), 700);
+  }
+
+  #[test]
+  fn accumulates_host_time() {
+    let mut clock = Clock::default();
+    assert_eq!(clock.frames_for(Duration::from_millis(10)), 0);
+    assert_eq!(clock.frames_for(Duration::from_millis(10)), 1);
+    assert_eq!(clock.frames_for(Duration::from_millis(15)), 1);
+    let frames: u32 = (0..1000)
+      .map(|_| clock.frames_for(Duration::from_millis(1)))
+      .sum();
+    assert_eq!(frames, 60);
+  }
+
+  #[test]
+  fn drops_time_it_cannot_catch_up_on() {
+    let mut clock = Clock::default();
+    assert_eq!(
+      clock.frames_for(Duration::from_secs(10)),
+      MAX_CATCH_UP_FRAMES
+    );
+    assert_eq!(clock.frames_for(Duration::from_millis(1)), 0);
+  }
+}
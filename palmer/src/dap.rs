@@ -0,0 +1,722 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+
+use super::audio::Silent;
+use super::debugger::{Debugger, StopReason};
+use super::error::EmulationError;
+use super::processor::Chip8;
+use super::quirks::Quirks;
+use super::source_map::SourceMap;
+
+const THREAD_ID: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+const TIMERS_REFERENCE: u64 = 2;
+/// Instructions run between checks for new requests while the ROM runs.
+const INSTRUCTIONS_PER_POLL: usize = 10_000;
+/// How long `next` and `stepOut` may run before they give up and pause.
+const MAX_STEP_INSTRUCTIONS: usize = 1_000_000;
+
+/// Reads one `Content-Length` framed message. Returns `None` at the end of
+/// the input.
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+  let mut length = None;
+  loop {
+    let mut header = String::new();
+    if input.read_line(&mut header)? == 0 {
+      return Ok(None);
+    }
+    let header = header.trim_end();
+    if header.is_empty() {
+      if length.is_some() {
+        break;
+      }
+      continue;
+    }
+    if let Some(value) = header.strip_prefix("Content-Length:") {
+      length = value.trim().parse::<usize>().ok();
+    }
+  }
+
+  let mut body = vec![0; length.unwrap()];
+  input.read_exact(&mut body)?;
+  serde_json::from_slice(&body)
+    .map(Some)
+    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+  let body = message.to_string();
+  write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+  output.flush()
+}
+
+fn base64(bytes: &[u8]) -> String {
+  const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+  let mut text = String::new();
+  for chunk in bytes.chunks(3) {
+    let group = chunk.iter().enumerate().fold(0u32, |group, (index, byte)| {
+      group | (*byte as u32) << (16 - 8 * index)
+    });
+    for index in 0..4 {
+      if index <= chunk.len() {
+        text.push(ALPHABET[(group >> (18 - 6 * index) & 0x3F) as usize] as char);
+      } else {
+        text.push('=');
+      }
+    }
+  }
+  text
+}
+
+fn parse_address(text: &str) -> Option<usize> {
+  let digits = text
+    .strip_prefix("0x")
+    .or_else(|| text.strip_prefix("0X"))?;
+  usize::from_str_radix(digits, 16).ok()
+}
+
+fn parse_quirks(name: &str) -> Option<Quirks> {
+  match name {
+    "cosmacVip" => Some(Quirks::cosmac_vip()),
+    "chip48" => Some(Quirks::chip48()),
+    "superChip" => Some(Quirks::super_chip()),
+    "modern" => Some(Quirks::modern()),
+    _ => None,
+  }
+}
+
+struct Session {
+  debugger: Debugger<Silent>,
+  source_map: SourceMap,
+  /// Where the files named in the source map live.
+  source_directory: PathBuf,
+  /// Breakpoint addresses set for each source file.
+  breakpoints: HashMap<PathBuf, Vec<u16>>,
+  stop_on_entry: bool,
+}
+
+impl Session {
+  fn source(&self, address: u16) -> Option<(Value, usize)> {
+    let line = self.source_map.line_at(address)?;
+    let path = self.source_directory.join(&line.file);
+    let name = Path::new(&line.file)
+      .file_name()
+      .map(|name| name.to_string_lossy().into_owned())
+      .unwrap_or_else(|| line.file.clone());
+    Some((
+      json!({ "name": name, "path": path.to_string_lossy() }),
+      line.line,
+    ))
+  }
+}
+
+/// A Debug Adapter Protocol server running ROMs under a `Debugger`.
+///
+/// `launch` takes the ROM as `program`, plus optional `stopOnEntry`,
+/// `quirks` (`cosmacVip`, `chip48`, `superChip` or `modern`) and
+/// `instructionsPerSecond`. Breakpoints are set by source line through the
+/// `SourceMap` in `<program>.map`, when there is one. There is a single
+/// thread, the registers and timers are the variables and the whole address
+/// space can be read as memory.
+pub struct DapServer<W: Write> {
+  output: W,
+  sequence: u64,
+  session: Option<Session>,
+  is_running: bool,
+}
+
+impl<W: Write> DapServer<W> {
+  pub fn new(output: W) -> DapServer<W> {
+    DapServer {
+      output,
+      sequence: 0,
+      session: None,
+      is_running: false,
+    }
+  }
+
+  /// Answers requests from `input` until the client disconnects. Input is
+  /// read on its own thread so that `pause` can reach a running ROM.
+  pub fn serve(&mut self, input: impl BufRead + Send + 'static) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+      let mut input = input;
+      loop {
+        let message = read_message(&mut input).transpose();
+        let is_done = !matches!(message, Some(Ok(_)));
+        if let Some(message) = message {
+          if sender.send(message).is_err() {
+            break;
+          }
+        }
+        if is_done {
+          break;
+        }
+      }
+    });
+
+    loop {
+      let message = if self.is_running {
+        self.run()?;
+        match receiver.try_recv() {
+          Ok(message) => message,
+          Err(TryRecvError::Empty) => continue,
+          Err(TryRecvError::Disconnected) => return Ok(()),
+        }
+      } else {
+        match receiver.recv() {
+          Ok(message) => message,
+          Err(_) => return Ok(()),
+        }
+      };
+
+      if !self.handle(&message?)? {
+        return Ok(());
+      }
+    }
+  }
+
+  fn send(&mut self, mut message: Value) -> io::Result<()> {
+    self.sequence += 1;
+    message["seq"] = json!(self.sequence);
+    write_message(&mut self.output, &message)
+  }
+
+  fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+    let mut message = json!({ "type": "event", "event": event });
+    if !body.is_null() {
+      message["body"] = body;
+    }
+    self.send(message)
+  }
+
+  fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+    let mut response = json!({
+      "type": "response",
+      "request_seq": request["seq"],
+      "command": request["command"],
+      "success": result.is_ok(),
+    });
+    match result {
+      Ok(Value::Null) => {}
+      Ok(body) => response["body"] = body,
+      Err(message) => response["message"] = json!(message),
+    }
+    self.send(response)
+  }
+
+  fn session(&mut self) -> Result<&mut Session, String> {
+    self
+      .session
+      .as_mut()
+      .ok_or_else(|| "no ROM has been launched".to_string())
+  }
+
+  /// Handles one request. Returns `false` once the client disconnects.
+  fn handle(&mut self, request: &Value) -> io::Result<bool> {
+    let arguments = &request["arguments"];
+    let command = request["command"].as_str().unwrap_or("");
+
+    match command {
+      "initialize" => {
+        let capabilities = json!({
+          "supportsConfigurationDoneRequest": true,
+          "supportsReadMemoryRequest": true,
+        });
+        self.respond(request, Ok(capabilities))?;
+        self.event("initialized", Value::Null)?;
+      }
+      "launch" => {
+        let result = self.launch(arguments).map(|_| Value::Null);
+        self.respond(request, result)?;
+      }
+      "configurationDone" => {
+        let stop_on_entry = self.session().map(|session| session.stop_on_entry);
+        self.respond(request, stop_on_entry.clone().map(|_| Value::Null))?;
+        match stop_on_entry {
+          Ok(true) => self.stopped("entry", None)?,
+          Ok(false) => self.is_running = true,
+          Err(_) => {}
+        }
+      }
+      "setBreakpoints" => {
+        let result = self.set_breakpoints(arguments);
+        self.respond(request, result)?;
+      }
+      "threads" => {
+        let threads = json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] });
+        self.respond(request, Ok(threads))?;
+      }
+      "stackTrace" => {
+        let result = self.stack_trace();
+        self.respond(request, result)?;
+      }
+      "scopes" => {
+        // Registers are global, so every frame has the same scopes.
+        let scopes = json!({ "scopes": [
+          { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+          { "name": "Timers", "variablesReference": TIMERS_REFERENCE, "expensive": false },
+        ]});
+        self.respond(request, Ok(scopes))?;
+      }
+      "variables" => {
+        let result = self.variables(arguments["variablesReference"].as_u64());
+        self.respond(request, result)?;
+      }
+      "readMemory" => {
+        let result = self.read_memory(arguments);
+        self.respond(request, result)?;
+      }
+      "continue" => {
+        let result = self
+          .session()
+          .map(|_| json!({ "allThreadsContinued": true }));
+        let is_ok = result.is_ok();
+        self.respond(request, result)?;
+        self.is_running = is_ok;
+      }
+      "next" | "stepIn" | "stepOut" => {
+        let result = self.session().map(|_| Value::Null);
+        if result.is_err() {
+          return self.respond(request, result).map(|_| true);
+        }
+        self.respond(request, result)?;
+        self.is_running = false;
+        let debugger = &mut self.session().unwrap().debugger;
+        let stop = match command {
+          "next" => debugger.step_over(MAX_STEP_INSTRUCTIONS),
+          "stepIn" => debugger.step(),
+          _ => debugger.step_out(MAX_STEP_INSTRUCTIONS),
+        };
+        self.report(stop)?;
+      }
+      "pause" => {
+        let result = self.session().map(|_| Value::Null);
+        self.respond(request, result)?;
+        if self.is_running {
+          self.is_running = false;
+          self.stopped("pause", None)?;
+        }
+      }
+      "disconnect" | "terminate" => {
+        self.respond(request, Ok(Value::Null))?;
+        return Ok(false);
+      }
+      _ => {
+        let message = format!("`{}` is not supported", command);
+        self.respond(request, Err(message))?;
+      }
+    }
+
+    Ok(true)
+  }
+
+  fn launch(&mut self, arguments: &Value) -> Result<(), String> {
+    let program = arguments["program"]
+      .as_str()
+      .ok_or_else(|| "`program` must be the path to a ROM".to_string())?;
+    let quirks = match arguments["quirks"].as_str() {
+      None => Quirks::default(),
+      Some(name) => parse_quirks(name).ok_or_else(|| format!("unknown quirks `{}`", name))?,
+    };
+
+    let rom = fs::read(program).map_err(|error| format!("{}: {}", program, error))?;
+    let mut chip8 = Chip8::new(Silent, quirks);
+    chip8.load(rom).map_err(|error| error.to_string())?;
+    if let Some(instructions_per_second) = arguments["instructionsPerSecond"].as_u64() {
+      chip8.set_instructions_per_second(instructions_per_second as u32);
+    }
+
+    let map_path = format!("{}.map", program);
+    let source_map = match fs::read_to_string(&map_path) {
+      Ok(text) => SourceMap::parse(&text).map_err(|error| format!("{}: {}", map_path, error))?,
+      Err(_) => SourceMap::default(),
+    };
+
+    self.session = Some(Session {
+      debugger: Debugger::new(chip8),
+      source_map,
+      source_directory: Path::new(program)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default(),
+      breakpoints: HashMap::new(),
+      stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+    });
+    Ok(())
+  }
+
+  fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+    let session = self.session()?;
+    let path = PathBuf::from(
+      arguments["source"]["path"]
+        .as_str()
+        .ok_or_else(|| "`source.path` is required".to_string())?,
+    );
+
+    for address in session.breakpoints.remove(&path).unwrap_or_default() {
+      session.debugger.remove_breakpoint(address);
+    }
+
+    let mut addresses = vec![];
+    let mut breakpoints = vec![];
+    let requested = arguments["breakpoints"]
+      .as_array()
+      .cloned()
+      .unwrap_or_default();
+    for breakpoint in requested {
+      let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+      match session.source_map.address_for(&path, line) {
+        Some(source_line) => {
+          addresses.push(source_line.address);
+          breakpoints.push(json!({ "verified": true, "line": source_line.line }));
+        }
+        None => breakpoints.push(json!({
+          "verified": false,
+          "line": line,
+          "message": "no instruction at or after this line",
+        })),
+      }
+    }
+
+    for address in addresses.iter() {
+      session.debugger.add_breakpoint(*address);
+    }
+    session.breakpoints.insert(path, addresses);
+    Ok(json!({ "breakpoints": breakpoints }))
+  }
+
+  fn stack_trace(&mut self) -> Result<Value, String> {
+    let session = self.session()?;
+    let chip8 = session.debugger.chip8();
+
+    // The innermost frame is where the program is; the others are the
+    // `CALL`s below each return address.
+    let addresses: Vec<u16> = Some(chip8.program_counter())
+      .into_iter()
+      .chain(
+        chip8
+          .stack()
+          .iter()
+          .rev()
+          .map(|address| address.wrapping_sub(2)),
+      )
+      .collect();
+
+    let frames: Vec<Value> = addresses
+      .iter()
+      .enumerate()
+      .map(|(id, address)| {
+        let mut frame = json!({
+          "id": id,
+          "name": format!("{:#06X}", address),
+          "line": 0,
+          "column": 0,
+          "instructionPointerReference": format!("{:#06X}", address),
+        });
+        if let Some((source, line)) = session.source(*address) {
+          frame["source"] = source;
+          frame["line"] = json!(line);
+          frame["column"] = json!(1);
+        }
+        frame
+      })
+      .collect();
+
+    Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+  }
+
+  fn variables(&mut self, reference: Option<u64>) -> Result<Value, String> {
+    let chip8 = self.session()?.debugger.chip8();
+    let byte = |name: String, value: u8| {
+      json!({
+        "name": name,
+        "value": format!("{:#04X} ({})", value, value),
+        "variablesReference": 0,
+      })
+    };
+    let address = |name: &str, value: u16| {
+      json!({
+        "name": name,
+        "value": format!("{:#06X}", value),
+        "variablesReference": 0,
+        "memoryReference": format!("{:#06X}", value),
+      })
+    };
+
+    let variables: Vec<Value> = match reference {
+      Some(REGISTERS_REFERENCE) => {
+        let mut variables: Vec<Value> = chip8
+          .registers()
+          .iter()
+          .enumerate()
+          .map(|(register, value)| byte(format!("V{:X}", register), *value))
+          .collect();
+        variables.push(address("I", chip8.index()));
+        variables.push(address("PC", chip8.program_counter()));
+        variables.push(byte("SP".to_string(), chip8.stack().len() as u8));
+        variables
+      }
+      Some(TIMERS_REFERENCE) => vec![
+        byte("DT".to_string(), chip8.delay_timer()),
+        byte("ST".to_string(), chip8.sound_timer()),
+      ],
+      _ => return Err("unknown variables reference".to_string()),
+    };
+    Ok(json!({ "variables": variables }))
+  }
+
+  fn read_memory(&mut self, arguments: &Value) -> Result<Value, String> {
+    let memory = self.session()?.debugger.chip8().memory();
+    let reference = arguments["memoryReference"]
+      .as_str()
+      .and_then(parse_address)
+      .ok_or_else(|| "`memoryReference` must be a hex address".to_string())?;
+    let overflow = || "the memory range overflows".to_string();
+    let start = i64::try_from(reference)
+      .ok()
+      .and_then(|reference| reference.checked_add(arguments["offset"].as_i64().unwrap_or(0)))
+      .ok_or_else(overflow)?;
+    let count = i64::try_from(arguments["count"].as_u64().unwrap_or(0)).map_err(|_| overflow())?;
+    let end = start.checked_add(count).ok_or_else(overflow)?;
+
+    let size = memory.len() as i64;
+    let first = start.max(0).min(size) as usize;
+    let end = end.max(0).min(size) as usize;
+    let unreadable = count - (end.saturating_sub(first)) as i64;
+    Ok(json!({
+      "address": format!("{:#06X}", first),
+      "data": base64(&memory[first..end.max(first)]),
+      "unreadableBytes": unreadable,
+    }))
+  }
+
+  /// Runs the ROM for a while, reporting it if it stops.
+  fn run(&mut self) -> io::Result<()> {
+    let stop = match self.session.as_mut() {
+      Some(session) => session.debugger.run(INSTRUCTIONS_PER_POLL),
+      None => Ok(StopReason::Limit),
+    };
+    if !matches!(stop, Ok(StopReason::Limit)) {
+      self.is_running = false;
+      self.report(stop)?;
+    }
+    Ok(())
+  }
+
+  fn report(&mut self, stop: Result<StopReason, EmulationError>) -> io::Result<()> {
+    match stop {
+      Ok(StopReason::Step) => self.stopped("step", None),
+      Ok(StopReason::Breakpoint { .. }) => self.stopped("breakpoint", None),
+      Ok(StopReason::Watchpoint { .. }) => self.stopped("data breakpoint", None),
+      Ok(StopReason::Limit) => self.stopped("pause", None),
+      Ok(StopReason::Halted) => {
+        self.event("exited", json!({ "exitCode": 0 }))?;
+        self.event("terminated", Value::Null)
+      }
+      Err(error) => self.stopped("exception", Some(error.to_string())),
+    }
+  }
+
+  fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+    let mut body = json!({
+      "reason": reason,
+      "threadId": THREAD_ID,
+      "allThreadsStopped": true,
+    });
+    if let Some(text) = text {
+      body["text"] = json!(text);
+    }
+    self.event("stopped", body)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::assembler::assemble_with_source_map;
+  use std::io::Cursor;
+
+  const SOURCE: &str = "  LD V0, 0
+loop:
+  ADD V0, 1
+  CALL double
+  SE V0, 3
+  JP loop
+  EXIT
+double:
+  LD V1, V0
+  ADD V1, V0
+  RET
+";
+
+  fn frame(message: &Value) -> Vec<u8> {
+    let mut bytes = vec![];
+    write_message(&mut bytes, message).unwrap();
+    bytes
+  }
+
+  /// Runs a recorded session and returns everything the server sent.
+  fn session(name: &str, requests: Vec<Value>) -> Vec<Value> {
+    let directory =
+      std::env::temp_dir().join(format!("palmer-dap-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let (rom, source_map) =
+      assemble_with_source_map(SOURCE, "game.asm", |_| unreachable!()).unwrap();
+    let program = directory.join("game.ch8");
+    fs::write(&program, rom).unwrap();
+    fs::write(directory.join("game.ch8.map"), source_map.to_string()).unwrap();
+
+    let mut input = vec![];
+    for (seq, mut request) in requests.into_iter().enumerate() {
+      request["seq"] = json!(seq + 1);
+      request["type"] = json!("request");
+      let text = request
+        .to_string()
+        .replace("$PROGRAM", &program.to_string_lossy());
+      let text = text.replace("$SOURCE", &directory.join("game.asm").to_string_lossy());
+      input.extend(frame(&serde_json::from_str(&text).unwrap()));
+    }
+
+    let mut output = vec![];
+    DapServer::new(&mut output)
+      .serve(Cursor::new(input))
+      .unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+
+    let mut output = Cursor::new(output);
+    let mut messages = vec![];
+    while let Some(message) = read_message(&mut output).unwrap() {
+      messages.push(message);
+    }
+    messages
+  }
+
+  fn find<'a>(messages: &'a [Value], kind: &str, name: &str) -> Vec<&'a Value> {
+    let key = if kind == "event" { "event" } else { "command" };
+    messages
+      .iter()
+      .filter(|message| message["type"] == kind && message[key] == name)
+      .collect()
+  }
+
+  #[test]
+  fn frames_messages() {
+    let message = json!({ "seq": 1, "type": "request", "command": "threads" });
+    let bytes = frame(&message);
+    assert!(bytes.starts_with(b"Content-Length: 46\r\n\r\n{"));
+    assert_eq!(
+      read_message(&mut Cursor::new(bytes)).unwrap(),
+      Some(message)
+    );
+    assert_eq!(base64(b"Man"), "TWFu");
+    assert_eq!(base64(b"Ma"), "TWE=");
+    assert_eq!(base64(b"M"), "TQ==");
+  }
+
+  #[test]
+  fn breakpoints_by_line_and_stack_frames() {
+    let messages = session(
+      "breakpoints",
+      vec![
+        json!({ "command": "initialize", "arguments": { "adapterID": "palmer" } }),
+        json!({ "command": "launch", "arguments": { "program": "$PROGRAM" } }),
+        json!({ "command": "setBreakpoints", "arguments": {
+          "source": { "path": "$SOURCE" },
+          "breakpoints": [{ "line": 8 }, { "line": 50 }],
+        }}),
+        json!({ "command": "configurationDone" }),
+        json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+        json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+        json!({ "command": "readMemory", "arguments": { "memoryReference": "0x0200", "count": 4 } }),
+        json!({ "command": "stepOut", "arguments": { "threadId": 1 } }),
+        json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+        json!({ "command": "disconnect" }),
+      ],
+    );
+
+    assert!(messages
+      .iter()
+      .all(|message| message["type"] == "event" || message["success"] == true));
+    assert_eq!(find(&messages, "event", "initialized").len(), 1);
+
+    let breakpoints = &find(&messages, "response", "setBreakpoints")[0]["body"]["breakpoints"];
+    assert_eq!(breakpoints[0], json!({ "verified": true, "line": 9 }));
+    assert_eq!(breakpoints[1]["verified"], false);
+
+    let stops = find(&messages, "event", "stopped");
+    assert_eq!(stops[0]["body"]["reason"], "breakpoint");
+    assert_eq!(stops[1]["body"]["reason"], "step");
+
+    let traces = find(&messages, "response", "stackTrace");
+    let frames = &traces[0]["body"]["stackFrames"];
+    assert_eq!(frames.as_array().unwrap().len(), 2);
+    assert_eq!(frames[0]["line"], 9);
+    assert_eq!(frames[0]["source"]["name"], "game.asm");
+    assert_eq!(frames[1]["name"], "0x0204");
+    assert_eq!(frames[1]["line"], 4);
+    assert_eq!(traces[1]["body"]["stackFrames"][0]["line"], 5);
+
+    let variables = &find(&messages, "response", "variables")[0]["body"]["variables"];
+    assert_eq!(variables[0]["value"], "0x01 (1)");
+    assert_eq!(
+      variables[17],
+      json!({
+        "name": "PC",
+        "value": "0x020C",
+        "variablesReference": 0,
+        "memoryReference": "0x020C",
+      })
+    );
+
+    let memory = &find(&messages, "response", "readMemory")[0]["body"];
+    assert_eq!(memory["address"], "0x0200");
+    assert_eq!(memory["data"], base64(&[0x60, 0x00, 0x70, 0x01]));
+  }
+
+  #[test]
+  fn runs_to_the_end() {
+    let messages = session(
+      "exit",
+      vec![
+        json!({ "command": "initialize", "arguments": {} }),
+        json!({ "command": "launch", "arguments": { "program": "$PROGRAM", "stopOnEntry": true } }),
+        json!({ "command": "configurationDone" }),
+        json!({ "command": "next", "arguments": { "threadId": 1 } }),
+        json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+        json!({ "command": "evaluate", "arguments": { "expression": "v0" } }),
+        json!({ "command": "readMemory", "arguments": {
+          "memoryReference": "0x0200",
+          "offset": i64::MAX,
+          "count": 1,
+        }}),
+        json!({ "command": "readMemory", "arguments": {
+          "memoryReference": "0x0200",
+          "count": u64::MAX,
+        }}),
+        json!({ "command": "disconnect" }),
+      ],
+    );
+
+    let stops = find(&messages, "event", "stopped");
+    assert_eq!(stops[0]["body"]["reason"], "entry");
+    assert_eq!(stops[1]["body"]["reason"], "step");
+    assert_eq!(find(&messages, "event", "exited")[0]["body"]["exitCode"], 0);
+    assert_eq!(find(&messages, "event", "terminated").len(), 1);
+
+    let variables = &find(&messages, "response", "variables")[0]["body"]["variables"];
+    assert_eq!(variables[0]["value"], "0x03 (3)");
+    assert_eq!(variables[1]["value"], "0x06 (6)");
+    assert_eq!(find(&messages, "response", "evaluate")[0]["success"], false);
+    let reads = find(&messages, "response", "readMemory");
+    assert_eq!(reads.len(), 2);
+    assert!(reads.iter().all(|read| read["success"] == false));
+  }
+}
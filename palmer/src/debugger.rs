@@ -0,0 +1,469 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use super::audio::AudioDriver;
+use super::clock::TIMER_FREQUENCY;
+use super::error::EmulationError;
+use super::instructions::{self, Instruction};
+use super::processor::{Chip8, MemoryAccess};
+use super::random::{RandomSource, XorShift};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+  Equal,
+  NotEqual,
+  Less,
+  LessOrEqual,
+  Greater,
+  GreaterOrEqual,
+}
+
+/// Makes a breakpoint only stop when a register holds a certain value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+  pub register: u8,
+  pub comparison: Comparison,
+  pub value: u8,
+}
+
+impl Condition {
+  fn holds(&self, registers: &[u8; 16]) -> bool {
+    let register = registers[self.register as usize & 0xF];
+    match self.comparison {
+      Comparison::Equal => register == self.value,
+      Comparison::NotEqual => register != self.value,
+      Comparison::Less => register < self.value,
+      Comparison::LessOrEqual => register <= self.value,
+      Comparison::Greater => register > self.value,
+      Comparison::GreaterOrEqual => register >= self.value,
+    }
+  }
+}
+
+/// Which accesses a watchpoint stops on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+  Read,
+  Write,
+  Access,
+}
+
+impl Watch {
+  fn matches(self, access: MemoryAccess) -> bool {
+    match self {
+      Watch::Read => access == MemoryAccess::Read,
+      Watch::Write => access == MemoryAccess::Write,
+      Watch::Access => true,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+  pub addresses: Range<usize>,
+  pub watch: Watch,
+}
+
+/// Why the debugger handed control back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+  /// The requested step finished.
+  Step,
+  /// The program counter reached a breakpoint whose condition, if any, held.
+  Breakpoint { address: u16 },
+  /// The last instruction touched memory covered by a watchpoint.
+  Watchpoint {
+    watchpoint: Watchpoint,
+    access: MemoryAccess,
+    addresses: Range<usize>,
+  },
+  /// The ROM executed `00FD`.
+  Halted,
+  /// The instruction budget ran out before anything else happened.
+  Limit,
+}
+
+/// Wraps a `Chip8` to run it under control: breakpoints, watchpoints and
+/// stepping. Nothing here draws or reads the keyboard, so it can be
+/// scripted in tests as well as driven by a frontend.
+///
+/// Timers tick once for every frame worth of instructions executed, so a
+/// ROM sees the same timing it would at full speed.
+pub struct Debugger<T: AudioDriver, R: RandomSource = XorShift> {
+  chip8: Chip8<T, R>,
+  breakpoints: BTreeMap<u16, Option<Condition>>,
+  watchpoints: Vec<Watchpoint>,
+  instructions_until_tick: u32,
+  /// The breakpoint execution last stopped at, which resuming runs past.
+  stopped_at: Option<u16>,
+}
+
+impl<T: AudioDriver, R: RandomSource> Debugger<T, R> {
+  pub fn new(chip8: Chip8<T, R>) -> Debugger<T, R> {
+    let mut debugger = Debugger {
+      chip8,
+      breakpoints: BTreeMap::new(),
+      watchpoints: vec![],
+      instructions_until_tick: 0,
+      stopped_at: None,
+    };
+    debugger.instructions_until_tick = debugger.instructions_per_frame();
+    debugger
+  }
+
+  pub fn chip8(&self) -> &Chip8<T, R> {
+    &self.chip8
+  }
+
+  pub fn chip8_mut(&mut self) -> &mut Chip8<T, R> {
+    &mut self.chip8
+  }
+
+  pub fn into_inner(self) -> Chip8<T, R> {
+    self.chip8
+  }
+
+  /// Stops before the instruction at `address` runs. Setting a breakpoint
+  /// where there already is one replaces it.
+  pub fn add_breakpoint(&mut self, address: u16) {
+    self.breakpoints.insert(address, None);
+  }
+
+  /// Like `add_breakpoint`, but only stops when `condition` holds.
+  pub fn add_conditional_breakpoint(&mut self, address: u16, condition: Condition) {
+    self.breakpoints.insert(address, Some(condition));
+  }
+
+  /// Returns whether there was a breakpoint at `address`.
+  pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+    self.breakpoints.remove(&address).is_some()
+  }
+
+  pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+    self.breakpoints.keys().copied()
+  }
+
+  pub fn clear_breakpoints(&mut self) {
+    self.breakpoints.clear();
+  }
+
+  /// Stops after an instruction that accesses any of `addresses`.
+  pub fn add_watchpoint(&mut self, addresses: Range<usize>, watch: Watch) {
+    let watchpoint = Watchpoint { addresses, watch };
+    if !self.watchpoints.contains(&watchpoint) {
+      self.watchpoints.push(watchpoint);
+    }
+  }
+
+  /// Returns whether a watchpoint with these exact addresses and kind existed.
+  pub fn remove_watchpoint(&mut self, addresses: Range<usize>, watch: Watch) -> bool {
+    let watchpoint = Watchpoint { addresses, watch };
+    let count = self.watchpoints.len();
+    self.watchpoints.retain(|existing| *existing != watchpoint);
+    self.watchpoints.len() != count
+  }
+
+  pub fn watchpoints(&self) -> &[Watchpoint] {
+    &self.watchpoints
+  }
+
+  /// Executes a single instruction.
+  pub fn step(&mut self) -> Result<StopReason, EmulationError> {
+    if self.chip8.is_halted() {
+      return Ok(StopReason::Halted);
+    }
+    Ok(self.execute()?.unwrap_or(StopReason::Step))
+  }
+
+  /// Executes a single instruction, running a `CALL` through to its return.
+  pub fn step_over(&mut self, max_instructions: usize) -> Result<StopReason, EmulationError> {
+    let program_counter = self.chip8.program_counter();
+    let is_call = matches!(self.current_instruction(), Some(Instruction::Call(_)));
+    if !is_call || self.chip8.is_waiting_for_key() {
+      return self.step();
+    }
+
+    let depth = self.chip8.stack().len();
+    self.run_until(max_instructions, |chip8| {
+      chip8.stack().len() == depth && chip8.program_counter() == program_counter + 2
+    })
+  }
+
+  /// Runs until the current subroutine returns to its caller.
+  pub fn step_out(&mut self, max_instructions: usize) -> Result<StopReason, EmulationError> {
+    let depth = self.chip8.stack().len();
+    self.run_until(max_instructions, |chip8| chip8.stack().len() < depth)
+  }
+
+  /// Runs until a breakpoint or watchpoint is hit, the ROM halts or
+  /// `max_instructions` have run. Running again after stopping at a
+  /// breakpoint resumes past it.
+  pub fn run(&mut self, max_instructions: usize) -> Result<StopReason, EmulationError> {
+    self.run_until(max_instructions, |_| false)
+  }
+
+  fn run_until(
+    &mut self,
+    max_instructions: usize,
+    is_done: impl Fn(&Chip8<T, R>) -> bool,
+  ) -> Result<StopReason, EmulationError> {
+    for _ in 0..max_instructions {
+      if self.chip8.is_halted() {
+        return Ok(StopReason::Halted);
+      }
+      if let Some(address) = self.breakpoint_hit() {
+        self.stopped_at = Some(address);
+        self.dump_trace();
+        return Ok(StopReason::Breakpoint { address });
+      }
+      if let Some(reason) = self.execute()? {
+        self.dump_trace();
+        return Ok(reason);
+      }
+      if is_done(&self.chip8) {
+        return Ok(StopReason::Step);
+      }
+    }
+    Ok(StopReason::Limit)
+  }
+
+  fn dump_trace(&mut self) {
+    if let Some(tracer) = self.chip8.tracer_mut() {
+      tracer.dump();
+    }
+  }
+
+  fn breakpoint_hit(&self) -> Option<u16> {
+    let address = self.chip8.program_counter();
+    if self.stopped_at == Some(address) {
+      return None;
+    }
+    match self.breakpoints.get(&address)? {
+      Some(condition) if !condition.holds(&self.chip8.registers()) => None,
+      _ => Some(address),
+    }
+  }
+
+  fn current_instruction(&self) -> Option<Instruction> {
+    let address = self.chip8.program_counter() as usize;
+    let bytes = self.chip8.memory().get(address..address + 2)?;
+    Some(instructions::decode(
+      (bytes[0] as u16) << 8 | bytes[1] as u16,
+    ))
+  }
+
+  fn instructions_per_frame(&self) -> u32 {
+    (self.chip8.instructions_per_second() / TIMER_FREQUENCY).max(1)
+  }
+
+  /// Runs one instruction, returning why to stop if it hit a watchpoint.
+  fn execute(&mut self) -> Result<Option<StopReason>, EmulationError> {
+    let access = self.chip8.pending_memory_access();
+    self.stopped_at = None;
+    self.chip8.emulate_cycle()?;
+
+    self.instructions_until_tick -= 1;
+    if self.instructions_until_tick == 0 {
+      self.chip8.end_frame();
+      self.instructions_until_tick = self.instructions_per_frame();
+    }
+
+    let (access, addresses) = match access {
+      Some(access) => access,
+      None => return Ok(None),
+    };
+    let watchpoint = self.watchpoints.iter().find(|watchpoint| {
+      watchpoint.watch.matches(access)
+        && watchpoint.addresses.start < addresses.end
+        && addresses.start < watchpoint.addresses.end
+    });
+    Ok(watchpoint.map(|watchpoint| StopReason::Watchpoint {
+      watchpoint: watchpoint.clone(),
+      access,
+      addresses,
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audio::Silent;
+  use crate::input::Button;
+  use crate::octo;
+  use crate::Quirks;
+
+  const PROGRAM: &str = "
+    : main
+      v0 := 0
+      loop
+        v0 += 1
+        double
+        if v0 != 5 then
+      again
+      i := buffer
+      save v1
+      exit
+
+    : double
+      v1 := v0
+      v1 += v0
+      return
+
+    : buffer
+      0 0
+  ";
+
+  fn debugger() -> Debugger<Silent> {
+    let mut chip8 = Chip8::new(Silent, Quirks::modern());
+    chip8.load(octo::compile(PROGRAM).unwrap()).unwrap();
+    Debugger::new(chip8)
+  }
+
+  #[test]
+  fn stops_at_breakpoints() {
+    let mut debugger = debugger();
+    // `double` starts after main's 8 instructions.
+    debugger.add_breakpoint(0x210);
+
+    assert_eq!(
+      debugger.run(1000).unwrap(),
+      StopReason::Breakpoint { address: 0x210 }
+    );
+    assert_eq!(debugger.chip8().registers()[0], 1);
+    assert_eq!(
+      debugger.run(1000).unwrap(),
+      StopReason::Breakpoint { address: 0x210 }
+    );
+    assert_eq!(debugger.chip8().registers()[0], 2);
+
+    assert!(debugger.remove_breakpoint(0x210));
+    assert_eq!(debugger.run(1000).unwrap(), StopReason::Halted);
+  }
+
+  #[test]
+  fn conditional_breakpoints() {
+    let mut debugger = debugger();
+    debugger.add_conditional_breakpoint(
+      0x210,
+      Condition {
+        register: 0,
+        comparison: Comparison::GreaterOrEqual,
+        value: 4,
+      },
+    );
+
+    assert_eq!(
+      debugger.run(1000).unwrap(),
+      StopReason::Breakpoint { address: 0x210 }
+    );
+    assert_eq!(debugger.chip8().registers()[0], 4);
+  }
+
+  #[test]
+  fn watchpoints() {
+    let mut debugger = debugger();
+    debugger.add_watchpoint(0x217..0x218, Watch::Read);
+    debugger.add_watchpoint(0x217..0x218, Watch::Write);
+
+    match debugger.run(1000).unwrap() {
+      StopReason::Watchpoint {
+        watchpoint,
+        access,
+        addresses,
+      } => {
+        assert_eq!(watchpoint.watch, Watch::Write);
+        assert_eq!(access, MemoryAccess::Write);
+        assert_eq!(addresses, 0x216..0x218);
+      }
+      reason => panic!("unexpected stop: {:?}", reason),
+    }
+    assert_eq!(debugger.chip8().memory()[0x216..0x218], [5, 10]);
+  }
+
+  #[test]
+  fn key_presses_last_one_frame() {
+    let mut chip8 = Chip8::new(Silent, Quirks::modern());
+    chip8
+      .load(
+        octo::compile(
+          ": main
+            loop
+              v1 += 1
+              if v1 != 100 then
+            again
+            v0 := key
+            v2 := 1
+            exit",
+        )
+        .unwrap(),
+      )
+      .unwrap();
+    let mut debugger = Debugger::new(chip8);
+    debugger.chip8_mut().input.key_down(Button::Five);
+    debugger.chip8_mut().input.key_up(Button::Five);
+
+    // The tap is frames old by the time `FX0A` runs, so it keeps waiting.
+    assert_eq!(debugger.run(1000).unwrap(), StopReason::Limit);
+    assert_eq!(debugger.chip8().registers()[2], 0);
+
+    debugger.chip8_mut().input.key_down(Button::Six);
+    debugger.chip8_mut().input.key_up(Button::Six);
+    assert_eq!(debugger.run(1000).unwrap(), StopReason::Halted);
+    assert_eq!(debugger.chip8().registers()[0], 6);
+  }
+
+  #[test]
+  fn stepping() {
+    let mut debugger = debugger();
+    assert_eq!(debugger.step().unwrap(), StopReason::Step);
+    assert_eq!(debugger.step().unwrap(), StopReason::Step);
+    assert_eq!(debugger.chip8().program_counter(), 0x204);
+
+    // Step over the call to `double`.
+    assert_eq!(debugger.step_over(1000).unwrap(), StopReason::Step);
+    assert_eq!(debugger.chip8().program_counter(), 0x206);
+    assert_eq!(debugger.chip8().registers()[1], 2);
+    assert!(debugger.chip8().stack().is_empty());
+
+    // Go around the loop and step into it, then back out.
+    for _ in 0..4 {
+      debugger.step().unwrap();
+    }
+    assert_eq!(debugger.chip8().program_counter(), 0x210);
+    assert_eq!(debugger.chip8().stack(), [0x206]);
+    assert_eq!(debugger.step_out(1000).unwrap(), StopReason::Step);
+    assert_eq!(debugger.chip8().program_counter(), 0x206);
+  }
+
+  #[test]
+  fn breakpoints_interrupt_steps() {
+    let mut debugger = debugger();
+    debugger.add_breakpoint(0x212);
+    debugger.step().unwrap();
+    debugger.step().unwrap();
+
+    assert_eq!(
+      debugger.step_over(1000).unwrap(),
+      StopReason::Breakpoint { address: 0x212 }
+    );
+    assert_eq!(debugger.run(3).unwrap(), StopReason::Limit);
+  }
+
+  #[test]
+  fn ticks_timers_once_per_frame() {
+    let mut chip8 = Chip8::new(Silent, Quirks::modern());
+    chip8.set_instructions_per_frame(10);
+    chip8
+      .load(octo::compile(": main v0 := 3 delay := v0 loop again").unwrap())
+      .unwrap();
+    let mut debugger = Debugger::new(chip8);
+
+    debugger.run(9).unwrap();
+    assert_eq!(debugger.chip8().delay_timer(), 3);
+    debugger.step().unwrap();
+    assert_eq!(debugger.chip8().delay_timer(), 2);
+    debugger.run(20).unwrap();
+    assert_eq!(debugger.chip8().delay_timer(), 0);
+  }
+}
@@ -0,0 +1,92 @@
+use std::ops::Range;
+
+use super::instructions::{self, Instruction};
+
+/// Instructions already decoded, by address, so hot loops skip fetching
+/// and decoding.
+///
+/// An entry only depends on the two bytes at its address, so it stays valid
+/// until one of them is written. Whoever writes memory has to call
+/// `invalidate` for the bytes it touched, or `clear` when it can't tell.
+pub struct DecodeCache {
+  entries: Box<[Option<(u16, Instruction)>]>,
+}
+
+impl DecodeCache {
+  pub fn new(size: usize) -> DecodeCache {
+    DecodeCache {
+      entries: vec![None; size].into_boxed_slice(),
+    }
+  }
+
+  /// The op code at `address` and its decoded instruction, decoding and
+  /// keeping them if `address` hasn't been seen since it was last written.
+  pub fn fetch(&mut self, memory: &[u8], address: usize) -> (u16, Instruction) {
+    if let Some(entry) = self.entries[address] {
+      return entry;
+    }
+    let op_code = (memory[address] as u16) << 8 | memory[address + 1] as u16;
+    let entry = (op_code, instructions::decode(op_code));
+    self.entries[address] = Some(entry);
+    entry
+  }
+
+  /// Forgets the instructions that overlap `written`, including the one
+  /// starting on the byte before it.
+  pub fn invalidate(&mut self, written: Range<usize>) {
+    let start = written.start.saturating_sub(1);
+    let end = written.end.min(self.entries.len());
+    for entry in self.entries[start..end].iter_mut() {
+      *entry = None;
+    }
+  }
+
+  pub fn clear(&mut self) {
+    for entry in self.entries.iter_mut() {
+      *entry = None;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn decodes_once_until_written() {
+    let mut memory = [0x60, 0x01, 0x70, 0x02, 0x00, 0xE0];
+    let mut cache = DecodeCache::new(memory.len());
+    assert_eq!(cache.fetch(&memory, 2).0, 0x7002);
+
+    memory[2] = 0x71;
+    assert_eq!(cache.fetch(&memory, 2).0, 0x7002);
+    cache.invalidate(2..3);
+    assert_eq!(cache.fetch(&memory, 2).0, 0x7102);
+
+    memory[3] = 0x05;
+    cache.invalidate(3..4);
+    assert_eq!(cache.fetch(&memory, 2).0, 0x7105);
+    assert_eq!(cache.fetch(&memory, 4).1, Instruction::ClearDisplay);
+  }
+
+  #[test]
+  fn invalidates_every_overlapping_instruction() {
+    let memory = [0x12; 8];
+    let mut cache = DecodeCache::new(memory.len());
+    for address in 0..7 {
+      cache.fetch(&memory, address);
+    }
+
+    cache.invalidate(3..6);
+    let cached: Vec<bool> = cache.entries.iter().map(Option::is_some).collect();
+    assert_eq!(
+      cached,
+      [true, true, false, false, false, false, true, false]
+    );
+
+    cache.invalidate(0..1);
+    assert!(cache.entries[0].is_none());
+    cache.clear();
+    assert!(cache.entries.iter().all(Option::is_none));
+  }
+}
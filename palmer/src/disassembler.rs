@@ -0,0 +1,288 @@
+use byteorder::{BigEndian, ReadBytesExt};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use super::instructions::{self, Instruction};
+use super::processor::PROGRAM_START;
+
+const BYTES_PER_DATA_LINE: usize = 8;
+
+/// What a label points at. When an address is used in more than one way,
+/// the greatest kind names it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+  Data,
+  Jump,
+  Subroutine,
+}
+
+struct Disassembler<'a> {
+  rom: &'a [u8],
+  /// Offsets into the ROM where an instruction starts, with its size.
+  instructions: BTreeMap<usize, usize>,
+  /// Whether each byte of the ROM belongs to an instruction.
+  is_code: Vec<bool>,
+  labels: HashMap<usize, LabelKind>,
+}
+
+/// Turns a ROM back into assembly.
+///
+/// Code is told apart from data by following every path the program can
+/// take from `PROGRAM_START`: jumps, calls, both sides of skips and `JP V0`
+/// tables. Anything never reached is emitted as `db` lines, and branch and
+/// `LD I` targets inside the ROM get labels.
+pub fn disassemble(rom: &[u8]) -> String {
+  let mut disassembler = Disassembler {
+    rom,
+    instructions: BTreeMap::new(),
+    is_code: vec![false; rom.len()],
+    labels: HashMap::new(),
+  };
+  disassembler.trace();
+  disassembler.render()
+}
+
+impl<'a> Disassembler<'a> {
+  fn offset(&self, address: usize) -> Option<usize> {
+    address
+      .checked_sub(PROGRAM_START)
+      .filter(|offset| *offset < self.rom.len())
+  }
+
+  fn read_u16(&self, offset: usize) -> Option<u16> {
+    let mut bytes = self.rom.get(offset..offset + 2)?;
+    bytes.read_u16::<BigEndian>().ok()
+  }
+
+  fn add_label(&mut self, address: usize, kind: LabelKind) {
+    let label = self.labels.entry(address).or_insert(kind);
+    *label = (*label).max(kind);
+  }
+
+  fn trace(&mut self) {
+    let mut pending = vec![PROGRAM_START];
+
+    while let Some(address) = pending.pop() {
+      let offset = match self.offset(address) {
+        Some(offset) if !self.instructions.contains_key(&offset) => offset,
+        _ => continue,
+      };
+      let instruction = match self.read_u16(offset).map(instructions::decode) {
+        None | Some(Instruction::InvalidInstruction) => continue,
+        Some(instruction) => instruction,
+      };
+      let size = match instruction {
+        Instruction::SetIAsLong => 4,
+        _ => 2,
+      };
+      // Paths that land halfway into a known instruction are not code.
+      match self.is_code.get(offset..offset + size) {
+        Some(bytes) if bytes.iter().all(|is_code| !is_code) => {}
+        _ => continue,
+      }
+
+      self.instructions.insert(offset, size);
+      for is_code in &mut self.is_code[offset..offset + size] {
+        *is_code = true;
+      }
+
+      let next = address + size;
+      match instruction {
+        Instruction::Return | Instruction::Exit => {}
+        Instruction::GoTo(target) | Instruction::GoToNPlusV0(target) => {
+          self.add_label(target as usize, LabelKind::Jump);
+          pending.push(target as usize);
+        }
+        Instruction::Call(target) => {
+          self.add_label(target as usize, LabelKind::Subroutine);
+          pending.push(target as usize);
+          pending.push(next);
+        }
+        Instruction::SkipIfEqual(_)
+        | Instruction::SkipIfDifferent(_)
+        | Instruction::SkipIfRegisterEqual(_)
+        | Instruction::SkipIfRegisterDifferent(_)
+        | Instruction::SkipIfKeyPressed(_)
+        | Instruction::SkipIfKeyNotPressed(_) => {
+          let skipped = match self.read_u16(offset + size) {
+            Some(0xF000) => 4,
+            _ => 2,
+          };
+          pending.push(next + skipped);
+          pending.push(next);
+        }
+        Instruction::SetIAs(target) => {
+          self.add_label(target as usize, LabelKind::Data);
+          pending.push(next);
+        }
+        Instruction::SetIAsLong => {
+          if let Some(target) = self.read_u16(offset + 2) {
+            self.add_label(target as usize, LabelKind::Data);
+          }
+          pending.push(next);
+        }
+        _ => pending.push(next),
+      }
+    }
+  }
+
+  /// The label for `address`, as long as a line starts there.
+  fn label(&self, address: usize) -> Option<String> {
+    let kind = self.labels.get(&address)?;
+    let offset = self.offset(address)?;
+    if self.is_code[offset] && !self.instructions.contains_key(&offset) {
+      return None;
+    }
+
+    let prefix = match kind {
+      LabelKind::Data => "data",
+      LabelKind::Jump => "label",
+      LabelKind::Subroutine => "sub",
+    };
+    Some(format!("{}_{:03X}", prefix, address))
+  }
+
+  fn address(&self, address: u16) -> String {
+    self
+      .label(address as usize)
+      .unwrap_or_else(|| format!("{:#05X}", address))
+  }
+
+  fn render_instruction(&self, offset: usize) -> String {
+    let op_code = self.read_u16(offset).unwrap();
+    match instructions::decode(op_code) {
+      Instruction::GoTo(target) => format!("JP {}", self.address(target)),
+      Instruction::Call(target) => format!("CALL {}", self.address(target)),
+      Instruction::SetIAs(target) => format!("LD I, {}", self.address(target)),
+      Instruction::GoToNPlusV0(target) => format!("JP V0, {}", self.address(target)),
+      Instruction::SetIAsLong => {
+        let target = self.read_u16(offset + 2).unwrap();
+        match self.label(target as usize) {
+          Some(label) => format!("LD I, LONG {}", label),
+          None => format!("LD I, LONG {:#06X}", target),
+        }
+      }
+      instruction => instruction.to_string(),
+    }
+  }
+
+  fn render(&self) -> String {
+    let mut output = String::new();
+    let mut offset = 0;
+
+    while offset < self.rom.len() {
+      if let Some(label) = self.label(PROGRAM_START + offset) {
+        writeln!(output, "{}:", label).unwrap();
+      }
+
+      if let Some(size) = self.instructions.get(&offset) {
+        writeln!(output, "  {}", self.render_instruction(offset)).unwrap();
+        offset += size;
+        continue;
+      }
+
+      let mut end = offset + 1;
+      while end < self.rom.len()
+        && end - offset < BYTES_PER_DATA_LINE
+        && !self.is_code[end]
+        && self.label(PROGRAM_START + end).is_none()
+      {
+        end += 1;
+      }
+      let bytes: Vec<String> = self.rom[offset..end]
+        .iter()
+        .map(|byte| format!("{:#04X}", byte))
+        .collect();
+      writeln!(output, "  db {}", bytes.join(", ")).unwrap();
+      offset = end;
+    }
+
+    output
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn labels_code_and_data() {
+    let rom = [
+      0x00, 0xE0, // clear
+      0xA2, 0x0C, // I = sprite
+      0x22, 0x08, // call 0x208
+      0x12, 0x06, // jump to itself
+      0xD0, 0x15, // draw
+      0x00, 0xEE, // return
+      0xF0, 0x90, 0x90, 0x90, 0xF0, // sprite
+    ];
+
+    assert_eq!(
+      disassemble(&rom),
+      "  CLS
+  LD I, data_20C
+  CALL sub_208
+label_206:
+  JP label_206
+sub_208:
+  DRW V0, V1, 5
+  RET
+data_20C:
+  db 0xF0, 0x90, 0x90, 0x90, 0xF0
+"
+    );
+  }
+
+  #[test]
+  fn follows_both_sides_of_skips() {
+    let rom = [
+      0x30, 0x00, // skip if v0 == 0
+      0xF0, 0x00, 0x02, 0x0A, // I = long 0x20A
+      0x12, 0x06, // jump to itself
+      0xFF, 0xFF, // unreachable
+      0x55, // data
+    ];
+
+    assert_eq!(
+      disassemble(&rom),
+      "  SE V0, 0x00
+  LD I, LONG data_20A
+label_206:
+  JP label_206
+  db 0xFF, 0xFF
+data_20A:
+  db 0x55
+"
+    );
+  }
+
+  #[test]
+  fn keeps_addresses_without_a_line() {
+    let rom = [
+      0xA0, 0x50, // I = font, outside the ROM
+      0x12, 0x03, // jump into the middle of an instruction
+      0x00, 0x00, // unreachable
+    ];
+
+    assert_eq!(
+      disassemble(&rom),
+      "  LD I, 0x050
+  JP 0x203
+  db 0x00, 0x00
+"
+    );
+  }
+
+  #[test]
+  fn splits_long_data() {
+    let rom = [0x00, 0xEE, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+    assert_eq!(
+      disassemble(&rom),
+      "  RET
+  db 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08
+  db 0x09
+"
+    );
+  }
+}
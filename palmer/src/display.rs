@@ -1,59 +1,370 @@
 pub const SCREEN_WIDTH: usize = 64;
 pub const SCREEN_HEIGHT: usize = 32;
+pub const HIRES_SCREEN_WIDTH: usize = 128;
+pub const HIRES_SCREEN_HEIGHT: usize = 64;
 
-pub type Pixels = [bool; SCREEN_WIDTH * SCREEN_HEIGHT];
+pub const PLANE_COUNT: usize = 2;
 
+/// Default RGB colours for each pixel value: off, plane 1, plane 2 and both.
+pub const PALETTE: [[u8; 3]; 4] = [
+  [0x00, 0x00, 0x00],
+  [0xFF, 0xFF, 0xFF],
+  [0xA7, 0x86, 0xDF],
+  [0xFE, 0xC7, 0xD7],
+];
+
+/// One line of a plane, with column 0 in the most significant bit. Low
+/// resolution only uses the top 64 bits.
+pub type Row = u128;
+
+const ROW_BITS: usize = HIRES_SCREEN_WIDTH;
+
+/// The bit of `Row` for a column.
+fn column_bit(x: usize) -> Row {
+  1 << (ROW_BITS - 1 - x)
+}
+
+/// A rectangle of the screen, in pixels of the current resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+  pub x: usize,
+  pub y: usize,
+  pub width: usize,
+  pub height: usize,
+}
+
+/// Each bitplane is stored as one `Row` per line, so a pixel's value is a
+/// colour from 0 to 3 made of its bit in each plane.
+///
+/// The display also remembers which pixels changed since the frontend last
+/// called `acknowledge`, so it only has to repaint those.
 #[derive(Debug, Clone)]
 pub struct Display {
-  pub pixels: Pixels,
+  planes_rows: [[Row; HIRES_SCREEN_HEIGHT]; PLANE_COUNT],
+  hires: bool,
+  planes: u8,
+  dirty: [Row; HIRES_SCREEN_HEIGHT],
+}
+
+impl PartialEq for Display {
+  /// Displays are equal when they show the same thing, whatever has been
+  /// acknowledged.
+  fn eq(&self, other: &Display) -> bool {
+    self.planes_rows == other.planes_rows
+      && self.hires == other.hires
+      && self.planes == other.planes
+  }
 }
 
 impl Display {
+  /// A blank display, dirty all over so that it gets painted once.
   pub fn new() -> Display {
     Display {
-      pixels: [false; SCREEN_WIDTH * SCREEN_HEIGHT],
+      planes_rows: [[0; HIRES_SCREEN_HEIGHT]; PLANE_COUNT],
+      hires: false,
+      planes: 0b01,
+      dirty: [!0; HIRES_SCREEN_HEIGHT],
     }
   }
 
+  /// Clears the selected planes.
   pub fn clear(&mut self) {
-    self.pixels = [false; SCREEN_WIDTH * SCREEN_HEIGHT];
+    for plane in self.selected() {
+      for (row, dirty) in self.planes_rows[plane]
+        .iter_mut()
+        .zip(self.dirty.iter_mut())
+      {
+        *dirty |= *row;
+        *row = 0;
+      }
+    }
+  }
+
+  /// The bitplanes affected by drawing, clearing and scrolling, as a bitmask.
+  pub fn planes(&self) -> u8 {
+    self.planes
+  }
+
+  pub fn select_planes(&mut self, planes: u8) {
+    self.planes = planes & 0b11;
+  }
+
+  pub fn selected_plane_count(&self) -> usize {
+    self.planes.count_ones() as usize
   }
 
-  fn set_pixel(&mut self, x: usize, y: usize, value: bool) {
-    self.pixels[x + y * SCREEN_WIDTH] = value;
+  pub fn is_hires(&self) -> bool {
+    self.hires
+  }
+
+  /// Switches between 64x32 and 128x64 modes, clearing every plane and
+  /// making the whole screen dirty.
+  pub fn set_hires(&mut self, hires: bool) {
+    self.hires = hires;
+    self.planes_rows = [[0; HIRES_SCREEN_HEIGHT]; PLANE_COUNT];
+    self.dirty = [!0; HIRES_SCREEN_HEIGHT];
+  }
+
+  pub fn width(&self) -> usize {
+    if self.hires {
+      HIRES_SCREEN_WIDTH
+    } else {
+      SCREEN_WIDTH
+    }
+  }
+
+  pub fn height(&self) -> usize {
+    if self.hires {
+      HIRES_SCREEN_HEIGHT
+    } else {
+      SCREEN_HEIGHT
+    }
+  }
+
+  /// The visible lines of one plane (0 or 1), borrowed rather than copied.
+  pub fn rows(&self, plane: usize) -> &[Row] {
+    &self.planes_rows[plane][..self.height()]
+  }
+
+  /// Replaces a line of a plane, as `rows` returns it.
+  pub fn set_row(&mut self, plane: usize, y: usize, row: Row) {
+    let rows = &mut self.planes_rows[plane];
+    self.dirty[y] |= rows[y] ^ row;
+    rows[y] = row;
+  }
+
+  /// The value of a pixel, from 0 to 3, with one bit per plane.
+  pub fn pixel(&self, x: usize, y: usize) -> u8 {
+    let bit = column_bit(x);
+    (0..PLANE_COUNT)
+      .filter(|plane| self.planes_rows[*plane][y] & bit != 0)
+      .fold(0, |value, plane| value | 1 << plane)
+  }
+
+  /// Sets the value of a pixel in every plane, whichever are selected.
+  pub fn set_pixel_value(&mut self, x: usize, y: usize, value: u8) {
+    let bit = column_bit(x);
+    for (plane, rows) in self.planes_rows.iter_mut().enumerate() {
+      if value & (1 << plane) != 0 {
+        rows[y] |= bit;
+      } else {
+        rows[y] &= !bit;
+      }
+    }
+    self.dirty[y] |= bit;
   }
 
-  fn get_pixel(&self, x: usize, y: usize) -> bool {
-    self.pixels[x + y * SCREEN_WIDTH]
+  /// The values of one visible line, from left to right.
+  pub fn line(&self, y: usize) -> impl Iterator<Item = u8> + '_ {
+    (0..self.width()).map(move |x| self.pixel(x, y))
   }
 
-  fn xor_pixel(&mut self, x: usize, y: usize, new_value: bool) -> bool {
-    let current = self.get_pixel(x, y);
-    let new_pixel = current ^ new_value;
-    self.set_pixel(x, y, new_pixel);
-    current && !new_pixel
+  /// The values of the visible pixels, one `line` at a time.
+  pub fn lines(&self) -> impl Iterator<Item = impl Iterator<Item = u8> + '_> + '_ {
+    (0..self.height()).map(move |y| self.line(y))
   }
 
-  pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8]) -> u8 {
-    let mut new_vf = 0;
+  /// Every visible pixel value, line after line.
+  pub fn pixels(&self) -> impl Iterator<Item = u8> + '_ {
+    self.lines().flatten()
+  }
+
+  /// Turns a pixel on or off in every selected plane.
+  pub fn set_pixel(&mut self, x: usize, y: usize, value: bool) {
+    let bit = column_bit(x);
+    for plane in self.selected() {
+      if value {
+        self.planes_rows[plane][y] |= bit;
+      } else {
+        self.planes_rows[plane][y] &= !bit;
+      }
+    }
+    self.dirty[y] |= bit;
+  }
+
+  /// Whether a pixel is on in any of the selected planes.
+  pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+    self.pixel(x, y) & self.planes != 0
+  }
+
+  /// Whether anything changed since the last `acknowledge`.
+  pub fn is_dirty(&self) -> bool {
+    self.dirty_regions().next().is_some()
+  }
+
+  /// The changes since the last `acknowledge`, one region per line that
+  /// changed, from the leftmost to the rightmost pixel that changed in it.
+  pub fn dirty_regions(&self) -> impl Iterator<Item = Region> + '_ {
+    let visible = self.visible_mask();
+    self.dirty[..self.height()]
+      .iter()
+      .enumerate()
+      .filter(move |(_, dirty)| **dirty & visible != 0)
+      .map(move |(y, dirty)| {
+        let dirty = dirty & visible;
+        let x = dirty.leading_zeros() as usize;
+        Region {
+          x,
+          y,
+          width: ROW_BITS - dirty.trailing_zeros() as usize - x,
+          height: 1,
+        }
+      })
+  }
+
+  /// The smallest region holding every change since the last `acknowledge`.
+  pub fn dirty_bounds(&self) -> Option<Region> {
+    self.dirty_regions().fold(None, |bounds, line| {
+      Some(match bounds {
+        None => line,
+        Some(bounds) => {
+          let x = bounds.x.min(line.x);
+          let end = (bounds.x + bounds.width).max(line.x + line.width);
+          Region {
+            x,
+            width: end - x,
+            height: line.y + 1 - bounds.y,
+            ..bounds
+          }
+        }
+      })
+    })
+  }
+
+  /// Tells the display that the frontend has painted every change so far.
+  pub fn acknowledge(&mut self) {
+    self.dirty = [0; HIRES_SCREEN_HEIGHT];
+  }
+
+  /// Indices of the selected planes.
+  fn selected(&self) -> impl Iterator<Item = usize> {
+    let planes = self.planes;
+    (0..PLANE_COUNT).filter(move |plane| planes & (1 << plane) != 0)
+  }
+
+  /// The bits of a `Row` that are on screen in the current resolution.
+  fn visible_mask(&self) -> Row {
+    !0 << (ROW_BITS - self.width())
+  }
+
+  /// Draws an 8 pixel wide sprite, one byte per line. When more than one
+  /// plane is selected, the sprite holds the lines for each plane in turn.
+  ///
+  /// Returns the new value of VF: in low resolution it is 1 if any pixel was
+  /// erased, in high resolution it is the number of lines that either erased
+  /// a pixel or were clipped by the bottom of the screen.
+  pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8], wrap: bool) -> u8 {
+    self.draw_planes(x, y, sprite, 1, wrap)
+  }
+
+  /// Draws a 16x16 sprite, two bytes per line and 32 bytes per plane.
+  pub fn draw_large(&mut self, x: usize, y: usize, sprite: &[u8], wrap: bool) -> u8 {
+    self.draw_planes(x, y, sprite, 2, wrap)
+  }
+
+  fn draw_planes(
+    &mut self,
+    x: usize,
+    y: usize,
+    sprite: &[u8],
+    line_bytes: usize,
+    wrap: bool,
+  ) -> u8 {
+    let plane_count = self.selected_plane_count();
+    if plane_count == 0 {
+      return 0;
+    }
+
+    let line_count = sprite.chunks(line_bytes).len();
+    let plane_bytes = (line_count / plane_count).max(1) * line_bytes;
+
+    let new_vf = self
+      .selected()
+      .zip(sprite.chunks(plane_bytes))
+      .map(|(plane, lines)| self.draw_lines(x, y, lines, line_bytes, plane, wrap))
+      .fold(0u8, |acc, new_vf| acc.saturating_add(new_vf));
+
+    if self.hires {
+      new_vf
+    } else {
+      new_vf.min(1)
+    }
+  }
+
+  fn draw_lines(
+    &mut self,
+    x: usize,
+    y: usize,
+    lines: &[u8],
+    line_bytes: usize,
+    plane: usize,
+    wrap: bool,
+  ) -> u8 {
+    let (width, height) = (self.width(), self.height());
+    let x = x % width;
+    let y = y % height;
+    let visible = self.visible_mask();
+    let mut collided_lines = 0;
+
+    for (line_number, bytes) in lines.chunks(line_bytes).enumerate() {
+      if wrap || line_number + y < height {
+        // The line with its leftmost pixel in column 0, then moved to `x`.
+        let line = bytes
+          .iter()
+          .fold(0 as Row, |line, byte| line << 8 | *byte as Row)
+          << (ROW_BITS - 8 * bytes.len());
+        let mut mask = (line >> x) & visible;
+        if wrap {
+          mask |= line.checked_shl((width - x) as u32).unwrap_or(0) & visible;
+        }
+
+        let row_number = (y + line_number) % height;
+        let row = &mut self.planes_rows[plane][row_number];
+        if *row & mask != 0 {
+          collided_lines += 1;
+        }
+        *row ^= mask;
+        self.dirty[row_number] |= mask;
+      } else if self.hires {
+        collided_lines += 1;
+      }
+    }
 
-    sprite.iter().enumerate().for_each(|(line_number, line)| {
-      if line_number + y < SCREEN_HEIGHT {
-        format!("{:08b}", line)
-          .chars()
-          .map(|char| char == '1')
-          .enumerate()
-          .for_each(|(column_number, pixel)| {
-            if column_number + x < SCREEN_WIDTH {
-              if self.xor_pixel(x + column_number, y + line_number, pixel) {
-                new_vf = 1;
-              }
-            }
-          });
+    collided_lines
+  }
+
+  pub fn scroll_down(&mut self, amount: usize) {
+    let height = self.height();
+    for plane in self.selected() {
+      let rows = &mut self.planes_rows[plane];
+      for y in (0..height).rev() {
+        let row = if y >= amount { rows[y - amount] } else { 0 };
+        self.dirty[y] |= rows[y] ^ row;
+        rows[y] = row;
       }
-    });
+    }
+  }
+
+  pub fn scroll_right(&mut self, amount: usize) {
+    let visible = self.visible_mask();
+    self.shift_rows(|row| row.checked_shr(amount as u32).unwrap_or(0) & visible);
+  }
+
+  pub fn scroll_left(&mut self, amount: usize) {
+    let visible = self.visible_mask();
+    self.shift_rows(|row| row.checked_shl(amount as u32).unwrap_or(0) & visible);
+  }
 
-    new_vf
+  fn shift_rows(&mut self, shift: impl Fn(Row) -> Row) {
+    let height = self.height();
+    for plane in self.selected() {
+      let rows = self.planes_rows[plane][..height].iter_mut();
+      for (row, dirty) in rows.zip(self.dirty.iter_mut()) {
+        let shifted = shift(*row);
+        *dirty |= *row ^ shifted;
+        *row = shifted;
+      }
+    }
   }
 }
 
@@ -64,7 +375,7 @@ mod tests {
   #[test]
   fn get_pixel() {
     let mut display = Display::new();
-    display.pixels[1] = true;
+    display.set_pixel_value(1, 0, 1);
     assert_eq!(display.get_pixel(1, 0), true);
     assert_eq!(display.get_pixel(0, 0), false);
   }
@@ -84,14 +395,14 @@ mod tests {
     display.set_pixel(1, 3, true);
     display.set_pixel(5, 15, true);
     display.clear();
-    assert!(display.pixels.iter().all(|pixel| { !*pixel }));
+    assert!(display.pixels().all(|pixel| pixel == 0));
   }
 
   #[test]
   fn draw() {
     let lines: [u8; 4] = [0b01101100, 0b00011000, 0b00011000, 0b00111100];
     let mut display = Display::new();
-    display.draw(0, 0, &lines);
+    display.draw(0, 0, &lines, false);
     for i in 0..4 {
       for j in 0..8 {
         if i == 0 {
@@ -105,12 +416,213 @@ mod tests {
     }
   }
 
+  #[test]
+  fn rows_are_packed_from_the_left() {
+    let mut display = Display::new();
+    display.draw(62, 1, &[0b10010000], true);
+    assert_eq!(display.rows(0).len(), SCREEN_HEIGHT);
+    assert_eq!(display.rows(0)[1], 1 << 65 | 1 << 126);
+    assert!(display.rows(1).iter().all(|row| *row == 0));
+    display.set_hires(true);
+    assert_eq!(display.rows(0).len(), HIRES_SCREEN_HEIGHT);
+  }
+
+  #[test]
+  fn tracks_what_changed() {
+    let mut display = Display::new();
+    assert_eq!(
+      display.dirty_bounds(),
+      Some(Region {
+        x: 0,
+        y: 0,
+        width: SCREEN_WIDTH,
+        height: SCREEN_HEIGHT
+      })
+    );
+    display.acknowledge();
+    assert!(!display.is_dirty());
+
+    display.draw(62, 3, &[0b10100000, 0, 0b01000000], true);
+    let regions: Vec<Region> = display.dirty_regions().collect();
+    assert_eq!(
+      regions,
+      [
+        Region {
+          x: 0,
+          y: 3,
+          width: 63,
+          height: 1
+        },
+        Region {
+          x: 63,
+          y: 5,
+          width: 1,
+          height: 1
+        },
+      ]
+    );
+    assert_eq!(
+      display.dirty_bounds(),
+      Some(Region {
+        x: 0,
+        y: 3,
+        width: 64,
+        height: 3
+      })
+    );
+
+    display.acknowledge();
+    // Row 5 scrolls off the screen.
+    display.scroll_right(4);
+    assert_eq!(display.dirty_regions().count(), 2);
+    display.acknowledge();
+    display.clear();
+    assert_eq!(
+      display
+        .dirty_regions()
+        .map(|region| region.y)
+        .collect::<Vec<_>>(),
+      [3]
+    );
+    display.acknowledge();
+    display.clear();
+    assert!(!display.is_dirty());
+  }
+
   #[test]
   fn draw_erases() {
     let lines: [u8; 4] = [0b01101100, 0b00011000, 0b00011000, 0b00111100];
     let mut display = Display::new();
-    display.draw(0, 0, &lines);
-    display.draw(0, 0, &lines);
-    assert!(display.pixels.iter().all(|pixel| { !*pixel }));
+    display.draw(0, 0, &lines, false);
+    display.draw(0, 0, &lines, false);
+    assert!(display.pixels().all(|pixel| pixel == 0));
+  }
+
+  #[test]
+  fn draw_clips() {
+    let lines: [u8; 2] = [0b11000000, 0b11000000];
+    let mut display = Display::new();
+    display.draw(SCREEN_WIDTH - 1, SCREEN_HEIGHT - 1, &lines, false);
+    assert!(display.get_pixel(SCREEN_WIDTH - 1, SCREEN_HEIGHT - 1));
+    assert!(!display.get_pixel(0, SCREEN_HEIGHT - 1));
+    assert!(!display.get_pixel(SCREEN_WIDTH - 1, 0));
+    assert!(!display.get_pixel(0, 0));
+  }
+
+  #[test]
+  fn draw_wraps() {
+    let lines: [u8; 2] = [0b11000000, 0b11000000];
+    let mut display = Display::new();
+    display.draw(SCREEN_WIDTH - 1, SCREEN_HEIGHT - 1, &lines, true);
+    assert!(display.get_pixel(SCREEN_WIDTH - 1, SCREEN_HEIGHT - 1));
+    assert!(display.get_pixel(0, SCREEN_HEIGHT - 1));
+    assert!(display.get_pixel(SCREEN_WIDTH - 1, 0));
+    assert!(display.get_pixel(0, 0));
+  }
+
+  #[test]
+  fn draw_wraps_starting_position() {
+    let lines: [u8; 1] = [0b10000000];
+    let mut display = Display::new();
+    display.draw(SCREEN_WIDTH + 2, SCREEN_HEIGHT + 1, &lines, false);
+    assert!(display.get_pixel(2, 1));
+  }
+
+  #[test]
+  fn switch_resolution() {
+    let mut display = Display::new();
+    display.set_pixel(3, 3, true);
+    display.set_hires(true);
+    assert_eq!(display.width(), HIRES_SCREEN_WIDTH);
+    assert_eq!(display.height(), HIRES_SCREEN_HEIGHT);
+    assert!(display.pixels().all(|pixel| pixel == 0));
+    display.draw(120, 60, &[0xFF], false);
+    assert!(display.get_pixel(127, 60));
+    assert_eq!(display.lines().count(), HIRES_SCREEN_HEIGHT);
+    display.set_hires(false);
+    assert_eq!(display.lines().count(), SCREEN_HEIGHT);
+  }
+
+  #[test]
+  fn draw_large() {
+    let mut sprite = [0u8; 32];
+    sprite[0] = 0x80;
+    sprite[31] = 0x01;
+    let mut display = Display::new();
+    display.set_hires(true);
+    assert_eq!(display.draw_large(0, 0, &sprite, false), 0);
+    assert!(display.get_pixel(0, 0));
+    assert!(display.get_pixel(15, 15));
+    assert!(!display.get_pixel(1, 0));
+  }
+
+  #[test]
+  fn hires_counts_collided_lines() {
+    let lines: [u8; 3] = [0xFF, 0x00, 0xFF];
+    let mut display = Display::new();
+    display.set_hires(true);
+    display.draw(0, 0, &lines, false);
+    assert_eq!(display.draw(0, 0, &lines, false), 2);
+    assert_eq!(display.draw(0, HIRES_SCREEN_HEIGHT - 1, &lines, false), 2);
+  }
+
+  #[test]
+  fn lores_collision_is_a_flag() {
+    let lines: [u8; 3] = [0xFF, 0x00, 0xFF];
+    let mut display = Display::new();
+    display.draw(0, 0, &lines, false);
+    assert_eq!(display.draw(0, 0, &lines, false), 1);
+  }
+
+  #[test]
+  fn scroll() {
+    let mut display = Display::new();
+    display.set_pixel(4, 0, true);
+    display.scroll_down(2);
+    assert!(display.get_pixel(4, 2));
+    assert!(!display.get_pixel(4, 0));
+    display.scroll_right(4);
+    assert!(display.get_pixel(8, 2));
+    assert!(!display.get_pixel(4, 2));
+    display.scroll_left(4);
+    display.scroll_left(4);
+    assert!(display.get_pixel(0, 2));
+    display.scroll_left(4);
+    assert!(display.pixels().all(|pixel| pixel == 0));
+  }
+
+  #[test]
+  fn draw_on_both_planes() {
+    let sprite: [u8; 2] = [0b10000000, 0b11000000];
+    let mut display = Display::new();
+    display.select_planes(0b11);
+    display.draw(0, 0, &sprite, false);
+    assert_eq!(display.pixel(0, 0), 0b11);
+    assert_eq!(display.pixel(1, 0), 0b10);
+    display.select_planes(0b10);
+    assert_eq!(display.draw(0, 0, &sprite[1..], false), 1);
+    assert_eq!(display.pixel(0, 0), 0b01);
+    assert_eq!(display.pixel(1, 0), 0b00);
+  }
+
+  #[test]
+  fn clear_and_scroll_selected_planes() {
+    let mut display = Display::new();
+    display.set_pixel_value(0, 0, 0b11);
+    display.select_planes(0b10);
+    display.scroll_right(4);
+    assert_eq!(display.pixel(0, 0), 0b01);
+    assert_eq!(display.pixel(4, 0), 0b10);
+    display.clear();
+    assert_eq!(display.pixel(0, 0), 0b01);
+    assert_eq!(display.pixel(4, 0), 0b00);
+  }
+
+  #[test]
+  fn no_planes_selected() {
+    let mut display = Display::new();
+    display.select_planes(0);
+    assert_eq!(display.draw(0, 0, &[0xFF], false), 0);
+    assert!(display.pixels().all(|pixel| pixel == 0));
   }
 }
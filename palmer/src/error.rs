@@ -0,0 +1,219 @@
+use std::error::Error;
+use std::fmt;
+
+/// A fault raised by a misbehaving ROM. The emulator is left at the
+/// instruction that caused it, so it can be inspected but not resumed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmulationError {
+  StackOverflow {
+    program_counter: u16,
+    op_code: u16,
+  },
+  StackUnderflow {
+    program_counter: u16,
+    op_code: u16,
+  },
+  MemoryOutOfBounds {
+    program_counter: u16,
+    op_code: u16,
+    address: usize,
+    length: usize,
+  },
+  RomTooLarge {
+    size: usize,
+    max_size: usize,
+  },
+  /// The instruction at `program_counter` sent the program counter past the
+  /// end of memory. `op_code` is 0 if no instruction could be read at all.
+  ProgramCounterOutOfRange {
+    program_counter: u16,
+    op_code: u16,
+  },
+}
+
+impl fmt::Display for EmulationError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      EmulationError::StackOverflow {
+        program_counter,
+        op_code,
+      } => write!(
+        f,
+        "stack overflow at {:#06X} (op code {:04X})",
+        program_counter, op_code
+      ),
+      EmulationError::StackUnderflow {
+        program_counter,
+        op_code,
+      } => write!(
+        f,
+        "stack underflow at {:#06X} (op code {:04X})",
+        program_counter, op_code
+      ),
+      EmulationError::MemoryOutOfBounds {
+        program_counter,
+        op_code,
+        address,
+        length,
+      } => write!(
+        f,
+        "access to {} bytes at {:#06X} is out of bounds at {:#06X} (op code {:04X})",
+        length, address, program_counter, op_code
+      ),
+      EmulationError::RomTooLarge { size, max_size } => write!(
+        f,
+        "ROM has {} bytes, but at most {} fit in memory",
+        size, max_size
+      ),
+      EmulationError::ProgramCounterOutOfRange {
+        program_counter,
+        op_code,
+      } => write!(
+        f,
+        "program counter left memory at {:#06X} (op code {:04X})",
+        program_counter, op_code
+      ),
+    }
+  }
+}
+
+impl Error for EmulationError {}
+
+/// Why `Chip8::load_state` refused a save state. The running machine is left
+/// untouched when this is returned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StateError {
+  /// The data does not start with the save state magic bytes.
+  NotAState,
+  UnsupportedVersion {
+    version: u8,
+    supported: u8,
+  },
+  InvalidSize {
+    size: usize,
+    expected: usize,
+  },
+  /// A field holds a value the emulator could never have produced.
+  InvalidField {
+    field: &'static str,
+  },
+}
+
+impl fmt::Display for StateError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      StateError::NotAState => write!(f, "data is not a save state"),
+      StateError::UnsupportedVersion { version, supported } => write!(
+        f,
+        "save state version {} is not supported (expected {})",
+        version, supported
+      ),
+      StateError::InvalidSize { size, expected } => write!(
+        f,
+        "save state has {} bytes, but {} were expected",
+        size, expected
+      ),
+      StateError::InvalidField { field } => {
+        write!(f, "save state has an invalid {}", field)
+      }
+    }
+  }
+}
+
+impl Error for StateError {}
+
+/// Why a movie could not be read or played back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MovieError {
+  /// The data does not start with the movie magic bytes.
+  NotAMovie,
+  UnsupportedVersion {
+    version: u8,
+    supported: u8,
+  },
+  InvalidSize {
+    size: usize,
+    expected: usize,
+  },
+  InvalidField {
+    field: &'static str,
+  },
+  /// The movie was recorded with another ROM, whose hash is `expected`.
+  /// Playing it anyway would desync right away.
+  RomMismatch {
+    expected: u64,
+    actual: u64,
+  },
+  /// The ROM faulted during playback.
+  Emulation(EmulationError),
+}
+
+impl fmt::Display for MovieError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      MovieError::NotAMovie => write!(f, "data is not a movie"),
+      MovieError::UnsupportedVersion { version, supported } => write!(
+        f,
+        "movie version {} is not supported (expected {})",
+        version, supported
+      ),
+      MovieError::InvalidSize { size, expected } => write!(
+        f,
+        "movie has {} bytes, but {} were expected",
+        size, expected
+      ),
+      MovieError::InvalidField { field } => write!(f, "movie has an invalid {}", field),
+      MovieError::RomMismatch { expected, actual } => write!(
+        f,
+        "movie was recorded with ROM {:016x}, not {:016x}, and would desync",
+        expected, actual
+      ),
+      MovieError::Emulation(error) => write!(f, "{}", error),
+    }
+  }
+}
+
+impl Error for MovieError {}
+
+impl From<EmulationError> for MovieError {
+  fn from(error: EmulationError) -> MovieError {
+    MovieError::Emulation(error)
+  }
+}
+
+/// A line of a keymap config that could not be read, 1-based.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeymapError {
+  pub line: usize,
+  pub message: String,
+}
+
+impl fmt::Display for KeymapError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "line {}: {}", self.line, self.message)
+  }
+}
+
+impl Error for KeymapError {}
+
+/// A problem in assembly source, with the 1-based line and column it was
+/// found at. `file` is the included file it is in, if it is not in the
+/// source that was passed in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssemblyError {
+  pub file: Option<String>,
+  pub line: usize,
+  pub column: usize,
+  pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if let Some(file) = &self.file {
+      write!(f, "{}:", file)?;
+    }
+    write!(f, "{}:{}: {}", self.line, self.column, self.message)
+  }
+}
+
+impl Error for AssemblyError {}
@@ -0,0 +1,632 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use super::audio::AudioDriver;
+use super::debugger::{Debugger, StopReason, Watch};
+use super::error::EmulationError;
+use super::random::{RandomSource, XorShift};
+
+/// Instructions run between checks for an interrupt from the client.
+const INSTRUCTIONS_PER_POLL: usize = 10_000;
+const PACKET_SIZE: usize = 0x1000;
+
+/// Register numbers, in the order `g` sends them. 16-bit registers are big
+/// endian, like everything else on the CHIP-8.
+const INDEX_REGISTER: usize = 16;
+const PROGRAM_COUNTER_REGISTER: usize = 17;
+const STACK_POINTER_REGISTER: usize = 18;
+const DELAY_TIMER_REGISTER: usize = 19;
+const SOUND_TIMER_REGISTER: usize = 20;
+const REGISTER_COUNT: usize = 21;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.xipe-oito.chip8">
+    <reg name="v0" bitsize="8" regnum="0"/>
+    <reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/>
+    <reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/>
+    <reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/>
+    <reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/>
+    <reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/>
+    <reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/>
+    <reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/>
+    <reg name="vf" bitsize="8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="dt" bitsize="8"/>
+    <reg name="st" bitsize="8"/>
+  </feature>
+</target>
+"#;
+
+/// A byte stream a GDB client is attached through.
+pub trait Connection: Read + Write {
+  /// Whether the client asked to interrupt the running ROM (a `0x03` byte).
+  /// Only called while the ROM runs, so it must not block.
+  fn poll_interrupt(&mut self) -> io::Result<bool> {
+    Ok(false)
+  }
+}
+
+impl Connection for TcpStream {
+  fn poll_interrupt(&mut self) -> io::Result<bool> {
+    let mut byte = [0];
+    self.set_nonblocking(true)?;
+    let peeked = self.peek(&mut byte);
+    self.set_nonblocking(false)?;
+    match peeked {
+      Ok(1) if byte[0] == 0x03 => {
+        self.read_exact(&mut byte)?;
+        Ok(true)
+      }
+      Ok(_) => Ok(false),
+      Err(error) if error.kind() == io::ErrorKind::WouldBlock => Ok(false),
+      Err(error) => Err(error),
+    }
+  }
+}
+
+/// Standard input and output, for clients that start the stub themselves
+/// (`target remote | gdbstub --stdio rom.ch8`). Interrupts are not seen
+/// while the ROM runs.
+pub struct Stdio;
+
+impl Read for Stdio {
+  fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    io::stdin().read(buffer)
+  }
+}
+
+impl Write for Stdio {
+  fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+    io::stdout().write(buffer)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    io::stdout().flush()
+  }
+}
+
+impl Connection for Stdio {}
+
+fn checksum(data: &str) -> u8 {
+  data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+  if text.len() % 2 == 1 {
+    return None;
+  }
+  (0..text.len())
+    .step_by(2)
+    .map(|start| u8::from_str_radix(text.get(start..start + 2)?, 16).ok())
+    .collect()
+}
+
+fn parse_address(text: &str) -> Option<usize> {
+  usize::from_str_radix(text, 16).ok()
+}
+
+/// Parses `address,length`.
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+  let mut parts = text.splitn(2, ',');
+  let address = parse_address(parts.next()?)?;
+  let length = parse_address(parts.next()?)?;
+  Some((address, length))
+}
+
+/// Serves the GDB remote serial protocol for a ROM running under a
+/// `Debugger`.
+///
+/// The registers are V0 to VF, I, PC, SP, DT and ST, in that order, and the
+/// memory is the whole address space, XO-CHIP's included. `Z0`/`Z1` set
+/// breakpoints and `Z2`, `Z3` and `Z4` set write, read and access
+/// watchpoints.
+pub struct GdbStub<T: AudioDriver, R: RandomSource = XorShift> {
+  debugger: Debugger<T, R>,
+  acknowledge: bool,
+  last_stop: String,
+}
+
+impl<T: AudioDriver, R: RandomSource> GdbStub<T, R> {
+  pub fn new(debugger: Debugger<T, R>) -> GdbStub<T, R> {
+    GdbStub {
+      debugger,
+      acknowledge: true,
+      last_stop: "S05".to_string(),
+    }
+  }
+
+  pub fn debugger(&self) -> &Debugger<T, R> {
+    &self.debugger
+  }
+
+  pub fn into_inner(self) -> Debugger<T, R> {
+    self.debugger
+  }
+
+  /// Answers packets until the client detaches, kills the ROM or hangs up.
+  pub fn serve(&mut self, connection: &mut impl Connection) -> io::Result<()> {
+    while let Some(packet) = self.read_packet(connection)? {
+      if packet == "k" {
+        break;
+      }
+      let reply = self.handle(&packet, connection)?;
+      self.write_packet(connection, &reply)?;
+      if packet == "QStartNoAckMode" {
+        self.acknowledge = false;
+      }
+      if packet.starts_with('D') {
+        break;
+      }
+    }
+    Ok(())
+  }
+
+  fn read_byte(connection: &mut impl Connection) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match connection.read(&mut byte)? {
+      0 => Ok(None),
+      _ => Ok(Some(byte[0])),
+    }
+  }
+
+  /// Reads the next `$data#checksum` packet, skipping acknowledgements and
+  /// stray interrupts. Returns `None` once the client hangs up.
+  fn read_packet(&mut self, connection: &mut impl Connection) -> io::Result<Option<String>> {
+    loop {
+      match Self::read_byte(connection)? {
+        None => return Ok(None),
+        Some(b'$') => {}
+        Some(_) => continue,
+      }
+
+      let mut data = vec![];
+      loop {
+        match Self::read_byte(connection)? {
+          None => return Ok(None),
+          Some(b'#') => break,
+          Some(byte) => data.push(byte),
+        }
+      }
+      let mut sent_checksum = [0; 2];
+      connection.read_exact(&mut sent_checksum)?;
+
+      let data = String::from_utf8_lossy(&data).into_owned();
+      let is_valid = std::str::from_utf8(&sent_checksum)
+        .ok()
+        .and_then(|text| u8::from_str_radix(text, 16).ok())
+        == Some(checksum(&data));
+      if self.acknowledge {
+        connection.write_all(if is_valid { b"+" } else { b"-" })?;
+        connection.flush()?;
+      }
+      if is_valid {
+        return Ok(Some(data));
+      }
+    }
+  }
+
+  fn write_packet(&mut self, connection: &mut impl Connection, data: &str) -> io::Result<()> {
+    write!(connection, "${}#{:02x}", data, checksum(data))?;
+    connection.flush()
+  }
+
+  fn handle(&mut self, packet: &str, connection: &mut impl Connection) -> io::Result<String> {
+    let command = packet.get(..1).unwrap_or("");
+    let arguments = packet.get(1..).unwrap_or("");
+    let reply = match command {
+      "?" => self.last_stop.clone(),
+      "g" => to_hex(&self.read_registers()),
+      "G" => self.write_registers(arguments),
+      "p" => match parse_address(arguments).and_then(|register| self.read_register(register)) {
+        Some(bytes) => to_hex(&bytes),
+        None => "E01".to_string(),
+      },
+      "P" => self.write_register(arguments),
+      "m" => self.read_memory(arguments),
+      "M" => self.write_memory(arguments),
+      "c" | "s" => {
+        if !arguments.is_empty() {
+          match parse_address(arguments) {
+            Some(address) if address < self.debugger.chip8().memory_size() => {
+              self
+                .debugger
+                .chip8_mut()
+                .set_program_counter(address as u16);
+            }
+            _ => return Ok("E01".to_string()),
+          }
+        }
+        let result = if command == "s" {
+          self.debugger.step()
+        } else {
+          self.resume(connection)?
+        };
+        self.last_stop = stop_reply(result);
+        self.last_stop.clone()
+      }
+      "Z" | "z" => self.set_point(command == "Z", arguments),
+      "H" | "T" | "D" => "OK".to_string(),
+      _ => self.query(packet),
+    };
+    Ok(reply)
+  }
+
+  fn query(&self, packet: &str) -> String {
+    if packet.starts_with("qSupported") {
+      return format!(
+        "PacketSize={:x};QStartNoAckMode+;qXfer:features:read+;swbreak+",
+        PACKET_SIZE
+      );
+    }
+    if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+      return match parse_range(range) {
+        Some((offset, length)) => {
+          let start = offset.min(TARGET_XML.len());
+          let end = (start + length).min(TARGET_XML.len());
+          let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+          format!("{}{}", marker, &TARGET_XML[start..end])
+        }
+        None => "E01".to_string(),
+      };
+    }
+    match packet {
+      "QStartNoAckMode" => "OK",
+      "qAttached" => "1",
+      "qC" => "QC1",
+      "qfThreadInfo" => "m1",
+      "qsThreadInfo" => "l",
+      _ => "",
+    }
+    .to_string()
+  }
+
+  /// Runs until something stops the ROM or the client interrupts it.
+  fn resume(
+    &mut self,
+    connection: &mut impl Connection,
+  ) -> io::Result<Result<StopReason, EmulationError>> {
+    loop {
+      match self.debugger.run(INSTRUCTIONS_PER_POLL) {
+        Ok(StopReason::Limit) => {}
+        result => return Ok(result),
+      }
+      if connection.poll_interrupt()? {
+        return Ok(Ok(StopReason::Limit));
+      }
+    }
+  }
+
+  fn read_register(&self, register: usize) -> Option<Vec<u8>> {
+    let chip8 = self.debugger.chip8();
+    let value = match register {
+      0..=15 => vec![chip8.registers()[register]],
+      INDEX_REGISTER => chip8.index().to_be_bytes().to_vec(),
+      PROGRAM_COUNTER_REGISTER => chip8.program_counter().to_be_bytes().to_vec(),
+      STACK_POINTER_REGISTER => vec![chip8.stack().len() as u8],
+      DELAY_TIMER_REGISTER => vec![chip8.delay_timer()],
+      SOUND_TIMER_REGISTER => vec![chip8.sound_timer()],
+      _ => return None,
+    };
+    Some(value)
+  }
+
+  fn read_registers(&self) -> Vec<u8> {
+    (0..REGISTER_COUNT)
+      .flat_map(|register| self.read_register(register).unwrap())
+      .collect()
+  }
+
+  /// Writes one register from its big endian bytes. The stack pointer can
+  /// only be read.
+  fn set_register(&mut self, register: usize, bytes: &[u8]) -> bool {
+    let chip8 = self.debugger.chip8_mut();
+    match (register, bytes) {
+      (0..=15, [value]) => {
+        let mut registers = chip8.registers();
+        registers[register] = *value;
+        chip8.set_registers(registers);
+      }
+      (INDEX_REGISTER, [high, low]) => chip8.set_index(u16::from_be_bytes([*high, *low])),
+      (PROGRAM_COUNTER_REGISTER, [high, low]) => {
+        chip8.set_program_counter(u16::from_be_bytes([*high, *low]))
+      }
+      (STACK_POINTER_REGISTER, [value]) => return *value as usize == chip8.stack().len(),
+      (DELAY_TIMER_REGISTER, [value]) => chip8.set_delay_timer(*value),
+      (SOUND_TIMER_REGISTER, [value]) => chip8.set_sound_timer(*value),
+      _ => return false,
+    }
+    true
+  }
+
+  fn write_register(&mut self, arguments: &str) -> String {
+    let mut parts = arguments.splitn(2, '=');
+    let register = parts.next().and_then(parse_address);
+    let bytes = parts.next().and_then(from_hex);
+    match (register, bytes) {
+      (Some(register), Some(bytes)) if self.set_register(register, &bytes) => "OK",
+      _ => "E01",
+    }
+    .to_string()
+  }
+
+  fn write_registers(&mut self, arguments: &str) -> String {
+    let bytes = match from_hex(arguments) {
+      Some(bytes) if bytes.len() == self.read_registers().len() => bytes,
+      _ => return "E01".to_string(),
+    };
+    let mut rest = &bytes[..];
+    for register in 0..REGISTER_COUNT {
+      let size = self.read_register(register).unwrap().len();
+      let (value, remaining) = rest.split_at(size);
+      rest = remaining;
+      // GDB sends every register back, so a stack pointer it cannot change
+      // is left alone rather than failing the whole write.
+      if register != STACK_POINTER_REGISTER && !self.set_register(register, value) {
+        return "E01".to_string();
+      }
+    }
+    "OK".to_string()
+  }
+
+  fn read_memory(&self, arguments: &str) -> String {
+    match parse_range(arguments) {
+      Some((address, length)) if address < self.debugger.chip8().memory_size() => {
+        let memory = self.debugger.chip8().memory();
+        let end = address.saturating_add(length).min(memory.len());
+        to_hex(&memory[address..end])
+      }
+      _ => "E01".to_string(),
+    }
+  }
+
+  fn write_memory(&mut self, arguments: &str) -> String {
+    let mut parts = arguments.splitn(2, ':');
+    let range = parts
+      .next()
+      .and_then(parse_range)
+      .and_then(|(address, length)| Some(address..address.checked_add(length)?));
+    let bytes = parts.next().and_then(from_hex);
+    match (range, bytes) {
+      (Some(range), Some(bytes))
+        if bytes.len() == range.len() && range.end <= self.debugger.chip8().memory_size() =>
+      {
+        self.debugger.chip8_mut().memory_mut()[range].copy_from_slice(&bytes);
+        "OK"
+      }
+      _ => "E01",
+    }
+    .to_string()
+  }
+
+  /// Handles `Z`/`z` packets: `type,address,kind`.
+  fn set_point(&mut self, insert: bool, arguments: &str) -> String {
+    let mut parts = arguments.splitn(3, ',');
+    let kind = parts.next();
+    let address = parts.next().and_then(parse_address);
+    let length = parts.next().and_then(parse_address);
+    let addresses = match (address, length) {
+      (Some(address), Some(length)) if address < self.debugger.chip8().memory_size() => {
+        match address.checked_add(length.max(1)) {
+          Some(end) => address..end,
+          None => return "E01".to_string(),
+        }
+      }
+      _ => return "E01".to_string(),
+    };
+    let address = addresses.start;
+
+    let watch = match kind {
+      Some("0") | Some("1") => {
+        if insert {
+          self.debugger.add_breakpoint(address as u16);
+        } else {
+          self.debugger.remove_breakpoint(address as u16);
+        }
+        return "OK".to_string();
+      }
+      Some("2") => Watch::Write,
+      Some("3") => Watch::Read,
+      Some("4") => Watch::Access,
+      _ => return String::new(),
+    };
+    if insert {
+      self.debugger.add_watchpoint(addresses, watch);
+    } else {
+      self.debugger.remove_watchpoint(addresses, watch);
+    }
+    "OK".to_string()
+  }
+}
+
+fn stop_reply(result: Result<StopReason, EmulationError>) -> String {
+  match result {
+    Ok(StopReason::Step) => "S05".to_string(),
+    Ok(StopReason::Breakpoint { .. }) => "T05swbreak:;".to_string(),
+    Ok(StopReason::Watchpoint {
+      watchpoint,
+      addresses,
+      ..
+    }) => {
+      let kind = match watchpoint.watch {
+        Watch::Write => "watch",
+        Watch::Read => "rwatch",
+        Watch::Access => "awatch",
+      };
+      let address = watchpoint.addresses.start.max(addresses.start);
+      format!("T05{}:{:x};", kind, address)
+    }
+    Ok(StopReason::Halted) => "W00".to_string(),
+    // Only returned when the client interrupts a running ROM.
+    Ok(StopReason::Limit) => "S02".to_string(),
+    // The emulator stays at the faulting instruction, like after a SIGSEGV.
+    Err(_) => "S0B".to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audio::Silent;
+  use crate::{octo, Chip8, Quirks};
+  use std::net::TcpListener;
+  use std::thread;
+
+  const PROGRAM: &str = "
+    : main
+      v0 := 0
+      loop
+        v0 += 1
+        i := counter
+        save v0
+        if v0 != 3 then
+      again
+      exit
+    : counter
+      0
+  ";
+
+  struct Client {
+    stream: TcpStream,
+  }
+
+  impl Client {
+    fn send(&mut self, data: &str) -> String {
+      write!(self.stream, "${}#{:02x}", data, checksum(data)).unwrap();
+      let mut byte = [0];
+      self.stream.read_exact(&mut byte).unwrap();
+      assert_eq!(byte[0], b'+');
+      self.receive()
+    }
+
+    fn receive(&mut self) -> String {
+      let mut byte = [0];
+      self.stream.read_exact(&mut byte).unwrap();
+      assert_eq!(byte[0], b'$');
+      let mut data = vec![];
+      loop {
+        self.stream.read_exact(&mut byte).unwrap();
+        if byte[0] == b'#' {
+          break;
+        }
+        data.push(byte[0]);
+      }
+      let mut sum = [0; 2];
+      self.stream.read_exact(&mut sum).unwrap();
+      let data = String::from_utf8(data).unwrap();
+      assert_eq!(
+        std::str::from_utf8(&sum).unwrap(),
+        format!("{:02x}", checksum(&data))
+      );
+      self.stream.write_all(b"+").unwrap();
+      data
+    }
+  }
+
+  fn with_stub(script: impl FnOnce(&mut Client)) -> Debugger<Silent> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+      let mut chip8 = Chip8::new(Silent, Quirks::modern());
+      chip8.load(octo::compile(PROGRAM).unwrap()).unwrap();
+      let mut stub = GdbStub::new(Debugger::new(chip8));
+      let (mut stream, _) = listener.accept().unwrap();
+      stream.set_nodelay(true).unwrap();
+      stub.serve(&mut stream).unwrap();
+      stub.into_inner()
+    });
+
+    let mut client = Client {
+      stream: TcpStream::connect(address).unwrap(),
+    };
+    client.stream.set_nodelay(true).unwrap();
+    script(&mut client);
+    server.join().unwrap()
+  }
+
+  #[test]
+  fn handshake_and_registers() {
+    with_stub(|client| {
+      assert!(client
+        .send("qSupported:swbreak+")
+        .contains("qXfer:features:read+"));
+      assert_eq!(client.send("?"), "S05");
+      assert!(client
+        .send("qXfer:features:read:target.xml:0,ffff")
+        .starts_with("l<?xml"));
+
+      let registers = client.send("g");
+      assert_eq!(registers.len(), 23 * 2);
+      assert_eq!(&registers[36..40], "0200");
+      assert_eq!(client.send("p11"), "0200");
+
+      assert_eq!(client.send("P3=2a"), "OK");
+      assert_eq!(client.send("p3"), "2a");
+      assert_eq!(client.send("P10=0300"), "OK");
+      assert_eq!(client.send("p10"), "0300");
+      assert_eq!(client.send("P12=05"), "E01");
+      assert_eq!(client.send("D"), "OK");
+    });
+  }
+
+  #[test]
+  fn memory_and_stepping() {
+    let debugger = with_stub(|client| {
+      assert_eq!(client.send("m200,4"), "60007001");
+      assert_eq!(client.send("M300,2:beef"), "OK");
+      assert_eq!(client.send("m300,2"), "beef");
+      assert_eq!(client.send("m10000,1"), "E01");
+      assert_eq!(client.send("Mffffffffffffffff,1:00"), "E01");
+      assert_eq!(client.send("Z2,300,ffffffffffffffff"), "E01");
+
+      assert_eq!(client.send("s"), "S05");
+      assert_eq!(client.send("s"), "S05");
+      assert_eq!(client.send("p0"), "01");
+      assert_eq!(client.send("p11"), "0204");
+      write!(client.stream, "$k#6b").unwrap();
+    });
+    assert_eq!(debugger.chip8().memory()[0x300..0x302], [0xBE, 0xEF]);
+  }
+
+  #[test]
+  fn breakpoints_and_watchpoints() {
+    with_stub(|client| {
+      assert_eq!(client.send("QStartNoAckMode"), "OK");
+      // Acknowledgements are off from here on.
+      let mut send = |data: &str| {
+        write!(client.stream, "${}#{:02x}", data, checksum(data)).unwrap();
+        client.receive()
+      };
+
+      assert_eq!(send("Z0,202,2"), "OK");
+      assert_eq!(send("c"), "T05swbreak:;");
+      assert_eq!(send("c"), "T05swbreak:;");
+      assert_eq!(send("p0"), "01");
+      assert_eq!(send("z0,202,2"), "OK");
+
+      // `counter` sits after the 7 instructions of main.
+      assert_eq!(send("Z2,20e,1"), "OK");
+      assert_eq!(send("c"), "T05watch:20e;");
+      assert_eq!(send("m20e,1"), "02");
+      assert_eq!(send("z2,20e,1"), "OK");
+
+      assert_eq!(send("c"), "W00");
+      assert_eq!(send("?"), "W00");
+      send("D");
+    });
+  }
+}
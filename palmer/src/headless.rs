@@ -0,0 +1,292 @@
+use serde_json::{json, Value};
+
+use super::audio::AudioDriver;
+use super::error::EmulationError;
+use super::input::{Button, BUTTON_LIST};
+use super::processor::Chip8;
+use super::random::RandomSource;
+
+/// Presses or releases a key right before `frame` runs.
+#[derive(Clone, Copy, PartialEq)]
+pub struct KeyEvent {
+  pub frame: usize,
+  pub button: Button,
+  pub pressed: bool,
+}
+
+impl KeyEvent {
+  /// Parses `<frame>:<key>`, with the key as a hex digit.
+  pub fn parse(text: &str, pressed: bool) -> Result<KeyEvent, String> {
+    let invalid = || format!("invalid key event `{}`, expected `<frame>:<key>`", text);
+    let mut parts = text.splitn(2, ':');
+    let frame = parts
+      .next()
+      .and_then(|frame| frame.parse().ok())
+      .ok_or_else(invalid)?;
+    let key = parts
+      .next()
+      .and_then(|key| u8::from_str_radix(key, 16).ok())
+      .ok_or_else(invalid)?;
+    let button = *BUTTON_LIST
+      .iter()
+      .find(|button| **button as u8 == key)
+      .ok_or_else(invalid)?;
+    Ok(KeyEvent {
+      frame,
+      button,
+      pressed,
+    })
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopCondition {
+  Halted,
+  /// Stops right before the instruction at this address runs.
+  ProgramCounter(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+  /// Ran every frame, with no stop condition to meet.
+  Finished,
+  /// Met the stop condition.
+  Stopped,
+  /// Ran every frame without meeting the stop condition.
+  TimedOut,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+  pub outcome: Outcome,
+  /// How many frames ran, counting the one the stop condition cut short.
+  pub frames: usize,
+}
+
+/// Runs a ROM without a front-end: a number of frames, the keys to press
+/// along the way, and optionally a condition to stop at.
+#[derive(Clone, PartialEq)]
+pub struct Script {
+  pub frames: usize,
+  pub keys: Vec<KeyEvent>,
+  pub stop: Option<StopCondition>,
+}
+
+impl Script {
+  pub fn new(frames: usize) -> Script {
+    Script {
+      frames,
+      keys: vec![],
+      stop: None,
+    }
+  }
+
+  pub fn run<T: AudioDriver, R: RandomSource>(
+    &self,
+    chip8: &mut Chip8<T, R>,
+  ) -> Result<Report, EmulationError> {
+    self.run_frames(chip8, |_| {})
+  }
+
+  /// Like `run`, also appending the buzzer's samples to `samples` as each
+  /// frame runs. They only depend on the ROM and the script, so a recording
+  /// makes a golden file as well as a screen does.
+  pub fn run_with_audio<T: AudioDriver, R: RandomSource>(
+    &self,
+    chip8: &mut Chip8<T, R>,
+    sample_rate: u32,
+    samples: &mut Vec<f32>,
+  ) -> Result<Report, EmulationError> {
+    let result = self.run_frames(chip8, |chip8| record_audio(chip8, sample_rate, samples));
+    // Whatever ran of the frame that stopped or failed.
+    record_audio(chip8, sample_rate, samples);
+    result
+  }
+
+  fn run_frames<T: AudioDriver, R: RandomSource, F: FnMut(&mut Chip8<T, R>)>(
+    &self,
+    chip8: &mut Chip8<T, R>,
+    mut after_frame: F,
+  ) -> Result<Report, EmulationError> {
+    for frame in 0..self.frames {
+      for event in self.keys.iter().filter(|event| event.frame == frame) {
+        if event.pressed {
+          chip8.input.key_down(event.button);
+        } else {
+          chip8.input.key_up(event.button);
+        }
+      }
+
+      let completed = match self.stop {
+        Some(StopCondition::ProgramCounter(address)) => {
+          chip8.run_frame_while(|chip8| chip8.program_counter() != address)?
+        }
+        _ => {
+          chip8.run_frame()?;
+          true
+        }
+      };
+
+      after_frame(chip8);
+
+      let stopped = match self.stop {
+        Some(StopCondition::Halted) => chip8.is_halted(),
+        Some(StopCondition::ProgramCounter(_)) => !completed,
+        None => false,
+      };
+      if stopped {
+        return Ok(Report {
+          outcome: Outcome::Stopped,
+          frames: frame + 1,
+        });
+      }
+    }
+
+    let outcome = match self.stop {
+      Some(_) => Outcome::TimedOut,
+      None => Outcome::Finished,
+    };
+    Ok(Report {
+      outcome,
+      frames: self.frames,
+    })
+  }
+}
+
+/// Appends the buzzer's samples up to where the engine is. Call it after
+/// every frame, as the engine only keeps the last second.
+pub fn record_audio<T: AudioDriver, R: RandomSource>(
+  chip8: &mut Chip8<T, R>,
+  sample_rate: u32,
+  samples: &mut Vec<f32>,
+) {
+  let start = samples.len();
+  samples.resize(start + chip8.pending_audio_samples(sample_rate), 0.);
+  chip8.fill_audio(&mut samples[start..], sample_rate);
+}
+
+/// The registers, timers, stack and memory of the engine, with the memory
+/// as one hex string.
+pub fn state_json<T: AudioDriver, R: RandomSource>(chip8: &Chip8<T, R>) -> Value {
+  let memory: String = chip8
+    .memory()
+    .iter()
+    .map(|byte| format!("{:02x}", byte))
+    .collect();
+  json!({
+    "registers": chip8.registers(),
+    "index": chip8.index(),
+    "program_counter": chip8.program_counter(),
+    "stack": chip8.stack(),
+    "delay_timer": chip8.delay_timer(),
+    "sound_timer": chip8.sound_timer(),
+    "halted": chip8.is_halted(),
+    "waiting_for_key": chip8.is_waiting_for_key(),
+    "memory": memory,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audio::Silent;
+  use crate::octo;
+  use crate::Quirks;
+
+  fn chip8(source: &str) -> Chip8<Silent> {
+    let mut chip8 = Chip8::new(Silent, Quirks::default());
+    chip8.load(octo::compile(source).unwrap()).unwrap();
+    chip8.set_instructions_per_frame(10);
+    chip8
+  }
+
+  #[test]
+  fn parses_key_events() {
+    let event = KeyEvent::parse("12:a", true).unwrap();
+    assert_eq!(event.frame, 12);
+    assert!(event.button == Button::A);
+    assert!(event.pressed);
+    assert!(KeyEvent::parse("12", true).is_err());
+    assert!(KeyEvent::parse("12:10", true).is_err());
+  }
+
+  #[test]
+  fn presses_keys_and_stops_at_the_program_counter() {
+    let mut chip8 = chip8(
+      ": main
+        v0 := key
+        v1 := 1
+       : done
+        loop again",
+    );
+    let mut script = Script::new(10);
+    script.keys.push(KeyEvent::parse("3:5", true).unwrap());
+    script.keys.push(KeyEvent::parse("5:5", false).unwrap());
+    script.stop = Some(StopCondition::ProgramCounter(0x204));
+
+    let report = script.run(&mut chip8).unwrap();
+    assert_eq!(report.outcome, Outcome::Stopped);
+    assert_eq!(report.frames, 6);
+    assert_eq!(chip8.registers()[0], 5);
+    assert_eq!(chip8.registers()[1], 1);
+  }
+
+  #[test]
+  fn times_out_or_finishes() {
+    let mut script = Script::new(5);
+    assert_eq!(
+      script.run(&mut chip8(": main loop again")).unwrap(),
+      Report {
+        outcome: Outcome::Finished,
+        frames: 5
+      }
+    );
+
+    script.stop = Some(StopCondition::Halted);
+    assert_eq!(
+      script.run(&mut chip8(": main loop again")).unwrap().outcome,
+      Outcome::TimedOut
+    );
+    assert_eq!(
+      script.run(&mut chip8(": main exit")).unwrap(),
+      Report {
+        outcome: Outcome::Stopped,
+        frames: 1
+      }
+    );
+  }
+
+  #[test]
+  fn records_the_buzzer() {
+    let record = || {
+      let mut chip8 = chip8(": main v0 := 2 buzzer := v0 loop again");
+      let mut samples = vec![];
+      Script::new(4)
+        .run_with_audio(&mut chip8, 600, &mut samples)
+        .unwrap();
+      samples
+    };
+    let samples = record();
+
+    // 10 samples a frame. The buzzer starts on the second instruction, at
+    // the middle of the square wave's edge, and sounds for two frames.
+    assert_eq!(samples.len(), 40);
+    let sounding: Vec<usize> = (0..40).filter(|index| samples[*index] != 0.).collect();
+    assert_eq!(sounding.first(), Some(&2));
+    assert_eq!(sounding.last(), Some(&19));
+    assert_eq!(samples, record());
+  }
+
+  #[test]
+  fn dumps_the_state() {
+    let mut chip8 = chip8(": main v3 := 7 i := 0x300");
+    chip8.run_frame().unwrap();
+    let state = state_json(&chip8);
+    assert_eq!(state["registers"][3], 7);
+    assert_eq!(state["index"], 0x300);
+    assert_eq!(
+      state["memory"].as_str().unwrap().len(),
+      chip8.memory().len() * 2
+    );
+  }
+}
@@ -1,9 +1,13 @@
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Input {
   pub keypad: [bool; 16],
+  /// Keys that went down or up since the frame started, kept even if they
+  /// went back before the engine looked.
+  pressed: [bool; 16],
+  released: [bool; 16],
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Button {
   One = 0x1,
   Two = 0x2,
@@ -69,20 +73,83 @@ impl Input {
   pub fn new() -> Input {
     Input {
       keypad: [false; 16],
+      pressed: [false; 16],
+      released: [false; 16],
     }
   }
 
+  /// Whether the key is held. Keys past 0xF don't exist, and never are.
   pub fn is_pressed(&self, key: u8) -> bool {
-    self.keypad[key as usize]
+    self.keypad.get(key as usize).copied().unwrap_or(false)
+  }
+
+  /// Whether the key went down since the frame started.
+  pub fn was_pressed(&self, key: u8) -> bool {
+    self.pressed.get(key as usize).copied().unwrap_or(false)
+  }
+
+  /// Whether the key went up since the frame started.
+  pub fn was_released(&self, key: u8) -> bool {
+    self.released.get(key as usize).copied().unwrap_or(false)
   }
 
   pub fn key_up(&mut self, key: Button) {
+    if self.keypad[key as usize] {
+      self.released[key as usize] = true;
+    }
     self.keypad[key as usize] = false;
   }
 
   pub fn key_down(&mut self, key: Button) {
+    if !self.keypad[key as usize] {
+      self.pressed[key as usize] = true;
+    }
     self.keypad[key as usize] = true;
   }
+
+  /// Forgets the key's presses and releases so far, once they were used.
+  pub fn clear_edges(&mut self, key: u8) {
+    if let (Some(pressed), Some(released)) = (
+      self.pressed.get_mut(key as usize),
+      self.released.get_mut(key as usize),
+    ) {
+      *pressed = false;
+      *released = false;
+    }
+  }
+
+  /// Starts a new frame, with no presses or releases yet.
+  pub fn end_frame(&mut self) {
+    self.pressed = [false; 16];
+    self.released = [false; 16];
+  }
+
+  /// Packs the held keys, presses and releases into one mask each, key 0
+  /// in the lowest bit.
+  pub(crate) fn to_bits(self) -> [u16; 3] {
+    let mask = |keys: [bool; 16]| {
+      keys
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (key, down)| bits | (*down as u16) << key)
+    };
+    [mask(self.keypad), mask(self.pressed), mask(self.released)]
+  }
+
+  pub(crate) fn from_bits(bits: [u16; 3]) -> Input {
+    let keys = |mask: u16| {
+      let mut keys = [false; 16];
+      for (key, down) in keys.iter_mut().enumerate() {
+        *down = mask & (1 << key) != 0;
+      }
+      keys
+    };
+    Input {
+      keypad: keys(bits[0]),
+      pressed: keys(bits[1]),
+      released: keys(bits[2]),
+    }
+  }
 }
 
 #[cfg(test)]
@@ -119,4 +186,43 @@ mod tests {
     input.key_up(Button::Seven);
     assert!(!input.is_pressed(7))
   }
+
+  #[test]
+  fn keeps_edges_until_the_frame_ends() {
+    let mut input = Input::new();
+    input.key_down(Button::Three);
+    input.key_up(Button::Three);
+    input.key_up(Button::Four);
+    assert!(input.was_pressed(3) && input.was_released(3));
+    assert!(!input.was_released(4));
+
+    input.end_frame();
+    input.key_down(Button::A);
+    input.key_down(Button::A);
+    assert!(!input.was_pressed(3) && !input.was_released(3));
+    assert!(input.was_pressed(0xA));
+    input.clear_edges(0xA);
+    assert!(!input.was_pressed(0xA) && input.is_pressed(0xA));
+  }
+
+  #[test]
+  fn keys_past_f_are_never_pressed() {
+    let mut input = Input::new();
+    input.key_down(Button::Zero);
+    input.clear_edges(0x20);
+    assert!(!input.is_pressed(0x10));
+    assert!(!input.was_pressed(0x20));
+    assert!(!input.was_released(0xFF));
+  }
+
+  #[test]
+  fn bits_round_trip() {
+    let mut input = Input::new();
+    input.key_down(Button::F);
+    input.end_frame();
+    input.key_down(Button::Zero);
+    input.key_up(Button::F);
+    assert_eq!(input.to_bits(), [0x0001, 0x0001, 0x8000]);
+    assert!(Input::from_bits(input.to_bits()) == input);
+  }
 }
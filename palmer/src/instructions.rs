@@ -1,3 +1,5 @@
+use std::fmt;
+
 macro_rules! hex_group_to_integer {
   ( $nibble1:expr, $nibble2:expr ) => {{
     (($nibble1 << 4) as u8) | $nibble2
@@ -13,28 +15,36 @@ macro_rules! hex_group_to_integer {
   }};
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct TargetSourcePair {
   pub target: u8,
   pub source: u8,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct RegisterValuePair {
   pub register: u8,
   pub value: u8,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Instruction {
   CallMachineCode(u16),
   ClearDisplay,
   Return,
+  ScrollDown(u8),
+  ScrollRight,
+  ScrollLeft,
+  Exit,
+  LowResolution,
+  HighResolution,
   GoTo(u16),
   Call(u16),
   SkipIfEqual(RegisterValuePair),
   SkipIfDifferent(RegisterValuePair),
   SkipIfRegisterEqual(TargetSourcePair),
+  DumpRegisterRange { from: u8, to: u8 },
+  LoadRegisterRange { from: u8, to: u8 },
   AssignValueToRegister(RegisterValuePair),
   AddValueToRegister(RegisterValuePair),
   AssignVYToVX(TargetSourcePair),
@@ -43,11 +53,12 @@ pub enum Instruction {
   SetXXorY(TargetSourcePair),
   AddYToX(TargetSourcePair),
   SubYFromX(TargetSourcePair),
-  ShiftRight(u8),
+  ShiftRight(TargetSourcePair),
   SetXAsYMinusX(TargetSourcePair),
-  ShiftLeft(u8),
+  ShiftLeft(TargetSourcePair),
   SkipIfRegisterDifferent(TargetSourcePair),
   SetIAs(u16),
+  SetIAsLong,
   GoToNPlusV0(u16),
   Random(RegisterValuePair),
   Draw { x: u8, y: u8, height: u8 },
@@ -57,11 +68,17 @@ pub enum Instruction {
   WaitForInputAndStoreIn(u8),
   SetDelayAsX(u8),
   SetSoundAsX(u8),
+  SelectPlanes(u8),
+  LoadAudioPattern,
+  SetPitch(u8),
   AddXToI(u8),
   SetIAsFontSprite(u8),
+  SetIAsBigFontSprite(u8),
   StoreBCD(u8),
   DumpRegisters(u8),
   LoadRegisters(u8),
+  StoreFlags(u8),
+  LoadFlags(u8),
   InvalidInstruction,
 }
 
@@ -90,12 +107,20 @@ pub fn decode(op_code: u16) -> Instruction {
   match nibble_array {
     [0x0, 0x0, 0xE, 0x0] => Instruction::ClearDisplay,
     [0x0, 0x0, 0xE, 0xE] => Instruction::Return,
+    [0x0, 0x0, 0xC, rows] => Instruction::ScrollDown(rows),
+    [0x0, 0x0, 0xF, 0xB] => Instruction::ScrollRight,
+    [0x0, 0x0, 0xF, 0xC] => Instruction::ScrollLeft,
+    [0x0, 0x0, 0xF, 0xD] => Instruction::Exit,
+    [0x0, 0x0, 0xF, 0xE] => Instruction::LowResolution,
+    [0x0, 0x0, 0xF, 0xF] => Instruction::HighResolution,
     [0x0, c1, c2, c3] => Instruction::CallMachineCode(hex_group_to_integer!(c1, c2, c3)),
     [0x1, c1, c2, c3] => Instruction::GoTo(hex_group_to_integer!(c1, c2, c3)),
     [0x2, c1, c2, c3] => Instruction::Call(hex_group_to_integer!(c1, c2, c3)),
     [0x3, register, c1, c2] => Instruction::SkipIfEqual(as_rv_pair(register, c1, c2)),
     [0x4, register, c1, c2] => Instruction::SkipIfDifferent(as_rv_pair(register, c1, c2)),
     [0x5, x, y, 0x0] => Instruction::SkipIfRegisterEqual(as_ts_pair(x, y)),
+    [0x5, from, to, 0x2] => Instruction::DumpRegisterRange { from, to },
+    [0x5, from, to, 0x3] => Instruction::LoadRegisterRange { from, to },
     [0x6, register, c1, c2] => Instruction::AssignValueToRegister(as_rv_pair(register, c1, c2)),
     [0x7, register, c1, c2] => Instruction::AddValueToRegister(as_rv_pair(register, c1, c2)),
     [0x8, x, y, 0x0] => Instruction::AssignVYToVX(as_ts_pair(x, y)),
@@ -104,9 +129,9 @@ pub fn decode(op_code: u16) -> Instruction {
     [0x8, x, y, 0x3] => Instruction::SetXXorY(as_ts_pair(x, y)),
     [0x8, x, y, 0x4] => Instruction::AddYToX(as_ts_pair(x, y)),
     [0x8, x, y, 0x5] => Instruction::SubYFromX(as_ts_pair(x, y)),
-    [0x8, x, _, 0x6] => Instruction::ShiftRight(x),
+    [0x8, x, y, 0x6] => Instruction::ShiftRight(as_ts_pair(x, y)),
     [0x8, x, y, 0x7] => Instruction::SetXAsYMinusX(as_ts_pair(x, y)),
-    [0x8, x, _, 0xE] => Instruction::ShiftLeft(x),
+    [0x8, x, y, 0xE] => Instruction::ShiftLeft(as_ts_pair(x, y)),
     [0x9, x, y, 0x0] => Instruction::SkipIfRegisterDifferent(as_ts_pair(x, y)),
     [0xA, c1, c2, c3] => Instruction::SetIAs(hex_group_to_integer!(c1, c2, c3)),
     [0xB, c1, c2, c3] => Instruction::GoToNPlusV0(hex_group_to_integer!(c1, c2, c3)),
@@ -114,19 +139,117 @@ pub fn decode(op_code: u16) -> Instruction {
     [0xD, x, y, height] => Instruction::Draw { x, y, height },
     [0xE, x, 0x9, 0xE] => Instruction::SkipIfKeyPressed(x),
     [0xE, x, 0xA, 0x1] => Instruction::SkipIfKeyNotPressed(x),
+    [0xF, 0x0, 0x0, 0x0] => Instruction::SetIAsLong,
+    [0xF, planes, 0x0, 0x1] => Instruction::SelectPlanes(planes),
+    [0xF, 0x0, 0x0, 0x2] => Instruction::LoadAudioPattern,
     [0xF, x, 0x0, 0x7] => Instruction::SetXAsDelay(x),
     [0xF, x, 0x0, 0xA] => Instruction::WaitForInputAndStoreIn(x),
     [0xF, x, 0x1, 0x5] => Instruction::SetDelayAsX(x),
     [0xF, x, 0x1, 0x8] => Instruction::SetSoundAsX(x),
     [0xF, x, 0x1, 0xE] => Instruction::AddXToI(x),
     [0xF, x, 0x2, 0x9] => Instruction::SetIAsFontSprite(x),
+    [0xF, x, 0x3, 0x0] => Instruction::SetIAsBigFontSprite(x),
+    [0xF, x, 0x3, 0xA] => Instruction::SetPitch(x),
     [0xF, x, 0x3, 0x3] => Instruction::StoreBCD(x),
     [0xF, x, 0x5, 0x5] => Instruction::DumpRegisters(x),
     [0xF, x, 0x6, 0x5] => Instruction::LoadRegisters(x),
+    [0xF, x, 0x7, 0x5] => Instruction::StoreFlags(x),
+    [0xF, x, 0x8, 0x5] => Instruction::LoadFlags(x),
     _ => Instruction::InvalidInstruction,
   }
 }
 
+impl fmt::Display for Instruction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Instruction::CallMachineCode(address) => write!(f, "SYS {:#05X}", address),
+      Instruction::ClearDisplay => write!(f, "CLS"),
+      Instruction::Return => write!(f, "RET"),
+      Instruction::ScrollDown(rows) => write!(f, "SCD {}", rows),
+      Instruction::ScrollRight => write!(f, "SCR"),
+      Instruction::ScrollLeft => write!(f, "SCL"),
+      Instruction::Exit => write!(f, "EXIT"),
+      Instruction::LowResolution => write!(f, "LOW"),
+      Instruction::HighResolution => write!(f, "HIGH"),
+      Instruction::GoTo(address) => write!(f, "JP {:#05X}", address),
+      Instruction::Call(address) => write!(f, "CALL {:#05X}", address),
+      Instruction::SkipIfEqual(RegisterValuePair { register, value }) => {
+        write!(f, "SE V{:X}, {:#04X}", register, value)
+      }
+      Instruction::SkipIfDifferent(RegisterValuePair { register, value }) => {
+        write!(f, "SNE V{:X}, {:#04X}", register, value)
+      }
+      Instruction::SkipIfRegisterEqual(TargetSourcePair { target, source }) => {
+        write!(f, "SE V{:X}, V{:X}", target, source)
+      }
+      Instruction::DumpRegisterRange { from, to } => write!(f, "SAVE V{:X}, V{:X}", from, to),
+      Instruction::LoadRegisterRange { from, to } => write!(f, "LOAD V{:X}, V{:X}", from, to),
+      Instruction::AssignValueToRegister(RegisterValuePair { register, value }) => {
+        write!(f, "LD V{:X}, {:#04X}", register, value)
+      }
+      Instruction::AddValueToRegister(RegisterValuePair { register, value }) => {
+        write!(f, "ADD V{:X}, {:#04X}", register, value)
+      }
+      Instruction::AssignVYToVX(TargetSourcePair { target, source }) => {
+        write!(f, "LD V{:X}, V{:X}", target, source)
+      }
+      Instruction::SetXOrY(TargetSourcePair { target, source }) => {
+        write!(f, "OR V{:X}, V{:X}", target, source)
+      }
+      Instruction::SetXAndY(TargetSourcePair { target, source }) => {
+        write!(f, "AND V{:X}, V{:X}", target, source)
+      }
+      Instruction::SetXXorY(TargetSourcePair { target, source }) => {
+        write!(f, "XOR V{:X}, V{:X}", target, source)
+      }
+      Instruction::AddYToX(TargetSourcePair { target, source }) => {
+        write!(f, "ADD V{:X}, V{:X}", target, source)
+      }
+      Instruction::SubYFromX(TargetSourcePair { target, source }) => {
+        write!(f, "SUB V{:X}, V{:X}", target, source)
+      }
+      Instruction::ShiftRight(TargetSourcePair { target, source }) => {
+        write!(f, "SHR V{:X}, V{:X}", target, source)
+      }
+      Instruction::SetXAsYMinusX(TargetSourcePair { target, source }) => {
+        write!(f, "SUBN V{:X}, V{:X}", target, source)
+      }
+      Instruction::ShiftLeft(TargetSourcePair { target, source }) => {
+        write!(f, "SHL V{:X}, V{:X}", target, source)
+      }
+      Instruction::SkipIfRegisterDifferent(TargetSourcePair { target, source }) => {
+        write!(f, "SNE V{:X}, V{:X}", target, source)
+      }
+      Instruction::SetIAs(address) => write!(f, "LD I, {:#05X}", address),
+      // The address is in the two bytes that follow, which `decode` never sees.
+      Instruction::SetIAsLong => write!(f, "LD I, LONG"),
+      Instruction::GoToNPlusV0(address) => write!(f, "JP V0, {:#05X}", address),
+      Instruction::Random(RegisterValuePair { register, value }) => {
+        write!(f, "RND V{:X}, {:#04X}", register, value)
+      }
+      Instruction::Draw { x, y, height } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, height),
+      Instruction::SkipIfKeyPressed(register) => write!(f, "SKP V{:X}", register),
+      Instruction::SkipIfKeyNotPressed(register) => write!(f, "SKNP V{:X}", register),
+      Instruction::SetXAsDelay(register) => write!(f, "LD V{:X}, DT", register),
+      Instruction::WaitForInputAndStoreIn(register) => write!(f, "LD V{:X}, K", register),
+      Instruction::SetDelayAsX(register) => write!(f, "LD DT, V{:X}", register),
+      Instruction::SetSoundAsX(register) => write!(f, "LD ST, V{:X}", register),
+      Instruction::SelectPlanes(planes) => write!(f, "PLANE {}", planes),
+      Instruction::LoadAudioPattern => write!(f, "AUDIO"),
+      Instruction::SetPitch(register) => write!(f, "PITCH V{:X}", register),
+      Instruction::AddXToI(register) => write!(f, "ADD I, V{:X}", register),
+      Instruction::SetIAsFontSprite(register) => write!(f, "LD F, V{:X}", register),
+      Instruction::SetIAsBigFontSprite(register) => write!(f, "LD HF, V{:X}", register),
+      Instruction::StoreBCD(register) => write!(f, "LD B, V{:X}", register),
+      Instruction::DumpRegisters(register) => write!(f, "LD [I], V{:X}", register),
+      Instruction::LoadRegisters(register) => write!(f, "LD V{:X}, [I]", register),
+      Instruction::StoreFlags(register) => write!(f, "LD R, V{:X}", register),
+      Instruction::LoadFlags(register) => write!(f, "LD V{:X}, R", register),
+      Instruction::InvalidInstruction => write!(f, "INVALID"),
+    }
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -159,6 +282,36 @@ mod tests {
     assert_eq!(Instruction::Return, decode(0x00EE))
   }
 
+  #[test]
+  fn scroll_down() {
+    assert_eq!(Instruction::ScrollDown(0x4), decode(0x00C4))
+  }
+
+  #[test]
+  fn scroll_right() {
+    assert_eq!(Instruction::ScrollRight, decode(0x00FB))
+  }
+
+  #[test]
+  fn scroll_left() {
+    assert_eq!(Instruction::ScrollLeft, decode(0x00FC))
+  }
+
+  #[test]
+  fn exit() {
+    assert_eq!(Instruction::Exit, decode(0x00FD))
+  }
+
+  #[test]
+  fn low_resolution() {
+    assert_eq!(Instruction::LowResolution, decode(0x00FE))
+  }
+
+  #[test]
+  fn high_resolution() {
+    assert_eq!(Instruction::HighResolution, decode(0x00FF))
+  }
+
   #[test]
   fn go_to() {
     assert_eq!(Instruction::GoTo(0x0ABA), decode(0x1ABA))
@@ -202,6 +355,22 @@ mod tests {
     )
   }
 
+  #[test]
+  fn dump_register_range() {
+    assert_eq!(
+      Instruction::DumpRegisterRange { from: 0x3, to: 0x1 },
+      decode(0x5312)
+    )
+  }
+
+  #[test]
+  fn load_register_range() {
+    assert_eq!(
+      Instruction::LoadRegisterRange { from: 0x2, to: 0xA },
+      decode(0x52A3)
+    )
+  }
+
   #[test]
   fn assign_value_to_register() {
     assert_eq!(
@@ -292,7 +461,13 @@ mod tests {
 
   #[test]
   fn shift_right() {
-    assert_eq!(Instruction::ShiftRight(0x2), decode(0x82A6))
+    assert_eq!(
+      Instruction::ShiftRight(TargetSourcePair {
+        target: 0x2,
+        source: 0xA
+      }),
+      decode(0x82A6)
+    )
   }
 
   #[test]
@@ -308,7 +483,13 @@ mod tests {
 
   #[test]
   fn shift_left() {
-    assert_eq!(Instruction::ShiftLeft(0xE), decode(0x8EAE))
+    assert_eq!(
+      Instruction::ShiftLeft(TargetSourcePair {
+        target: 0xE,
+        source: 0xA
+      }),
+      decode(0x8EAE)
+    )
   }
 
   #[test]
@@ -327,6 +508,11 @@ mod tests {
     assert_eq!(Instruction::SetIAs(0x0EEE), decode(0xAEEE))
   }
 
+  #[test]
+  fn set_i_as_long() {
+    assert_eq!(Instruction::SetIAsLong, decode(0xF000))
+  }
+
   #[test]
   fn go_to_n_plus_v0() {
     assert_eq!(Instruction::GoToNPlusV0(0xABF), decode(0xBABF))
@@ -385,6 +571,21 @@ mod tests {
     assert_eq!(Instruction::SetSoundAsX(0xD), decode(0xFD18))
   }
 
+  #[test]
+  fn select_planes() {
+    assert_eq!(Instruction::SelectPlanes(0x3), decode(0xF301))
+  }
+
+  #[test]
+  fn load_audio_pattern() {
+    assert_eq!(Instruction::LoadAudioPattern, decode(0xF002))
+  }
+
+  #[test]
+  fn set_pitch() {
+    assert_eq!(Instruction::SetPitch(0x4), decode(0xF43A))
+  }
+
   #[test]
   fn add_vx_to_i() {
     assert_eq!(Instruction::AddXToI(0xA), decode(0xFA1E))
@@ -395,6 +596,11 @@ mod tests {
     assert_eq!(Instruction::SetIAsFontSprite(0x3), decode(0xF329))
   }
 
+  #[test]
+  fn assign_big_font_sprite_to_i() {
+    assert_eq!(Instruction::SetIAsBigFontSprite(0x7), decode(0xF730))
+  }
+
   #[test]
   fn set_bcd() {
     assert_eq!(Instruction::StoreBCD(0xA), decode(0xFA33))
@@ -410,8 +616,48 @@ mod tests {
     assert_eq!(Instruction::LoadRegisters(0xB), decode(0xFB65))
   }
 
+  #[test]
+  fn store_flags() {
+    assert_eq!(Instruction::StoreFlags(0x5), decode(0xF575))
+  }
+
+  #[test]
+  fn load_flags() {
+    assert_eq!(Instruction::LoadFlags(0x3), decode(0xF385))
+  }
+
   #[test]
   fn invalid_instruction() {
     assert_eq!(Instruction::InvalidInstruction, decode(0x5AB4))
   }
+
+  #[test]
+  fn display_mnemonics() {
+    let cases = [
+      (0x00E0, "CLS"),
+      (0x00C3, "SCD 3"),
+      (0x1ABC, "JP 0xABC"),
+      (0x2204, "CALL 0x204"),
+      (0x3A0F, "SE VA, 0x0F"),
+      (0x5122, "SAVE V1, V2"),
+      (0x61F0, "LD V1, 0xF0"),
+      (0x8AB6, "SHR VA, VB"),
+      (0x8AB7, "SUBN VA, VB"),
+      (0xA123, "LD I, 0x123"),
+      (0xB300, "JP V0, 0x300"),
+      (0xC1FF, "RND V1, 0xFF"),
+      (0xD015, "DRW V0, V1, 5"),
+      (0xE3A1, "SKNP V3"),
+      (0xF000, "LD I, LONG"),
+      (0xF201, "PLANE 2"),
+      (0xF40A, "LD V4, K"),
+      (0xF530, "LD HF, V5"),
+      (0xF655, "LD [I], V6"),
+      (0xF785, "LD V7, R"),
+    ];
+
+    for (op_code, text) in cases.iter() {
+      assert_eq!(decode(*op_code).to_string(), *text);
+    }
+  }
 }
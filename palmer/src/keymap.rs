@@ -0,0 +1,373 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::error::KeymapError;
+use super::input::{Button, BUTTON_LIST};
+
+/// The names `Keymap::preset` knows, the default first.
+pub const PRESETS: [&str; 3] = ["qwerty", "hex", "numpad"];
+
+const QWERTY: [(&str, Button); 16] = [
+  ("Digit1", Button::One),
+  ("Digit2", Button::Two),
+  ("Digit3", Button::Three),
+  ("Digit4", Button::C),
+  ("KeyQ", Button::Four),
+  ("KeyW", Button::Five),
+  ("KeyE", Button::Six),
+  ("KeyR", Button::D),
+  ("KeyA", Button::Seven),
+  ("KeyS", Button::Eight),
+  ("KeyD", Button::Nine),
+  ("KeyF", Button::E),
+  ("KeyZ", Button::A),
+  ("KeyX", Button::Zero),
+  ("KeyC", Button::B),
+  ("KeyV", Button::F),
+];
+
+const HEX: [(&str, Button); 16] = [
+  ("Digit0", Button::Zero),
+  ("Digit1", Button::One),
+  ("Digit2", Button::Two),
+  ("Digit3", Button::Three),
+  ("Digit4", Button::Four),
+  ("Digit5", Button::Five),
+  ("Digit6", Button::Six),
+  ("Digit7", Button::Seven),
+  ("Digit8", Button::Eight),
+  ("Digit9", Button::Nine),
+  ("KeyA", Button::A),
+  ("KeyB", Button::B),
+  ("KeyC", Button::C),
+  ("KeyD", Button::D),
+  ("KeyE", Button::E),
+  ("KeyF", Button::F),
+];
+
+const NUMPAD: [(&str, Button); 16] = [
+  ("Numpad0", Button::Zero),
+  ("Numpad1", Button::One),
+  ("Numpad2", Button::Two),
+  ("Numpad3", Button::Three),
+  ("Numpad4", Button::Four),
+  ("Numpad5", Button::Five),
+  ("Numpad6", Button::Six),
+  ("Numpad7", Button::Seven),
+  ("Numpad8", Button::Eight),
+  ("Numpad9", Button::Nine),
+  ("NumpadDivide", Button::A),
+  ("NumpadMultiply", Button::B),
+  ("NumpadSubtract", Button::C),
+  ("NumpadAdd", Button::D),
+  ("NumpadEnter", Button::E),
+  ("NumpadDecimal", Button::F),
+];
+
+/// Which physical keys press which buttons.
+///
+/// Keys are named like the DOM's `KeyboardEvent.code`, such as `KeyQ`,
+/// `Digit1` or `ArrowUp`, after where they are on a US keyboard and not what
+/// is printed on them. Any number of keys can press the same button.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Keymap {
+  keys: BTreeMap<String, Button>,
+}
+
+impl Keymap {
+  /// A keymap with no keys bound.
+  pub fn new() -> Keymap {
+    Keymap::default()
+  }
+
+  /// The hex keypad laid over `1234`, `QWER`, `ASDF` and `ZXCV`.
+  pub fn qwerty() -> Keymap {
+    Keymap::from_pairs(&QWERTY)
+  }
+
+  /// Each button on the key with its digit or letter.
+  pub fn hex() -> Keymap {
+    Keymap::from_pairs(&HEX)
+  }
+
+  /// The digits on the numeric keypad, and `A` to `F` on the keys around
+  /// them: `/`, `*`, `-`, `+`, enter and the decimal point.
+  pub fn numpad() -> Keymap {
+    Keymap::from_pairs(&NUMPAD)
+  }
+
+  /// The preset called `name`, one of `PRESETS`.
+  pub fn preset(name: &str) -> Option<Keymap> {
+    match name {
+      "qwerty" => Some(Keymap::qwerty()),
+      "hex" => Some(Keymap::hex()),
+      "numpad" => Some(Keymap::numpad()),
+      _ => None,
+    }
+  }
+
+  fn from_pairs(pairs: &[(&str, Button)]) -> Keymap {
+    let mut keymap = Keymap::new();
+    for (key, button) in pairs.iter() {
+      keymap.bind(key, *button);
+    }
+    keymap
+  }
+
+  pub fn button(&self, key: &str) -> Option<Button> {
+    self.keys.get(key).copied()
+  }
+
+  /// The keys that press `button`, by name.
+  pub fn keys(&self, button: Button) -> impl Iterator<Item = &str> {
+    self
+      .keys
+      .iter()
+      .filter(move |(_, bound)| **bound == button)
+      .map(|(key, _)| key.as_str())
+  }
+
+  pub fn bindings(&self) -> impl Iterator<Item = (&str, Button)> {
+    self
+      .keys
+      .iter()
+      .map(|(key, button)| (key.as_str(), *button))
+  }
+
+  /// Has `key` press `button`, instead of whatever it pressed before.
+  pub fn bind(&mut self, key: &str, button: Button) {
+    self.keys.insert(key.to_string(), button);
+  }
+
+  pub fn unbind(&mut self, key: &str) {
+    self.keys.remove(key);
+  }
+
+  /// Makes `key` the only key that presses `button`.
+  pub fn remap(&mut self, button: Button, key: &str) {
+    self.keys.retain(|_, bound| *bound != button);
+    self.bind(key, button);
+  }
+}
+
+/// The keymap every ROM uses, and the ones that replace it for some ROMs,
+/// by `movie::rom_hash`.
+///
+/// As text, each line binds a key to a button as `<key> = <button>`, or
+/// unbinds it with `<key> = none`. `preset = <name>` replaces every binding
+/// with a preset's, and `preset = none` clears them. The keymap for all
+/// ROMs starts as `qwerty` and comes first; each `[rom <hash>]` section
+/// starts from it and ends with the next one. `#` starts a comment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeymapConfig {
+  pub keymap: Keymap,
+  pub roms: BTreeMap<u64, Keymap>,
+}
+
+impl Default for KeymapConfig {
+  fn default() -> KeymapConfig {
+    KeymapConfig {
+      keymap: Keymap::qwerty(),
+      roms: BTreeMap::new(),
+    }
+  }
+}
+
+impl KeymapConfig {
+  /// The keymap to play the ROM hashing to `rom_hash` with.
+  pub fn for_rom(&self, rom_hash: u64) -> &Keymap {
+    self.roms.get(&rom_hash).unwrap_or(&self.keymap)
+  }
+
+  pub fn parse(text: &str) -> Result<KeymapConfig, KeymapError> {
+    let mut config = KeymapConfig::default();
+    let mut rom = None;
+
+    for (index, line) in text.lines().enumerate() {
+      let error = |message: String| KeymapError {
+        line: index + 1,
+        message,
+      };
+      let line = line.split('#').next().unwrap().trim();
+      if line.is_empty() {
+        continue;
+      }
+
+      if line.starts_with('[') && line.ends_with(']') {
+        let section = line[1..line.len() - 1].trim();
+        let hash = section
+          .strip_prefix("rom")
+          .map(str::trim)
+          .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+          .ok_or_else(|| error(format!("`[{}]` is not a `[rom <hash>]` section", section)))?;
+        let keymap = config.keymap.clone();
+        config.roms.entry(hash).or_insert(keymap);
+        rom = Some(hash);
+        continue;
+      }
+
+      let mut parts = line.splitn(2, '=').map(str::trim);
+      let (name, value) = match (parts.next(), parts.next()) {
+        (Some(name), Some(value)) if !name.is_empty() && !name.contains(char::is_whitespace) => {
+          (name, value)
+        }
+        _ => {
+          return Err(error(format!(
+            "expected `<key> = <button>`, found `{}`",
+            line
+          )))
+        }
+      };
+      let keymap = match rom {
+        Some(hash) => config.roms.get_mut(&hash).unwrap(),
+        None => &mut config.keymap,
+      };
+
+      if name == "preset" {
+        *keymap = match value {
+          "none" => Keymap::new(),
+          value => {
+            Keymap::preset(value).ok_or_else(|| error(format!("unknown preset `{}`", value)))?
+          }
+        };
+      } else if value == "none" {
+        keymap.unbind(name);
+      } else {
+        let button = BUTTON_LIST
+          .iter()
+          .find(|button| button.to_label().eq_ignore_ascii_case(value))
+          .ok_or_else(|| error(format!("unknown button `{}`", value)))?;
+        keymap.bind(name, *button);
+      }
+    }
+
+    Ok(config)
+  }
+
+  /// The config as text that `parse` reads back the same.
+  pub fn to_text(&self) -> String {
+    let mut text = String::from("# <key> = <button>, with keys named like KeyboardEvent.code\n");
+    write_keymap(&mut text, &self.keymap);
+    for (hash, keymap) in self.roms.iter() {
+      writeln!(text, "\n[rom {:016x}]", hash).unwrap();
+      write_keymap(&mut text, keymap);
+    }
+    text
+  }
+}
+
+fn write_keymap(text: &mut String, keymap: &Keymap) {
+  let preset = PRESETS
+    .iter()
+    .find(|name| Keymap::preset(name).as_ref() == Some(keymap));
+  if let Some(name) = preset {
+    writeln!(text, "preset = {}", name).unwrap();
+    return;
+  }
+  text.push_str("preset = none\n");
+  for (key, button) in keymap.bindings() {
+    writeln!(text, "{} = {}", key, button.to_label()).unwrap();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn presets_cover_every_button() {
+    for name in PRESETS.iter() {
+      let keymap = Keymap::preset(name).unwrap();
+      for button in BUTTON_LIST.iter() {
+        assert_eq!(keymap.keys(*button).count(), 1, "{} {:?}", name, button);
+      }
+    }
+    assert_eq!(Keymap::qwerty().button("KeyX"), Some(Button::Zero));
+    assert_eq!(Keymap::qwerty().button("KeyB"), None);
+    assert_eq!(Keymap::preset("dvorak"), None);
+  }
+
+  #[test]
+  fn remaps_buttons() {
+    let mut keymap = Keymap::qwerty();
+    keymap.bind("ArrowUp", Button::Five);
+    assert_eq!(
+      keymap.keys(Button::Five).collect::<Vec<_>>(),
+      ["ArrowUp", "KeyW"]
+    );
+
+    keymap.remap(Button::Five, "Space");
+    assert_eq!(keymap.keys(Button::Five).collect::<Vec<_>>(), ["Space"]);
+    assert_eq!(keymap.button("KeyW"), None);
+
+    keymap.bind("Space", Button::Six);
+    assert_eq!(keymap.keys(Button::Five).count(), 0);
+  }
+
+  #[test]
+  fn reads_configs() {
+    let config = KeymapConfig::parse(
+      "# Arrows move everywhere
+      ArrowUp = 5
+      KeyW = none
+
+      [rom 00000000000000ff]
+      preset = hex # the menu asks for digits
+      Space = a",
+    )
+    .unwrap();
+    assert_eq!(config.keymap.button("ArrowUp"), Some(Button::Five));
+    assert_eq!(config.keymap.button("KeyW"), None);
+    assert_eq!(config.keymap.button("KeyQ"), Some(Button::Four));
+
+    let rom = config.for_rom(0xFF);
+    assert_eq!(rom.button("Digit5"), Some(Button::Five));
+    assert_eq!(rom.button("Space"), Some(Button::A));
+    assert_eq!(rom.button("ArrowUp"), None);
+    assert_eq!(config.for_rom(0xFE), &config.keymap);
+  }
+
+  #[test]
+  fn rom_sections_start_from_the_default() {
+    let config = KeymapConfig::parse("preset = numpad\n[rom 2]\nSpace = 0").unwrap();
+    let mut expected = Keymap::numpad();
+    expected.bind("Space", Button::Zero);
+    assert_eq!(config.for_rom(2), &expected);
+  }
+
+  #[test]
+  fn points_at_bad_lines() {
+    let error = |text| KeymapConfig::parse(text).unwrap_err();
+    assert_eq!(
+      error("KeyQ = 4\nKeyW = G"),
+      KeymapError {
+        line: 2,
+        message: String::from("unknown button `G`"),
+      }
+    );
+    assert_eq!(error("preset = azerty").message, "unknown preset `azerty`");
+    assert_eq!(error("\n\nKeyQ 4").line, 3);
+    assert_eq!(error("Key Q = 4").line, 1);
+    assert_eq!(error("[rom xyz]").line, 1);
+  }
+
+  #[test]
+  fn writes_configs_it_can_read() {
+    let mut config = KeymapConfig::default();
+    assert_eq!(
+      config.to_text().lines().skip(1).collect::<Vec<_>>(),
+      ["preset = qwerty"]
+    );
+
+    config.keymap.remap(Button::Five, "ArrowUp");
+    let mut rom = Keymap::hex();
+    rom.unbind("KeyF");
+    config.roms.insert(0xABCD, rom);
+    config.roms.insert(0x1234, Keymap::numpad());
+
+    let text = config.to_text();
+    assert!(text.contains("\n[rom 0000000000001234]\npreset = numpad\n"));
+    assert!(text.contains("ArrowUp = 5\n"));
+    assert_eq!(KeymapConfig::parse(&text), Ok(config));
+  }
+}
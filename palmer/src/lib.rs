@@ -1,8 +1,30 @@
+mod decode_cache;
 mod fontset;
-mod instructions;
 mod processor;
+mod synth;
 
+pub mod assembler;
 pub mod display;
+pub mod error;
+pub mod gdb;
 pub mod audio;
+pub mod clock;
+pub mod dap;
+pub mod debugger;
+pub mod disassembler;
+pub mod headless;
 pub mod input;
-pub use processor::Chip8;
+pub mod instructions;
+pub mod keymap;
+pub mod movie;
+pub mod octo;
+pub mod quirks;
+pub mod random;
+pub mod screenshot;
+pub mod source_map;
+pub mod tracer;
+pub mod wav;
+pub use error::{AssemblyError, EmulationError, KeymapError, MovieError, StateError};
+pub use processor::{Chip8, MemoryAccess, CLASSIC_MEMORY_SIZE, MEMORY_SIZE, PROGRAM_START};
+pub use quirks::Quirks;
+pub use random::{RandomSource, XorShift};
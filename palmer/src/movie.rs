@@ -0,0 +1,322 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::time::Duration;
+
+use super::audio::AudioDriver;
+use super::error::{EmulationError, MovieError};
+use super::input::Input;
+use super::processor::Chip8;
+use super::quirks::Quirks;
+use super::random::XorShift;
+
+const MOVIE_MAGIC: [u8; 4] = *b"XO8M";
+/// Older movies have fewer quirks: version 1 predates `key_wait_on_release`
+/// and version 2 `extended_memory`.
+const MOVIE_VERSION: u8 = 3;
+const HEADER_SIZE: usize = MOVIE_MAGIC.len()
+  + 1 // version
+  + 8 // ROM hash
+  + 8 // seed
+  + 1 // quirks
+  + 4 // instructions per second
+  + 4; // frame count
+/// The held keys, presses and releases, as one mask each.
+const FRAME_SIZE: usize = 3 * 2;
+
+/// A play session recorded from power-on: what it ran and the input at the
+/// start of every frame.
+///
+/// The engine only depends on its input, its seed and its settings, so
+/// playing a movie back runs exactly the same frames, down to every `CXNN`
+/// and `FX0A`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+  pub rom_hash: u64,
+  pub seed: u64,
+  pub quirks: Quirks,
+  pub instructions_per_second: u32,
+  pub frames: Vec<Input>,
+}
+
+/// A 64-bit FNV-1a hash of the ROM, to tell the ROM a movie was recorded
+/// with.
+pub fn rom_hash(rom: &[u8]) -> u64 {
+  rom.iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+    (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01B3)
+  })
+}
+
+/// Turns the engine off and on again with `rom`, `seed` and its current
+/// quirks and speed, and nothing held.
+fn power_on<T: AudioDriver>(
+  chip8: &mut Chip8<T>,
+  rom: &[u8],
+  seed: u64,
+) -> Result<(), EmulationError> {
+  chip8.reset();
+  chip8.set_rpl_flags([0; 16]);
+  chip8.set_random_source(XorShift::new(seed));
+  chip8.set_instructions_per_second(chip8.instructions_per_second());
+  chip8.input = Input::new();
+  chip8.load(rom.to_vec())
+}
+
+impl Movie {
+  /// Restarts `chip8` from power-on with `rom`, keeping its quirks and
+  /// speed, and starts recording. Run it through `record_frame` or
+  /// `record_for` from then on.
+  pub fn record<T: AudioDriver>(
+    chip8: &mut Chip8<T>,
+    rom: &[u8],
+    seed: u64,
+  ) -> Result<Movie, EmulationError> {
+    power_on(chip8, rom, seed)?;
+    Ok(Movie {
+      rom_hash: rom_hash(rom),
+      seed,
+      quirks: chip8.quirks(),
+      instructions_per_second: chip8.instructions_per_second(),
+      frames: vec![],
+    })
+  }
+
+  /// Runs one frame with the input as it is, and records it.
+  pub fn record_frame<T: AudioDriver>(
+    &mut self,
+    chip8: &mut Chip8<T>,
+  ) -> Result<(), EmulationError> {
+    self.frames.push(chip8.input);
+    chip8.run_frame()
+  }
+
+  /// Like `Chip8::run_for`, recording every frame.
+  pub fn record_for<T: AudioDriver>(
+    &mut self,
+    chip8: &mut Chip8<T>,
+    elapsed: Duration,
+  ) -> Result<(), EmulationError> {
+    for _ in 0..chip8.frames_due(elapsed) {
+      self.record_frame(chip8)?;
+    }
+    Ok(())
+  }
+
+  /// Restarts `chip8` the way the movie was recorded and replays all of
+  /// its frames. Refuses to start if `rom` is not the ROM it was recorded
+  /// with.
+  pub fn play<T: AudioDriver>(&self, chip8: &mut Chip8<T>, rom: &[u8]) -> Result<(), MovieError> {
+    self.play_with(chip8, rom, |_| {})
+  }
+
+  /// Like `play`, calling `after_frame` after every frame.
+  pub fn play_with<T: AudioDriver, F: FnMut(&mut Chip8<T>)>(
+    &self,
+    chip8: &mut Chip8<T>,
+    rom: &[u8],
+    mut after_frame: F,
+  ) -> Result<(), MovieError> {
+    let actual = rom_hash(rom);
+    if actual != self.rom_hash {
+      return Err(MovieError::RomMismatch {
+        expected: self.rom_hash,
+        actual,
+      });
+    }
+
+    chip8.set_quirks(self.quirks);
+    chip8.set_instructions_per_second(self.instructions_per_second);
+    power_on(chip8, rom, self.seed)?;
+    for input in self.frames.iter() {
+      chip8.input = *input;
+      chip8.run_frame()?;
+      after_frame(chip8);
+    }
+    Ok(())
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut movie = Vec::with_capacity(HEADER_SIZE + self.frames.len() * FRAME_SIZE);
+    movie.extend_from_slice(&MOVIE_MAGIC);
+    movie.push(MOVIE_VERSION);
+    movie.write_u64::<BigEndian>(self.rom_hash).unwrap();
+    movie.write_u64::<BigEndian>(self.seed).unwrap();
+    movie.push(self.quirks.to_bits());
+    movie
+      .write_u32::<BigEndian>(self.instructions_per_second)
+      .unwrap();
+    movie
+      .write_u32::<BigEndian>(self.frames.len() as u32)
+      .unwrap();
+    for input in self.frames.iter() {
+      for mask in input.to_bits().iter() {
+        movie.write_u16::<BigEndian>(*mask).unwrap();
+      }
+    }
+    movie
+  }
+
+  pub fn from_bytes(movie: &[u8]) -> Result<Movie, MovieError> {
+    if !movie.starts_with(&MOVIE_MAGIC) {
+      return Err(MovieError::NotAMovie);
+    }
+    let version = movie.get(MOVIE_MAGIC.len()).copied().unwrap_or(0);
+    if version != MOVIE_VERSION {
+      return Err(MovieError::UnsupportedVersion {
+        version,
+        supported: MOVIE_VERSION,
+      });
+    }
+    if movie.len() < HEADER_SIZE {
+      return Err(MovieError::InvalidSize {
+        size: movie.len(),
+        expected: HEADER_SIZE,
+      });
+    }
+
+    let mut reader = &movie[MOVIE_MAGIC.len() + 1..];
+    let rom_hash = reader.read_u64::<BigEndian>().unwrap();
+    let seed = reader.read_u64::<BigEndian>().unwrap();
+    let quirks = reader.read_u8().unwrap();
+    let instructions_per_second = reader.read_u32::<BigEndian>().unwrap();
+    let frame_count = reader.read_u32::<BigEndian>().unwrap() as usize;
+
+    let expected = HEADER_SIZE + frame_count * FRAME_SIZE;
+    if movie.len() != expected {
+      return Err(MovieError::InvalidSize {
+        size: movie.len(),
+        expected,
+      });
+    }
+    if quirks >> 7 != 0 {
+      return Err(MovieError::InvalidField { field: "quirks" });
+    }
+    if instructions_per_second == 0 {
+      return Err(MovieError::InvalidField {
+        field: "instructions per second",
+      });
+    }
+
+    let frames = (0..frame_count)
+      .map(|_| {
+        let mut masks = [0; 3];
+        for mask in masks.iter_mut() {
+          *mask = reader.read_u16::<BigEndian>().unwrap();
+        }
+        Input::from_bits(masks)
+      })
+      .collect();
+    Ok(Movie {
+      rom_hash,
+      seed,
+      quirks: Quirks::from_bits(quirks),
+      instructions_per_second,
+      frames,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audio::Silent;
+  use crate::input::Button;
+  use crate::octo;
+
+  /// Draws a random byte's digit wherever the last key pressed says, so the
+  /// screen depends on both the seed and the input.
+  fn rom() -> Vec<u8> {
+    octo::compile(
+      ": main
+        v0 := key
+        v1 := random 0xF
+        i := hex v1
+        sprite v0 v0 5
+        jump main",
+    )
+    .unwrap()
+  }
+
+  fn session(chip8: &mut Chip8<Silent>) -> Movie {
+    let mut movie = Movie::record(chip8, &rom(), 42).unwrap();
+    for frame in 0..30 {
+      match frame {
+        3 => chip8.input.key_down(Button::Five),
+        5 => chip8.input.key_up(Button::Five),
+        10 => {
+          chip8.input.key_down(Button::A);
+          chip8.input.key_up(Button::A);
+        }
+        _ => {}
+      }
+      movie.record_frame(chip8).unwrap();
+    }
+    movie
+  }
+
+  #[test]
+  fn plays_back_what_was_recorded() {
+    let mut recorder = Chip8::new(Silent, Quirks::cosmac_vip());
+    recorder.set_instructions_per_second(600);
+    let movie = session(&mut recorder);
+    assert_eq!(movie.frames.len(), 30);
+    assert_eq!(movie.quirks, Quirks::cosmac_vip());
+
+    let mut player = Chip8::new(Silent, Quirks::modern());
+    player.input.key_down(Button::One);
+    let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+    movie.play(&mut player, &rom()).unwrap();
+    assert!(player.display == recorder.display);
+    assert_eq!(player.save_state(), recorder.save_state());
+    assert_eq!(player.instructions_per_second(), 600);
+  }
+
+  #[test]
+  fn refuses_other_roms() {
+    let movie = session(&mut Chip8::new(Silent, Quirks::default()));
+    let mut other = rom();
+    other[0] ^= 1;
+    assert_eq!(
+      movie.play(&mut Chip8::new(Silent, Quirks::default()), &other),
+      Err(MovieError::RomMismatch {
+        expected: rom_hash(&rom()),
+        actual: rom_hash(&other),
+      })
+    );
+  }
+
+  #[test]
+  fn rejects_bad_files() {
+    let movie = session(&mut Chip8::new(Silent, Quirks::default())).to_bytes();
+    assert_eq!(Movie::from_bytes(b"XO8S"), Err(MovieError::NotAMovie));
+    assert_eq!(
+      Movie::from_bytes(&movie[..movie.len() - 1]),
+      Err(MovieError::InvalidSize {
+        size: movie.len() - 1,
+        expected: movie.len(),
+      })
+    );
+    let mut newer = movie.clone();
+    newer[4] = MOVIE_VERSION + 1;
+    assert_eq!(
+      Movie::from_bytes(&newer),
+      Err(MovieError::UnsupportedVersion {
+        version: MOVIE_VERSION + 1,
+        supported: MOVIE_VERSION
+      })
+    );
+    let mut older = movie.clone();
+    older[4] = 1;
+    assert_eq!(
+      Movie::from_bytes(&older),
+      Err(MovieError::UnsupportedVersion {
+        version: 1,
+        supported: MOVIE_VERSION
+      })
+    );
+  }
+
+  #[test]
+  fn hashes_roms() {
+    assert_eq!(rom_hash(b""), 0xCBF2_9CE4_8422_2325);
+    assert_eq!(rom_hash(b"a"), 0xAF63_DC4C_8601_EC8C);
+  }
+}
@@ -0,0 +1,1162 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use super::error::AssemblyError;
+use super::processor::{MEMORY_SIZE, PROGRAM_START};
+
+const MAX_MACRO_EXPANSIONS: usize = 10_000;
+
+const KEYWORDS: &[&str] = &[
+  ":",
+  ":=",
+  "|=",
+  "&=",
+  "^=",
+  "-=",
+  "=-",
+  "+=",
+  ">>=",
+  "<<=",
+  "==",
+  "!=",
+  "<",
+  ">",
+  "<=",
+  ">=",
+  "key",
+  "-key",
+  "hex",
+  "bighex",
+  "random",
+  "delay",
+  "buzzer",
+  "pitch",
+  "long",
+  "if",
+  "then",
+  "begin",
+  "else",
+  "end",
+  "jump",
+  "jump0",
+  "native",
+  "sprite",
+  "loop",
+  "while",
+  "again",
+  "return",
+  ";",
+  "clear",
+  "bcd",
+  "save",
+  "load",
+  "saveflags",
+  "loadflags",
+  "i",
+  "hires",
+  "lores",
+  "exit",
+  "audio",
+  "plane",
+  "scroll-down",
+  "scroll-left",
+  "scroll-right",
+];
+
+/// Compiles Octo source into a ROM for `Chip8::load`.
+///
+/// This covers the statements, directives and SUPER-CHIP and XO-CHIP
+/// extensions of the Octo language that this emulator can run. Like Octo,
+/// the program starts with a jump to `main`, which is left out when `: main`
+/// comes first.
+pub fn compile(source: &str) -> Result<Vec<u8>, AssemblyError> {
+  let mut compiler = Compiler::new(tokenize(source));
+  compiler.compile()?;
+  compiler.finish()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+  text: String,
+  line: usize,
+  column: usize,
+}
+
+impl Token {
+  fn error(&self, message: impl Into<String>) -> AssemblyError {
+    AssemblyError {
+      file: None,
+      line: self.line,
+      column: self.column,
+      message: message.into(),
+    }
+  }
+}
+
+fn tokenize(source: &str) -> Vec<Token> {
+  let mut tokens = vec![];
+
+  for (index, line) in source.lines().enumerate() {
+    let mut start = None;
+    for (column, character) in line.chars().chain(Some(' ')).enumerate() {
+      match (start, character.is_whitespace()) {
+        (None, false) if character == '#' => break,
+        (None, false) => start = Some(column),
+        (Some(first), true) => {
+          tokens.push(Token {
+            text: line.chars().skip(first).take(column - first).collect(),
+            line: index + 1,
+            column: first + 1,
+          });
+          start = None;
+        }
+        _ => {}
+      }
+    }
+  }
+
+  tokens
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+  let (negative, digits) = match text.strip_prefix('-') {
+    Some(digits) => (true, digits),
+    None => (false, text),
+  };
+  let value = if let Some(hex) = digits.strip_prefix("0x") {
+    i64::from_str_radix(hex, 16).ok()?
+  } else if let Some(binary) = digits.strip_prefix("0b") {
+    i64::from_str_radix(binary, 2).ok()?
+  } else if digits.chars().all(|character| character.is_ascii_digit()) {
+    digits.parse().ok()?
+  } else {
+    return None;
+  };
+  Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+  let mut characters = text.chars();
+  match (characters.next(), characters.next(), characters.next()) {
+    (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => {
+      digit.to_digit(16).map(|register| register as u8)
+    }
+    _ => None,
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+  Equal,
+  NotEqual,
+  Less,
+  Greater,
+  LessOrEqual,
+  GreaterOrEqual,
+  Key,
+  NotKey,
+}
+
+impl Comparison {
+  fn negate(self) -> Comparison {
+    match self {
+      Comparison::Equal => Comparison::NotEqual,
+      Comparison::NotEqual => Comparison::Equal,
+      Comparison::Less => Comparison::GreaterOrEqual,
+      Comparison::GreaterOrEqual => Comparison::Less,
+      Comparison::Greater => Comparison::LessOrEqual,
+      Comparison::LessOrEqual => Comparison::Greater,
+      Comparison::Key => Comparison::NotKey,
+      Comparison::NotKey => Comparison::Key,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+  Register(u8),
+  Byte(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Condition {
+  register: u8,
+  comparison: Comparison,
+  operand: Operand,
+}
+
+impl Condition {
+  fn negate(self) -> Condition {
+    Condition {
+      comparison: self.comparison.negate(),
+      ..self
+    }
+  }
+}
+
+/// How a label address is written into the ROM once it is known.
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+  /// The low 12 bits of the instruction at the address.
+  Address,
+  /// The whole word at the address, for `i := long` and `:pointer`.
+  Long,
+  /// The low nibble of the byte at the address, for `:unpack`.
+  UnpackHigh,
+  /// The byte at the address, for `:unpack`.
+  UnpackLow,
+}
+
+enum Block {
+  If {
+    token: Token,
+    jump: usize,
+    has_else: bool,
+  },
+  Loop {
+    token: Token,
+    start: usize,
+    breaks: Vec<usize>,
+  },
+}
+
+struct Macro {
+  arguments: Vec<String>,
+  body: Vec<Token>,
+}
+
+struct Compiler {
+  tokens: Vec<Token>,
+  position: usize,
+  rom: Vec<u8>,
+  here: usize,
+  labels: HashMap<String, usize>,
+  constants: HashMap<String, f64>,
+  aliases: HashMap<String, u8>,
+  macros: HashMap<String, Macro>,
+  fixups: Vec<(usize, Fixup, Token)>,
+  blocks: Vec<Block>,
+  main_jump: Option<usize>,
+  expansions: usize,
+}
+
+impl Compiler {
+  fn new(tokens: Vec<Token>) -> Compiler {
+    let starts_with_main = tokens.len() >= 2 && tokens[0].text == ":" && tokens[1].text == "main";
+    let mut compiler = Compiler {
+      tokens,
+      position: 0,
+      rom: vec![],
+      here: PROGRAM_START,
+      labels: HashMap::new(),
+      constants: HashMap::new(),
+      aliases: HashMap::new(),
+      macros: HashMap::new(),
+      fixups: vec![],
+      blocks: vec![],
+      main_jump: None,
+      expansions: 0,
+    };
+    if !starts_with_main {
+      compiler.main_jump = Some(compiler.here);
+      compiler.here += 2;
+    }
+    compiler
+  }
+
+  fn next(&mut self) -> Result<Token, AssemblyError> {
+    match self.tokens.get(self.position) {
+      Some(token) => {
+        self.position += 1;
+        Ok(token.clone())
+      }
+      None => Err(self.end_error("unexpected end of file")),
+    }
+  }
+
+  fn peek(&self) -> Option<&str> {
+    self
+      .tokens
+      .get(self.position)
+      .map(|token| token.text.as_str())
+  }
+
+  fn end_error(&self, message: &str) -> AssemblyError {
+    match self.tokens.last() {
+      Some(token) => AssemblyError {
+        file: None,
+        line: token.line,
+        column: token.column + token.text.chars().count(),
+        message: message.to_string(),
+      },
+      None => AssemblyError {
+        file: None,
+        line: 1,
+        column: 1,
+        message: message.to_string(),
+      },
+    }
+  }
+
+  fn expect(&mut self, text: &str) -> Result<Token, AssemblyError> {
+    let token = self.next()?;
+    if token.text == text {
+      Ok(token)
+    } else {
+      Err(token.error(format!("expected `{}`, found `{}`", text, token.text)))
+    }
+  }
+
+  fn emit_byte(&mut self, token: &Token, byte: u8) -> Result<(), AssemblyError> {
+    if self.here >= MEMORY_SIZE {
+      return Err(token.error("the program does not fit in memory"));
+    }
+    let offset = self.here - PROGRAM_START;
+    if self.rom.len() <= offset {
+      self.rom.resize(offset + 1, 0);
+    }
+    self.rom[offset] = byte;
+    self.here += 1;
+    Ok(())
+  }
+
+  fn emit(&mut self, token: &Token, op_code: u16) -> Result<(), AssemblyError> {
+    self.emit_byte(token, (op_code >> 8) as u8)?;
+    self.emit_byte(token, op_code as u8)
+  }
+
+  fn write(&mut self, address: usize, op_code: u16) {
+    let offset = address - PROGRAM_START;
+    self.rom[offset] = (op_code >> 8) as u8;
+    self.rom[offset + 1] = op_code as u8;
+  }
+
+  fn apply_fixup(
+    &mut self,
+    address: usize,
+    fixup: Fixup,
+    target: usize,
+    token: &Token,
+  ) -> Result<(), AssemblyError> {
+    let offset = address - PROGRAM_START;
+    match fixup {
+      Fixup::Address => {
+        if target > 0xFFF {
+          return Err(token.error(format!("{:#X} is out of reach, use `i := long`", target)));
+        }
+        let op_code = (self.rom[offset] as u16) << 8 & 0xF000;
+        self.write(address, op_code | target as u16);
+      }
+      Fixup::Long => self.write(address, target as u16),
+      Fixup::UnpackHigh => self.rom[offset] |= (target >> 8) as u8 & 0xF,
+      Fixup::UnpackLow => self.rom[offset] = target as u8,
+    }
+    Ok(())
+  }
+
+  fn is_defined(&self, name: &str) -> bool {
+    self.labels.contains_key(name)
+      || self.constants.contains_key(name)
+      || self.aliases.contains_key(name)
+      || self.macros.contains_key(name)
+  }
+
+  fn check_name(&self, token: &Token) -> Result<(), AssemblyError> {
+    let name = &token.text;
+    if KEYWORDS.contains(&name.as_str())
+      || name.starts_with(':')
+      || parse_register(name).is_some()
+      || parse_number(name).is_some()
+    {
+      Err(token.error(format!("`{}` is a reserved name", name)))
+    } else if self.is_defined(name) {
+      Err(token.error(format!("`{}` is already defined", name)))
+    } else {
+      Ok(())
+    }
+  }
+
+  fn define_label(&mut self, token: &Token, address: usize) -> Result<(), AssemblyError> {
+    self.check_name(token)?;
+    self.labels.insert(token.text.clone(), address);
+    Ok(())
+  }
+
+  fn register(&mut self) -> Result<u8, AssemblyError> {
+    let token = self.next()?;
+    self.register_of(&token)
+  }
+
+  fn register_of(&self, token: &Token) -> Result<u8, AssemblyError> {
+    self
+      .aliases
+      .get(&token.text)
+      .copied()
+      .or_else(|| parse_register(&token.text))
+      .ok_or_else(|| token.error(format!("expected a register, found `{}`", token.text)))
+  }
+
+  fn is_register(&self, text: &str) -> bool {
+    self.aliases.contains_key(text) || parse_register(text).is_some()
+  }
+
+  /// A number, constant or label that is already known.
+  fn known_value(&self, token: &Token) -> Option<f64> {
+    parse_number(&token.text)
+      .map(|value| value as f64)
+      .or_else(|| self.constants.get(&token.text).copied())
+      .or_else(|| self.labels.get(&token.text).map(|address| *address as f64))
+  }
+
+  fn integer(&self, token: &Token, min: i64, max: i64) -> Result<i64, AssemblyError> {
+    let value = self
+      .known_value(token)
+      .ok_or_else(|| token.error(format!("`{}` is not defined", token.text)))?;
+    let value = value.floor() as i64;
+    if value < min || value > max {
+      return Err(token.error(format!("{} is out of range", value)));
+    }
+    Ok(value)
+  }
+
+  fn byte(&mut self) -> Result<u8, AssemblyError> {
+    let token = self.next()?;
+    Ok(self.integer(&token, -128, 255)? as u8)
+  }
+
+  fn nibble(&mut self) -> Result<u16, AssemblyError> {
+    let token = self.next()?;
+    Ok(self.integer(&token, 0, 15)? as u16)
+  }
+
+  /// Emits `op_code` with a 12-bit address, fixed up later if the label is
+  /// not defined yet.
+  fn emit_with_address(&mut self, op_code: u16) -> Result<(), AssemblyError> {
+    let token = self.next()?;
+    let address = self.here;
+    self.emit(&token, op_code)?;
+    self.reference(address, Fixup::Address, token)
+  }
+
+  fn reference(&mut self, address: usize, fixup: Fixup, token: Token) -> Result<(), AssemblyError> {
+    if parse_number(&token.text).is_none() && !self.is_defined(&token.text) {
+      self.fixups.push((address, fixup, token));
+      return Ok(());
+    }
+    let target = self.integer(&token, 0, 0xFFFF)? as usize;
+    self.apply_fixup(address, fixup, target, &token)
+  }
+
+  fn condition(&mut self) -> Result<Condition, AssemblyError> {
+    let register = self.register()?;
+    let token = self.next()?;
+    let comparison = match token.text.as_str() {
+      "==" => Comparison::Equal,
+      "!=" => Comparison::NotEqual,
+      "<" => Comparison::Less,
+      ">" => Comparison::Greater,
+      "<=" => Comparison::LessOrEqual,
+      ">=" => Comparison::GreaterOrEqual,
+      "key" => Comparison::Key,
+      "-key" => Comparison::NotKey,
+      text => return Err(token.error(format!("expected a comparison, found `{}`", text))),
+    };
+    let operand = match comparison {
+      Comparison::Key | Comparison::NotKey => Operand::Byte(0),
+      _ => match self.peek() {
+        Some(text) if self.is_register(text) => Operand::Register(self.register()?),
+        _ => Operand::Byte(self.byte()?),
+      },
+    };
+    Ok(Condition {
+      register,
+      comparison,
+      operand,
+    })
+  }
+
+  /// Emits code that skips the next instruction unless `condition` holds.
+  /// Ordering comparisons go through VF, like they do in Octo.
+  fn skip_unless(&mut self, token: &Token, condition: Condition) -> Result<(), AssemblyError> {
+    let x = (condition.register as u16) << 8;
+    let load_vf = |operand| match operand {
+      Operand::Register(y) => 0x8F00 | (y as u16) << 4,
+      Operand::Byte(value) => 0x6F00 | value as u16,
+    };
+
+    let op_codes = match (condition.comparison, condition.operand) {
+      (Comparison::Equal, Operand::Byte(value)) => vec![0x4000 | x | value as u16],
+      (Comparison::Equal, Operand::Register(y)) => vec![0x9000 | x | (y as u16) << 4],
+      (Comparison::NotEqual, Operand::Byte(value)) => vec![0x3000 | x | value as u16],
+      (Comparison::NotEqual, Operand::Register(y)) => vec![0x5000 | x | (y as u16) << 4],
+      (Comparison::Key, _) => vec![0xE0A1 | x],
+      (Comparison::NotKey, _) => vec![0xE09E | x],
+      // vf := operand, vf =- vx leaves VF at 1 when vx >= operand.
+      (Comparison::GreaterOrEqual, operand) => vec![load_vf(operand), 0x8F07 | x >> 4, 0x4F01],
+      (Comparison::Less, operand) => vec![load_vf(operand), 0x8F07 | x >> 4, 0x4F00],
+      // vf := operand, vf -= vx leaves VF at 1 when operand >= vx.
+      (Comparison::LessOrEqual, operand) => vec![load_vf(operand), 0x8F05 | x >> 4, 0x4F01],
+      (Comparison::Greater, operand) => vec![load_vf(operand), 0x8F05 | x >> 4, 0x4F00],
+    };
+
+    for op_code in op_codes {
+      self.emit(token, op_code)?;
+    }
+    Ok(())
+  }
+
+  fn compile(&mut self) -> Result<(), AssemblyError> {
+    while self.position < self.tokens.len() {
+      self.statement()?;
+    }
+
+    if let Some(block) = self.blocks.pop() {
+      let (token, opener) = match block {
+        Block::If { token, .. } => (token, "if"),
+        Block::Loop { token, .. } => (token, "loop"),
+      };
+      return Err(token.error(format!("`{}` is never closed", opener)));
+    }
+    Ok(())
+  }
+
+  fn finish(mut self) -> Result<Vec<u8>, AssemblyError> {
+    for (address, fixup, token) in std::mem::take(&mut self.fixups) {
+      let target = match self.labels.get(&token.text) {
+        Some(target) => *target,
+        None => return Err(token.error(format!("`{}` is not defined", token.text))),
+      };
+      self.apply_fixup(address, fixup, target, &token)?;
+    }
+
+    if let Some(address) = self.main_jump {
+      let main = match self.labels.get("main") {
+        Some(main) => *main,
+        None => return Err(self.end_error("the program has no `main`")),
+      };
+      if self.rom.len() < 2 {
+        self.rom.resize(2, 0);
+      }
+      if main > 0xFFF {
+        return Err(self.end_error("`main` is out of reach of the first jump"));
+      }
+      self.write(address, 0x1000 | main as u16);
+    }
+
+    Ok(self.rom)
+  }
+
+  fn statement(&mut self) -> Result<(), AssemblyError> {
+    let token = self.next()?;
+
+    if self.is_register(&token.text) {
+      return self.register_statement(&token);
+    }
+
+    match token.text.as_str() {
+      ":" => {
+        let name = self.next()?;
+        self.define_label(&name, self.here)
+      }
+      ":next" => {
+        let name = self.next()?;
+        self.define_label(&name, self.here + 1)
+      }
+      ":alias" => {
+        let name = self.next()?;
+        self.check_name(&name)?;
+        let register = self.register()?;
+        self.aliases.insert(name.text, register);
+        Ok(())
+      }
+      ":const" => {
+        let name = self.next()?;
+        self.check_name(&name)?;
+        let value_token = self.next()?;
+        let value = self
+          .known_value(&value_token)
+          .ok_or_else(|| value_token.error(format!("`{}` is not defined", value_token.text)))?;
+        self.constants.insert(name.text, value);
+        Ok(())
+      }
+      ":calc" => {
+        let name = self.next()?;
+        self.check_name(&name)?;
+        let value = self.calc_block()?;
+        self.constants.insert(name.text, value);
+        Ok(())
+      }
+      ":byte" => {
+        let value = if self.peek() == Some("{") {
+          self.calc_block()?.floor() as i64
+        } else {
+          let value_token = self.next()?;
+          self.integer(&value_token, -128, 255)?
+        };
+        if !(-128..=255).contains(&value) {
+          return Err(token.error(format!("{} does not fit in a byte", value)));
+        }
+        self.emit_byte(&token, value as u8)
+      }
+      ":pointer" => {
+        let target = self.next()?;
+        let address = self.here;
+        self.emit(&token, 0)?;
+        self.reference(address, Fixup::Long, target)
+      }
+      ":org" => {
+        let value_token = self.next()?;
+        self.here =
+          self.integer(&value_token, PROGRAM_START as i64, MEMORY_SIZE as i64 - 1)? as usize;
+        Ok(())
+      }
+      ":call" => self.emit_with_address(0x2000),
+      ":unpack" => {
+        let nibble = self.nibble()?;
+        let target = self.next()?;
+        self.emit(&token, 0x6000 | nibble << 4)?;
+        self.reference(self.here - 1, Fixup::UnpackHigh, target.clone())?;
+        self.emit(&token, 0x6100)?;
+        self.reference(self.here - 1, Fixup::UnpackLow, target)
+      }
+      ":macro" => self.define_macro(),
+      ":breakpoint" => self.next().map(|_| ()),
+      ":monitor" => {
+        self.next()?;
+        self.next().map(|_| ())
+      }
+      "clear" => self.emit(&token, 0x00E0),
+      "return" | ";" => self.emit(&token, 0x00EE),
+      "hires" => self.emit(&token, 0x00FF),
+      "lores" => self.emit(&token, 0x00FE),
+      "exit" => self.emit(&token, 0x00FD),
+      "scroll-down" => {
+        let rows = self.nibble()?;
+        self.emit(&token, 0x00C0 | rows)
+      }
+      "scroll-left" => self.emit(&token, 0x00FC),
+      "scroll-right" => self.emit(&token, 0x00FB),
+      "audio" => self.emit(&token, 0xF002),
+      "plane" => {
+        let planes = self.nibble()?;
+        self.emit(&token, 0xF001 | planes << 8)
+      }
+      "native" => self.emit_with_address(0x0000),
+      "jump" => self.emit_with_address(0x1000),
+      "jump0" => self.emit_with_address(0xB000),
+      "sprite" => {
+        let x = self.register()? as u16;
+        let y = self.register()? as u16;
+        let height = self.nibble()?;
+        self.emit(&token, 0xD000 | x << 8 | y << 4 | height)
+      }
+      "bcd" | "save" | "load" | "saveflags" | "loadflags" => self.register_command(&token),
+      "delay" | "buzzer" | "pitch" => {
+        self.expect(":=")?;
+        let x = (self.register()? as u16) << 8;
+        let op_code = match token.text.as_str() {
+          "delay" => 0xF015,
+          "buzzer" => 0xF018,
+          _ => 0xF03A,
+        };
+        self.emit(&token, op_code | x)
+      }
+      "i" => self.index_statement(&token),
+      "if" => {
+        let condition = self.condition()?;
+        let keyword = self.next()?;
+        match keyword.text.as_str() {
+          "then" => self.skip_unless(&token, condition),
+          "begin" => {
+            self.skip_unless(&token, condition.negate())?;
+            let jump = self.here;
+            self.emit(&token, 0x1000)?;
+            self.blocks.push(Block::If {
+              token,
+              jump,
+              has_else: false,
+            });
+            Ok(())
+          }
+          text => Err(keyword.error(format!("expected `then` or `begin`, found `{}`", text))),
+        }
+      }
+      "else" => match self.blocks.pop() {
+        Some(Block::If {
+          token: opener,
+          jump,
+          has_else: false,
+        }) => {
+          let end_jump = self.here;
+          self.emit(&token, 0x1000)?;
+          self.apply_fixup(jump, Fixup::Address, self.here, &token)?;
+          self.blocks.push(Block::If {
+            token: opener,
+            jump: end_jump,
+            has_else: true,
+          });
+          Ok(())
+        }
+        _ => Err(token.error("`else` without `if ... begin`")),
+      },
+      "end" => match self.blocks.pop() {
+        Some(Block::If { jump, .. }) => self.apply_fixup(jump, Fixup::Address, self.here, &token),
+        _ => Err(token.error("`end` without `if ... begin`")),
+      },
+      "loop" => {
+        self.blocks.push(Block::Loop {
+          token,
+          start: self.here,
+          breaks: vec![],
+        });
+        Ok(())
+      }
+      "while" => {
+        let condition = self.condition()?;
+        self.skip_unless(&token, condition.negate())?;
+        let jump = self.here;
+        self.emit(&token, 0x1000)?;
+        match self
+          .blocks
+          .iter_mut()
+          .rev()
+          .find(|block| matches!(block, Block::Loop { .. }))
+        {
+          Some(Block::Loop { breaks, .. }) => {
+            breaks.push(jump);
+            Ok(())
+          }
+          _ => Err(token.error("`while` outside of `loop`")),
+        }
+      }
+      "again" => match self.blocks.pop() {
+        Some(Block::Loop { start, breaks, .. }) => {
+          self.emit(&token, 0x1000)?;
+          self.apply_fixup(self.here - 2, Fixup::Address, start, &token)?;
+          for jump in breaks {
+            self.apply_fixup(jump, Fixup::Address, self.here, &token)?;
+          }
+          Ok(())
+        }
+        _ => Err(token.error("`again` without `loop`")),
+      },
+      text if self.macros.contains_key(text) => self.expand_macro(&token),
+      text if self.known_value(&token).is_some() && !self.labels.contains_key(text) => {
+        let value = self.integer(&token, -128, 255)?;
+        self.emit_byte(&token, value as u8)
+      }
+      text if text.starts_with(':') || KEYWORDS.contains(&text) => {
+        Err(token.error(format!("unexpected `{}`", text)))
+      }
+      _ => {
+        // Anything else names a subroutine to call.
+        self.position -= 1;
+        self.emit_with_address(0x2000)
+      }
+    }
+  }
+
+  fn register_command(&mut self, token: &Token) -> Result<(), AssemblyError> {
+    let x = self.register()? as u16;
+    if (token.text == "save" || token.text == "load") && self.peek() == Some("-") {
+      self.next()?;
+      let y = self.register()? as u16;
+      let op_code = if token.text == "save" { 0x5002 } else { 0x5003 };
+      return self.emit(token, op_code | x << 8 | y << 4);
+    }
+    let op_code = match token.text.as_str() {
+      "bcd" => 0xF033,
+      "save" => 0xF055,
+      "load" => 0xF065,
+      "saveflags" => 0xF075,
+      _ => 0xF085,
+    };
+    self.emit(token, op_code | x << 8)
+  }
+
+  fn index_statement(&mut self, token: &Token) -> Result<(), AssemblyError> {
+    let operator = self.next()?;
+    match operator.text.as_str() {
+      "+=" => {
+        let x = self.register()? as u16;
+        self.emit(token, 0xF01E | x << 8)
+      }
+      ":=" => match self.peek() {
+        Some("hex") | Some("bighex") => {
+          let kind = self.next()?;
+          let x = self.register()? as u16;
+          let op_code = if kind.text == "hex" { 0xF029 } else { 0xF030 };
+          self.emit(token, op_code | x << 8)
+        }
+        Some("long") => {
+          self.next()?;
+          let target = self.next()?;
+          self.emit(token, 0xF000)?;
+          let address = self.here;
+          self.emit(token, 0)?;
+          self.reference(address, Fixup::Long, target)
+        }
+        _ => self.emit_with_address(0xA000),
+      },
+      text => Err(operator.error(format!("expected `:=` or `+=`, found `{}`", text))),
+    }
+  }
+
+  fn register_statement(&mut self, token: &Token) -> Result<(), AssemblyError> {
+    let x = (self.register_of(token)? as u16) << 8;
+    let operator = self.next()?;
+    let source_is_register = matches!(self.peek(), Some(text) if self.is_register(text));
+
+    let op_code = match (operator.text.as_str(), source_is_register) {
+      (":=", true) => 0x8000 | x | (self.register()? as u16) << 4,
+      (":=", false) => match self.peek() {
+        Some("key") => {
+          self.next()?;
+          0xF00A | x
+        }
+        Some("delay") => {
+          self.next()?;
+          0xF007 | x
+        }
+        Some("random") => {
+          self.next()?;
+          0xC000 | x | self.byte()? as u16
+        }
+        _ => 0x6000 | x | self.byte()? as u16,
+      },
+      ("+=", false) => 0x7000 | x | self.byte()? as u16,
+      ("-=", false) => 0x7000 | x | self.byte()?.wrapping_neg() as u16,
+      (operator_text, true) => {
+        let y = (self.register()? as u16) << 4;
+        let operation = match operator_text {
+          "|=" => 0x1,
+          "&=" => 0x2,
+          "^=" => 0x3,
+          "+=" => 0x4,
+          "-=" => 0x5,
+          ">>=" => 0x6,
+          "=-" => 0x7,
+          "<<=" => 0xE,
+          text => return Err(operator.error(format!("unknown operator `{}`", text))),
+        };
+        0x8000 | x | y | operation
+      }
+      (text, false) => {
+        return Err(operator.error(format!("`{}` needs a register on the right", text)));
+      }
+    };
+
+    self.emit(token, op_code)
+  }
+
+  fn define_macro(&mut self) -> Result<(), AssemblyError> {
+    let name = self.next()?;
+    self.check_name(&name)?;
+    let mut arguments = vec![];
+    loop {
+      let token = self.next()?;
+      if token.text == "{" {
+        break;
+      }
+      arguments.push(token.text);
+    }
+
+    let mut body = vec![];
+    let mut depth = 1;
+    loop {
+      let token = self.next()?;
+      match token.text.as_str() {
+        "{" => depth += 1,
+        "}" => depth -= 1,
+        _ => {}
+      }
+      if depth == 0 {
+        break;
+      }
+      body.push(token);
+    }
+
+    self.macros.insert(name.text, Macro { arguments, body });
+    Ok(())
+  }
+
+  fn expand_macro(&mut self, token: &Token) -> Result<(), AssemblyError> {
+    self.expansions += 1;
+    if self.expansions > MAX_MACRO_EXPANSIONS {
+      return Err(token.error("too many macro expansions, is a macro expanding itself?"));
+    }
+
+    let argument_count = self.macros[&token.text].arguments.len();
+    let mut values = vec![];
+    for _ in 0..argument_count {
+      values.push(self.next()?);
+    }
+
+    let definition = &self.macros[&token.text];
+    let expansion: Vec<Token> = definition
+      .body
+      .iter()
+      .map(|body_token| {
+        match definition
+          .arguments
+          .iter()
+          .position(|argument| *argument == body_token.text)
+        {
+          Some(index) => Token {
+            text: values[index].text.clone(),
+            ..body_token.clone()
+          },
+          None => body_token.clone(),
+        }
+      })
+      .collect();
+
+    self.tokens.splice(self.position..self.position, expansion);
+    Ok(())
+  }
+
+  /// Evaluates `{ expression }`. Like Octo, operators have no precedence
+  /// and are evaluated right to left.
+  fn calc_block(&mut self) -> Result<f64, AssemblyError> {
+    self.expect("{")?;
+    let value = self.calc_expression()?;
+    self.expect("}")?;
+    Ok(value)
+  }
+
+  fn calc_expression(&mut self) -> Result<f64, AssemblyError> {
+    let left = self.calc_term()?;
+    match self.peek() {
+      Some("}") | Some(")") | None => return Ok(left),
+      _ => {}
+    }
+
+    let operator = self.next()?;
+    let right = self.calc_expression()?;
+    let boolean = |condition: bool| if condition { 1. } else { 0. };
+    let shift = |shift: fn(i64, u32) -> Option<i64>| {
+      u32::try_from(right as i64)
+        .ok()
+        .and_then(|amount| shift(left as i64, amount))
+        .map(|value| value as f64)
+        .ok_or_else(|| operator.error(format!("cannot shift by {}", right)))
+    };
+    Ok(match operator.text.as_str() {
+      "+" => left + right,
+      "-" => left - right,
+      "*" => left * right,
+      "/" => left / right,
+      "%" => left % right,
+      "&" => (left as i64 & right as i64) as f64,
+      "|" => (left as i64 | right as i64) as f64,
+      "^" => (left as i64 ^ right as i64) as f64,
+      "<<" => shift(i64::checked_shl)?,
+      ">>" => shift(i64::checked_shr)?,
+      "pow" => left.powf(right),
+      "min" => left.min(right),
+      "max" => left.max(right),
+      "<" => boolean(left < right),
+      ">" => boolean(left > right),
+      "<=" => boolean(left <= right),
+      ">=" => boolean(left >= right),
+      "==" => boolean(left == right),
+      "!=" => boolean(left != right),
+      text => return Err(operator.error(format!("unknown operator `{}`", text))),
+    })
+  }
+
+  fn calc_term(&mut self) -> Result<f64, AssemblyError> {
+    let token = self.next()?;
+    let unary = |function: fn(f64) -> f64, compiler: &mut Compiler| -> Result<f64, AssemblyError> {
+      Ok(function(compiler.calc_term()?))
+    };
+
+    match token.text.as_str() {
+      "(" => {
+        let value = self.calc_expression()?;
+        self.expect(")")?;
+        Ok(value)
+      }
+      "-" => unary(|value| -value, self),
+      "~" => unary(|value| !(value as i64) as f64, self),
+      "!" => unary(|value| if value == 0. { 1. } else { 0. }, self),
+      "sin" => unary(f64::sin, self),
+      "cos" => unary(f64::cos, self),
+      "tan" => unary(f64::tan, self),
+      "exp" => unary(f64::exp, self),
+      "log" => unary(f64::ln, self),
+      "abs" => unary(f64::abs, self),
+      "sqrt" => unary(f64::sqrt, self),
+      "sign" => unary(f64::signum, self),
+      "ceil" => unary(f64::ceil, self),
+      "floor" => unary(f64::floor, self),
+      "HERE" => Ok(self.here as f64),
+      "PI" => Ok(std::f64::consts::PI),
+      "E" => Ok(std::f64::consts::E),
+      _ => self
+        .known_value(&token)
+        .ok_or_else(|| token.error(format!("`{}` is not defined", token.text))),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn error(source: &str) -> (usize, usize, String) {
+    let error = compile(source).unwrap_err();
+    (error.line, error.column, error.message)
+  }
+
+  #[test]
+  fn jumps_to_main() {
+    assert_eq!(compile(": main clear").unwrap(), vec![0x00, 0xE0]);
+    assert_eq!(
+      compile(": sub return : main sub").unwrap(),
+      vec![0x12, 0x04, 0x00, 0xEE, 0x22, 0x02]
+    );
+    assert_eq!(
+      error("clear"),
+      (1, 6, "the program has no `main`".to_string())
+    );
+  }
+
+  #[test]
+  fn compiles_statements() {
+    let source = "
+: main
+  v0 := 5       v1 := v0      v2 := key     v3 := delay
+  v4 := random 0x0F
+  v0 += 1       v0 -= 1       v0 += v1      v0 -= v1      v0 =- v1
+  v0 |= v1      v0 &= v1      v0 ^= v1      v0 >>= v1     v0 <<= v1
+  i := 0x300    i := hex v1   i := bighex v1  i += v2
+  delay := v0   buzzer := v1  pitch := v2
+  sprite v0 v1 15
+  bcd v3  save v3  load v3  save v1 - v2  load v2 - v1
+  saveflags v7  loadflags v7
+  hires lores scroll-down 4 scroll-left scroll-right plane 3 audio exit
+";
+    let expected: Vec<u16> = vec![
+      0x6005, 0x8100, 0xF20A, 0xF307, 0xC40F, 0x7001, 0x70FF, 0x8014, 0x8015, 0x8017, 0x8011,
+      0x8012, 0x8013, 0x8016, 0x801E, 0xA300, 0xF129, 0xF130, 0xF21E, 0xF015, 0xF118, 0xF23A,
+      0xD01F, 0xF333, 0xF355, 0xF365, 0x5122, 0x5213, 0xF775, 0xF785, 0x00FF, 0x00FE, 0x00C4,
+      0x00FC, 0x00FB, 0xF301, 0xF002, 0x00FD,
+    ];
+    let rom: Vec<u16> = compile(source)
+      .unwrap()
+      .chunks(2)
+      .map(|word| (word[0] as u16) << 8 | word[1] as u16)
+      .collect();
+    assert_eq!(rom, expected);
+  }
+
+  #[test]
+  fn compiles_control_flow() {
+    let source = "
+: main
+  if v0 == 1 then v1 := 2
+  if v0 key begin v1 := 3 else v1 := 4 end
+  loop
+    while v0 != v1
+    v0 += 1
+  again
+";
+    assert_eq!(
+      compile(source).unwrap(),
+      vec![
+        0x40, 0x01, 0x61, 0x02, // if v0 == 1 then
+        0xE0, 0x9E, 0x12, 0x0C, 0x61, 0x03, 0x12, 0x0E, 0x61, 0x04, // if/else/end
+        0x90, 0x10, 0x12, 0x16, 0x70, 0x01, 0x12, 0x0E, // loop/while/again
+      ]
+    );
+  }
+
+  #[test]
+  fn compiles_comparisons_through_vf() {
+    assert_eq!(
+      compile(": main if v0 < 5 then clear").unwrap(),
+      vec![0x6F, 0x05, 0x8F, 0x07, 0x4F, 0x00, 0x00, 0xE0]
+    );
+    assert_eq!(
+      compile(": main if v0 > v1 then clear").unwrap(),
+      vec![0x8F, 0x10, 0x8F, 0x05, 0x4F, 0x00, 0x00, 0xE0]
+    );
+  }
+
+  #[test]
+  fn compiles_directives() {
+    let source = "
+:alias counter v3
+:const SIZE 4
+:calc DOUBLE { SIZE * 2 + 1 }
+:macro twice register { register += 1 register += 1 }
+: main
+  counter := DOUBLE
+  twice counter
+  i := tile
+  i := long far
+  :unpack 0xA tile
+  jump main
+: tile
+  SIZE 0xFF :byte { 1 << 4 } :pointer tile
+:org 0x1000
+: far
+";
+    assert_eq!(
+      compile(source).unwrap(),
+      vec![
+        0x12, 0x02, // jump main
+        0x63, 0x0C, 0x73, 0x01, 0x73, 0x01, // counter := 4 * (2 + 1), twice counter
+        0xA2, 0x14, 0xF0, 0x00, 0x10, 0x00, // i := tile, i := long far
+        0x60, 0xA2, 0x61, 0x14, 0x12, 0x02, // :unpack 0xA tile, jump main
+        0x04, 0xFF, 0x10, 0x02, 0x14, // tile
+      ]
+    );
+  }
+
+  #[test]
+  fn rejects_shifts_out_of_range() {
+    assert_eq!(
+      error(":calc x { 1 << 70 }"),
+      (1, 13, "cannot shift by 70".to_string())
+    );
+    assert_eq!(
+      error(":calc x { 1 >> -1 }"),
+      (1, 13, "cannot shift by -1".to_string())
+    );
+  }
+
+  #[test]
+  fn next_labels_the_operand_byte() {
+    assert_eq!(
+      compile(": main :next target v0 := 0 i := target").unwrap(),
+      vec![0x60, 0x00, 0xA2, 0x01]
+    );
+  }
+
+  #[test]
+  fn reports_positions() {
+    assert_eq!(
+      error(": main\n  jump nowhere"),
+      (2, 8, "`nowhere` is not defined".to_string())
+    );
+    assert_eq!(
+      error(": main\n  v0 := 256"),
+      (2, 9, "256 is out of range".to_string())
+    );
+    assert_eq!(
+      error(": main\n  loop\n  v0 += 1"),
+      (2, 3, "`loop` is never closed".to_string())
+    );
+    assert_eq!(
+      error(": main\n  v0 +="),
+      (2, 8, "unexpected end of file".to_string())
+    );
+    assert_eq!(
+      error(": main : main"),
+      (1, 10, "`main` is already defined".to_string())
+    );
+    assert_eq!(error(": v1"), (1, 3, "`v1` is a reserved name".to_string()));
+  }
+}
@@ -1,30 +1,90 @@
-use byteorder::{BigEndian, ReadBytesExt};
-
-use super::audio::AudioDriver;
-use super::display::Display;
-use super::fontset::FONTSET;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use std::ops::Range;
+use std::time::Duration;
+
+use super::audio::{pattern_sample_rate, AudioDriver, Pattern, Tone, DEFAULT_PITCH, PATTERN_SIZE};
+use super::clock::{Clock, TIMER_FREQUENCY};
+use super::decode_cache::DecodeCache;
+use super::display::{
+  Display, HIRES_SCREEN_HEIGHT, HIRES_SCREEN_WIDTH, PLANE_COUNT, SCREEN_HEIGHT, SCREEN_WIDTH,
+};
+use super::error::{EmulationError, StateError};
+use super::fontset::{BIG_FONTSET, BIG_FONTSET_ADDRESS, FONTSET};
 use super::input::Input;
 use super::instructions::{self, Instruction, RegisterValuePair, TargetSourcePair};
-
-const MEMORY_SIZE: usize = 4096;
+use super::quirks::Quirks;
+use super::random::{RandomSource, XorShift};
+use super::synth::Synth;
+use super::tracer::{TraceEntry, Tracer};
+
+/// The most memory any preset addresses: the 64 KiB of XO-CHIP.
+pub const MEMORY_SIZE: usize = 0x10000;
+/// The memory of CHIP-8 and SUPER-CHIP, past which accesses are out of
+/// bounds unless the quirks extend it.
+pub const CLASSIC_MEMORY_SIZE: usize = 0x1000;
+/// Address ROMs are loaded at and start running from.
+pub const PROGRAM_START: usize = 0x200;
+const STACK_SIZE: usize = 16;
 const OP_SIZE: u16 = 2;
 
-#[cfg(target_arch = "wasm32")]
-fn get_random() -> u8 {
-  unsafe { js_sys::Math::floor(js_sys::Math::random() * 255.) as u8 }
+const STATE_MAGIC: [u8; 4] = *b"XO8S";
+const STATE_VERSION: u8 = 4;
+/// The part of a save state that is the same size for every machine. The
+/// memory and the screen follow it, sized by the quirks and resolution.
+const STATE_HEADER_SIZE: usize = STATE_MAGIC.len()
+  + 1 // version
+  + 1 // quirks
+  + 2 // resolution and planes
+  + 16 // registers
+  + 2 // index
+  + 2 // program counter
+  + 2 // timers
+  + STACK_SIZE * 2
+  + 1 // stack pointer
+  + 1 // waiting for key
+  + 1 // awaited key
+  + 1 // halted
+  + 16 // rpl flags
+  + PATTERN_SIZE
+  + 1 // pitch
+  + 1 + 8 // whether the random source is saved, and its state
+  + 1 // instruction remainder
+  + 8; // frames
+const NOT_WAITING: u8 = 0xFF;
+
+/// The size of a save state with `memory_size` bytes of memory.
+fn state_size(memory_size: usize, hires: bool) -> usize {
+  let (width, height) = if hires {
+    (HIRES_SCREEN_WIDTH, HIRES_SCREEN_HEIGHT)
+  } else {
+    (SCREEN_WIDTH, SCREEN_HEIGHT)
+  };
+  STATE_HEADER_SIZE + memory_size + PLANE_COUNT * width * height / 8
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-fn get_random() -> u8 {
-  rand::random()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccess {
+  Read,
+  Write,
 }
 
 enum ProgramCounter {
   Next,
+  NextLong,
   Skip,
   Jump(u16),
 }
 
+/// Registers from `from` to `to`, in descending order if `from` is the larger.
+fn register_range(from: u8, to: u8) -> Vec<u8> {
+  if from <= to {
+    (from..=to).collect()
+  } else {
+    (to..=from).rev().collect()
+  }
+}
+
 fn skip_if(condition: bool) -> ProgramCounter {
   if condition {
     ProgramCounter::Skip
@@ -33,7 +93,7 @@ fn skip_if(condition: bool) -> ProgramCounter {
   }
 }
 
-pub struct Chip8<T: AudioDriver> {
+pub struct Chip8<T: AudioDriver, R: RandomSource = XorShift> {
   pub display: Display,
   pub input: Input,
   memory: [u8; MEMORY_SIZE],
@@ -42,21 +102,49 @@ pub struct Chip8<T: AudioDriver> {
   program_counter: u16,
   delay_timer: u8,
   sound_timer: u8,
-  stack: [u16; 16],
+  stack: [u16; STACK_SIZE],
   stack_pointer: usize,
   waiting_for_key: Option<u8>,
+  /// The key pressed during an `FX0A` wait, which ends it once released.
+  awaited_key: Option<u8>,
   audio_driver: T,
+  tone_playing: bool,
+  synth: Synth,
+  /// Frames run so far, and how far into them the engine is, for the synth.
+  frames: u64,
+  audio_time: f64,
   should_draw: bool,
+  quirks: Quirks,
+  rpl_flags: [u8; 16],
+  halted: bool,
+  audio_pattern: Pattern,
+  pitch: u8,
+  clock: Clock,
+  random_source: R,
+  tracer: Option<Tracer>,
+  decode_cache: Option<DecodeCache>,
 }
 
 impl<T: AudioDriver> Chip8<T> {
-  pub fn new(audio_driver: T) -> Chip8<T> {
+  /// Creates an engine whose random numbers come from `XorShift` with its
+  /// default seed, so every run is the same until it is reseeded.
+  pub fn new(audio_driver: T, quirks: Quirks) -> Chip8<T> {
+    Chip8::with_random_source(audio_driver, quirks, XorShift::default())
+  }
+}
+
+impl<T: AudioDriver, R: RandomSource> Chip8<T, R> {
+  pub fn with_random_source(audio_driver: T, quirks: Quirks, random_source: R) -> Chip8<T, R> {
     let mut memory = [0; MEMORY_SIZE];
 
     for (index, character) in FONTSET.iter().enumerate() {
       memory[index] = *character;
     }
 
+    for (index, character) in BIG_FONTSET.iter().enumerate() {
+      memory[BIG_FONTSET_ADDRESS + index] = *character;
+    }
+
     Chip8 {
       memory,
       registers: [0; 16],
@@ -65,25 +153,49 @@ impl<T: AudioDriver> Chip8<T> {
       display: Display::new(),
       delay_timer: 0,
       sound_timer: 0,
-      stack: [0; 16],
+      stack: [0; STACK_SIZE],
       stack_pointer: 0,
       input: Input::new(),
       waiting_for_key: None,
+      awaited_key: None,
       should_draw: false,
       audio_driver,
+      tone_playing: false,
+      synth: Synth::new(),
+      frames: 0,
+      audio_time: 0.,
+      quirks,
+      rpl_flags: [0; 16],
+      halted: false,
+      audio_pattern: [0; PATTERN_SIZE],
+      pitch: DEFAULT_PITCH,
+      clock: Clock::default(),
+      random_source,
+      tracer: None,
+      decode_cache: Some(DecodeCache::new(MEMORY_SIZE)),
     }
   }
 
-  pub fn load(&mut self, buffer: Vec<u8>) {
-    for (index, value) in buffer.iter().enumerate() {
-      self.memory[index + 512] = *value;
+  pub fn load(&mut self, buffer: Vec<u8>) -> Result<(), EmulationError> {
+    let max_size = self.memory_size() - PROGRAM_START;
+    if buffer.len() > max_size {
+      return Err(EmulationError::RomTooLarge {
+        size: buffer.len(),
+        max_size,
+      });
     }
+
+    let rom = PROGRAM_START..PROGRAM_START + buffer.len();
+    self.memory[rom.clone()].copy_from_slice(&buffer);
+    self.memory_written(rom);
+    Ok(())
   }
 
   pub fn reset(&mut self) {
-    for index in 512..MEMORY_SIZE {
+    for index in PROGRAM_START..MEMORY_SIZE {
       self.memory[index] = 0;
     }
+    self.memory_written(PROGRAM_START..MEMORY_SIZE);
 
     self.registers = [0; 16];
     self.index = 0;
@@ -91,21 +203,400 @@ impl<T: AudioDriver> Chip8<T> {
     self.display = Display::new();
     self.delay_timer = 0;
     self.sound_timer = 0;
-    self.stack = [0; 16];
+    self.stack = [0; STACK_SIZE];
     self.stack_pointer = 0;
     self.waiting_for_key = None;
+    self.awaited_key = None;
+    self.halted = false;
+    self.update_tone();
+    self.audio_pattern = [0; PATTERN_SIZE];
+    self.pitch = DEFAULT_PITCH;
+    self.clock = Clock::new(self.clock.instructions_per_second());
   }
 
   pub fn should_draw(&self) -> bool {
     self.should_draw
   }
 
-  fn set_register(&mut self, register: u8, value: u8) {
-    self.registers[register as usize] = value;
+  pub fn quirks(&self) -> Quirks {
+    self.quirks
+  }
+
+  pub fn set_quirks(&mut self, quirks: Quirks) {
+    self.quirks = quirks;
+  }
+
+  pub fn instructions_per_second(&self) -> u32 {
+    self.clock.instructions_per_second()
+  }
+
+  pub fn set_instructions_per_second(&mut self, instructions_per_second: u32) {
+    self
+      .clock
+      .set_instructions_per_second(instructions_per_second);
+  }
+
+  pub fn set_instructions_per_frame(&mut self, instructions_per_frame: u32) {
+    self
+      .clock
+      .set_instructions_per_second(instructions_per_frame * TIMER_FREQUENCY);
+  }
+
+  /// Replaces the source `CXNN` draws from, e.g. to reseed it.
+  pub fn set_random_source(&mut self, random_source: R) {
+    self.random_source = random_source;
+  }
+
+  /// Records every instruction run from now on into `tracer`, or stops
+  /// tracing with `None`. Returns the tracer that was there before.
+  pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+    std::mem::replace(&mut self.tracer, tracer)
+  }
+
+  pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+    self.tracer.as_mut()
+  }
+
+  /// Whether the ROM has executed `00FD`.
+  pub fn is_halted(&self) -> bool {
+    self.halted
+  }
+
+  /// The SUPER-CHIP RPL user flags. They survive `reset` so that a frontend
+  /// can keep them between runs, like the HP48 does.
+  pub fn rpl_flags(&self) -> [u8; 16] {
+    self.rpl_flags
+  }
+
+  pub fn set_rpl_flags(&mut self, flags: [u8; 16]) {
+    self.rpl_flags = flags;
+  }
+
+  pub fn registers(&self) -> [u8; 16] {
+    self.registers
+  }
+
+  pub fn set_registers(&mut self, registers: [u8; 16]) {
+    self.registers = registers;
+  }
+
+  pub fn index(&self) -> u16 {
+    self.index
+  }
+
+  pub fn set_index(&mut self, index: u16) {
+    self.index = index;
+  }
+
+  pub fn program_counter(&self) -> u16 {
+    self.program_counter
+  }
+
+  pub fn set_program_counter(&mut self, program_counter: u16) {
+    self.program_counter = program_counter;
+  }
+
+  /// Return addresses of the subroutines currently running, outermost first.
+  pub fn stack(&self) -> &[u16] {
+    &self.stack[..self.stack_pointer]
+  }
+
+  pub fn delay_timer(&self) -> u8 {
+    self.delay_timer
+  }
+
+  pub fn set_delay_timer(&mut self, delay_timer: u8) {
+    self.delay_timer = delay_timer;
+  }
+
+  pub fn sound_timer(&self) -> u8 {
+    self.sound_timer
+  }
+
+  pub fn set_sound_timer(&mut self, sound_timer: u8) {
+    self.sound_timer = sound_timer;
+    self.update_tone();
+  }
+
+  /// Changes how the buzzer sounds, see `AudioDriver::set_tone`.
+  pub fn set_tone(&mut self, tone: Tone) {
+    self.audio_driver.set_tone(tone);
+    self.synth.set_tone(tone);
+  }
+
+  /// Renders the buzzer into `buffer` as mono samples between -1 and 1,
+  /// carrying on from the previous call. Tones start and stop on the sample
+  /// matching the instruction that set the sound timer, as long as the
+  /// samples keep up with the engine: ask for `pending_audio_samples` after
+  /// running it.
+  pub fn fill_audio(&mut self, buffer: &mut [f32], sample_rate: u32) {
+    self.synth.fill(buffer, sample_rate);
+  }
+
+  /// How many samples `fill_audio` has to render to catch up with the
+  /// engine. It never lags more than a second behind.
+  pub fn pending_audio_samples(&self, sample_rate: u32) -> usize {
+    self.synth.pending(self.audio_time, sample_rate)
   }
 
-  fn set_memory(&mut self, index: u16, value: u8) {
-    self.memory[index as usize] = value;
+  /// How much memory the program can address: `MEMORY_SIZE` with the
+  /// `extended_memory` quirk, `CLASSIC_MEMORY_SIZE` otherwise.
+  pub fn memory_size(&self) -> usize {
+    if self.quirks.extended_memory {
+      MEMORY_SIZE
+    } else {
+      CLASSIC_MEMORY_SIZE
+    }
+  }
+
+  /// The memory the program can address.
+  pub fn memory(&self) -> &[u8] {
+    &self.memory[..self.memory_size()]
+  }
+
+  /// Memory for the caller to change. The decoded instruction cache can't
+  /// tell what changes, so it starts over.
+  pub fn memory_mut(&mut self) -> &mut [u8] {
+    self.memory_written(0..MEMORY_SIZE);
+    let size = self.memory_size();
+    &mut self.memory[..size]
+  }
+
+  /// Turns the decoded instruction cache on or off. It is on by default,
+  /// and the engine behaves the same either way, only slower without it.
+  pub fn set_decode_cache(&mut self, enabled: bool) {
+    self.decode_cache = if enabled {
+      Some(DecodeCache::new(MEMORY_SIZE))
+    } else {
+      None
+    };
+  }
+
+  /// Whether an `FX0A` is holding the program until a key is pressed, or
+  /// released.
+  pub fn is_waiting_for_key(&self) -> bool {
+    self.waiting_for_key.is_some()
+  }
+
+  /// The memory the next instruction will read or write, if any.
+  pub fn pending_memory_access(&self) -> Option<(MemoryAccess, Range<usize>)> {
+    if self.halted || self.waiting_for_key.is_some() {
+      return None;
+    }
+    let op_code = self.read_u16(0, self.program_counter as usize).ok()?;
+    let (access, length) = match instructions::decode(op_code) {
+      Instruction::Draw { height, .. } => (MemoryAccess::Read, self.sprite_size(height)),
+      Instruction::LoadRegisters(limit) => (MemoryAccess::Read, limit as usize + 1),
+      Instruction::LoadRegisterRange { from, to } => {
+        (MemoryAccess::Read, register_range(from, to).len())
+      }
+      Instruction::LoadAudioPattern => (MemoryAccess::Read, PATTERN_SIZE),
+      Instruction::StoreBCD(_) => (MemoryAccess::Write, 3),
+      Instruction::DumpRegisters(limit) => (MemoryAccess::Write, limit as usize + 1),
+      Instruction::DumpRegisterRange { from, to } => {
+        (MemoryAccess::Write, register_range(from, to).len())
+      }
+      _ => return None,
+    };
+    let start = self.index as usize;
+    Some((access, start..(start + length).min(self.memory_size())))
+  }
+
+  /// Snapshots the machine into a versioned binary blob that `load_state`
+  /// accepts. Input, audio driver and speed are left out, as they belong to
+  /// the frontend. Only the memory the quirks address and the planes of the
+  /// visible lines are kept, one bit per pixel.
+  pub fn save_state(&self) -> Vec<u8> {
+    let hires = self.display.is_hires();
+    let mut state = Vec::with_capacity(state_size(self.memory_size(), hires));
+    state.extend_from_slice(&STATE_MAGIC);
+    state.push(STATE_VERSION);
+    state.push(self.quirks.to_bits());
+    state.push(hires as u8);
+    state.push(self.display.planes());
+    state.extend_from_slice(&self.registers);
+    state.write_u16::<BigEndian>(self.index).unwrap();
+    state.write_u16::<BigEndian>(self.program_counter).unwrap();
+    state.push(self.delay_timer);
+    state.push(self.sound_timer);
+    for address in self.stack.iter() {
+      state.write_u16::<BigEndian>(*address).unwrap();
+    }
+    state.push(self.stack_pointer as u8);
+    state.push(self.waiting_for_key.unwrap_or(NOT_WAITING));
+    state.push(self.awaited_key.unwrap_or(NOT_WAITING));
+    state.push(self.halted as u8);
+    state.extend_from_slice(&self.rpl_flags);
+    state.extend_from_slice(&self.audio_pattern);
+    state.push(self.pitch);
+    let random_state = self.random_source.state();
+    state.push(random_state.is_some() as u8);
+    state
+      .write_u64::<BigEndian>(random_state.unwrap_or(0))
+      .unwrap();
+    state.push(self.clock.instruction_remainder() as u8);
+    state.write_u64::<BigEndian>(self.frames).unwrap();
+    state.extend_from_slice(self.memory());
+    let row_bytes = self.display.width() / 8;
+    for plane in 0..PLANE_COUNT {
+      for row in self.display.rows(plane) {
+        state.extend_from_slice(&row.to_be_bytes()[..row_bytes]);
+      }
+    }
+    state
+  }
+
+  /// Restores a blob made by `save_state`. Nothing is changed unless the
+  /// whole state is valid.
+  pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+    if !state.starts_with(&STATE_MAGIC) {
+      return Err(StateError::NotAState);
+    }
+    let version = state.get(STATE_MAGIC.len()).copied().unwrap_or(0);
+    if version != STATE_VERSION {
+      return Err(StateError::UnsupportedVersion {
+        version,
+        supported: STATE_VERSION,
+      });
+    }
+    if state.len() < STATE_HEADER_SIZE {
+      return Err(StateError::InvalidSize {
+        size: state.len(),
+        expected: STATE_HEADER_SIZE,
+      });
+    }
+
+    let mut reader = &state[STATE_MAGIC.len() + 1..];
+    let mut read_bytes = |length: usize| {
+      let (bytes, rest) = reader.split_at(length);
+      reader = rest;
+      bytes
+    };
+    let invalid = |field| Err(StateError::InvalidField { field });
+
+    let quirks = read_bytes(1)[0];
+    let hires = read_bytes(1)[0];
+    let planes = read_bytes(1)[0];
+    if quirks >> 7 != 0 {
+      return invalid("quirks");
+    }
+    if hires > 1 {
+      return invalid("resolution");
+    }
+    let quirks = Quirks::from_bits(quirks);
+    let memory_size = if quirks.extended_memory {
+      MEMORY_SIZE
+    } else {
+      CLASSIC_MEMORY_SIZE
+    };
+    let expected = state_size(memory_size, hires == 1);
+    if state.len() != expected {
+      return Err(StateError::InvalidSize {
+        size: state.len(),
+        expected,
+      });
+    }
+
+    let mut registers = [0; 16];
+    registers.copy_from_slice(read_bytes(16));
+    let index = read_bytes(2).read_u16::<BigEndian>().unwrap();
+    let program_counter = read_bytes(2).read_u16::<BigEndian>().unwrap();
+    let timers = read_bytes(2);
+    let (delay_timer, sound_timer) = (timers[0], timers[1]);
+    let mut stack = [0; STACK_SIZE];
+    for address in stack.iter_mut() {
+      *address = read_bytes(2).read_u16::<BigEndian>().unwrap();
+    }
+    let stack_pointer = read_bytes(1)[0] as usize;
+    let waiting_for_key = match read_bytes(1)[0] {
+      NOT_WAITING => None,
+      register => Some(register),
+    };
+    let awaited_key = match read_bytes(1)[0] {
+      NOT_WAITING => None,
+      key => Some(key),
+    };
+    let halted = read_bytes(1)[0];
+    let mut rpl_flags = [0; 16];
+    rpl_flags.copy_from_slice(read_bytes(16));
+    let mut audio_pattern = [0; PATTERN_SIZE];
+    audio_pattern.copy_from_slice(read_bytes(PATTERN_SIZE));
+    let pitch = read_bytes(1)[0];
+    let has_random_state = read_bytes(1)[0];
+    let random_state = read_bytes(8).read_u64::<BigEndian>().unwrap();
+    let instruction_remainder = read_bytes(1)[0] as u32;
+    let frames = read_bytes(8).read_u64::<BigEndian>().unwrap();
+    let memory = read_bytes(memory_size);
+    let screen = reader;
+
+    if program_counter as usize + OP_SIZE as usize > memory_size {
+      return invalid("program counter");
+    }
+    if stack_pointer > STACK_SIZE {
+      return invalid("stack pointer");
+    }
+    if matches!(waiting_for_key, Some(register) if register > 0xF) {
+      return invalid("key wait register");
+    }
+    if matches!(awaited_key, Some(key) if key > 0xF || waiting_for_key.is_none()) {
+      return invalid("awaited key");
+    }
+    if halted > 1 {
+      return invalid("halted flag");
+    }
+    if planes > 0b11 {
+      return invalid("display planes");
+    }
+    if has_random_state > 1 {
+      return invalid("random state");
+    }
+    if instruction_remainder >= TIMER_FREQUENCY {
+      return invalid("instruction remainder");
+    }
+
+    self.quirks = quirks;
+    self.memory[..memory_size].copy_from_slice(memory);
+    for byte in self.memory[memory_size..].iter_mut() {
+      *byte = 0;
+    }
+    self.memory_written(0..MEMORY_SIZE);
+    self.registers = registers;
+    self.index = index;
+    self.program_counter = program_counter;
+    self.delay_timer = delay_timer;
+    self.sound_timer = sound_timer;
+    self.frames = frames;
+    self.audio_time = frames as f64;
+    self.synth.seek(self.audio_time);
+    self.update_tone();
+    self.stack = stack;
+    self.stack_pointer = stack_pointer;
+    self.waiting_for_key = waiting_for_key;
+    self.awaited_key = awaited_key;
+    self.halted = halted == 1;
+    self.rpl_flags = rpl_flags;
+    self.audio_pattern = audio_pattern;
+    self.pitch = pitch;
+    self.pattern_changed();
+    if has_random_state == 1 {
+      self.random_source.restore(random_state);
+    }
+    self.clock.set_instruction_remainder(instruction_remainder);
+    self.display.set_hires(hires == 1);
+    self.display.select_planes(planes);
+    let row_bytes = self.display.width() / 8;
+    for (index, bytes) in screen.chunks(row_bytes).enumerate() {
+      let mut row = [0; 16];
+      row[..row_bytes].copy_from_slice(bytes);
+      let (plane, y) = (index / self.display.height(), index % self.display.height());
+      self.display.set_row(plane, y, u128::from_be_bytes(row));
+    }
+    self.should_draw = true;
+    Ok(())
+  }
+
+  fn set_register(&mut self, register: u8, value: u8) {
+    self.registers[register as usize] = value;
   }
 
   fn set_vf(&mut self, value: u8) {
@@ -116,239 +607,604 @@ impl<T: AudioDriver> Chip8<T> {
     self.registers[register as usize]
   }
 
+  #[cfg(test)]
   fn get_memory(&self, index: u16) -> u8 {
     self.memory[index as usize]
   }
 
-  fn push_stack(&mut self) {
-    self.stack[self.stack_pointer] = self.program_counter + 2;
+  /// Drops the decoded instructions that overlap `range` after it changed.
+  fn memory_written(&mut self, range: Range<usize>) {
+    if let Some(cache) = &mut self.decode_cache {
+      if range == (0..MEMORY_SIZE) {
+        cache.clear();
+      } else {
+        cache.invalidate(range);
+      }
+    }
+  }
+
+  /// Checks that `length` bytes starting at `start` fit in memory.
+  fn memory_range(
+    &self,
+    op_code: u16,
+    start: impl Into<usize>,
+    length: usize,
+  ) -> Result<Range<usize>, EmulationError> {
+    let start = start.into();
+    let range = start..start + length;
+    if range.end > self.memory_size() {
+      Err(EmulationError::MemoryOutOfBounds {
+        program_counter: self.program_counter,
+        op_code,
+        address: start,
+        length,
+      })
+    } else {
+      Ok(range)
+    }
+  }
+
+  fn read_u16(&self, op_code: u16, address: usize) -> Result<u16, EmulationError> {
+    let mut bytes = &self.memory[self.memory_range(op_code, address, 2)?];
+    Ok(bytes.read_u16::<BigEndian>().unwrap())
+  }
+
+  /// Size of the instruction at `address`, which is 4 bytes for `F000 NNNN`.
+  fn op_size_at(&mut self, address: usize) -> usize {
+    match self.fetch(address) {
+      Ok((0xF000, _)) => 2 * OP_SIZE as usize,
+      _ => OP_SIZE as usize,
+    }
+  }
+
+  fn sprite_size(&self, height: u8) -> usize {
+    let plane_size = if height == 0 { 32 } else { height as usize };
+    plane_size * self.display.selected_plane_count()
+  }
+
+  fn shift_operand(&self, target: u8, source: u8) -> u8 {
+    if self.quirks.shift_uses_vy {
+      self.get_register(source)
+    } else {
+      self.get_register(target)
+    }
+  }
+
+  fn push_stack(&mut self, op_code: u16) -> Result<(), EmulationError> {
+    if self.stack_pointer >= STACK_SIZE {
+      return Err(EmulationError::StackOverflow {
+        program_counter: self.program_counter,
+        op_code,
+      });
+    }
+    self.stack[self.stack_pointer] = self.program_counter.wrapping_add(OP_SIZE);
     self.stack_pointer += 1;
+    Ok(())
   }
 
-  fn pop_stack(&mut self) -> u16 {
+  fn pop_stack(&mut self, op_code: u16) -> Result<u16, EmulationError> {
+    if self.stack_pointer == 0 {
+      return Err(EmulationError::StackUnderflow {
+        program_counter: self.program_counter,
+        op_code,
+      });
+    }
     self.stack_pointer -= 1;
-    self.stack[self.stack_pointer]
+    Ok(self.stack[self.stack_pointer])
+  }
+
+  /// The op code at `address` and its instruction, from the decode cache
+  /// when it is on.
+  fn fetch(&mut self, address: usize) -> Result<(u16, Instruction), EmulationError> {
+    if address + OP_SIZE as usize > self.memory_size() {
+      return Err(EmulationError::ProgramCounterOutOfRange {
+        program_counter: self.program_counter,
+        op_code: 0,
+      });
+    }
+    match &mut self.decode_cache {
+      Some(cache) => Ok(cache.fetch(&self.memory, address)),
+      None => {
+        let op_code = self.read_u16(0, address)?;
+        Ok((op_code, instructions::decode(op_code)))
+      }
+    }
   }
 
-  fn execute_op(&mut self, op_code: u16) -> ProgramCounter {
-    match instructions::decode(op_code) {
+  fn execute_op(
+    &mut self,
+    op_code: u16,
+    instruction: Instruction,
+  ) -> Result<ProgramCounter, EmulationError> {
+    match instruction {
       Instruction::CallMachineCode(_) => {
         println!("Do you hate me or something?");
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
       Instruction::ClearDisplay => {
         self.display.clear();
-        ProgramCounter::Next
+        self.should_draw = true;
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::ScrollDown(rows) => {
+        self.display.scroll_down(rows as usize);
+        self.should_draw = true;
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::ScrollRight => {
+        self.display.scroll_right(4);
+        self.should_draw = true;
+        Ok(ProgramCounter::Next)
       }
-      Instruction::Return => ProgramCounter::Jump(self.pop_stack()),
-      Instruction::GoTo(addr) => ProgramCounter::Jump(addr),
+      Instruction::ScrollLeft => {
+        self.display.scroll_left(4);
+        self.should_draw = true;
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::Exit => {
+        self.halted = true;
+        Ok(ProgramCounter::Jump(self.program_counter))
+      }
+      Instruction::LowResolution => {
+        self.display.set_hires(false);
+        self.should_draw = true;
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::HighResolution => {
+        self.display.set_hires(true);
+        self.should_draw = true;
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::Return => Ok(ProgramCounter::Jump(self.pop_stack(op_code)?)),
+      Instruction::GoTo(addr) => Ok(ProgramCounter::Jump(addr)),
       Instruction::Call(addr) => {
-        self.push_stack();
-        ProgramCounter::Jump(addr)
+        self.push_stack(op_code)?;
+        Ok(ProgramCounter::Jump(addr))
       }
       Instruction::SkipIfEqual(RegisterValuePair { register, value }) => {
-        skip_if(self.get_register(register) == value)
+        Ok(skip_if(self.get_register(register) == value))
       }
       Instruction::SkipIfDifferent(RegisterValuePair { register, value }) => {
-        skip_if(self.get_register(register) != value)
+        Ok(skip_if(self.get_register(register) != value))
       }
       Instruction::SkipIfRegisterEqual(TargetSourcePair { target, source }) => {
-        skip_if(self.get_register(target) == self.get_register(source))
+        Ok(skip_if(self.get_register(target) == self.get_register(source)))
+      }
+      Instruction::DumpRegisterRange { from, to } => {
+        let registers = register_range(from, to);
+        let range = self.memory_range(op_code, self.index, registers.len())?;
+        for (offset, register) in registers.into_iter().enumerate() {
+          self.memory[range.start + offset] = self.get_register(register);
+        }
+        self.memory_written(range);
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::LoadRegisterRange { from, to } => {
+        let registers = register_range(from, to);
+        let start = self.memory_range(op_code, self.index, registers.len())?.start;
+        for (offset, register) in registers.into_iter().enumerate() {
+          self.set_register(register, self.memory[start + offset]);
+        }
+        Ok(ProgramCounter::Next)
       }
       Instruction::AssignValueToRegister(RegisterValuePair { register, value }) => {
         self.set_register(register, value);
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
       Instruction::AddValueToRegister(RegisterValuePair { register, value }) => {
         let (sum, _) = self.get_register(register).overflowing_add(value);
         self.set_register(register, sum);
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
       Instruction::AssignVYToVX(TargetSourcePair { target, source }) => {
         self.set_register(target, self.get_register(source));
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
       Instruction::SetXOrY(TargetSourcePair { target, source }) => {
         let result = self.get_register(target) | self.get_register(source);
         self.set_register(target, result);
-        ProgramCounter::Next
+        if self.quirks.logic_resets_vf {
+          self.set_vf(0);
+        }
+        Ok(ProgramCounter::Next)
       }
       Instruction::SetXAndY(TargetSourcePair { target, source }) => {
         let result = self.get_register(target) & self.get_register(source);
         self.set_register(target, result);
-        ProgramCounter::Next
+        if self.quirks.logic_resets_vf {
+          self.set_vf(0);
+        }
+        Ok(ProgramCounter::Next)
       }
       Instruction::SetXXorY(TargetSourcePair { target, source }) => {
         let result = self.get_register(target) ^ self.get_register(source);
         self.set_register(target, result);
-        ProgramCounter::Next
+        if self.quirks.logic_resets_vf {
+          self.set_vf(0);
+        }
+        Ok(ProgramCounter::Next)
       }
       Instruction::AddYToX(TargetSourcePair { target, source }) => {
         let (result, did_overflow) = self
           .get_register(target)
           .overflowing_add(self.get_register(source));
+        self.set_register(target, result);
         if did_overflow {
           self.set_vf(1);
         } else {
           self.set_vf(0);
         }
-        self.set_register(target, result);
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
       Instruction::SubYFromX(TargetSourcePair { target, source }) => {
         let (result, did_overflow) = self
           .get_register(target)
           .overflowing_sub(self.get_register(source));
+        self.set_register(target, result);
         if did_overflow {
           self.set_vf(0)
         } else {
           self.set_vf(1)
         }
-        self.set_register(target, result);
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
-      Instruction::ShiftRight(register) => {
-        let reg_value = self.get_register(register);
+      Instruction::ShiftRight(TargetSourcePair { target, source }) => {
+        let reg_value = self.shift_operand(target, source);
+        self.set_register(target, reg_value >> 1);
         self.set_vf(reg_value & 0b1);
-        self.set_register(register, reg_value >> 1);
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
       Instruction::SetXAsYMinusX(TargetSourcePair { target, source }) => {
         let (result, did_overflow) = self
           .get_register(source)
           .overflowing_sub(self.get_register(target));
+        self.set_register(target, result);
         if did_overflow {
           self.set_vf(0)
         } else {
           self.set_vf(1)
         }
-        self.set_register(target, result);
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
-      Instruction::ShiftLeft(register) => {
-        let reg_value = self.get_register(register);
+      Instruction::ShiftLeft(TargetSourcePair { target, source }) => {
+        let reg_value = self.shift_operand(target, source);
+        self.set_register(target, reg_value << 1);
         self.set_vf((reg_value & 0b10000000) / 128);
-        self.set_register(register, reg_value << 1);
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
       Instruction::SkipIfRegisterDifferent(TargetSourcePair { target, source }) => {
-        skip_if(self.get_register(target) != self.get_register(source))
+        Ok(skip_if(self.get_register(target) != self.get_register(source)))
       }
       Instruction::SetIAs(value) => {
         self.index = value;
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::SetIAsLong => {
+        self.index = self.read_u16(op_code, self.program_counter as usize + OP_SIZE as usize)?;
+        Ok(ProgramCounter::NextLong)
+      }
+      Instruction::GoToNPlusV0(addr) => {
+        let register = if self.quirks.jump_uses_vx {
+          ((addr >> 8) & 0xF) as u8
+        } else {
+          0x0
+        };
+        Ok(ProgramCounter::Jump(addr + self.get_register(register) as u16))
       }
-      Instruction::GoToNPlusV0(addr) => ProgramCounter::Jump(addr + self.get_register(0x0) as u16),
       Instruction::Random(RegisterValuePair { register, value }) => {
-        let rnd: u8 = get_random();
+        let rnd = self.random_source.next_byte();
         self.set_register(register, rnd & value);
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::Draw { x, y, height: 0 } => {
+        let sprite = self.memory_range(op_code, self.index, self.sprite_size(0))?;
+        let new_vf = self.display.draw_large(
+          self.get_register(x) as usize,
+          self.get_register(y) as usize,
+          &self.memory[sprite],
+          self.quirks.wrap_sprites,
+        );
+        self.set_vf(new_vf);
+        self.should_draw = true;
+        Ok(ProgramCounter::Next)
       }
       Instruction::Draw { x, y, height } => {
+        let sprite = self.memory_range(op_code, self.index, self.sprite_size(height))?;
         let new_vf = self.display.draw(
           self.get_register(x) as usize,
           self.get_register(y) as usize,
-          &self.memory[self.index as usize..(self.index + height as u16) as usize],
+          &self.memory[sprite],
+          self.quirks.wrap_sprites,
         );
         self.set_vf(new_vf);
         self.should_draw = true;
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
+      // Only the low nibble of VX picks a key, as on the VIP's keypad.
       Instruction::SkipIfKeyPressed(register) => {
-        let reg_value = self.get_register(register);
-        skip_if(self.input.is_pressed(reg_value))
+        let key = self.get_register(register) & 0xF;
+        Ok(skip_if(self.input.is_pressed(key)))
       }
       Instruction::SkipIfKeyNotPressed(register) => {
-        let reg_value = self.get_register(register);
-        skip_if(!self.input.is_pressed(reg_value))
+        let key = self.get_register(register) & 0xF;
+        Ok(skip_if(!self.input.is_pressed(key)))
       }
       Instruction::SetXAsDelay(register) => {
         self.set_register(register, self.delay_timer);
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
       Instruction::WaitForInputAndStoreIn(register) => {
         self.waiting_for_key = Some(register);
-        ProgramCounter::Next
+        self.awaited_key = None;
+        Ok(ProgramCounter::Next)
       }
       Instruction::SetDelayAsX(register) => {
         self.delay_timer = self.get_register(register);
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
       Instruction::SetSoundAsX(register) => {
         self.sound_timer = self.get_register(register);
-        ProgramCounter::Next
+        self.update_tone();
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::SelectPlanes(planes) => {
+        self.display.select_planes(planes);
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::LoadAudioPattern => {
+        let pattern = self.memory_range(op_code, self.index, PATTERN_SIZE)?;
+        self.audio_pattern.copy_from_slice(&self.memory[pattern]);
+        self.pattern_changed();
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::SetPitch(register) => {
+        self.pitch = self.get_register(register);
+        self.pattern_changed();
+        Ok(ProgramCounter::Next)
       }
       Instruction::AddXToI(register) => {
         let (result, _) = self
           .index
           .overflowing_add(self.get_register(register) as u16);
         self.index = result;
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
       Instruction::StoreBCD(register) => {
         let value = self.get_register(register);
-        self.set_memory(self.index, value / 100);
-        self.set_memory(self.index + 1, (value % 100) / 10);
-        self.set_memory(self.index + 2, value % 10);
-        ProgramCounter::Next
+        let range = self.memory_range(op_code, self.index, 3)?;
+        self.memory[range.start] = value / 100;
+        self.memory[range.start + 1] = (value % 100) / 10;
+        self.memory[range.start + 2] = value % 10;
+        self.memory_written(range);
+        Ok(ProgramCounter::Next)
       }
       Instruction::DumpRegisters(limit) => {
+        let range = self.memory_range(op_code, self.index, limit as usize + 1)?;
         for i in 0..=limit {
-          self.set_memory(self.index, self.get_register(i));
-          self.index += 1;
+          self.memory[range.start + i as usize] = self.get_register(i);
+        }
+        self.memory_written(range);
+        if self.quirks.load_store_increments_index {
+          self.index = self.index.wrapping_add(limit as u16 + 1);
         }
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
       }
       Instruction::LoadRegisters(limit) => {
+        let start = self.memory_range(op_code, self.index, limit as usize + 1)?.start;
         for i in 0..=limit {
-          self.set_register(i, self.get_memory(self.index));
-          self.index += 1;
+          self.set_register(i, self.memory[start + i as usize]);
         }
-        ProgramCounter::Next
+        if self.quirks.load_store_increments_index {
+          self.index = self.index.wrapping_add(limit as u16 + 1);
+        }
+        Ok(ProgramCounter::Next)
       }
       Instruction::SetIAsFontSprite(register) => {
         self.index = self.get_register(register) as u16 * 5;
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::SetIAsBigFontSprite(register) => {
+        self.index = BIG_FONTSET_ADDRESS as u16 + (self.get_register(register) & 0xF) as u16 * 10;
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::StoreFlags(limit) => {
+        for i in 0..=limit {
+          self.rpl_flags[i as usize] = self.get_register(i);
+        }
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::LoadFlags(limit) => {
+        for i in 0..=limit {
+          self.set_register(i, self.rpl_flags[i as usize]);
+        }
+        Ok(ProgramCounter::Next)
+      }
+      Instruction::InvalidInstruction => Ok(ProgramCounter::Next),
+    }
+  }
+
+  /// Executes a single instruction. Timers are left alone, so callers
+  /// stepping manually should call `end_frame` at 60 Hz themselves.
+  pub fn emulate_cycle(&mut self) -> Result<(), EmulationError> {
+    self.should_draw = false;
+    self.step()
+  }
+
+  /// Executes one 60 Hz frame worth of instructions and ticks the timers once.
+  pub fn run_frame(&mut self) -> Result<(), EmulationError> {
+    self.should_draw = false;
+    self.frame()
+  }
+
+  /// Like `run_frame`, but checks `keep_going` before every instruction and
+  /// stops as soon as it returns false, leaving the timers untouched.
+  /// Returns whether the whole frame ran.
+  pub fn run_frame_while<F>(&mut self, keep_going: F) -> Result<bool, EmulationError>
+  where
+    F: FnMut(&Self) -> bool,
+  {
+    self.should_draw = false;
+    self.frame_while(keep_going)
+  }
+
+  /// Runs as many frames as fit in `elapsed`, carrying what is left over to
+  /// the next call. Frontends can call this at any interval and still get
+  /// the right game speed.
+  pub fn run_for(&mut self, elapsed: Duration) -> Result<(), EmulationError> {
+    self.should_draw = false;
+    for _ in 0..self.frames_due(elapsed) {
+      self.frame()?;
+    }
+    Ok(())
+  }
+
+  /// Adds `elapsed` to the clock and returns how many frames `run_for`
+  /// would run, for frontends that run them one at a time.
+  pub fn frames_due(&mut self, elapsed: Duration) -> u32 {
+    self.clock.frames_for(elapsed)
+  }
+
+  /// Closes a 60 Hz frame: ticks the timers and forgets the key presses and
+  /// releases the frame has seen. Runs after every frame `run_frame` and
+  /// friends execute, and is there for callers that step instructions one
+  /// at a time.
+  pub fn end_frame(&mut self) {
+    self.frames += 1;
+    self.audio_time = self.frames as f64;
+    self.tick_timers();
+    self.input.end_frame();
+  }
+
+  pub fn tick_timers(&mut self) {
+    if self.delay_timer > 0 {
+      self.delay_timer -= 1
+    };
+
+    if self.sound_timer > 0 {
+      self.sound_timer -= 1;
+      self.update_tone();
+    }
+  }
+
+  /// Keeps the buzzer sounding exactly while the sound timer is above zero.
+  fn update_tone(&mut self) {
+    let active = self.sound_timer > 0;
+    if active != self.tone_playing {
+      self.tone_playing = active;
+      self.synth.set_active(self.audio_time, active);
+      if active {
+        self.audio_driver.start_tone();
+      } else {
+        self.audio_driver.stop_tone();
+      }
+    }
+  }
+
+  fn pattern_changed(&mut self) {
+    let sample_rate = pattern_sample_rate(self.pitch);
+    self.audio_driver.set_pattern(self.audio_pattern, sample_rate);
+    self.synth.set_pattern(self.audio_pattern, sample_rate);
+  }
+
+  fn frame(&mut self) -> Result<(), EmulationError> {
+    self.frame_while(|_| true).map(|_| ())
+  }
+
+  fn frame_while<F>(&mut self, mut keep_going: F) -> Result<bool, EmulationError>
+  where
+    F: FnMut(&Self) -> bool,
+  {
+    let instructions = self.clock.instructions_for_next_frame();
+    for instruction in 0..instructions {
+      if self.halted {
+        break;
+      }
+      if !keep_going(self) {
+        return Ok(false);
+      }
+      self.audio_time = self.frames as f64 + instruction as f64 / instructions as f64;
+      self.step()?;
+    }
+    self.end_frame();
+    Ok(true)
+  }
+
+  /// The key that ends an `FX0A` wait, if it is over: the first one pressed
+  /// since the wait started, once released unless the quirks take presses.
+  /// Keys held from before have to go up and down again.
+  fn key_wait_result(&mut self) -> Option<u8> {
+    if self.awaited_key.is_none() {
+      self.awaited_key = (0..16).find(|key| self.input.was_pressed(*key));
+    }
+    let key = self.awaited_key?;
+    let released = !self.input.is_pressed(key) || self.input.was_released(key);
+    if released || !self.quirks.key_wait_on_release {
+      Some(key)
+    } else {
+      None
+    }
+  }
+
+  fn step(&mut self) -> Result<(), EmulationError> {
+    let result = self.execute_step();
+    if result.is_err() {
+      if let Some(tracer) = &mut self.tracer {
+        tracer.dump();
       }
-      Instruction::InvalidInstruction => ProgramCounter::Next,
     }
+    result
   }
 
-  pub fn emulate_cycle(&mut self) {
-    if self.should_draw {
-      self.should_draw = false;
+  fn execute_step(&mut self) -> Result<(), EmulationError> {
+    if self.halted {
+      return Ok(());
     }
     if let Some(register) = self.waiting_for_key {
-      if let Some(index) = self.input.keypad.iter().position(|val| *val) {
+      if let Some(key) = self.key_wait_result() {
         self.waiting_for_key = None;
-        self.set_register(register, index as u8);
+        self.awaited_key = None;
+        self.input.clear_edges(key);
+        self.set_register(register, key);
       }
     } else {
       let position = self.program_counter as usize;
-      let mut op_code = &self.memory[position..position + 2];
-      let op_code = op_code.read_u16::<BigEndian>().unwrap();
-
-      let pg_op = self.execute_op(op_code);
+      let (op_code, instruction) = self.fetch(position)?;
+
+      if let Some(tracer) = &mut self.tracer {
+        tracer.record(TraceEntry {
+          program_counter: self.program_counter,
+          op_code,
+          registers: self.registers,
+          index: self.index,
+          stack_pointer: self.stack_pointer as u8,
+          delay_timer: self.delay_timer,
+          sound_timer: self.sound_timer,
+        });
+      }
 
-      self.program_counter = match pg_op {
-        ProgramCounter::Next => self.program_counter + OP_SIZE,
-        ProgramCounter::Skip => self.program_counter + 2 * OP_SIZE,
-        ProgramCounter::Jump(addr) => addr,
-      };
+      let pg_op = self.execute_op(op_code, instruction)?;
 
-      if self.delay_timer > 0 {
-        self.delay_timer -= 1
+      let next_position = match pg_op {
+        ProgramCounter::Next => position + OP_SIZE as usize,
+        ProgramCounter::NextLong => position + 2 * OP_SIZE as usize,
+        ProgramCounter::Skip => {
+          let next = position + OP_SIZE as usize;
+          next + self.op_size_at(next)
+        }
+        ProgramCounter::Jump(addr) => addr as usize,
       };
 
-      match self.sound_timer {
-        0 => {}
-        1 => {
-          self.audio_driver.play_sound();
-          self.sound_timer -= 1
-        }
-        _ => self.sound_timer -= 1,
+      if next_position + OP_SIZE as usize > self.memory_size() {
+        return Err(EmulationError::ProgramCounterOutOfRange {
+          program_counter: self.program_counter,
+          op_code,
+        });
       }
+
+      self.program_counter = next_position as u16;
     }
+
+    Ok(())
   }
 }
 
@@ -356,30 +1212,52 @@ impl<T: AudioDriver> Chip8<T> {
 mod tests {
   struct TAD {
     pub is_playing: bool,
+    pub tones_started: usize,
+    pub tone: Option<Tone>,
+    pub pattern: Option<(Pattern, f32)>,
   }
 
   impl AudioDriver for TAD {
     fn new() -> Self {
-      Self { is_playing: false }
+      Self {
+        is_playing: false,
+        tones_started: 0,
+        tone: None,
+        pattern: None,
+      }
     }
 
-    fn play_sound(&mut self) {
+    fn start_tone(&mut self) {
       self.is_playing = true;
+      self.tones_started += 1;
+    }
+
+    fn stop_tone(&mut self) {
+      self.is_playing = false;
+    }
+
+    fn set_tone(&mut self, tone: Tone) {
+      self.tone = Some(tone);
+    }
+
+    fn set_pattern(&mut self, pattern: Pattern, sample_rate: f32) {
+      self.pattern = Some((pattern, sample_rate));
     }
   }
 
   use super::*;
+  use crate::input::Button;
 
   fn emulate_cycles(chip: &mut Chip8<TAD>, number_of_cycles: usize) {
     for _ in 0..number_of_cycles {
-      chip.emulate_cycle();
+      chip.emulate_cycle().unwrap();
     }
   }
 
   #[test]
   fn load_cartridge_and_reset() {
-    let mut chip8 = Chip8::new(TAD::new());
-    chip8.load(vec![0xFF, 0xF1, 0x01, 0x22]);
+    let mut chip8 = Chip8::new(TAD::new(), Quirks::default());
+    chip8.load(vec![0xFF, 0xF1, 0x01, 0x22]).unwrap();
     assert_eq!(chip8.memory[512..=515], [0xFF, 0xF1, 0x01, 0x22]);
     chip8.reset();
     for index in 512..MEMORY_SIZE {
@@ -389,22 +1267,22 @@ mod tests {
 
   #[test]
   fn call_subroutine_return_and_jump() {
-    let mut chip8 = Chip8::new(TAD::new());
-    chip8.load(vec![0x22, 0x04, 0x12, 0x00, 0x00, 0xEE]);
-    chip8.emulate_cycle();
+    let mut chip8 = Chip8::new(TAD::new(), Quirks::default());
+    chip8.load(vec![0x22, 0x04, 0x12, 0x00, 0x00, 0xEE]).unwrap();
+    chip8.emulate_cycle().unwrap();
     assert_eq!(chip8.stack[0], 0x202);
     assert_eq!(chip8.stack_pointer, 1);
     assert_eq!(chip8.program_counter, 0x204);
-    chip8.emulate_cycle();
+    chip8.emulate_cycle().unwrap();
     assert_eq!(chip8.stack_pointer, 0);
     assert_eq!(chip8.program_counter, 0x202);
-    chip8.emulate_cycle();
+    chip8.emulate_cycle().unwrap();
     assert_eq!(chip8.program_counter, 0x200);
   }
 
   #[test]
   fn vx_operations() {
-    let mut chip8 = Chip8::new(TAD::new());
+    let mut chip8 = Chip8::new(TAD::new(), Quirks::default());
 
     let instructions = vec![
       0x61, 0xF0, // v1 = 0xf0
@@ -421,46 +1299,46 @@ mod tests {
       0x81, 0x25, // v1 = v1 - v2 => 0xf0; vf = 0x00
     ];
 
-    chip8.load(instructions);
+    chip8.load(instructions).unwrap();
 
-    chip8.emulate_cycle();
+    chip8.emulate_cycle().unwrap();
     assert_eq!(chip8.get_register(1), 0xF0);
-    chip8.emulate_cycle();
+    chip8.emulate_cycle().unwrap();
     assert_eq!(chip8.get_register(1), 0x01);
     assert_eq!(chip8.get_register(0xf), 0x00);
-    chip8.emulate_cycle();
+    chip8.emulate_cycle().unwrap();
     assert_eq!(chip8.get_register(1), chip8.get_register(2));
-    chip8.emulate_cycle();
-    chip8.emulate_cycle();
-    chip8.emulate_cycle();
+    chip8.emulate_cycle().unwrap();
+    chip8.emulate_cycle().unwrap();
+    chip8.emulate_cycle().unwrap();
     assert_eq!(chip8.get_register(1), 0xf1);
-    chip8.emulate_cycle();
+    chip8.emulate_cycle().unwrap();
     assert_eq!(chip8.get_register(1), 0x11);
-    chip8.emulate_cycle();
-    chip8.emulate_cycle();
+    chip8.emulate_cycle().unwrap();
+    chip8.emulate_cycle().unwrap();
     assert_eq!(chip8.get_register(1), 0x30);
-    chip8.emulate_cycle();
-    chip8.emulate_cycle();
+    chip8.emulate_cycle().unwrap();
+    chip8.emulate_cycle().unwrap();
     assert_eq!(chip8.get_register(1), 0x01);
     assert_eq!(chip8.get_register(0xf), 0x01);
-    chip8.emulate_cycle();
+    chip8.emulate_cycle().unwrap();
     assert_eq!(chip8.get_register(1), 0xf0);
     assert_eq!(chip8.get_register(0xf), 0x00);
   }
 
   #[test]
   fn set_i_register() {
-    let mut chip8 = Chip8::new(TAD::new());
+    let mut chip8 = Chip8::new(TAD::new(), Quirks::default());
 
     let instructions = vec![
       0xA5, 0x00, 0x60, 0x05, 0xF0, 0x1E, 0x60, 0x03, 0xF0, 0x29, 0xA5, 0x00, 0x60, 218, 0xF0, 0x33,
     ];
 
-    chip8.load(instructions);
+    chip8.load(instructions).unwrap();
 
     assert_eq!(chip8.index, 0x0);
 
-    chip8.emulate_cycle();
+    chip8.emulate_cycle().unwrap();
 
     assert_eq!(chip8.index, 0x500);
 
@@ -481,7 +1359,7 @@ mod tests {
 
   #[test]
   fn dump_and_load_registers() {
-    let mut chip8 = Chip8::new(TAD::new());
+    let mut chip8 = Chip8::new(TAD::new(), Quirks::default());
 
     let instructions = vec![
       0xA4, 0x00, 0x60, 0xF0, 0x61, 0xDD, 0x62, 0x1E, 0x63, 0x17, 0x64, 0x4D, 0x65, 0x29, 0xF5,
@@ -489,15 +1367,15 @@ mod tests {
       0xF5, 0x65,
     ];
 
-    chip8.load(instructions);
+    chip8.load(instructions).unwrap();
 
-    chip8.emulate_cycle();
+    chip8.emulate_cycle().unwrap();
 
     assert_eq!(chip8.index, 0x400);
 
     emulate_cycles(&mut chip8, 6);
 
-    chip8.emulate_cycle();
+    chip8.emulate_cycle().unwrap();
 
     assert_eq!(chip8.index, 0x406);
 
@@ -526,26 +1404,1045 @@ mod tests {
 
   #[test]
   fn timers() {
-    let mut chip8 = Chip8::new(TAD::new());
+    let mut chip8 = Chip8::new(TAD::new(), Quirks::default());
 
     let instructions = vec![0x60, 0x02, 0xF0, 0x15, 0xF0, 0x18];
 
-    chip8.load(instructions);
+    chip8.load(instructions).unwrap();
+
+    emulate_cycles(&mut chip8, 3);
 
-    chip8.emulate_cycle();
+    assert_eq!(chip8.delay_timer, 2);
+    assert_eq!(chip8.sound_timer, 2);
+    assert!(chip8.audio_driver.is_playing);
 
-    chip8.emulate_cycle();
+    chip8.tick_timers();
 
     assert_eq!(chip8.delay_timer, 1);
+    assert_eq!(chip8.sound_timer, 1);
+    assert!(chip8.audio_driver.is_playing);
 
-    chip8.emulate_cycle();
+    chip8.tick_timers();
 
     assert_eq!(chip8.delay_timer, 0);
-    assert_eq!(chip8.sound_timer, 1);
+    assert!(!chip8.audio_driver.is_playing);
+    assert_eq!(chip8.sound_timer, 0);
 
-    chip8.emulate_cycle();
+    chip8.tick_timers();
 
+    assert_eq!(chip8.delay_timer, 0);
+  }
+
+  #[test]
+  fn tone_follows_the_sound_timer() {
+    let instructions = vec![
+      0x60, 0x03, // v0 = 3
+      0xF0, 0x18, // sound = v0
+      0xF0, 0x18, // sound = v0
+      0x60, 0x00, // v0 = 0
+      0xF0, 0x18, // sound = v0
+    ];
+    let mut chip8 = Chip8::new(TAD::new(), Quirks::default());
+    chip8.load(instructions).unwrap();
+
+    emulate_cycles(&mut chip8, 2);
     assert!(chip8.audio_driver.is_playing);
-    assert_eq!(chip8.sound_timer, 0);
+    chip8.tick_timers();
+    emulate_cycles(&mut chip8, 1);
+    assert_eq!(chip8.audio_driver.tones_started, 1);
+    emulate_cycles(&mut chip8, 2);
+    assert!(!chip8.audio_driver.is_playing);
+
+    chip8.set_sound_timer(1);
+    assert!(chip8.audio_driver.is_playing);
+    assert_eq!(chip8.audio_driver.tones_started, 2);
+    chip8.reset();
+    assert!(!chip8.audio_driver.is_playing);
+
+    chip8.set_tone(Tone::default());
+    assert_eq!(chip8.audio_driver.tone, Some(Tone::default()));
+  }
+
+  #[test]
+  fn renders_the_buzzer_in_step_with_the_sound_timer() {
+    let instructions = vec![
+      0x60, 0x02, // v0 = 2
+      0xF0, 0x18, // sound = v0
+      0x12, 0x04, // jump to itself
+    ];
+    let mut chip8 = Chip8::new(TAD::new(), Quirks::default());
+    chip8.set_instructions_per_second(600);
+    chip8.load(instructions).unwrap();
+    for _ in 0..4 {
+      chip8.run_frame().unwrap();
+    }
+
+    // 7680 Hz is 128 samples per frame, and the timer is set by the second
+    // of ten instructions in the first one, at sample 12.8. The square wave
+    // starts halfway up its edge, at 0.
+    assert_eq!(chip8.pending_audio_samples(7680), 512);
+    let mut samples = vec![0.; 512];
+    chip8.fill_audio(&mut samples, 7680);
+    let sounding: Vec<usize> = (0..512).filter(|index| samples[*index] != 0.).collect();
+    assert_eq!(sounding.first(), Some(&14));
+    assert_eq!(sounding.last(), Some(&255));
+    assert_eq!(chip8.pending_audio_samples(7680), 0);
+  }
+
+  fn with_quirks(quirks: Quirks, instructions: Vec<u8>) -> Chip8<TAD> {
+    let mut chip8 = Chip8::new(TAD::new(), quirks);
+    chip8.load(instructions).unwrap();
+    chip8
+  }
+
+  fn with_octo(quirks: Quirks, source: &str) -> Chip8<TAD> {
+    with_quirks(quirks, crate::octo::compile(source).unwrap())
+  }
+
+  fn run_until_halted(chip8: &mut Chip8<TAD>) {
+    for _ in 0..10_000 {
+      if chip8.is_halted() {
+        return;
+      }
+      chip8.emulate_cycle().unwrap();
+    }
+    panic!("the program never exited");
+  }
+
+  #[test]
+  fn shift_quirk() {
+    let instructions = vec![
+      0x61, 0x01, // v1 = 0x01
+      0x62, 0x82, // v2 = 0x82
+      0x81, 0x26, // v1 = v2 >> 1 or v1 >> 1
+      0x63, 0x01, // v3 = 0x01
+      0x83, 0x2E, // v3 = v2 << 1 or v3 << 1
+    ];
+
+    let mut chip8 = with_quirks(
+      Quirks {
+        shift_uses_vy: true,
+        ..Quirks::default()
+      },
+      instructions.clone(),
+    );
+    emulate_cycles(&mut chip8, 3);
+    assert_eq!(chip8.get_register(1), 0x41);
+    assert_eq!(chip8.get_register(0xF), 0x0);
+    emulate_cycles(&mut chip8, 2);
+    assert_eq!(chip8.get_register(3), 0x04);
+    assert_eq!(chip8.get_register(0xF), 0x1);
+
+    let mut chip8 = with_quirks(
+      Quirks {
+        shift_uses_vy: false,
+        ..Quirks::default()
+      },
+      instructions,
+    );
+    emulate_cycles(&mut chip8, 3);
+    assert_eq!(chip8.get_register(1), 0x00);
+    assert_eq!(chip8.get_register(0xF), 0x1);
+    emulate_cycles(&mut chip8, 2);
+    assert_eq!(chip8.get_register(3), 0x02);
+    assert_eq!(chip8.get_register(0xF), 0x0);
+  }
+
+  #[test]
+  fn load_store_quirk() {
+    let instructions = vec![
+      0xA4, 0x00, // i = 0x400
+      0xF2, 0x55, // dump v0..v2
+      0xF2, 0x65, // load v0..v2
+    ];
+
+    let mut chip8 = with_quirks(
+      Quirks {
+        load_store_increments_index: true,
+        ..Quirks::default()
+      },
+      instructions.clone(),
+    );
+    emulate_cycles(&mut chip8, 2);
+    assert_eq!(chip8.index, 0x403);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.index, 0x406);
+
+    let mut chip8 = with_quirks(
+      Quirks {
+        load_store_increments_index: false,
+        ..Quirks::default()
+      },
+      instructions,
+    );
+    emulate_cycles(&mut chip8, 2);
+    assert_eq!(chip8.index, 0x400);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.index, 0x400);
+  }
+
+  #[test]
+  fn jump_quirk() {
+    let instructions = vec![
+      0x60, 0x02, // v0 = 0x02
+      0x63, 0x04, // v3 = 0x04
+      0xB3, 0x00, // jump to 0x300 + v0 or 0x300 + v3
+    ];
+
+    let mut chip8 = with_quirks(
+      Quirks {
+        jump_uses_vx: false,
+        ..Quirks::default()
+      },
+      instructions.clone(),
+    );
+    emulate_cycles(&mut chip8, 3);
+    assert_eq!(chip8.program_counter, 0x302);
+
+    let mut chip8 = with_quirks(
+      Quirks {
+        jump_uses_vx: true,
+        ..Quirks::default()
+      },
+      instructions,
+    );
+    emulate_cycles(&mut chip8, 3);
+    assert_eq!(chip8.program_counter, 0x304);
+  }
+
+  #[test]
+  fn logic_quirk() {
+    let instructions = vec![
+      0x6F, 0x05, // vf = 0x05
+      0x81, 0x21, // v1 = v1 | v2
+      0x6F, 0x05, // vf = 0x05
+      0x81, 0x22, // v1 = v1 & v2
+      0x6F, 0x05, // vf = 0x05
+      0x81, 0x23, // v1 = v1 ^ v2
+    ];
+
+    let mut chip8 = with_quirks(
+      Quirks {
+        logic_resets_vf: true,
+        ..Quirks::default()
+      },
+      instructions.clone(),
+    );
+    for _ in 0..3 {
+      emulate_cycles(&mut chip8, 2);
+      assert_eq!(chip8.get_register(0xF), 0x0);
+    }
+
+    let mut chip8 = with_quirks(
+      Quirks {
+        logic_resets_vf: false,
+        ..Quirks::default()
+      },
+      instructions,
+    );
+    for _ in 0..3 {
+      emulate_cycles(&mut chip8, 2);
+      assert_eq!(chip8.get_register(0xF), 0x5);
+    }
+  }
+
+  #[test]
+  fn wrap_quirk() {
+    let instructions = vec![
+      0x60, 0x3E, // v0 = 62
+      0x61, 0x1E, // v1 = 30
+      0xF2, 0x29, // i = font sprite for v2 (0)
+      0xD0, 0x15, // draw 0 at (62, 30)
+    ];
+
+    let mut chip8 = with_quirks(
+      Quirks {
+        wrap_sprites: true,
+        ..Quirks::default()
+      },
+      instructions.clone(),
+    );
+    emulate_cycles(&mut chip8, 4);
+    assert_eq!(chip8.display.pixel(0, 30), 1);
+    assert_eq!(chip8.display.pixel(1, 0), 1);
+
+    let mut chip8 = with_quirks(
+      Quirks {
+        wrap_sprites: false,
+        ..Quirks::default()
+      },
+      instructions,
+    );
+    emulate_cycles(&mut chip8, 4);
+    assert_eq!(chip8.display.pixel(0, 30), 0);
+    assert_eq!(chip8.display.pixel(1, 0), 0);
+  }
+
+  #[test]
+  fn resolution_and_scrolling() {
+    let instructions = vec![
+      0x00, 0xFF, // hires
+      0xA0, 0x00, // i = font sprite for 0
+      0xD0, 0x01, // draw first line of 0 at (0, 0)
+      0x00, 0xC2, // scroll down 2 lines
+      0x00, 0xFB, // scroll right 4 pixels
+      0x00, 0xFC, // scroll left 4 pixels
+      0x00, 0xFE, // lores
+    ];
+
+    let mut chip8 = with_quirks(Quirks::super_chip(), instructions);
+    chip8.emulate_cycle().unwrap();
+    assert!(chip8.display.is_hires());
+    assert_eq!(chip8.display.width(), 128);
+    emulate_cycles(&mut chip8, 2);
+    assert_eq!(chip8.display.pixel(0, 0), 1);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.display.pixel(0, 0), 0);
+    assert_eq!(chip8.display.pixel(0, 2), 1);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.display.pixel(0, 2), 0);
+    assert_eq!(chip8.display.pixel(4, 2), 1);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.display.pixel(0, 2), 1);
+    chip8.emulate_cycle().unwrap();
+    assert!(!chip8.display.is_hires());
+    assert!(chip8.display.pixels().all(|pixel| pixel == 0));
+  }
+
+  #[test]
+  fn large_sprite_counts_collided_lines() {
+    let instructions = vec![
+      0x00, 0xFF, // hires
+      0x60, 0x00, // v0 = 0
+      0xA0, 0x50, // i = big font sprite for 0
+      0xD0, 0x00, // draw 16x16 at (0, 0)
+      0xD0, 0x00, // draw it again
+    ];
+
+    let mut chip8 = with_quirks(Quirks::super_chip(), instructions);
+    emulate_cycles(&mut chip8, 4);
+    assert_eq!(chip8.get_register(0xF), 0);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.get_register(0xF), 16);
+    assert!(chip8.display.pixels().all(|pixel| pixel == 0));
+  }
+
+  #[test]
+  fn big_font_sprite() {
+    let instructions = vec![
+      0x63, 0x03, // v3 = 3
+      0xF3, 0x30, // i = big font sprite for v3
+    ];
+
+    let mut chip8 = with_quirks(Quirks::super_chip(), instructions);
+    emulate_cycles(&mut chip8, 2);
+    assert_eq!(chip8.index, 0x50 + 30);
+    assert_eq!(chip8.get_memory(chip8.index + 2), 0x03);
+  }
+
+  #[test]
+  fn rpl_flags_survive_reset() {
+    let instructions = vec![
+      0x60, 0x11, // v0 = 0x11
+      0x61, 0x22, // v1 = 0x22
+      0xF1, 0x75, // store v0..v1 in flags
+      0x60, 0x00, // v0 = 0
+      0xF1, 0x85, // load v0..v1 from flags
+    ];
+
+    let mut chip8 = with_quirks(Quirks::super_chip(), instructions.clone());
+    emulate_cycles(&mut chip8, 4);
+    assert_eq!(chip8.get_register(0), 0);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.get_register(0), 0x11);
+    assert_eq!(chip8.get_register(1), 0x22);
+
+    chip8.reset();
+    chip8.load(vec![0xF1, 0x85]).unwrap();
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.get_register(0), 0x11);
+    assert_eq!(chip8.rpl_flags()[1], 0x22);
+  }
+
+  #[test]
+  fn exit_halts() {
+    let instructions = vec![
+      0x00, 0xFD, // exit
+      0x60, 0x01, // v0 = 1
+    ];
+
+    let mut chip8 = with_quirks(Quirks::super_chip(), instructions);
+    emulate_cycles(&mut chip8, 2);
+    assert!(chip8.is_halted());
+    assert_eq!(chip8.program_counter, 0x200);
+    assert_eq!(chip8.get_register(0), 0);
+  }
+
+  #[test]
+  fn long_index_and_skips() {
+    let instructions = vec![
+      0xF0, 0x00, 0xAB, 0xCD, // i = 0xabcd
+      0x30, 0x00, // skip if v0 == 0
+      0xF0, 0x00, 0x12, 0x34, // i = 0x1234, skipped as a whole
+      0x60, 0x01, // v0 = 1
+    ];
+
+    let mut chip8 = with_quirks(Quirks::modern(), instructions);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.index, 0xABCD);
+    assert_eq!(chip8.program_counter, 0x204);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.program_counter, 0x20A);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.index, 0xABCD);
+    assert_eq!(chip8.get_register(0), 1);
+  }
+
+  #[test]
+  fn register_ranges() {
+    let instructions = vec![
+      0xF0, 0x00, 0xF0, 0x00, // i = 0xf000
+      0x61, 0x11, // v1 = 0x11
+      0x62, 0x22, // v2 = 0x22
+      0x63, 0x33, // v3 = 0x33
+      0x51, 0x32, // save v1..v3
+      0x53, 0x13, // load v3..v1
+    ];
+
+    let mut chip8 = with_quirks(Quirks::modern(), instructions);
+    emulate_cycles(&mut chip8, 5);
+    assert_eq!(chip8.index, 0xF000);
+    assert_eq!(chip8.memory[0xF000..0xF003], [0x11, 0x22, 0x33]);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.get_register(1), 0x33);
+    assert_eq!(chip8.get_register(2), 0x22);
+    assert_eq!(chip8.get_register(3), 0x11);
+  }
+
+  #[test]
+  fn draw_on_selected_planes() {
+    let instructions = vec![
+      0xF3, 0x01, // select both planes
+      0xA3, 0x00, // i = 0x300
+      0xD0, 0x01, // draw one line on each plane at (0, 0)
+    ];
+
+    let mut chip8 = with_quirks(Quirks::modern(), instructions);
+    chip8.memory[0x300] = 0b10000000;
+    chip8.memory[0x301] = 0b11000000;
+    emulate_cycles(&mut chip8, 3);
+    assert_eq!(chip8.display.pixel(0, 0), 0b11);
+    assert_eq!(chip8.display.pixel(1, 0), 0b10);
+  }
+
+  #[test]
+  fn audio_pattern_and_pitch() {
+    let instructions = vec![
+      0xA3, 0x00, // i = 0x300
+      0xF0, 0x02, // load audio pattern
+      0x60, 0x70, // v0 = 112
+      0xF0, 0x3A, // pitch = v0
+    ];
+
+    let mut chip8 = with_quirks(Quirks::modern(), instructions);
+    chip8.memory[0x300] = 0xF0;
+    chip8.memory[0x30F] = 0x0F;
+    emulate_cycles(&mut chip8, 2);
+    let (pattern, sample_rate) = chip8.audio_driver.pattern.unwrap();
+    assert_eq!(pattern[0], 0xF0);
+    assert_eq!(pattern[15], 0x0F);
+    assert_eq!(sample_rate, 4000.);
+    emulate_cycles(&mut chip8, 2);
+    let (_, sample_rate) = chip8.audio_driver.pattern.unwrap();
+    assert_eq!(sample_rate, 8000.);
+  }
+
+  #[test]
+  fn rom_too_large() {
+    let mut chip8 = Chip8::new(TAD::new(), Quirks::default());
+    assert_eq!(
+      chip8.load(vec![0; MEMORY_SIZE - 0x1FF]),
+      Err(EmulationError::RomTooLarge {
+        size: MEMORY_SIZE - 0x1FF,
+        max_size: MEMORY_SIZE - 0x200
+      })
+    );
+    assert!(chip8.load(vec![0; MEMORY_SIZE - 0x200]).is_ok());
+  }
+
+  #[test]
+  fn classic_presets_address_4_kib() {
+    for quirks in [Quirks::cosmac_vip(), Quirks::chip48(), Quirks::super_chip()].iter() {
+      let mut chip8 = Chip8::new(TAD::new(), *quirks);
+      assert_eq!(chip8.memory().len(), CLASSIC_MEMORY_SIZE);
+      assert_eq!(
+        chip8.load(vec![0; CLASSIC_MEMORY_SIZE - 0x1FF]),
+        Err(EmulationError::RomTooLarge {
+          size: CLASSIC_MEMORY_SIZE - 0x1FF,
+          max_size: CLASSIC_MEMORY_SIZE - 0x200
+        })
+      );
+
+      let mut chip8 = with_quirks(
+        *quirks,
+        vec![
+          0xAF, 0xFF, // I = 0xFFF
+          0xF1, 0x65, // load v0 and v1
+        ],
+      );
+      chip8.emulate_cycle().unwrap();
+      assert_eq!(
+        chip8.emulate_cycle(),
+        Err(EmulationError::MemoryOutOfBounds {
+          program_counter: 0x202,
+          op_code: 0xF165,
+          address: 0xFFF,
+          length: 2,
+        })
+      );
+
+      let mut chip8 = with_quirks(*quirks, vec![0x1F, 0xFF]);
+      assert_eq!(
+        chip8.emulate_cycle(),
+        Err(EmulationError::ProgramCounterOutOfRange {
+          program_counter: 0x200,
+          op_code: 0x1FFF
+        })
+      );
+    }
+  }
+
+  #[test]
+  fn stack_underflow() {
+    let mut chip8 = with_quirks(Quirks::default(), vec![0x00, 0xEE]);
+    assert_eq!(
+      chip8.emulate_cycle(),
+      Err(EmulationError::StackUnderflow {
+        program_counter: 0x200,
+        op_code: 0x00EE
+      })
+    );
+  }
+
+  #[test]
+  fn stack_overflow() {
+    let mut chip8 = with_quirks(Quirks::default(), vec![0x22, 0x00]);
+    emulate_cycles(&mut chip8, STACK_SIZE);
+    assert_eq!(
+      chip8.emulate_cycle(),
+      Err(EmulationError::StackOverflow {
+        program_counter: 0x200,
+        op_code: 0x2200
+      })
+    );
+  }
+
+  #[test]
+  fn memory_out_of_bounds() {
+    let instructions = vec![
+      0xF0, 0x00, 0xFF, 0xFC, // i = 0xfffc
+      0xD0, 0x15, // draw 5 lines
+    ];
+
+    let mut chip8 = with_quirks(Quirks::default(), instructions);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(
+      chip8.emulate_cycle(),
+      Err(EmulationError::MemoryOutOfBounds {
+        program_counter: 0x204,
+        op_code: 0xD015,
+        address: 0xFFFC,
+        length: 5
+      })
+    );
+    assert_eq!(chip8.program_counter, 0x204);
+  }
+
+  #[test]
+  fn store_out_of_bounds_writes_nothing() {
+    let instructions = vec![
+      0xF0, 0x00, 0xFF, 0xFE, // i = 0xfffe
+      0x60, 0xAA, // v0 = 0xaa
+      0xF2, 0x55, // dump v0..v2
+    ];
+
+    let mut chip8 = with_quirks(Quirks::default(), instructions);
+    emulate_cycles(&mut chip8, 2);
+    assert!(chip8.emulate_cycle().is_err());
+    assert_eq!(chip8.get_memory(0xFFFE), 0);
+    assert_eq!(chip8.index, 0xFFFE);
+  }
+
+  #[test]
+  fn program_counter_out_of_range() {
+    let mut chip8 = with_quirks(Quirks::default(), vec![0x1F, 0xFF]);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.program_counter, 0xFFF);
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.program_counter, 0x1001);
+
+    let mut chip8 = with_quirks(Quirks::default(), vec![0x00, 0xE0]);
+    chip8.program_counter = 0xFFFE;
+    chip8.memory[0xFFFE] = 0x00;
+    chip8.memory[0xFFFF] = 0xE0;
+    assert_eq!(
+      chip8.emulate_cycle(),
+      Err(EmulationError::ProgramCounterOutOfRange {
+        program_counter: 0xFFFE,
+        op_code: 0x00E0
+      })
+    );
+  }
+
+  #[test]
+  fn run_frame_ticks_timers_once() {
+    let instructions = vec![
+      0x60, 0x05, // v0 = 5
+      0xF0, 0x15, // delay = v0
+      0x70, 0x01, // v0 += 1
+      0x12, 0x04, // jump to 0x204
+    ];
+
+    let mut chip8 = with_quirks(Quirks::default(), instructions);
+    chip8.set_instructions_per_frame(10);
+    chip8.run_frame().unwrap();
+    assert_eq!(chip8.delay_timer, 4);
+    assert_eq!(chip8.get_register(0), 5 + 4);
+    chip8.run_frame().unwrap();
+    assert_eq!(chip8.delay_timer, 3);
+    assert_eq!(chip8.get_register(0), 5 + 9);
+  }
+
+  #[test]
+  fn runs_code_it_has_just_rewritten() {
+    let instructions = vec![
+      0x72, 0x01, // v2 += 1, rewritten to v3 += 1
+      0x34, 0x01, // skip if v4 == 1
+      0x12, 0x0A, // jump to 0x20A
+      0x00, 0xFD, // exit
+      0x00, 0x00, // padding
+      0x60, 0x73, // v0 = 0x73
+      0x61, 0x01, // v1 = 0x01
+      0x64, 0x01, // v4 = 1
+      0xA2, 0x00, // i = 0x200
+      0xF1, 0x55, // save v0 - v1
+      0x12, 0x00, // jump to 0x200
+    ];
+
+    for cached in [true, false].iter() {
+      let mut chip8 = with_quirks(Quirks::default(), instructions.clone());
+      chip8.set_decode_cache(*cached);
+      run_until_halted(&mut chip8);
+      assert_eq!(chip8.get_register(2), 1);
+      assert_eq!(chip8.get_register(3), 1);
+    }
+  }
+
+  #[test]
+  fn run_frame_while_stops_before_the_timers() {
+    let instructions = vec![
+      0x60, 0x05, // v0 = 5
+      0xF0, 0x15, // delay = v0
+      0x70, 0x01, // v0 += 1
+      0x12, 0x04, // jump to 0x204
+    ];
+
+    let mut chip8 = with_quirks(Quirks::default(), instructions);
+    chip8.set_instructions_per_frame(10);
+    assert_eq!(
+      chip8.run_frame_while(|chip8| chip8.get_register(0) < 7),
+      Ok(false)
+    );
+    assert_eq!(chip8.program_counter(), 0x206);
+    assert_eq!(chip8.get_register(0), 7);
+    assert_eq!(chip8.delay_timer, 5);
+    assert_eq!(chip8.run_frame_while(|_| true), Ok(true));
+    assert_eq!(chip8.delay_timer, 4);
+  }
+
+  #[test]
+  fn key_skips_use_the_low_nibble_of_vx() {
+    let instructions = vec![
+      0x60, 0x20, // v0 = 0x20
+      0xE0, 0x9E, // skip if key 0 is pressed
+      0x61, 0x01, // v1 = 1
+      0xE0, 0xA1, // skip if key 0 is not pressed
+      0x62, 0x01, // v2 = 1
+    ];
+
+    let mut chip8 = with_quirks(Quirks::default(), instructions.clone());
+    emulate_cycles(&mut chip8, 5);
+    assert_eq!(chip8.get_register(1), 1);
+    assert_eq!(chip8.get_register(2), 0);
+
+    let mut chip8 = with_quirks(Quirks::default(), instructions);
+    chip8.input.key_down(Button::Zero);
+    emulate_cycles(&mut chip8, 4);
+    assert_eq!(chip8.get_register(1), 0);
+    assert_eq!(chip8.get_register(2), 1);
+  }
+
+  #[test]
+  fn run_for_accumulates_host_time() {
+    let instructions = vec![
+      0x70, 0x01, // v0 += 1
+      0x12, 0x00, // jump to 0x200
+    ];
+
+    let mut chip8 = with_quirks(Quirks::default(), instructions);
+    chip8.set_instructions_per_second(600);
+    for _ in 0..500 {
+      chip8.run_for(Duration::from_millis(2)).unwrap();
+    }
+    assert_eq!(chip8.get_register(0), (600 / 2) as u8);
+  }
+
+  #[test]
+  fn run_frame_keeps_draws_from_earlier_instructions() {
+    let instructions = vec![
+      0x00, 0xE0, // clear
+      0x12, 0x02, // jump to 0x202
+    ];
+
+    let mut chip8 = with_quirks(Quirks::default(), instructions);
+    chip8.run_frame().unwrap();
+    assert!(chip8.should_draw());
+    chip8.run_frame().unwrap();
+    assert!(!chip8.should_draw());
+  }
+
+  fn key_wait(quirks: Quirks) -> Chip8<TAD> {
+    let instructions = vec![
+      0x60, 0x00, // v0 = 0
+      0xF1, 0x0A, // wait for a key in v1
+      0x62, 0x01, // v2 = 1
+      0x12, 0x06, // jump to itself
+    ];
+    let mut chip8 = with_quirks(quirks, instructions);
+    chip8.set_instructions_per_frame(1);
+    chip8
+  }
+
+  #[test]
+  fn key_wait_ignores_keys_held_from_before() {
+    for quirks in [Quirks::cosmac_vip(), Quirks::chip48()].iter() {
+      let mut chip8 = key_wait(*quirks);
+      chip8.input.key_down(Button::Five);
+      for _ in 0..3 {
+        chip8.run_frame().unwrap();
+      }
+      assert!(chip8.is_waiting_for_key());
+
+      chip8.input.key_up(Button::Five);
+      chip8.run_frame().unwrap();
+      assert!(chip8.is_waiting_for_key());
+    }
+  }
+
+  #[test]
+  fn key_wait_ends_on_release_or_press() {
+    let mut chip8 = key_wait(Quirks::cosmac_vip());
+    chip8.run_frame().unwrap();
+    chip8.run_frame().unwrap();
+    chip8.input.key_down(Button::Seven);
+    chip8.run_frame().unwrap();
+    chip8.run_frame().unwrap();
+    assert!(chip8.is_waiting_for_key());
+    chip8.input.key_up(Button::Seven);
+    chip8.run_frame().unwrap();
+    assert!(!chip8.is_waiting_for_key());
+    assert_eq!(chip8.get_register(1), 7);
+
+    let mut chip8 = key_wait(Quirks::chip48());
+    chip8.run_frame().unwrap();
+    chip8.run_frame().unwrap();
+    chip8.input.key_down(Button::Seven);
+    chip8.run_frame().unwrap();
+    assert!(!chip8.is_waiting_for_key());
+    assert_eq!(chip8.get_register(1), 7);
+  }
+
+  #[test]
+  fn key_wait_catches_taps_within_a_frame() {
+    let mut chip8 = key_wait(Quirks::cosmac_vip());
+    chip8.run_frame().unwrap();
+    chip8.run_frame().unwrap();
+    chip8.input.key_down(Button::A);
+    chip8.input.key_up(Button::A);
+    chip8.run_frame().unwrap();
+    assert_eq!(chip8.get_register(1), 0xA);
+    chip8.run_frame().unwrap();
+    assert_eq!(chip8.get_register(2), 1);
+  }
+
+  #[test]
+  fn save_states_keep_the_key_being_waited_for() {
+    let mut chip8 = key_wait(Quirks::cosmac_vip());
+    chip8.run_frame().unwrap();
+    chip8.run_frame().unwrap();
+    chip8.input.key_down(Button::Nine);
+    chip8.run_frame().unwrap();
+    let state = chip8.save_state();
+
+    // Only the release is left, so no second press is needed.
+    let mut restored = Chip8::new(TAD::new(), Quirks::default());
+    restored.load_state(&state).unwrap();
+    restored.set_instructions_per_frame(1);
+    restored.input = chip8.input;
+    restored.input.key_up(Button::Nine);
+    restored.run_frame().unwrap();
+    assert!(!restored.is_waiting_for_key());
+    assert_eq!(restored.get_register(1), 9);
+  }
+
+  #[test]
+  fn timers_run_while_waiting_for_a_key() {
+    let mut chip8 = key_wait(Quirks::cosmac_vip());
+    chip8.set_delay_timer(10);
+    chip8.set_sound_timer(10);
+    for _ in 0..5 {
+      chip8.run_frame().unwrap();
+    }
+    assert!(chip8.is_waiting_for_key());
+    assert_eq!(chip8.delay_timer(), 5);
+    assert_eq!(chip8.sound_timer(), 5);
+  }
+
+  #[test]
+  fn save_state_round_trips() {
+    let instructions = vec![
+      0x00, 0xFF, // high resolution
+      0x60, 0x2A, // v0 = 0x2A
+      0xA0, 0x00, // I = font 0
+      0xF0, 0x15, // delay = v0
+      0xD0, 0x05, // draw font 0 at (v0, v0)
+      0x22, 0x10, // call 0x210
+      0x00, 0x00, // unreachable
+      0x00, 0x00, // unreachable
+      0xF1, 0x0A, // wait for a key in v1
+    ];
+
+    let mut chip8 = with_quirks(Quirks::cosmac_vip(), instructions);
+    emulate_cycles(&mut chip8, 7);
+    let state = chip8.save_state();
+
+    let mut restored = Chip8::new(TAD::new(), Quirks::default());
+    restored.load_state(&state).unwrap();
+    assert_eq!(restored.save_state(), state);
+    assert_eq!(restored.quirks(), Quirks::cosmac_vip());
+    assert_eq!(restored.program_counter, 0x212);
+    assert_eq!(restored.stack_pointer, 1);
+    assert_eq!(restored.delay_timer, 0x2A);
+    assert_eq!(restored.waiting_for_key, Some(1));
+    assert!(restored.display.is_hires());
+    assert!(restored.display == chip8.display);
+    assert!(restored.should_draw());
+  }
+
+  #[test]
+  fn load_state_rejects_bad_data() {
+    let mut chip8 = with_quirks(Quirks::default(), vec![0x60, 0x01]);
+    let state = chip8.save_state();
+
+    assert_eq!(chip8.load_state(b"ROM!"), Err(StateError::NotAState));
+
+    let mut future = state.clone();
+    future[4] = STATE_VERSION + 1;
+    assert_eq!(
+      chip8.load_state(&future),
+      Err(StateError::UnsupportedVersion {
+        version: STATE_VERSION + 1,
+        supported: STATE_VERSION,
+      })
+    );
+
+    // Version 1 had no room for the awaited key.
+    let mut older = state.clone();
+    older[4] = 1;
+    assert_eq!(
+      chip8.load_state(&older),
+      Err(StateError::UnsupportedVersion {
+        version: 1,
+        supported: STATE_VERSION,
+      })
+    );
+
+    assert_eq!(
+      chip8.load_state(&state[..state.len() - 1]),
+      Err(StateError::InvalidSize {
+        size: state.len() - 1,
+        expected: state.len(),
+      })
+    );
+
+    // The memory follows the quirks, so a classic machine's is too short.
+    let mut classic = state.clone();
+    classic[5] = Quirks::cosmac_vip().to_bits();
+    assert_eq!(
+      chip8.load_state(&classic),
+      Err(StateError::InvalidSize {
+        size: state.len(),
+        expected: state.len() - MEMORY_SIZE + CLASSIC_MEMORY_SIZE,
+      })
+    );
+
+    let mut corrupted = state.clone();
+    corrupted[7] = 0xFF;
+    assert_eq!(
+      chip8.load_state(&corrupted),
+      Err(StateError::InvalidField {
+        field: "display planes"
+      })
+    );
+
+    emulate_cycles(&mut chip8, 1);
+    assert_eq!(chip8.get_register(0), 1);
+  }
+
+  #[test]
+  fn loaded_states_run_on_like_the_original() {
+    let source = "
+      : main
+      loop
+        v0 := random 0xFF
+        v1 += v0
+        delay := v0
+        buzzer := v1
+      again
+    ";
+    let mut chip8 = with_octo(Quirks::default(), source);
+    chip8.set_instructions_per_second(650);
+    for _ in 0..7 {
+      chip8.run_frame().unwrap();
+    }
+    let state = chip8.save_state();
+
+    let mut restored = Chip8::with_random_source(TAD::new(), Quirks::default(), XorShift::new(7));
+    restored.set_instructions_per_second(650);
+    restored.load_state(&state).unwrap();
+    for _ in 0..5 {
+      chip8.run_frame().unwrap();
+      restored.run_frame().unwrap();
+    }
+    assert_eq!(restored.registers, chip8.registers);
+    assert_eq!(restored.save_state(), chip8.save_state());
+  }
+
+  #[test]
+  fn save_states_keep_only_what_the_machine_uses() {
+    let chip8 = with_quirks(Quirks::cosmac_vip(), vec![0x12, 0x00]);
+    let state = chip8.save_state();
+    assert!(state.len() < 5 * 1024);
+
+    let mut restored = Chip8::new(TAD::new(), Quirks::default());
+    restored.load_state(&state).unwrap();
+    assert_eq!(restored.memory().len(), CLASSIC_MEMORY_SIZE);
+    assert_eq!(restored.save_state(), state);
+  }
+
+  struct Sequence(Vec<u8>);
+
+  impl RandomSource for Sequence {
+    fn next_byte(&mut self) -> u8 {
+      self.0.remove(0)
+    }
+  }
+
+  #[test]
+  fn random_is_masked() {
+    let mut chip8 =
+      Chip8::with_random_source(TAD::new(), Quirks::default(), Sequence(vec![0xAB, 0xFF]));
+    chip8.load(vec![0xC0, 0x0F, 0xC1, 0xF0]).unwrap();
+    chip8.emulate_cycle().unwrap();
+    chip8.emulate_cycle().unwrap();
+    assert_eq!(chip8.get_register(0), 0x0B);
+    assert_eq!(chip8.get_register(1), 0xF0);
+  }
+
+  #[test]
+  fn random_is_reproducible_with_a_seed() {
+    let run = |seed| {
+      let mut chip8 = Chip8::with_random_source(TAD::new(), Quirks::default(), XorShift::new(seed));
+      chip8
+        .load(vec![0xC0, 0xFF, 0xC1, 0xFF, 0xC2, 0xFF])
+        .unwrap();
+      emulate_cycles(&mut chip8, 3);
+      chip8.registers
+    };
+
+    assert_eq!(run(7), run(7));
+    assert_ne!(run(7), run(8));
+  }
+
+  #[test]
+  fn octo_loops_and_comparisons() {
+    let mut chip8 = with_octo(
+      Quirks::modern(),
+      "
+      : main
+        v0 := 0
+        v1 := 0
+        loop
+          v0 += 1
+          if v0 > 5 then v1 += 1
+          while v0 != 10
+        again
+        exit
+      ",
+    );
+    run_until_halted(&mut chip8);
+    assert_eq!(chip8.get_register(0), 10);
+    assert_eq!(chip8.get_register(1), 5);
+  }
+
+  #[test]
+  fn flag_wins_when_vf_is_the_target() {
+    let mut chip8 = with_octo(
+      Quirks::modern(),
+      "
+      : main
+        vf := 200  vf += vf  v0 := vf
+        vf := 5  v1 := 10  vf -= v1  v1 := vf
+        vf := 3  vf <<= vf  v2 := vf
+        exit
+      ",
+    );
+    run_until_halted(&mut chip8);
+    assert_eq!(chip8.get_register(0), 1);
+    assert_eq!(chip8.get_register(1), 0);
+    assert_eq!(chip8.get_register(2), 0);
+  }
+
+  #[test]
+  fn tracer_dumps_on_errors() {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+      fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buffer)
+      }
+
+      fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+      }
+    }
+
+    let output = Shared::default();
+    let mut chip8 = with_octo(Quirks::modern(), ": main v0 := 1 v1 := 2 return");
+    chip8.set_tracer(Some(Tracer::ring_buffer(Box::new(output.clone()), 2)));
+    emulate_cycles(&mut chip8, 2);
+    assert!(output.0.lock().unwrap().is_empty());
+
+    assert!(chip8.emulate_cycle().is_err());
+    let trace = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = trace.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("0202 6102 LD V1, 0x02"));
+    assert!(lines[1].starts_with("0204 00EE RET"));
+    assert!(lines[1].contains("V=01 02 00"));
   }
 }
@@ -0,0 +1,149 @@
+/// The names `Quirks::preset` knows, oldest interpreter first.
+pub const PRESETS: [&str; 4] = ["cosmac_vip", "chip48", "super_chip", "modern"];
+
+/// Behaviours that differ between CHIP-8 interpreters.
+///
+/// ROMs are usually written against one specific interpreter, so these
+/// should be picked per ROM. The presets cover the most common targets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quirks {
+  /// `8XY6`/`8XYE` copy VY into VX before shifting.
+  pub shift_uses_vy: bool,
+  /// `FX55`/`FX65` leave I pointing after the last register accessed.
+  pub load_store_increments_index: bool,
+  /// `BNNN` is read as `BXNN`, jumping to `XNN + VX`.
+  pub jump_uses_vx: bool,
+  /// `8XY1`, `8XY2` and `8XY3` reset VF to zero.
+  pub logic_resets_vf: bool,
+  /// Sprites going past the edge of the screen wrap around to the other side.
+  pub wrap_sprites: bool,
+  /// `FX0A` waits for the key to be released, instead of taking it as soon
+  /// as it is pressed.
+  pub key_wait_on_release: bool,
+  /// All 64 KiB of XO-CHIP memory can be addressed, instead of the 4 KiB of
+  /// CHIP-8 and SUPER-CHIP.
+  pub extended_memory: bool,
+}
+
+impl Quirks {
+  pub fn cosmac_vip() -> Quirks {
+    Quirks {
+      shift_uses_vy: true,
+      load_store_increments_index: true,
+      jump_uses_vx: false,
+      logic_resets_vf: true,
+      wrap_sprites: false,
+      key_wait_on_release: true,
+      extended_memory: false,
+    }
+  }
+
+  pub fn chip48() -> Quirks {
+    Quirks {
+      shift_uses_vy: false,
+      load_store_increments_index: true,
+      jump_uses_vx: true,
+      logic_resets_vf: false,
+      wrap_sprites: false,
+      key_wait_on_release: false,
+      extended_memory: false,
+    }
+  }
+
+  pub fn super_chip() -> Quirks {
+    Quirks {
+      shift_uses_vy: false,
+      load_store_increments_index: false,
+      jump_uses_vx: true,
+      logic_resets_vf: false,
+      wrap_sprites: false,
+      key_wait_on_release: false,
+      extended_memory: false,
+    }
+  }
+
+  pub fn modern() -> Quirks {
+    Quirks {
+      shift_uses_vy: true,
+      load_store_increments_index: true,
+      jump_uses_vx: false,
+      logic_resets_vf: false,
+      wrap_sprites: true,
+      key_wait_on_release: true,
+      extended_memory: true,
+    }
+  }
+
+  /// The preset called `name`, one of `PRESETS`.
+  pub fn preset(name: &str) -> Option<Quirks> {
+    match name {
+      "cosmac_vip" => Some(Quirks::cosmac_vip()),
+      "chip48" => Some(Quirks::chip48()),
+      "super_chip" => Some(Quirks::super_chip()),
+      "modern" => Some(Quirks::modern()),
+      _ => None,
+    }
+  }
+
+  /// Packs the flags into a byte, one bit per field in declaration order.
+  pub(crate) fn to_bits(self) -> u8 {
+    [
+      self.shift_uses_vy,
+      self.load_store_increments_index,
+      self.jump_uses_vx,
+      self.logic_resets_vf,
+      self.wrap_sprites,
+      self.key_wait_on_release,
+      self.extended_memory,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |bits, (position, flag)| bits | (*flag as u8) << position)
+  }
+
+  pub(crate) fn from_bits(bits: u8) -> Quirks {
+    let flag = |position: u8| bits & (1 << position) != 0;
+    Quirks {
+      shift_uses_vy: flag(0),
+      load_store_increments_index: flag(1),
+      jump_uses_vx: flag(2),
+      logic_resets_vf: flag(3),
+      wrap_sprites: flag(4),
+      key_wait_on_release: flag(5),
+      extended_memory: flag(6),
+    }
+  }
+}
+
+impl Default for Quirks {
+  fn default() -> Quirks {
+    Quirks::modern()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bits_round_trip() {
+    for quirks in [
+      Quirks::cosmac_vip(),
+      Quirks::chip48(),
+      Quirks::super_chip(),
+      Quirks::modern(),
+    ]
+    .iter()
+    {
+      assert_eq!(Quirks::from_bits(quirks.to_bits()), *quirks);
+    }
+  }
+
+  #[test]
+  fn presets_by_name() {
+    assert_eq!(Quirks::preset("cosmac_vip"), Some(Quirks::cosmac_vip()));
+    assert_eq!(Quirks::preset("modern"), Some(Quirks::default()));
+    assert_eq!(Quirks::preset("xo_chip"), None);
+    assert!(PRESETS.iter().all(|name| Quirks::preset(name).is_some()));
+  }
+}
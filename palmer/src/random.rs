@@ -0,0 +1,105 @@
+/// Where `CXNN` gets its random bytes from.
+pub trait RandomSource {
+  fn next_byte(&mut self) -> u8;
+
+  /// Where the source is in its sequence, for save states. Sources that
+  /// can't be saved return `None`, and keep going as they are when a state
+  /// is loaded.
+  fn state(&self) -> Option<u64> {
+    None
+  }
+
+  /// Goes back to a `state` this kind of source returned.
+  fn restore(&mut self, _state: u64) {}
+}
+
+/// A xorshift64* generator. It only uses integer arithmetic, so a seed
+/// produces the same bytes on every target, wasm32 included.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct XorShift {
+  state: u64,
+}
+
+impl XorShift {
+  pub const DEFAULT_SEED: u64 = 0x5850_4F38;
+
+  pub fn new(seed: u64) -> XorShift {
+    // Scramble the seed with SplitMix64 so that small or similar seeds still
+    // start far apart. Xorshift never leaves the all-zero state, so skip it.
+    let mut state = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    state = (state ^ (state >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    state = (state ^ (state >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    state ^= state >> 31;
+
+    XorShift {
+      state: if state == 0 {
+        Self::DEFAULT_SEED
+      } else {
+        state
+      },
+    }
+  }
+
+  pub fn next_u64(&mut self) -> u64 {
+    self.state ^= self.state >> 12;
+    self.state ^= self.state << 25;
+    self.state ^= self.state >> 27;
+    self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+  }
+}
+
+impl Default for XorShift {
+  fn default() -> XorShift {
+    XorShift::new(Self::DEFAULT_SEED)
+  }
+}
+
+impl RandomSource for XorShift {
+  fn next_byte(&mut self) -> u8 {
+    (self.next_u64() >> 56) as u8
+  }
+
+  fn state(&self) -> Option<u64> {
+    Some(self.state)
+  }
+
+  fn restore(&mut self, state: u64) {
+    // Zero would get it stuck, and no xorshift ever returns it.
+    if state != 0 {
+      self.state = state;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn bytes(source: &mut impl RandomSource, count: usize) -> Vec<u8> {
+    (0..count).map(|_| source.next_byte()).collect()
+  }
+
+  #[test]
+  fn same_seed_same_bytes() {
+    let mut first = XorShift::new(42);
+    let mut second = XorShift::new(42);
+    assert_eq!(bytes(&mut first, 64), bytes(&mut second, 64));
+  }
+
+  #[test]
+  fn different_seeds_different_bytes() {
+    let mut first = XorShift::new(1);
+    let mut second = XorShift::new(2);
+    assert_ne!(bytes(&mut first, 64), bytes(&mut second, 64));
+  }
+
+  #[test]
+  fn bytes_are_spread_out() {
+    let mut source = XorShift::new(0);
+    let mut seen = [false; 256];
+    for byte in bytes(&mut source, 4096) {
+      seen[byte as usize] = true;
+    }
+    assert!(seen.iter().all(|seen| *seen));
+  }
+}
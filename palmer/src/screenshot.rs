@@ -0,0 +1,154 @@
+use super::display::{Display, PALETTE};
+
+/// Characters for each pixel value: off, plane 1, plane 2 and both.
+pub const ASCII_PIXELS: [char; 4] = ['.', '#', '+', '@'];
+
+/// The visible screen as text, one line per row of pixels.
+pub fn to_ascii(display: &Display) -> String {
+  let mut text = String::with_capacity((display.width() + 1) * display.height());
+  for line in display.lines() {
+    text.extend(line.map(|pixel| ASCII_PIXELS[pixel as usize]));
+    text.push('\n');
+  }
+  text
+}
+
+/// The visible screen as a plain PBM bitmap, where a pixel is black when it
+/// is on in any plane.
+pub fn to_pbm(display: &Display) -> Vec<u8> {
+  let mut image = format!("P1\n{} {}\n", display.width(), display.height());
+  for line in display.lines() {
+    let bits: Vec<&str> = line
+      .map(|pixel| if pixel == 0 { "0" } else { "1" })
+      .collect();
+    image.push_str(&bits.join(" "));
+    image.push('\n');
+  }
+  image.into_bytes()
+}
+
+/// The visible screen as an indexed PNG in the default `PALETTE`.
+pub fn to_png(display: &Display) -> Vec<u8> {
+  let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+
+  let mut header = vec![];
+  header.extend_from_slice(&(display.width() as u32).to_be_bytes());
+  header.extend_from_slice(&(display.height() as u32).to_be_bytes());
+  // 8 bits per pixel, indexed colour, default compression, filter and no interlacing.
+  header.extend_from_slice(&[8, 3, 0, 0, 0]);
+  write_chunk(&mut png, b"IHDR", &header);
+
+  let palette: Vec<u8> = PALETTE.iter().flatten().copied().collect();
+  write_chunk(&mut png, b"PLTE", &palette);
+
+  let mut scanlines = vec![];
+  for line in display.lines() {
+    // Each scanline starts with its filter type, and none is used.
+    scanlines.push(0);
+    scanlines.extend(line);
+  }
+  write_chunk(&mut png, b"IDAT", &zlib_stored(&scanlines));
+  write_chunk(&mut png, b"IEND", &[]);
+
+  png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+  png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+  let start = png.len();
+  png.extend_from_slice(kind);
+  png.extend_from_slice(data);
+  let crc = crc32(&png[start..]);
+  png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps `data` in a zlib stream without compressing it, which is plenty
+/// for screens of at most 8 KiB and saves pulling in a deflate encoder.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+  let mut stream = vec![0x78, 0x01];
+  let mut blocks = data.chunks(0xFFFF).peekable();
+  if blocks.peek().is_none() {
+    stream.extend_from_slice(&[1, 0x00, 0x00, 0xFF, 0xFF]);
+  }
+  while let Some(block) = blocks.next() {
+    let last = blocks.peek().is_none();
+    let length = block.len() as u16;
+    stream.push(last as u8);
+    stream.extend_from_slice(&length.to_le_bytes());
+    stream.extend_from_slice(&(!length).to_le_bytes());
+    stream.extend_from_slice(block);
+  }
+  stream.extend_from_slice(&adler32(data).to_be_bytes());
+  stream
+}
+
+fn crc32(data: &[u8]) -> u32 {
+  let mut crc = 0xFFFF_FFFFu32;
+  for byte in data {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      let mask = (crc & 1).wrapping_neg();
+      crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+    }
+  }
+  !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+  let (mut a, mut b) = (1u32, 0u32);
+  for byte in data {
+    a = (a + *byte as u32) % 65521;
+    b = (b + a) % 65521;
+  }
+  (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn display() -> Display {
+    let mut display = Display::new();
+    display.set_pixel(0, 0, true);
+    display.select_planes(0b10);
+    display.set_pixel(1, 0, true);
+    display.select_planes(0b11);
+    display.set_pixel(63, 31, true);
+    display
+  }
+
+  #[test]
+  fn draws_ascii_art() {
+    let text = to_ascii(&display());
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 32);
+    assert!(lines.iter().all(|line| line.len() == 64));
+    assert!(lines[0].starts_with("#+..."));
+    assert!(lines[31].ends_with("..@"));
+  }
+
+  #[test]
+  fn writes_pbm_images() {
+    let image = String::from_utf8(to_pbm(&display())).unwrap();
+    let mut lines = image.lines();
+    assert_eq!(lines.next(), Some("P1"));
+    assert_eq!(lines.next(), Some("64 32"));
+    assert!(lines.next().unwrap().starts_with("1 1 0 0"));
+    assert!(lines.last().unwrap().ends_with("0 0 1"));
+  }
+
+  #[test]
+  fn writes_png_images() {
+    let png = to_png(&display());
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(&png[16..24], &[0, 0, 0, 64, 0, 0, 0, 32]);
+    assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xaeB`\x82");
+  }
+
+  #[test]
+  fn checksums() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+  }
+}
@@ -0,0 +1,128 @@
+use std::fmt;
+use std::path::Path;
+
+/// The source line an instruction was assembled from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+  pub address: u16,
+  pub file: String,
+  pub line: usize,
+}
+
+/// Maps the instructions of a ROM back to the lines they came from, so
+/// debuggers can work in terms of source files.
+///
+/// As text, it is one instruction per line: the address in hex, the line
+/// number and the file, e.g. `0x0204 12 game.asm`. Files are relative to
+/// the map itself, and `#` starts a comment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+  /// Sorted by address.
+  lines: Vec<SourceLine>,
+}
+
+impl SourceMap {
+  pub fn new(mut lines: Vec<SourceLine>) -> SourceMap {
+    lines.sort_by_key(|line| line.address);
+    SourceMap { lines }
+  }
+
+  pub fn parse(text: &str) -> Result<SourceMap, String> {
+    let mut lines = vec![];
+
+    for (index, line) in text.lines().enumerate() {
+      let line = line.split('#').next().unwrap().trim();
+      if line.is_empty() {
+        continue;
+      }
+      let invalid = || format!("line {}: expected `<address> <line> <file>`", index + 1);
+      let mut parts = line.splitn(3, char::is_whitespace);
+      let address = parts
+        .next()
+        .and_then(|address| address.strip_prefix("0x"))
+        .and_then(|address| u16::from_str_radix(address, 16).ok())
+        .ok_or_else(invalid)?;
+      let source_line = parts
+        .next()
+        .and_then(|source_line| source_line.parse().ok())
+        .ok_or_else(invalid)?;
+      let file = parts.next().map(str::trim).ok_or_else(invalid)?;
+      lines.push(SourceLine {
+        address,
+        file: file.to_string(),
+        line: source_line,
+      });
+    }
+
+    Ok(SourceMap::new(lines))
+  }
+
+  pub fn lines(&self) -> &[SourceLine] {
+    &self.lines
+  }
+
+  /// The line of the instruction at `address`.
+  pub fn line_at(&self, address: u16) -> Option<&SourceLine> {
+    let index = self
+      .lines
+      .binary_search_by_key(&address, |line| line.address)
+      .ok()?;
+    Some(&self.lines[index])
+  }
+
+  /// Where to stop for a breakpoint on `line` of `file`: the first
+  /// instruction of that line, or of the next line that has code. `file`
+  /// matches when it ends with the path in the map.
+  pub fn address_for(&self, file: &Path, line: usize) -> Option<&SourceLine> {
+    self
+      .lines
+      .iter()
+      .filter(|source_line| file.ends_with(&source_line.file) && source_line.line >= line)
+      .min_by_key(|source_line| (source_line.line, source_line.address))
+  }
+}
+
+impl fmt::Display for SourceMap {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for line in self.lines.iter() {
+      writeln!(f, "{:#06x} {} {}", line.address, line.line, line.file)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trips_text() {
+    let text = "0x0200 3 game.asm\n0x0202 4 game.asm\n0x0300 1 lib/sprites.asm\n";
+    let map = SourceMap::parse(text).unwrap();
+    assert_eq!(map.lines().len(), 3);
+    assert_eq!(map.to_string(), text);
+    assert!(SourceMap::parse("# just a comment\n\n")
+      .unwrap()
+      .lines()
+      .is_empty());
+    assert_eq!(
+      SourceMap::parse("0x200 game.asm"),
+      Err("line 1: expected `<address> <line> <file>`".to_string())
+    );
+  }
+
+  #[test]
+  fn finds_lines_and_addresses() {
+    let map =
+      SourceMap::parse("0x0200 3 game.asm\n0x0202 5 game.asm\n0x0204 5 game.asm\n").unwrap();
+
+    assert_eq!(map.line_at(0x202).unwrap().line, 5);
+    assert_eq!(map.line_at(0x203), None);
+
+    let file = Path::new("/home/someone/rom/game.asm");
+    assert_eq!(map.address_for(file, 3).unwrap().address, 0x200);
+    assert_eq!(map.address_for(file, 4).unwrap().address, 0x202);
+    assert_eq!(map.address_for(file, 6), None);
+    assert_eq!(map.address_for(Path::new("other.asm"), 3), None);
+  }
+}
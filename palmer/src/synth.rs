@@ -0,0 +1,278 @@
+use std::collections::VecDeque;
+
+use super::audio::{Pattern, Tone, Waveform, PATTERN_SIZE};
+use super::clock::TIMER_FREQUENCY;
+
+/// How far, in frames, the samples may lag behind the engine before the
+/// synth skips ahead. It keeps the latency bounded, and the queue of changes
+/// short when nobody asks for samples.
+const MAX_LATENCY: f64 = TIMER_FREQUENCY as f64;
+
+/// Leeway, in samples, for times that land on a sample but not exactly
+/// after rounding.
+const EPSILON: f64 = 1e-6;
+
+/// Renders the buzzer as PCM samples on the engine's clock.
+///
+/// The engine tells it when the sound timer starts and stops, in frames
+/// since it started, down to the instruction. Samples are rendered from the
+/// last one handed out onwards, so each change lands on the exact sample
+/// for its time whatever size the buffers have.
+pub struct Synth {
+  tone: Tone,
+  pattern: Option<(Pattern, f32)>,
+  changes: VecDeque<(f64, bool)>,
+  active: bool,
+  /// The samples rendered at `sample_rate` since `start`, in frames. Counting
+  /// them keeps the time of each one exact.
+  start: f64,
+  sample_rate: u32,
+  rendered: u64,
+  /// How far into the sound the next sample is: in periods for waveforms,
+  /// in bits for patterns and square waves.
+  position: f64,
+}
+
+impl Synth {
+  pub fn new() -> Synth {
+    Synth {
+      tone: Tone::default(),
+      pattern: None,
+      changes: VecDeque::new(),
+      active: false,
+      start: 0.,
+      sample_rate: TIMER_FREQUENCY,
+      rendered: 0,
+      position: 0.,
+    }
+  }
+
+  pub fn set_tone(&mut self, tone: Tone) {
+    self.tone = tone;
+  }
+
+  pub fn set_pattern(&mut self, pattern: Pattern, sample_rate: f32) {
+    self.pattern = Some((pattern, sample_rate));
+    self.position = 0.;
+  }
+
+  /// Starts or stops the buzzer at `time`, in frames.
+  pub fn set_active(&mut self, time: f64, active: bool) {
+    self.changes.push_back((time, active));
+    let oldest = time - MAX_LATENCY;
+    if self.cursor() < oldest {
+      self.start = oldest;
+      self.rendered = 0;
+      self.apply_changes();
+    }
+  }
+
+  /// Jumps to `time`, in frames, dropping what was not rendered yet but
+  /// keeping the buzzer on or off as it would be by then.
+  pub fn seek(&mut self, time: f64) {
+    if let Some(&(_, active)) = self.changes.back() {
+      self.active = active;
+    }
+    self.changes.clear();
+    self.start = time;
+    self.rendered = 0;
+  }
+
+  /// How many samples it takes to catch up with `time`.
+  pub fn pending(&self, time: f64, sample_rate: u32) -> usize {
+    let (start, rendered) = if sample_rate == self.sample_rate {
+      (self.start, self.rendered)
+    } else {
+      (self.cursor(), 0)
+    };
+    let samples = (time - start) * sample_rate as f64 / TIMER_FREQUENCY as f64;
+    ((samples + EPSILON).floor().max(0.) as u64).saturating_sub(rendered) as usize
+  }
+
+  pub fn fill(&mut self, buffer: &mut [f32], sample_rate: u32) {
+    if sample_rate != self.sample_rate {
+      self.start = self.cursor();
+      self.sample_rate = sample_rate;
+      self.rendered = 0;
+    }
+    for sample in buffer.iter_mut() {
+      self.apply_changes();
+      *sample = if self.active {
+        self.next_sample(sample_rate as f64) * self.tone.volume
+      } else {
+        0.
+      };
+      self.rendered += 1;
+    }
+  }
+
+  /// The time of the next sample, in frames.
+  fn cursor(&self) -> f64 {
+    self.start + self.rendered as f64 * TIMER_FREQUENCY as f64 / self.sample_rate as f64
+  }
+
+  fn apply_changes(&mut self) {
+    while let Some(&(time, active)) = self.changes.front() {
+      let sample = (time - self.start) * self.sample_rate as f64 / TIMER_FREQUENCY as f64;
+      if sample > self.rendered as f64 + EPSILON {
+        break;
+      }
+      self.changes.pop_front();
+      if active && !self.active {
+        self.position = 0.;
+      }
+      self.active = active;
+    }
+  }
+
+  fn next_sample(&mut self, sample_rate: f64) -> f32 {
+    let (sample, step, period) = match self.pattern {
+      Some((pattern, pattern_rate)) => {
+        let step = pattern_rate as f64 / sample_rate;
+        let level = |bit: i64| {
+          let bit = bit.rem_euclid(PATTERN_SIZE as i64 * 8) as usize;
+          if (pattern[bit / 8] >> (7 - bit % 8)) & 1 == 1 {
+            1.
+          } else {
+            -1.
+          }
+        };
+        (
+          band_limited_bits(self.position, step, level),
+          step,
+          (PATTERN_SIZE * 8) as f64,
+        )
+      }
+      None => {
+        let frequency = self.tone.frequency as f64;
+        match self.tone.waveform {
+          Waveform::Square => {
+            // Two bits per period, high then low.
+            let step = 2. * frequency / sample_rate;
+            let level = |bit: i64| if bit.rem_euclid(2) == 0 { 1. } else { -1. };
+            (band_limited_bits(self.position, step, level), step, 2.)
+          }
+          Waveform::Sawtooth => {
+            let step = frequency / sample_rate;
+            let fraction = self.position.fract();
+            let sample = 2. * fraction - 1. - 2. * poly_blep(fraction, step);
+            (sample as f32, step, 1.)
+          }
+          waveform => {
+            let step = frequency / sample_rate;
+            (waveform.sample(self.position as f32), step, 1.)
+          }
+        }
+      }
+    };
+    self.position = (self.position + step) % period;
+    sample
+  }
+}
+
+/// A wave that holds `level(bit)` through each bit at `position`, advancing
+/// `step` bits per sample, with its jumps smoothed by polyBLEP so they don't
+/// alias.
+fn band_limited_bits<F: Fn(i64) -> f32>(position: f64, step: f64, level: F) -> f32 {
+  let bit = position.floor() as i64;
+  let fraction = position - position.floor();
+  let jump = if fraction < 0.5 {
+    level(bit) - level(bit - 1)
+  } else {
+    level(bit + 1) - level(bit)
+  };
+  level(bit) + jump * poly_blep(fraction, step) as f32
+}
+
+/// What to add to a wave that jumps up by 1 whenever `fraction` wraps
+/// around, advancing `step` per sample, so the jump is smoothed over the
+/// samples next to it.
+fn poly_blep(fraction: f64, step: f64) -> f64 {
+  let width = step.min(0.5);
+  if fraction < width {
+    let x = fraction / width;
+    (2. * x - x * x - 1.) / 2.
+  } else if fraction > 1. - width {
+    let x = (fraction - 1.) / width;
+    (x * x + 2. * x + 1.) / 2.
+  } else {
+    0.
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn square(volume: f32) -> Synth {
+    let mut synth = Synth::new();
+    synth.set_tone(Tone {
+      frequency: 440.,
+      volume,
+      waveform: Waveform::Square,
+    });
+    synth
+  }
+
+  #[test]
+  fn starts_and_stops_on_the_sample() {
+    let mut synth = square(0.5);
+    synth.set_active(1.5, true);
+    synth.set_active(2.25, false);
+
+    // 128 samples per frame, and the first one of a square wave is halfway
+    // up its edge.
+    let mut buffer = [0.; 384];
+    assert_eq!(synth.pending(3., 7680), 384);
+    synth.fill(&mut buffer[..120], 7680);
+    synth.fill(&mut buffer[120..], 7680);
+    assert_eq!(synth.pending(3., 7680), 0);
+
+    assert!(buffer[..=192].iter().all(|sample| *sample == 0.));
+    assert!(buffer[193..288].iter().all(|sample| *sample != 0.));
+    assert!(buffer[288..].iter().all(|sample| *sample == 0.));
+    assert!(buffer.iter().all(|sample| sample.abs() <= 0.5));
+  }
+
+  #[test]
+  fn smooths_the_edges_of_square_waves() {
+    let mut synth = square(1.);
+    synth.set_active(0., true);
+    let mut buffer = [0.; 4800];
+    synth.fill(&mut buffer, 48000);
+
+    let steepest = buffer
+      .windows(2)
+      .map(|pair| (pair[1] - pair[0]).abs())
+      .fold(0., f32::max);
+    assert!(steepest > 1. && steepest <= 1.5, "{}", steepest);
+    assert!(buffer.iter().filter(|sample| sample.abs() == 1.).count() > 4000);
+    let mean = buffer.iter().sum::<f32>() / buffer.len() as f32;
+    assert!(mean.abs() < 0.01, "{}", mean);
+  }
+
+  #[test]
+  fn plays_patterns_instead_of_the_tone() {
+    let mut synth = square(1.);
+    let mut pattern = [0; PATTERN_SIZE];
+    pattern[0] = 0xF0;
+    synth.set_pattern(pattern, 100.);
+    synth.set_active(0., true);
+
+    // One sample per bit, so only the samples on a jump are smoothed.
+    let mut buffer = [0.; 12];
+    synth.fill(&mut buffer, 100);
+    assert_eq!(buffer[..5], [0., 1., 1., 1., 0.]);
+    assert_eq!(buffer[5..12], [-1.; 7]);
+  }
+
+  #[test]
+  fn skips_ahead_when_nobody_listens() {
+    let mut synth = square(1.);
+    for frame in 0..1000 {
+      synth.set_active(frame as f64, frame % 2 == 0);
+    }
+    assert!(synth.changes.len() <= MAX_LATENCY as usize + 1);
+    assert_eq!(synth.pending(999., 60), MAX_LATENCY as usize);
+  }
+}
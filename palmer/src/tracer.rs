@@ -0,0 +1,201 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use super::instructions;
+
+/// The machine state right before an instruction runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceEntry {
+  pub program_counter: u16,
+  pub op_code: u16,
+  pub registers: [u8; 16],
+  pub index: u16,
+  pub stack_pointer: u8,
+  pub delay_timer: u8,
+  pub sound_timer: u8,
+}
+
+/// One line per entry, in fixed columns so that traces diff cleanly:
+///
+/// `0204 7001 ADD V0, 0x01         V=01 00 .. 00 I=0000 SP=00 DT=00 ST=00`
+impl fmt::Display for TraceEntry {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mnemonic = instructions::decode(self.op_code).to_string();
+    write!(
+      f,
+      "{:04X} {:04X} {:<20} V=",
+      self.program_counter, self.op_code, mnemonic
+    )?;
+    for (register, value) in self.registers.iter().enumerate() {
+      let separator = if register == 15 { "" } else { " " };
+      write!(f, "{:02X}{}", value, separator)?;
+    }
+    write!(
+      f,
+      " I={:04X} SP={:02X} DT={:02X} ST={:02X}",
+      self.index, self.stack_pointer, self.delay_timer, self.sound_timer
+    )
+  }
+}
+
+/// Records every executed instruction as a `TraceEntry` line.
+///
+/// By default lines are written as they happen. In ring buffer mode only
+/// the last entries are kept, and they are written out by `dump`, which the
+/// engine calls when it hits an `EmulationError` and the debugger calls
+/// when it stops at a breakpoint or watchpoint.
+///
+/// Tracing must not stop the emulator, so write errors are kept for
+/// `error` instead of being returned, and nothing is written after one.
+pub struct Tracer<W: Write = Box<dyn Write + Send>> {
+  output: W,
+  addresses: Option<Range<usize>>,
+  ring: Option<(usize, VecDeque<TraceEntry>)>,
+  error: Option<io::Error>,
+}
+
+impl<W: Write> Tracer<W> {
+  pub fn new(output: W) -> Tracer<W> {
+    Tracer {
+      output,
+      addresses: None,
+      ring: None,
+      error: None,
+    }
+  }
+
+  /// A tracer that only keeps the last `capacity` entries until `dump`.
+  pub fn ring_buffer(output: W, capacity: usize) -> Tracer<W> {
+    Tracer {
+      ring: Some((capacity, VecDeque::with_capacity(capacity))),
+      ..Tracer::new(output)
+    }
+  }
+
+  /// Only records instructions whose address is in `addresses`.
+  pub fn set_address_range(&mut self, addresses: Option<Range<usize>>) {
+    self.addresses = addresses;
+  }
+
+  pub fn record(&mut self, entry: TraceEntry) {
+    if let Some(addresses) = &self.addresses {
+      if !addresses.contains(&(entry.program_counter as usize)) {
+        return;
+      }
+    }
+
+    match &mut self.ring {
+      Some((capacity, entries)) => {
+        if *capacity == 0 {
+          return;
+        }
+        if entries.len() == *capacity {
+          entries.pop_front();
+        }
+        entries.push_back(entry);
+      }
+      None => self.write(&entry),
+    }
+  }
+
+  /// Writes out and forgets the entries kept in ring buffer mode.
+  pub fn dump(&mut self) {
+    let entries = match &mut self.ring {
+      Some((_, entries)) => std::mem::take(entries),
+      None => return,
+    };
+    for entry in entries.iter() {
+      self.write(entry);
+    }
+    if self.error.is_none() {
+      if let Err(error) = self.output.flush() {
+        self.error = Some(error);
+      }
+    }
+  }
+
+  /// The write error that stopped the trace, if any.
+  pub fn error(&self) -> Option<&io::Error> {
+    self.error.as_ref()
+  }
+
+  pub fn into_inner(self) -> W {
+    self.output
+  }
+
+  fn write(&mut self, entry: &TraceEntry) {
+    if self.error.is_some() {
+      return;
+    }
+    if let Err(error) = writeln!(self.output, "{}", entry) {
+      self.error = Some(error);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(program_counter: u16) -> TraceEntry {
+    TraceEntry {
+      program_counter,
+      op_code: 0x7001,
+      registers: [0; 16],
+      index: 0x300,
+      stack_pointer: 1,
+      delay_timer: 2,
+      sound_timer: 3,
+    }
+  }
+
+  fn lines(tracer: Tracer<Vec<u8>>) -> Vec<String> {
+    String::from_utf8(tracer.into_inner())
+      .unwrap()
+      .lines()
+      .map(str::to_string)
+      .collect()
+  }
+
+  #[test]
+  fn formats_entries() {
+    let mut entry = entry(0x204);
+    entry.registers[0] = 0xAB;
+    entry.registers[15] = 0x01;
+    assert_eq!(
+      entry.to_string(),
+      "0204 7001 ADD V0, 0x01         \
+       V=AB 00 00 00 00 00 00 00 00 00 00 00 00 00 00 01 I=0300 SP=01 DT=02 ST=03"
+    );
+  }
+
+  #[test]
+  fn filters_by_address() {
+    let mut tracer = Tracer::new(vec![]);
+    tracer.set_address_range(Some(0x202..0x206));
+    for address in (0x200..0x208).step_by(2) {
+      tracer.record(entry(address));
+    }
+    let lines = lines(tracer);
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("0202"));
+    assert!(lines[1].starts_with("0204"));
+  }
+
+  #[test]
+  fn ring_buffer_keeps_the_last_entries() {
+    let mut tracer = Tracer::ring_buffer(vec![], 2);
+    for address in (0x200..0x208).step_by(2) {
+      tracer.record(entry(address));
+    }
+    assert!(tracer.output.is_empty());
+    tracer.dump();
+    tracer.dump();
+    let lines = lines(tracer);
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("0204"));
+    assert!(lines[1].starts_with("0206"));
+  }
+}
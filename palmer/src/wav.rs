@@ -0,0 +1,50 @@
+/// Mono samples between -1 and 1 as a 16-bit PCM WAV file.
+pub fn to_wav(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+  const CHANNELS: u16 = 1;
+  const BYTES_PER_SAMPLE: u16 = 2;
+
+  let data_size = samples.len() as u32 * BYTES_PER_SAMPLE as u32;
+  let mut wav = Vec::with_capacity(44 + data_size as usize);
+  wav.extend_from_slice(b"RIFF");
+  wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+  wav.extend_from_slice(b"WAVE");
+
+  wav.extend_from_slice(b"fmt ");
+  wav.extend_from_slice(&16u32.to_le_bytes());
+  // Uncompressed PCM.
+  wav.extend_from_slice(&1u16.to_le_bytes());
+  wav.extend_from_slice(&CHANNELS.to_le_bytes());
+  wav.extend_from_slice(&sample_rate.to_le_bytes());
+  let block_align = CHANNELS * BYTES_PER_SAMPLE;
+  wav.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+  wav.extend_from_slice(&block_align.to_le_bytes());
+  wav.extend_from_slice(&(BYTES_PER_SAMPLE * 8).to_le_bytes());
+
+  wav.extend_from_slice(b"data");
+  wav.extend_from_slice(&data_size.to_le_bytes());
+  for sample in samples {
+    let sample = (sample.clamp(-1., 1.) * i16::MAX as f32).round() as i16;
+    wav.extend_from_slice(&sample.to_le_bytes());
+  }
+  wav
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn writes_16_bit_pcm() {
+    let wav = to_wav(&[0., 0.5, -1., 2.], 8000);
+    assert_eq!(wav.len(), 44 + 8);
+    assert_eq!(&wav[..4], b"RIFF");
+    assert_eq!(&wav[4..8], &44u32.to_le_bytes());
+    assert_eq!(&wav[8..16], b"WAVEfmt ");
+    assert_eq!(&wav[20..24], &[1, 0, 1, 0]);
+    assert_eq!(&wav[24..28], &8000u32.to_le_bytes());
+    assert_eq!(&wav[28..32], &16000u32.to_le_bytes());
+    assert_eq!(&wav[32..36], &[2, 0, 16, 0]);
+    assert_eq!(&wav[36..44], b"data\x08\0\0\0");
+    assert_eq!(&wav[44..], &[0, 0, 0x00, 0x40, 0x01, 0x80, 0xFF, 0x7F]);
+  }
+}
@@ -0,0 +1,229 @@
+//! Runs every ROM in `tests/roms` headless under each quirk preset and
+//! compares the final screen against the golden images in `tests/golden`.
+//!
+//! ROMs are either `.ch8` binaries or `.8o` Octo sources. The golden image
+//! for `name.ch8` is `name.txt`, as written by `screenshot::to_ascii`, unless
+//! a preset has its own `name.<preset>.txt`. ROMs that wait for input get
+//! their key presses from `name.keys`, one `press <frame>:<key>` or
+//! `release <frame>:<key>` per line. Run the tests with `PALMER_BLESS=1` to
+//! write the golden images of new or changed ROMs.
+
+use palmer::audio::Silent;
+use palmer::headless::{KeyEvent, Script};
+use palmer::{octo, screenshot, Chip8, Quirks};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Two seconds at 60 Hz, plenty for test ROMs to draw their results.
+const FRAMES: usize = 120;
+
+type Preset = (&'static str, fn() -> Quirks);
+
+const PRESETS: [Preset; 4] = [
+  ("cosmac_vip", Quirks::cosmac_vip),
+  ("chip48", Quirks::chip48),
+  ("super_chip", Quirks::super_chip),
+  ("modern", Quirks::modern),
+];
+
+fn tests_dir() -> PathBuf {
+  Path::new(env!("CARGO_MANIFEST_DIR")).join("tests")
+}
+
+fn roms() -> Vec<PathBuf> {
+  let mut roms: Vec<PathBuf> = fs::read_dir(tests_dir().join("roms"))
+    .unwrap()
+    .map(|entry| entry.unwrap().path())
+    .filter(|path| matches!(extension(path), Some("ch8") | Some("8o")))
+    .collect();
+  roms.sort();
+  roms
+}
+
+fn extension(path: &Path) -> Option<&str> {
+  path.extension().and_then(|extension| extension.to_str())
+}
+
+fn load(path: &Path) -> Result<Vec<u8>, String> {
+  let bytes = fs::read(path).map_err(|error| error.to_string())?;
+  if extension(path) == Some("8o") {
+    let source = String::from_utf8(bytes).map_err(|error| error.to_string())?;
+    octo::compile(&source).map_err(|error| error.to_string())
+  } else {
+    Ok(bytes)
+  }
+}
+
+/// The script for the ROM at `path`, with the keys from its `.keys` file.
+fn script(path: &Path) -> Result<Script, String> {
+  let mut script = Script::new(FRAMES);
+  let keys = match fs::read_to_string(path.with_extension("keys")) {
+    Ok(keys) => keys,
+    Err(_) => return Ok(script),
+  };
+  for line in keys.lines().map(str::trim) {
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let mut parts = line.splitn(2, ' ');
+    let pressed = match parts.next() {
+      Some("press") => true,
+      Some("release") => false,
+      _ => return Err(format!("invalid key script line `{}`", line)),
+    };
+    script
+      .keys
+      .push(KeyEvent::parse(parts.next().unwrap_or("").trim(), pressed)?);
+  }
+  Ok(script)
+}
+
+fn run(rom: &[u8], script: &Script, quirks: Quirks) -> Result<String, String> {
+  let mut chip8 = Chip8::new(Silent, quirks);
+  chip8
+    .load(rom.to_vec())
+    .map_err(|error| error.to_string())?;
+  script.run(&mut chip8).map_err(|error| error.to_string())?;
+  Ok(screenshot::to_ascii(&chip8.display))
+}
+
+/// Both screens side by side, with the lines that differ marked by `!`.
+fn diff(expected: &str, actual: &str) -> String {
+  let expected: Vec<&str> = expected.lines().collect();
+  let actual: Vec<&str> = actual.lines().collect();
+  let width = expected
+    .iter()
+    .map(|line| line.len())
+    .fold("expected".len(), usize::max);
+  let mut text = format!("  {:<width$} | actual\n", "expected", width = width);
+  for index in 0..expected.len().max(actual.len()) {
+    let left = expected.get(index).copied().unwrap_or("");
+    let right = actual.get(index).copied().unwrap_or("");
+    let marker = if left == right { ' ' } else { '!' };
+    text.push_str(&format!(
+      "{} {:<width$} | {}\n",
+      marker,
+      left,
+      right,
+      width = width
+    ));
+  }
+  text
+}
+
+/// Writes one golden image when every preset draws the same screen, and one
+/// per preset otherwise.
+fn bless(golden_dir: &Path, name: &str, screens: &[(&str, String)]) {
+  let shared = screens.iter().all(|(_, screen)| *screen == screens[0].1);
+  let base = golden_dir.join(format!("{}.txt", name));
+  if shared {
+    fs::write(&base, &screens[0].1).unwrap();
+  } else if base.exists() {
+    fs::remove_file(&base).unwrap();
+  }
+  for (preset, screen) in screens {
+    let path = golden_dir.join(format!("{}.{}.txt", name, preset));
+    if !shared {
+      fs::write(&path, screen).unwrap();
+    } else if path.exists() {
+      fs::remove_file(&path).unwrap();
+    }
+  }
+}
+
+#[test]
+fn roms_match_their_golden_images() {
+  let golden_dir = tests_dir().join("golden");
+  let blessing = env::var_os("PALMER_BLESS").is_some();
+  let mut failures = vec![];
+
+  for path in roms() {
+    let name = path.file_stem().unwrap().to_string_lossy().to_string();
+    let (rom, script) = match load(&path).and_then(|rom| Ok((rom, script(&path)?))) {
+      Ok(loaded) => loaded,
+      Err(error) => {
+        failures.push(format!("{}: {}", name, error));
+        continue;
+      }
+    };
+
+    let mut screens = vec![];
+    for (preset, quirks) in PRESETS.iter() {
+      match run(&rom, &script, quirks()) {
+        Ok(screen) => screens.push((*preset, screen)),
+        Err(error) => failures.push(format!("{} ({}): {}", name, preset, error)),
+      }
+    }
+    if screens.len() < PRESETS.len() {
+      continue;
+    }
+
+    if blessing {
+      bless(&golden_dir, &name, &screens);
+      continue;
+    }
+
+    for (preset, screen) in screens.iter() {
+      let own = golden_dir.join(format!("{}.{}.txt", name, preset));
+      let golden_path = if own.exists() {
+        own
+      } else {
+        golden_dir.join(format!("{}.txt", name))
+      };
+      match fs::read_to_string(&golden_path) {
+        Ok(golden) if golden == *screen => {}
+        Ok(golden) => failures.push(format!(
+          "{} ({}) doesn't match {}:\n{}",
+          name,
+          preset,
+          golden_path.display(),
+          diff(&golden, screen)
+        )),
+        Err(error) => failures.push(format!(
+          "{} ({}): {}: {}, run with PALMER_BLESS=1 to create it",
+          name,
+          preset,
+          golden_path.display(),
+          error
+        )),
+      }
+    }
+  }
+
+  assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn decode_cache_changes_nothing() {
+  for path in roms() {
+    let rom = load(&path).unwrap();
+    let script = script(&path).unwrap();
+    for (preset, quirks) in PRESETS.iter() {
+      let states: Vec<Vec<u8>> = [true, false]
+        .iter()
+        .map(|cached| {
+          let mut chip8 = Chip8::new(Silent, quirks());
+          chip8.set_decode_cache(*cached);
+          chip8.load(rom.clone()).unwrap();
+          let _ = script.run(&mut chip8);
+          chip8.save_state()
+        })
+        .collect();
+      assert!(
+        states[0] == states[1],
+        "{} ({}) runs differently with the decode cache",
+        path.display(),
+        preset
+      );
+    }
+  }
+}
+
+#[test]
+fn diff_marks_changed_lines() {
+  assert_eq!(
+    diff("..\n#.\n", "..\n.#\n"),
+    "  expected | actual\n  ..       | ..\n! #.       | .#\n"
+  );
+}